@@ -13,6 +13,7 @@ _xcp() {
 
   local options=(
     -T
+    -t
     -g
     -h
     -n
@@ -21,16 +22,28 @@ _xcp() {
     -v
     -w
     -L
+    -H
+    -x
+    -S
     "$(_parse_help "$1" -h)" # long options will be parsed from `--help`
   )
   local units='B K M G' # in line with most completions prefer M to MB/MiB
-  local drivers='parfile parblock'
+  local drivers='parfile parblock sequential auto'
   local reflink='auto always never'
-  local backup='none numbered auto'
+  local copy_method='cfr rw mmap'
+  local backup='none numbered auto simple'
+  local sparse='auto always never'
+  local progress='bar auto always json none never'
+  local manifest='text json csv'
+  local types='f d l s'
 
   case "$prev" in
   -h | --help) return ;;
 
+  --exclude | --include | --rename) return ;; # free-form patterns
+  -e | --rsh) return ;; # free-form commands
+  --chmod | --dir-mode) return ;; # free-form modes
+
   --block-size)
     if [[ -z $cur ]]; then
       COMPREPLY=(1M) # replace "nothing" with the default block size
@@ -42,23 +55,55 @@ _xcp() {
     return
     ;;
 
+  --bwlimit | --offset | --length | --min-size | --max-size | --max-total-size)
+    local num="${cur%%[^0-9]*}"
+    local unit="${cur##*[0-9]}"
+    COMPREPLY=($(compgen -P "$num" -W "$units" -- "$unit"))
+    return
+    ;;
+
   --reflink)
     COMPREPLY=($(compgen -W "$reflink" -- "$cur"))
     return
     ;;
 
+  --copy-method)
+    COMPREPLY=($(compgen -W "$copy_method" -- "$cur"))
+    return
+    ;;
+
   --backup)
     COMPREPLY=($(compgen -W "$backup" -- "$cur"))
     return
     ;;
 
+  --sparse)
+    COMPREPLY=($(compgen -W "$sparse" -- "$cur"))
+    return
+    ;;
+
+  --type)
+    COMPREPLY=($(compgen -W "$types" -- "$cur"))
+    return
+    ;;
+
+  --manifest-format)
+    COMPREPLY=($(compgen -W "$manifest" -- "$cur"))
+    return
+    ;;
+
+  --progress)
+    COMPREPLY=($(compgen -W "$progress" -- "$cur"))
+    return
+    ;;
+
   --driver)
     COMPREPLY=($(compgen -W "$drivers" -- "$cur"))
     return
     ;;
 
   -w | --workers)
-    COMPREPLY=($(compgen -W "{0..$(_ncpus)}" -- "$cur")) # 0 == auto
+    COMPREPLY=($(compgen -W "{1..$(_ncpus)}" -- "$cur"))
     return
     ;;
   esac
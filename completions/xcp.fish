@@ -1,6 +1,8 @@
 set -l drivers '
   parfile\t"parallelise at the file level (default)"
   parblock\t"parallelise at the block level"
+  sequential\t"copy one file at a time"
+  auto\t"choose a driver based on the source files"
 '
 
 set -l reflinks '
@@ -9,35 +11,106 @@ set -l reflinks '
   never\t"always perform a full data copy"
 '
 
+set -l copy_methods '
+  cfr\t"use copy_file_range where available (default)"
+  rw\t"read and write through a buffer"
+  mmap\t"write from a memory mapping of the source"
+'
+
 set -l backup '
   none\t"no backups (default)"
   numbered\t"follow the semantics of cp numbered backups"
   auto\t"create a numbered backup if previous backup exists"
+  simple\t"create a single backup with a suffix"
 '
 
 # short + long
 complete -c xcp -s T -l no-target-directory -d 'Overwrite target directory, do not create a subdirectory'
+complete -c xcp -l copy-contents -d 'Copy the contents of source directories given with a trailing /'
+complete -c xcp -s D -l parents -d 'Create missing parent directories of the destination'
+complete -c xcp -s R -l relative -d 'Copy sources to their full paths under the destination'
 complete -c xcp -s g -l glob -d 'Expand (glob) filename patterns'
 complete -c xcp -s h -l help -f -d 'Print help'
 complete -c xcp -s n -l no-clobber -d 'Do not overwrite an existing file'
+complete -c xcp -s u -l update -d 'Only copy files that are newer than the destination'
+complete -c xcp -l ignore-existing -d 'Only copy files that do not exist at the destination'
+complete -c xcp -l quick-check -d 'Skip files with the same size and modification time'
+complete -c xcp -l size-only -d 'Skip files with the same size'
+complete -c xcp -s c -l checksum -d 'Skip files with the same contents'
+complete -c xcp -l min-size -d 'Only copy files of at least this size' -x -a '(seq 1 16){B,K,M,G}'
+complete -c xcp -l max-size -d 'Only copy files of at most this size' -x -a '(seq 1 16){B,K,M,G}'
+complete -c xcp -l max-total-size -d 'Stop once the files queued total this size' -x -a '(seq 1 16){B,K,M,G}'
+complete -c xcp -l delete -d 'Delete destination files that are not in the source'
 complete -c xcp -s f -l force -d 'Compatibility only option'
 complete -c xcp -s r -l recursive -d 'Copy directories recursively'
+complete -c xcp -s d -l no-recursive -l no-directory-recursion -d 'Copy directories without recursing into them'
+complete -c xcp -l dirs-only -d 'Create only the directories of the source tree'
+complete -c xcp -l type -d 'Copy only entries of the given types' -x -a 'f d l s'
 complete -c xcp -s v -l verbose -d 'Increase verbosity (can be repeated)'
-complete -c xcp -s w -l workers -d 'Workers for recursive copies (0=auto)' -x -a '(seq 0 (getconf _NPROCESSORS_ONLN))'
+complete -c xcp -s q -l quiet -d 'Suppress non-error output'
+complete -c xcp -s w -l workers -d 'Number of parallel workers' -x -a '(seq 1 (getconf _NPROCESSORS_ONLN))'
 complete -c xcp -s L -l dereference -d 'Dereference symlinks in source'
+complete -c xcp -s H -l dereference-command-line -d 'Dereference symlinks given as sources'
+complete -c xcp -l read-special -d 'Copy the contents of FIFOs and devices given as sources'
+complete -c xcp -l pipe-through -d 'Stream data between source and destination FIFOs'
+complete -c xcp -s x -l one-file-system -d 'Stay on the source filesystem'
+complete -c xcp -s S -l suffix -d 'Suffix for simple backups' -x
 complete -c xcp -s o -l ownership -d 'Copy ownship (user/group)'
+complete -c xcp -l numeric-ids -d 'Compatibility only option'
 
 # long
 complete -c xcp -l fsync -d 'Sync each file to disk after it is written'
-complete -c xcp -l target-directory -d 'Copy into a subdirectory of the target'
+complete -c xcp -l sync-dest-dirs -d 'Sync the target directories to disk once the copy is complete'
+complete -c xcp -l no-cache -d 'Avoid filling the page cache with the copied data'
+complete -c xcp -l direct-io -d 'Copy with direct I/O, bypassing the page cache'
+complete -c xcp -s t -l target-directory -d 'Copy all sources into DIR' -r
+complete -c xcp -l tee -d 'Also copy the sources to DEST, reading them only once' -r
+complete -c xcp -s e -l rsh -d 'Remote shell used for remote destinations' -x
+complete -c xcp -l from-file -d 'Read the sources from a file, or stdin if -' -r
+complete -c xcp -l from0 -d 'The --from-file paths are NUL-separated'
+complete -c xcp -l manifest -d 'Write a manifest of the copied files' -r
+complete -c xcp -l manifest-format -d 'The format of the manifest' -x -a 'text json csv'
+complete -c xcp -l checkpoint -d 'Record the completed files, to resume an interrupted copy' -r
+complete -c xcp -l dedup -d 'Link files with identical contents to the first copy of them'
 complete -c xcp -l gitignore -d 'Use .gitignore if present'
+complete -c xcp -l ignore-file -d 'Ignore files matching the rules in a file' -r
+complete -c xcp -l no-gitignore -d "Don't read any ignore files"
+complete -c xcp -l max-depth -d 'Descend at most N directory levels' -x
+complete -c xcp -l exclude -d 'Exclude files matching a pattern' -x
+complete -c xcp -l include -d 'Include files matching a pattern' -x
 complete -c xcp -l no-perms -d 'Do not copy file permissions'
+complete -c xcp -l no-preserve-special-bits -d 'Clear the setuid, setgid and sticky bits of copied files'
 complete -c xcp -l no-timestamps -d 'Do not copy file timestamps'
+complete -c xcp -l no-xattr -d 'Do not copy extended attributes'
 complete -c xcp -l no-progress -d 'Disable progress bar'
+complete -c xcp -l progress -d 'How to report progress' -x -a 'bar auto always json none never'
+complete -c xcp -l progress-interval -d 'Update the progress at most once every MS milliseconds' -x
+complete -c xcp -l max-open-files -d 'Maximum number of files to hold open at once' -x
+complete -c xcp -l retries -d 'Retry transient block-copy errors N times' -x
 complete -c xcp -l block-size -d 'Block size for file operations' -x -a '(seq 1 16){B,K,M,G}'
+complete -c xcp -l auto-block-size -d 'Pick the block size for each file automatically'
+complete -c xcp -l bwlimit -d 'Limit the copy rate in bytes per second' -x -a '(seq 1 16){B,K,M,G}'
+complete -c xcp -l timeout -d 'Fail any file that takes longer than this many seconds' -x
+complete -c xcp -l offset -d 'Only copy the source file from this byte offset' -x
+complete -c xcp -l length -d 'Only copy this many bytes of the source file' -x
+complete -c xcp -l append -l partial -d 'Resume an interrupted copy of a single file'
 complete -c xcp -l driver -d 'Parallelise at the file or at the block level' -x -a "$drivers"
 complete -c xcp -l reflink -d 'Whether and how to use reflinks' -x -a "$reflinks"
+complete -c xcp -l copy-method -d 'How to copy file data' -x -a "$copy_methods"
+complete -c xcp -l warn-no-reflink -d 'Warn about files that could not be reflinked'
 complete -c xcp -l backup -d 'Whether to create backups of overwritten files' -x -a "$backup"
+complete -c xcp -l sparse -d 'How to handle sparse files' -x -a 'auto always never'
+complete -c xcp -l no-punch-holes -d 'Write the holes of sparse files as zeros'
+complete -c xcp -l preserve -d 'Preserve the specified attributes' -x -a 'mode ownership timestamps xattr links acl flags all'
+complete -c xcp -l continue-on-error -d 'Continue copying after an error'
+complete -c xcp -l dry-run -d 'Show what would be copied'
+complete -c xcp -l remove-source-files -d 'Remove source files once copied'
+complete -c xcp -l strict -d 'Fail files that change while being copied'
+complete -c xcp -l atomic -d 'Replace destination files atomically' -x -a 'auto always never'
+complete -c xcp -l chmod -d 'Set the permissions of copied files' -x
+complete -c xcp -l dir-mode -d 'Set the permissions of created destination directories' -x
+complete -c xcp -l rename -d 'Rename copied files and directories' -x
+complete -c xcp -l verify -d 'Verify checksums of copied files' -x -a 'xxhash sha256'
 
 # docs: https://fishshell.com/docs/current/completions.html
 # path: /usr/share/fish/vendor_completions.d/xcp.fish
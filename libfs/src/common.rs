@@ -15,47 +15,163 @@
  */
 
 
-use log::{debug, warn};
+use log::debug;
 use rustix::fs::{fsync, ftruncate};
 use rustix::io::{pread, pwrite};
-use std::cmp;
+use rustix::mm::{mmap, munmap, MapFlags, ProtFlags};
+use rustix::param::page_size;
+use std::{cmp, ptr, slice};
 use std::fs::{File, FileTimes};
-use std::io::{ErrorKind, Read, Write};
-use std::os::unix::fs::{fchown, MetadataExt};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::{fchown, lchown, MetadataExt};
 use std::path::Path;
 use xattr::FileExt;
 
 use crate::errors::{Result, Error};
 use crate::{Extent, XATTR_SUPPORTED, copy_sparse, probably_sparse, copy_file_bytes};
 
-fn copy_xattr(infd: &File, outfd: &File) -> Result<()> {
-    // FIXME: Flag for xattr.
+// Filesystems without xattr support return ENOTSUP; treat this as
+// "nothing to copy".
+fn xattr_unsupported(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+// The xattr holding a file's capabilities; see copy_capabilities().
+const CAPABILITY_XATTR: &str = "security.capability";
+
+/// Copy [xattr](https://man7.org/linux/man-pages/man7/xattr.7.html)'s
+/// between files. This is silently skipped if the OS or either
+/// filesystem does not support them. File capabilities are not
+/// included; see [copy_capabilities].
+pub fn copy_xattr(infd: &File, outfd: &File) -> Result<()> {
     if XATTR_SUPPORTED {
         debug!("Starting xattr copy...");
-        for attr in infd.list_xattr()? {
+        let attrs = match infd.list_xattr() {
+            Ok(attrs) => attrs,
+            Err(e) if xattr_unsupported(&e) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for attr in attrs.filter(|a| a != CAPABILITY_XATTR) {
             if let Some(val) = infd.get_xattr(&attr)? {
                 debug!("Copy xattr {:?}", attr);
-                outfd.set_xattr(attr, val.as_slice())?;
+                match outfd.set_xattr(&attr, val.as_slice()) {
+                    Err(e) if xattr_unsupported(&e) => return Ok(()),
+                    r => r?,
+                }
             }
         }
     }
     Ok(())
 }
 
-/// Copy file permissions. Will also copy
-/// [xattr](https://man7.org/linux/man-pages/man7/xattr.7.html)'s if
-/// possible.
-pub fn copy_permissions(infd: &File, outfd: &File) -> Result<()> {
-    let xr = copy_xattr(infd, outfd);
-    if let Err(e) = xr {
-        // FIXME: We don't have a way of detecting if the
-        // target FS supports XAttr, so assume any error is
-        // "Unsupported" for now.
-        warn!("Failed to copy xattrs from {:?}: {}", infd, e);
+/// Copy the [capabilities](https://man7.org/linux/man-pages/man7/capabilities.7.html)
+/// of a file (the `security.capability` xattr). Changing a file's
+/// owner clears its capabilities, so this should follow
+/// [copy_owner]. Setting them requires `CAP_SETFCAP`, so may fail
+/// where [copy_xattr] succeeds. Returns `false` if the source has
+/// none, or the OS or either filesystem does not support them.
+pub fn copy_capabilities(infd: &File, outfd: &File) -> Result<bool> {
+    if !XATTR_SUPPORTED {
+        return Ok(false);
+    }
+    let val = match infd.get_xattr(CAPABILITY_XATTR) {
+        Ok(Some(val)) => val,
+        Ok(None) => return Ok(false),
+        Err(e) if xattr_unsupported(&e) => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    debug!("Copy capabilities");
+    match outfd.set_xattr(CAPABILITY_XATTR, val.as_slice()) {
+        Ok(()) => Ok(true),
+        Err(e) if xattr_unsupported(&e) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Copy the xattrs of a symlink. Unlike [copy_xattr] this applies to
+/// the link itself rather than its target.
+pub fn copy_link_xattr(src: &Path, dest: &Path) -> Result<()> {
+    if XATTR_SUPPORTED {
+        let attrs = match xattr::list(src) {
+            Ok(attrs) => attrs,
+            Err(e) if xattr_unsupported(&e) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for attr in attrs {
+            if let Some(val) = xattr::get(src, &attr)? {
+                debug!("Copy link xattr {:?}", attr);
+                match xattr::set(dest, &attr, val.as_slice()) {
+                    Err(e) if xattr_unsupported(&e) => return Ok(()),
+                    r => r?,
+                }
+            }
+        }
     }
+    Ok(())
+}
 
-    // FIXME: ACLs, selinux, etc.
+// Linux stores POSIX ACLs as xattrs under these names.
+#[cfg(target_os = "linux")]
+const ACL_ACCESS: &str = "system.posix_acl_access";
+#[cfg(target_os = "linux")]
+const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+// Copy one of the ACL xattrs, removing it from the target if the
+// source has none. Returns `false` if either filesystem does not
+// support ACLs.
+#[cfg(target_os = "linux")]
+fn copy_acl_xattr(infd: &File, outfd: &File, name: &str) -> Result<bool> {
+    let res = match infd.get_xattr(name) {
+        Ok(Some(val)) => {
+            debug!("Copy ACL {:?}", name);
+            outfd.set_xattr(name, val.as_slice())
+        }
+        // The target may have inherited a default ACL from its parent.
+        Ok(None) => match outfd.remove_xattr(name) {
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => Ok(()),
+            r => r,
+        },
+        Err(e) => Err(e),
+    };
+    match res {
+        Err(e) if xattr_unsupported(&e) => Ok(false),
+        Err(e) => Err(e.into()),
+        Ok(()) => Ok(true),
+    }
+}
+
+/// Copy the access [ACL](https://man7.org/linux/man-pages/man5/acl.5.html)
+/// of a file or directory. On Linux these are stored as xattrs, and
+/// so are also copied by [copy_xattr]. Returns `false` if the OS or
+/// either filesystem does not support ACLs.
+#[cfg(target_os = "linux")]
+pub fn copy_acl(infd: &File, outfd: &File) -> Result<bool> {
+    copy_acl_xattr(infd, outfd, ACL_ACCESS)
+}
+
+/// Copy the default ACL of a directory, which is inherited by new
+/// entries within it. Returns `false` if the OS or either filesystem
+/// does not support ACLs.
+#[cfg(target_os = "linux")]
+pub fn copy_default_acl(infd: &File, outfd: &File) -> Result<bool> {
+    copy_acl_xattr(infd, outfd, ACL_DEFAULT)
+}
 
+/// ACLs are currently only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn copy_acl(_infd: &File, _outfd: &File) -> Result<bool> {
+    Ok(false)
+}
+
+/// ACLs are currently only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn copy_default_acl(_infd: &File, _outfd: &File) -> Result<bool> {
+    Ok(false)
+}
+
+/// Copy file permissions. Note that this does not include xattrs (and
+/// hence ACLs); see [copy_xattr] and [copy_acl].
+pub fn copy_permissions(infd: &File, outfd: &File) -> Result<()> {
     let inmeta = infd.metadata()?;
 
     debug!("Performing permissions copy");
@@ -77,6 +193,8 @@ pub fn copy_timestamps(infd: &File, outfd: &File) -> Result<()> {
     Ok(())
 }
 
+/// Copy file ownership (user/group). This generally requires root
+/// permissions or appropriate capabilities.
 pub fn copy_owner(infd: &File, outfd: &File) -> Result<()> {
     let inmeta = infd.metadata()?;
     fchown(outfd, Some(inmeta.uid()), Some(inmeta.gid()))?;
@@ -84,6 +202,15 @@ pub fn copy_owner(infd: &File, outfd: &File) -> Result<()> {
     Ok(())
 }
 
+/// Copy the ownership of a symlink. Unlike [copy_owner] this applies
+/// to the link itself rather than its target.
+pub fn copy_link_owner(src: &Path, dest: &Path) -> Result<()> {
+    let inmeta = src.symlink_metadata()?;
+    lchown(dest, Some(inmeta.uid()), Some(inmeta.gid()))?;
+
+    Ok(())
+}
+
 pub(crate) fn read_bytes(fd: &File, buf: &mut [u8], off: usize) -> Result<usize> {
     Ok(pread(fd, buf, off as u64)?)
 }
@@ -121,6 +248,160 @@ pub(crate) fn copy_range_uspace(reader: &File, writer: &File, nbytes: usize, off
     Ok(written)
 }
 
+// Granularity of zero-block detection; this should match the most
+// common filesystem block size.
+const ZERO_CHUNK: usize = 4096;
+const ZERO_BUF: usize = 1024 * 1024;
+
+// Whether a buffer is all zeros. Comparing a word at a time is much
+// faster than byte-by-byte, and can be vectorised.
+fn is_zeros(buf: &[u8]) -> bool {
+    let mut words = buf.chunks_exact(16);
+    words.by_ref().all(|w| u128::from_ne_bytes(w.try_into().unwrap()) == 0)
+        && words.remainder().iter().all(|b| *b == 0)
+}
+
+/// Copy a block of bytes at an offset between files, skipping any
+/// chunks that consist entirely of zeros. The destination should
+/// have been allocated with [allocate_file], in which case the skipped
+/// chunks will be left as holes. This allows a sparse destination to
+/// be created regardless of whether the source is detected as sparse.
+pub fn copy_file_offset_sparse(infd: &File, outfd: &File, bytes: u64, off: i64) -> Result<usize> {
+    let nbytes = bytes as usize;
+    let off = off as usize;
+    let mut buf = vec![0; cmp::min(nbytes, ZERO_BUF)];
+
+    let mut copied: usize = 0;
+    while copied < nbytes {
+        let next = cmp::min(nbytes - copied, buf.len());
+        let noff = off + copied;
+
+        let rlen = match read_bytes(infd, &mut buf[..next], noff) {
+            Ok(0) => return Err(Error::InvalidSource("Source file ended prematurely.")),
+            Ok(len) => len,
+            Err(e) => return Err(e),
+        };
+
+        for (i, chunk) in buf[..rlen].chunks_mut(ZERO_CHUNK).enumerate() {
+            if is_zeros(chunk) {
+                continue;
+            }
+            let clen = chunk.len();
+            if write_bytes(outfd, chunk, noff + (i * ZERO_CHUNK))? < clen {
+                return Err(Error::InvalidSource("Failed write to file."));
+            }
+        }
+
+        copied += rlen;
+    }
+    Ok(copied)
+}
+
+/// Write `bytes` zeros to a file at an offset. Unlike leaving a hole
+/// this allocates the space on disk.
+pub fn write_zeros(outfd: &File, bytes: u64, off: i64) -> Result<usize> {
+    let nbytes = bytes as usize;
+    let off = off as usize;
+    let mut buf = vec![0; cmp::min(nbytes, ZERO_BUF)];
+
+    let mut written: usize = 0;
+    while written < nbytes {
+        let next = cmp::min(nbytes - written, buf.len());
+        match write_bytes(outfd, &mut buf[..next], off + written)? {
+            0 => return Err(Error::InvalidSource("Failed write to file.")),
+            len => written += len,
+        }
+    }
+    Ok(written)
+}
+
+// The largest buffer used for a direct I/O copy.
+const DIRECT_BUF: usize = 1024 * 1024;
+
+/// Copy a block of bytes at an offset between files opened for direct
+/// I/O (see `set_direct_io`), through a buffer aligned to
+/// `align`. `bytes` and `off` must be multiples of `align`, which
+/// should be the block size of both filesystems. Unlike
+/// [copy_file_offset](crate::copy_file_offset) this never goes through
+/// the page cache.
+pub fn copy_file_offset_direct(infd: &File, outfd: &File, bytes: u64, off: i64, align: u64) -> Result<usize> {
+    let nbytes = bytes as usize;
+    let off = off as usize;
+    let align = align as usize;
+    let chunk = cmp::max(DIRECT_BUF / align, 1) * align;
+    let len = cmp::min(nbytes, chunk);
+
+    // Over-allocate, and use the aligned part.
+    let mut alloc = vec![0u8; len + align];
+    let pad = (align - alloc.as_ptr() as usize % align) % align;
+    let buf = &mut alloc[pad..pad + len];
+
+    let mut copied: usize = 0;
+    while copied < nbytes {
+        let next = cmp::min(nbytes - copied, buf.len());
+        let noff = off + copied;
+
+        let rlen = match read_bytes(infd, &mut buf[..next], noff) {
+            Ok(0) => return Err(Error::InvalidSource("Source file ended prematurely.")),
+            Ok(len) => len,
+            Err(e) => return Err(e),
+        };
+        if write_bytes(outfd, &mut buf[..rlen], noff)? < rlen {
+            return Err(Error::InvalidSource("Failed write to file."));
+        }
+
+        copied += rlen;
+    }
+    Ok(copied)
+}
+
+/// Copy a block of bytes at an offset between files with
+/// pread/pwrite, through a bounded buffer. Unlike
+/// [copy_file_offset](crate::copy_file_offset) this never uses
+/// `copy_file_range`, which can be slow on some filesystems.
+pub fn copy_file_offset_rw(infd: &File, outfd: &File, bytes: u64, off: i64) -> Result<usize> {
+    // Any buffer is aligned to a single byte.
+    copy_file_offset_direct(infd, outfd, bytes, off, 1)
+}
+
+/// Copy a block of bytes at an offset between files by mapping the
+/// source into memory and writing from the mapping. The block must
+/// lie within the source file.
+pub fn copy_file_offset_mmap(infd: &File, outfd: &File, bytes: u64, off: i64) -> Result<usize> {
+    let nbytes = bytes as usize;
+    let off = off as u64;
+    // Pages past the end of the file can't be read.
+    if off + bytes > infd.metadata()?.len() {
+        return Err(Error::InvalidSource("Source file ended prematurely."));
+    }
+    if nbytes == 0 {
+        return Ok(0);
+    }
+    // Mappings must start on a page boundary.
+    let start = off - off % page_size() as u64;
+    let pad = (off - start) as usize;
+    let len = pad + nbytes;
+
+    // SAFETY: The mapping is private to this function and unmapped
+    // before returning. As with any mapped copy, truncating the
+    // source while it is mapped raises SIGBUS.
+    let map = unsafe { mmap(ptr::null_mut(), len, ProtFlags::READ, MapFlags::SHARED, infd, start)? };
+    let data = unsafe { slice::from_raw_parts(map as *const u8, len) };
+    let mut written = 0;
+    let result = loop {
+        if written == nbytes {
+            break Ok(written);
+        }
+        match pwrite(outfd, &data[pad + written..], off + written as u64) {
+            Ok(0) => break Err(Error::InvalidSource("Failed write to file.")),
+            Ok(n) => written += n,
+            Err(e) => break Err(e.into()),
+        }
+    };
+    unsafe { munmap(map, len)? };
+    result
+}
+
 /// Slightly modified version of io::copy() that only copies a set amount of bytes.
 pub(crate) fn copy_bytes_uspace(mut reader: &File, mut writer: &File, nbytes: usize) -> Result<usize> {
     let mut buf = vec![0; nbytes];
@@ -294,6 +575,56 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_copy_file_offset_methods() {
+        let dir = tempdir().unwrap();
+        let from = dir.path().join("from.bin");
+        let data = (0..3 * 4096 + 17).map(|i| i as u8).collect::<Vec<u8>>();
+        std::fs::write(&from, &data).unwrap();
+        let infd = File::open(&from).unwrap();
+
+        type CopyFn = fn(&File, &File, u64, i64) -> Result<usize>;
+        for copy in [copy_file_offset_rw as CopyFn, copy_file_offset_mmap] {
+            let to = dir.path().join("to.bin");
+            let outfd = File::create(&to).unwrap();
+            // In reverse, with an unaligned offset.
+            assert_eq!(5000, copy(&infd, &outfd, 5000, 7305).unwrap());
+            assert_eq!(7305, copy(&infd, &outfd, 7305, 0).unwrap());
+            assert_eq!(data, read(&to).unwrap());
+            assert!(copy(&infd, &outfd, 100, data.len() as i64).is_err());
+        }
+    }
+
+    #[test]
+    fn test_is_zeros() {
+        assert!(is_zeros(&[]));
+        assert!(is_zeros(&[0; 4096]));
+        assert!(is_zeros(&[0; 21]));
+        for i in [0, 15, 16, 20] {
+            let mut buf = [0; 21];
+            buf[i] = 1;
+            assert!(!is_zeros(&buf));
+        }
+    }
+
+    #[test]
+    fn test_write_zeros() {
+        let dir = tempdir().unwrap();
+        let to = dir.path().join("to.bin");
+        let size = ZERO_BUF + 1024;
+
+        let outfd = File::create(&to).unwrap();
+        write!(&outfd, "{}", "X".repeat(size + 10)).unwrap();
+        let written = write_zeros(&outfd, size as u64, 5).unwrap();
+        assert_eq!(size, written);
+
+        let data = read(&to).unwrap();
+        assert_eq!(size + 10, data.len());
+        assert_eq!(b"XXXXX", &data[..5]);
+        assert!(data[5..size + 5].iter().all(|b| *b == 0));
+        assert_eq!(b"XXXXX", &data[size + 5..]);
+    }
+
     #[test]
     fn test_extent_merge() -> Result<()> {
         assert_eq!(merge_extents(vec!())?, vec!());
@@ -343,6 +674,28 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_copy_link_owner() -> Result<()> {
+        let dir = tempdir()?;
+        let target = dir.path().join("target.txt");
+        let from = dir.path().join("from.link");
+        let to = dir.path().join("to.link");
+
+        File::create(&target)?;
+        std::os::unix::fs::symlink(&target, &from)?;
+        std::os::unix::fs::symlink(&target, &to)?;
+
+        // Non-root users can always chown to themselves.
+        copy_link_owner(&from, &to)?;
+
+        let fmeta = from.symlink_metadata()?;
+        let tmeta = to.symlink_metadata()?;
+        assert_eq!(fmeta.uid(), tmeta.uid());
+        assert_eq!(fmeta.gid(), tmeta.gid());
+
+        Ok(())
+    }
+
     #[test]
     fn test_copy_file() -> Result<()> {
         let dir = tempdir()?;
@@ -34,4 +34,15 @@ pub enum Error {
     UnsupportedOperation,
 }
 
+impl Error {
+    /// The underlying OS error code, if any.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Error::IOError(e) => e.raw_os_error(),
+            Error::OSError(e) => Some(e.raw_os_error()),
+            _ => None,
+        }
+    }
+}
+
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -19,7 +19,7 @@ use std::path::Path;
 
 use log::warn;
 
-use crate::Extent;
+use crate::{Extent, NoReflink};
 use crate::common::{copy_bytes_uspace, copy_range_uspace};
 use crate::errors::{Result, Error};
 
@@ -47,6 +47,10 @@ pub fn next_sparse_segments(_infd: &File, _outfd: &File, _pos: u64) -> Result<(u
     Err(Error::UnsupportedOperation {})
 }
 
+pub fn leading_data_len(fd: &File) -> Result<u64> {
+    Ok(fd.metadata()?.len())
+}
+
 pub fn copy_sparse(infd: &File, outfd: &File) -> Result<u64> {
     let len = infd.metadata()?.len();
     copy_file_bytes(&infd, &outfd, len)
@@ -62,3 +66,57 @@ pub fn copy_node(src: &Path, _dest: &Path) -> Result<()> {
 pub fn reflink(_infd: &File, _outfd: &File) -> Result<bool> {
     Ok(false)
 }
+
+pub fn reflink_with_reason(_infd: &File, _outfd: &File) -> Result<Option<NoReflink>> {
+    Ok(Some(NoReflink::Unsupported))
+}
+
+pub fn reflink_extents(_infd: &File, _outfd: &File) -> Result<Option<NoReflink>> {
+    Ok(Some(NoReflink::Unsupported))
+}
+
+/// Create `dest` as a copy-on-write clone of `src`, using
+/// `clonefile(2)`. `dest` must not already exist. If the filesystem
+/// does not support cloning, or the files are on different volumes,
+/// this returns `false`.
+#[cfg(target_os = "macos")]
+pub fn clone_file(src: &Path, dest: &Path) -> Result<bool> {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+
+    let csrc = CString::new(src.as_os_str().as_bytes())
+        .map_err(|_| Error::InvalidPath(src.to_path_buf()))?;
+    let cdest = CString::new(dest.as_os_str().as_bytes())
+        .map_err(|_| Error::InvalidPath(dest.to_path_buf()))?;
+    if unsafe { libc::clonefile(csrc.as_ptr(), cdest.as_ptr(), 0) } != 0 {
+        let oserr = io::Error::last_os_error();
+        return match oserr.raw_os_error() {
+            Some(libc::ENOTSUP) | Some(libc::EXDEV) => Ok(false),
+            _ => Err(oserr.into()),
+        };
+    }
+    Ok(true)
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn clone_file(_src: &Path, _dest: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+pub fn advise_sequential(_fd: &File) -> Result<()> {
+    Ok(())
+}
+
+pub fn drop_cache(_fd: &File, _off: u64, _len: u64) -> Result<()> {
+    Ok(())
+}
+
+pub fn set_direct_io(_fd: &File, _enable: bool) -> Result<()> {
+    Err(Error::UnsupportedOperation)
+}
+
+/// Inode flags are currently only supported on Linux.
+pub fn copy_flags(_infd: &File, _outfd: &File) -> Result<bool> {
+    Ok(false)
+}
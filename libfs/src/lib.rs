@@ -17,7 +17,7 @@
 mod common;
 mod errors;
 
-use std::{fs, ops::Range};
+use std::{fmt, fs, ops::Range};
 
 use cfg_if::cfg_if;
 use rustix::fs::FileTypeExt;
@@ -32,24 +32,43 @@ cfg_if! {
     }
 }
 pub use backend::{
+    advise_sequential,
+    clone_file,
     copy_file_bytes,
+    copy_flags,
     copy_file_offset,
     copy_node,
     copy_sparse,
+    drop_cache,
+    leading_data_len,
     probably_sparse,
     next_sparse_segments,
     map_extents,
     reflink,
+    reflink_extents,
+    reflink_with_reason,
+    set_direct_io,
 };
 pub use common::{
     allocate_file,
+    copy_acl,
+    copy_capabilities,
+    copy_default_acl,
     copy_file,
+    copy_file_offset_direct,
+    copy_file_offset_mmap,
+    copy_file_offset_rw,
+    copy_file_offset_sparse,
+    copy_link_owner,
+    copy_link_xattr,
     copy_owner,
     copy_permissions,
     copy_timestamps,
+    copy_xattr,
     is_same_file,
     merge_extents,
     sync,
+    write_zeros,
 };
 pub use errors::Error;
 
@@ -68,6 +87,11 @@ pub const XATTR_SUPPORTED: bool = {
     }
 };
 
+/// Flag whether the current OS clones files by path with
+/// [clone_file], rather than with [reflink] on an open descriptor. In
+/// that case the target must not exist when it is cloned.
+pub const CLONEFILE_SUPPORTED: bool = cfg!(target_os = "macos");
+
 /// Enum mapping for various *nix file types. Mapped from
 /// [std::fs::FileType] and [rustix::fs::FileTypeExt].
 #[derive(Debug)]
@@ -104,6 +128,33 @@ impl From<fs::FileType> for FileType {
     }
 }
 
+/// The reason a [reflink] was not possible; see
+/// [reflink_with_reason].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoReflink {
+    /// The filesystem does not support reflinks (`EOPNOTSUPP`).
+    Unsupported,
+    /// The files are on different filesystems (`EXDEV`).
+    CrossDevice,
+    /// The filesystem refused to reflink these files, e.g. as they
+    /// are on different subvolumes or mounts (`EINVAL`).
+    Refused,
+    /// The source is an active swap file (`ETXTBSY`).
+    Busy,
+}
+
+impl fmt::Display for NoReflink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            NoReflink::Unsupported => "the filesystem does not support reflinks",
+            NoReflink::CrossDevice => "the files are on different filesystems",
+            NoReflink::Refused => "the filesystem refused to reflink the files",
+            NoReflink::Busy => "the file is in use as swap",
+        };
+        f.write_str(reason)
+    }
+}
+
 /// Struct representing a file extent metadata.
 #[derive(Debug, PartialEq)]
 pub struct Extent {
@@ -14,18 +14,18 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::{fs::File, path::Path};
+use std::{cmp, fs::File, path::Path};
 use std::io;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::PermissionsExt;
 
-use linux_raw_sys::ioctl::{FS_IOC_FIEMAP, FIEMAP_EXTENT_LAST, FICLONE, FIEMAP_EXTENT_SHARED};
+use linux_raw_sys::ioctl::{FS_IOC_FIEMAP, FIEMAP_EXTENT_LAST, FICLONE, FICLONERANGE, FIEMAP_EXTENT_SHARED};
 use rustix::fs::CWD;
-use rustix::{fs::{copy_file_range, seek, mknodat, FileType, Mode, RawMode, SeekFrom}, io::Errno};
+use rustix::{fs::{copy_file_range, fadvise, fcntl_getfl, fcntl_setfl, ioctl_getflags, ioctl_setflags, seek, mknodat, Advice, FileType, IFlags, Mode, OFlags, RawMode, SeekFrom}, io::Errno};
 
-use crate::Extent;
+use crate::{Extent, NoReflink};
 use crate::errors::Result;
-use crate::common::{copy_bytes_uspace, copy_range_uspace};
+use crate::common::{copy_bytes_uspace, copy_range_uspace, merge_extents};
 
 // Wrapper for copy_file_range(2) that checks for non-fatal errors due
 // to limitations of the syscall.
@@ -219,6 +219,15 @@ pub fn next_sparse_segments(infd: &File, outfd: &File, pos: u64) -> Result<(u64,
     Ok((next_data, next_hole))
 }
 
+/// The length of the data at the start of the file, up to its first
+/// hole; the whole file if it has none.
+pub fn leading_data_len(fd: &File) -> Result<u64> {
+    match lseek(fd, SeekFrom::Hole(0))? {
+        SeekOff::Offset(off) => Ok(off),
+        SeekOff::EOF => Ok(fd.metadata()?.len()),
+    }
+}
+
 /// Copy data between files, looking for sparse blocks and skipping
 /// them.
 pub fn copy_sparse(infd: &File, outfd: &File) -> Result<u64> {
@@ -253,30 +262,147 @@ pub fn copy_node(src: &Path, dest: &Path) -> Result<()> {
 /// updates. Only certain filesystems support this; if not supported
 /// the function returns `false`.
 pub fn reflink(infd: &File, outfd: &File) -> Result<bool> {
+    Ok(reflink_with_reason(infd, outfd)?.is_none())
+}
+
+/// As [reflink], but returns the reason if the file could not be
+/// reflinked, or `None` if it was.
+pub fn reflink_with_reason(infd: &File, outfd: &File) -> Result<Option<NoReflink>> {
     if unsafe { libc::ioctl(outfd.as_raw_fd(), FICLONE as u64, infd.as_raw_fd()) } != 0 {
-        let oserr = io::Error::last_os_error();
-        match oserr.raw_os_error() {
-            Some(libc::EOPNOTSUPP)
-                | Some(libc::EINVAL)
-                | Some(libc::EXDEV)
-                | Some(libc::ETXTBSY) =>
-                return Ok(false),
-            _ =>
-                return  Err(oserr.into()),
+        return no_reflink_reason();
+    }
+    Ok(None)
+}
+
+// Why the last reflink attempt failed, or the error if it was not
+// a refusal.
+fn no_reflink_reason() -> Result<Option<NoReflink>> {
+    let oserr = io::Error::last_os_error();
+    let reason = match oserr.raw_os_error() {
+        Some(libc::EOPNOTSUPP) => NoReflink::Unsupported,
+        Some(libc::EXDEV) => NoReflink::CrossDevice,
+        Some(libc::EINVAL) => NoReflink::Refused,
+        Some(libc::ETXTBSY) => NoReflink::Busy,
+        _ => return Err(oserr.into()),
+    };
+    Ok(Some(reason))
+}
+
+// Reflink `len` bytes from `off` of the source to the same offset of
+// the target, with
+// [FICLONERANGE](https://man7.org/linux/man-pages/man2/ioctl_ficlonerange.2.html).
+fn reflink_range(infd: &File, outfd: &File, off: u64, len: u64) -> Result<Option<NoReflink>> {
+    let range = libc::file_clone_range {
+        src_fd: infd.as_raw_fd() as i64,
+        src_offset: off,
+        src_length: len,
+        dest_offset: off,
+    };
+    if unsafe { libc::ioctl(outfd.as_raw_fd(), FICLONERANGE as u64, &range) } != 0 {
+        return no_reflink_reason();
+    }
+    Ok(None)
+}
+
+/// As [reflink_with_reason], but reflinks each data extent of the
+/// source separately, for files that can't be reflinked whole.
+/// Holes in the source are left as holes in the target, which is
+/// extended to the length of the source. If an extent can't be
+/// reflinked the target may be left partially written.
+pub fn reflink_extents(infd: &File, outfd: &File) -> Result<Option<NoReflink>> {
+    let len = infd.metadata()?.len();
+    let Some(extents) = map_extents(infd)? else {
+        return Ok(Some(NoReflink::Unsupported));
+    };
+    for extent in merge_extents(extents)? {
+        // The last extent may run past the end of the file, which
+        // must then be the end of the range.
+        let end = cmp::min(extent.end, len);
+        if extent.start >= end {
+            continue;
+        }
+        if let Some(reason) = reflink_range(infd, outfd, extent.start, end - extent.start)? {
+            return Ok(Some(reason));
         }
     }
-    Ok(true)
+    outfd.set_len(len)?;
+    Ok(None)
+}
+
+/// Cloning by path is not needed on Linux; see [reflink].
+pub fn clone_file(_src: &Path, _dest: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+/// Hint to the kernel that the file will be read or written
+/// sequentially, which increases readahead. See
+/// [posix_fadvise](https://man7.org/linux/man-pages/man2/posix_fadvise.2.html).
+pub fn advise_sequential(fd: &File) -> Result<()> {
+    fadvise(fd, 0, 0, Advice::Sequential)?;
+    Ok(())
+}
+
+/// Drop the cached pages of the range `off..off+len` of a file. Dirty
+/// pages are only dropped once they have been written back, so this
+/// is only partially effective on files being written.
+pub fn drop_cache(fd: &File, off: u64, len: u64) -> Result<()> {
+    fadvise(fd, off, len, Advice::DontNeed)?;
+    Ok(())
+}
+
+/// Enable or disable direct I/O (`O_DIRECT`) on a file, bypassing the
+/// page cache. While enabled, reads and writes must be aligned to the
+/// filesystem's block size. Not all filesystems support this
+/// (e.g. tmpfs), in which case an error is returned.
+pub fn set_direct_io(fd: &File, enable: bool) -> Result<()> {
+    let flags = fcntl_getfl(fd)?;
+    let flags = if enable { flags | OFlags::DIRECT } else { flags - OFlags::DIRECT };
+    fcntl_setfl(fd, flags)?;
+    Ok(())
+}
+
+// The inode flags users may change; FS_FL_USER_MODIFIABLE in
+// linux/fs.h.
+const USER_MODIFIABLE_FLAGS: u32 = 0x000380FF;
+
+// Whether an inode flags ioctl failed because the filesystem doesn't
+// support them.
+fn flags_unsupported(errno: Errno) -> bool {
+    matches!(errno, Errno::NOTTY | Errno::NOTSUP | Errno::INVAL)
+}
+
+/// Copy the [inode flags](https://man7.org/linux/man-pages/man2/ioctl_iflags.2.html)
+/// that users may set, such as the immutable and append-only
+/// flags. These can prevent any further changes to the target, so
+/// should be copied last. Returns `false` if either filesystem does
+/// not support inode flags.
+pub fn copy_flags(infd: &File, outfd: &File) -> Result<bool> {
+    let (inflags, outflags) = match (ioctl_getflags(infd), ioctl_getflags(outfd)) {
+        (Ok(inflags), Ok(outflags)) => (inflags.bits(), outflags.bits()),
+        (Err(e), _) | (_, Err(e)) if flags_unsupported(e) => return Ok(false),
+        (Err(e), _) | (_, Err(e)) => return Err(e.into()),
+    };
+    let flags = (outflags & !USER_MODIFIABLE_FLAGS) | (inflags & USER_MODIFIABLE_FLAGS);
+    if flags == outflags {
+        return Ok(true);
+    }
+    match ioctl_setflags(outfd, IFlags::from_bits_retain(flags)) {
+        Ok(()) => Ok(true),
+        Err(e) if flags_unsupported(e) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
 }
 
 #[cfg(test)]
 #[allow(unused)]
 mod tests {
     use super::*;
-    use crate::{allocate_file, copy_permissions};
+    use crate::{allocate_file, copy_acl, copy_default_acl, copy_file_offset_direct, copy_file_offset_sparse, copy_permissions, copy_xattr};
     use std::env::{current_dir, var};
     use std::fs::{read, OpenOptions};
     use std::io::{self, Seek, Write};
     use std::iter;
+    use std::os::unix::fs::FileExt;
     use std::os::unix::net::UnixListener;
     use std::path::PathBuf;
     use std::process::Command;
@@ -376,7 +502,7 @@ mod tests {
         {
             let mut fd = OpenOptions::new().write(true).append(false).open(&file)?;
             let s = "x".repeat(512*1024);
-            fd.write(s.as_bytes())?;
+            fd.write_all(s.as_bytes())?;
             assert!(probably_sparse(&fd)?);
         }
 
@@ -412,6 +538,38 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_copy_offset_sparse_zeros() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let size = 1024 * 1024;
+
+        {
+            // Dense file, mostly zeros
+            let mut fd = File::create(&from)?;
+            let mut data = vec![0u8; size];
+            data[100] = 1;
+            data[size - 1] = 1;
+            fd.write_all(&data)?;
+        }
+        assert!(!probably_sparse(&File::open(&from)?)?);
+
+        {
+            let infd = File::open(&from)?;
+            let outfd = File::create(&to)?;
+            allocate_file(&outfd, size as u64)?;
+            let copied = copy_file_offset_sparse(&infd, &outfd, size as u64, 0)?;
+            assert_eq!(size, copied);
+        }
+
+        assert!(probably_sparse(&File::open(&to)?)?);
+        assert_eq!(read(&from)?, read(&to)?);
+
+        Ok(())
+    }
+
     #[test]
     #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
     fn test_sparse_copy_middle() -> Result<()> {
@@ -542,6 +700,51 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[cfg_attr(feature = "test_no_reflink", ignore = "No FS support")]
+    fn test_reflink_extents() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("copy.bin");
+        let block = vec![b'X'; 64 * 1024];
+
+        {
+            let fd = File::create(&from)?;
+            fd.set_len(2 * 1024 * 1024)?;
+            fd.write_all_at(&block, 0)?;
+            fd.write_all_at(&block, 1024 * 1024)?;
+        }
+
+        let from_fd = File::open(&from)?;
+        let to_fd = File::create(&to)?;
+        assert_eq!(None, reflink_extents(&from_fd, &to_fd)?);
+
+        assert_eq!(read(&from)?, read(&to)?);
+        let extents = map_extents(&to_fd)?.unwrap();
+        assert!(extents.len() >= 2);
+        assert!(extents.iter().all(|e| e.shared));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_leading_data_len() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("sparse.bin");
+        let block = vec![1u8; 64 * 1024];
+
+        let fd = File::create(&file)?;
+        assert_eq!(0, leading_data_len(&fd)?);
+        fd.write_all_at(&block, 0)?;
+        assert_eq!(block.len() as u64, leading_data_len(&fd)?);
+        fd.set_len(1024 * 1024)?;
+        fd.write_all_at(&block, 512 * 1024)?;
+        assert_eq!(block.len() as u64, leading_data_len(&fd)?);
+
+        Ok(())
+    }
+
     #[test]
     #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
     fn test_sparse_rust_seek() -> Result<()> {
@@ -674,8 +877,8 @@ mod tests {
         assert!(extents_p.is_some());
         let extents = extents_p.unwrap();
         assert_eq!(extents.len(), 1);
-        assert_eq!(extents[0].start, offset as u64);
-        assert_eq!(extents[0].end, offset as u64 + 4 * 1024); // FIXME: Assume 4k blocks
+        assert_eq!(extents[0].start, offset);
+        assert_eq!(extents[0].end, offset + 4 * 1024); // FIXME: Assume 4k blocks
         assert!(!extents[0].shared);
 
         Ok(())
@@ -695,7 +898,7 @@ mod tests {
         let fsize = 1024 * 1024;
         // FIXME: Assumes 4k blocks
         let bsize = 4 * 1024;
-        let block = iter::repeat(0xff_u8).take(bsize).collect::<Vec<u8>>();
+        let block = vec![0xff_u8; bsize];
 
         let mut fd = OpenOptions::new().write(true).append(false).open(&file)?;
         // Skip every-other block
@@ -731,7 +934,7 @@ mod tests {
         let extents = extents_p.unwrap();
 
         assert_eq!(1, extents.len());
-        assert_eq!(0 as u64, extents[0].start);
+        assert_eq!(0_u64, extents[0].start);
         assert_eq!(size as u64, extents[0].end);
 
         Ok(())
@@ -817,6 +1020,7 @@ mod tests {
             let from_fd: File = File::open(&from)?;
             let to_fd: File = File::open(&to)?;
             copy_permissions(&from_fd, &to_fd)?;
+            copy_xattr(&from_fd, &to_fd)?;
         }
 
         let to_acl = getfacl(&from, None)?;
@@ -824,4 +1028,142 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    #[cfg_attr(feature = "test_no_acl", ignore = "No FS support")]
+    fn test_copy_default_acl() -> Result<()> {
+        use exacl::{getfacl, AclEntry, AclOption, Flag, Perm, setfacl};
+
+        let dir = tempdir()?;
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        std::fs::create_dir(&from)?;
+        std::fs::create_dir(&to)?;
+
+        let access = AclEntry::allow_user("mail", Perm::READ | Perm::EXECUTE, None);
+        let default = AclEntry::allow_user("mail", Perm::READ, Flag::DEFAULT);
+        let mut acl = getfacl(&from, None)?;
+        acl.push(access.clone());
+        acl.push(AclEntry::allow_mask(Perm::READ | Perm::EXECUTE, None));
+        acl.extend(exacl::from_mode(0o750).into_iter().map(|mut e| {
+            e.flags = Flag::DEFAULT;
+            e
+        }));
+        acl.push(default.clone());
+        acl.push(AclEntry::allow_mask(Perm::READ, Flag::DEFAULT));
+        setfacl(&[&from], &acl, None)?;
+
+        {
+            let from_fd = File::open(&from)?;
+            let to_fd = File::open(&to)?;
+            assert!(copy_default_acl(&from_fd, &to_fd)?);
+        }
+        let to_default = getfacl(&to, AclOption::DEFAULT_ACL)?;
+        assert!(to_default.contains(&default));
+        assert!(!getfacl(&to, AclOption::ACCESS_ACL)?.contains(&access));
+
+        {
+            let from_fd = File::open(&from)?;
+            let to_fd = File::open(&to)?;
+            assert!(copy_acl(&from_fd, &to_fd)?);
+        }
+        assert!(getfacl(&to, AclOption::ACCESS_ACL)?.contains(&access));
+
+        // New entries inherit the copied default ACL.
+        let child = to.join("file.txt");
+        File::create(&child)?;
+        assert!(getfacl(&child, None)?.contains(&AclEntry::allow_user("mail", Perm::READ, None)));
+
+        // A source without a default ACL clears the target's.
+        let plain = dir.path().join("plain");
+        std::fs::create_dir(&plain)?;
+        {
+            let from_fd = File::open(&plain)?;
+            let to_fd = File::open(&to)?;
+            assert!(copy_default_acl(&from_fd, &to_fd)?);
+        }
+        assert!(getfacl(&to, AclOption::DEFAULT_ACL)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fadvise() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("file.bin");
+        std::fs::write(&file, vec![1u8; 64 * 1024])?;
+        let fd = File::open(&file)?;
+
+        advise_sequential(&fd)?;
+        drop_cache(&fd, 0, 32 * 1024)?;
+        drop_cache(&fd, 32 * 1024, 1024 * 1024)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_flags() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("file.txt");
+        let to = dir.path().join("copy.txt");
+        std::fs::write(&from, "data")?;
+        std::fs::write(&to, "data")?;
+
+        // Unlike the immutable flag, no-dump needs no privileges
+        // and doesn't prevent cleaning up.
+        let infd = File::open(&from)?;
+        let outfd = File::open(&to)?;
+        match ioctl_setflags(&infd, ioctl_getflags(&infd)? | IFlags::NODUMP) {
+            Err(e) if flags_unsupported(e) => {
+                warn!("No inode flag support, skipping test");
+                return Ok(());
+            }
+            r => r?,
+        }
+        assert!(copy_flags(&infd, &outfd)?);
+        assert!(ioctl_getflags(&outfd)?.contains(IFlags::NODUMP));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_direct_io() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("file.bin");
+        let to = dir.path().join("copy.bin");
+        let data = vec![7u8; 64 * 1024];
+        std::fs::write(&from, &data)?;
+
+        let infd = File::open(&from)?;
+        let outfd = File::create(&to)?;
+        allocate_file(&outfd, data.len() as u64)?;
+        set_direct_io(&infd, true)?;
+        set_direct_io(&outfd, true)?;
+        assert_eq!(32 * 1024, copy_file_offset(&infd, &outfd, 32 * 1024, 0)?);
+        assert_eq!(32 * 1024, copy_file_offset_direct(&infd, &outfd, 32 * 1024, 32 * 1024, 4096)?);
+        // Unaligned I/O is refused.
+        assert!(copy_file_offset_direct(&infd, &outfd, 100, 100, 1).is_err());
+        set_direct_io(&infd, false)?;
+        set_direct_io(&outfd, false)?;
+        assert_eq!(data, read(&to)?);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "test_no_reflink"), ignore = "Requires an FS without reflink support")]
+    fn test_reflink_reason() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("file.bin");
+        let to = dir.path().join("copy.bin");
+        std::fs::write(&from, "X".repeat(4096))?;
+
+        let from_fd = File::open(from)?;
+        let to_fd = File::create(to)?;
+        let reason = reflink_with_reason(&from_fd, &to_fd)?;
+        assert!(matches!(reason, Some(NoReflink::Unsupported | NoReflink::Refused)));
+        assert!(!reflink(&from_fd, &to_fd)?);
+
+        Ok(())
+    }
 }
@@ -15,9 +15,13 @@ fn get_regex() -> &'static Regex {
     BAK_REGEX.get_or_init(|| Regex::new(BAK_PATTTERN).unwrap())
 }
 
-pub(crate) fn get_backup_path(file: &Path) -> Result<PathBuf> {
-    let num = next_backup_num(file)?;
-    let suffix = format!(".~{}~", num);
+pub(crate) fn get_backup_path(file: &Path, conf: &Config) -> Result<PathBuf> {
+    let suffix = if conf.backup == Backup::Simple {
+        conf.backup_suffix.clone()
+    } else {
+        let num = next_backup_num(file)?;
+        format!(".~{}~", num)
+    };
     // Messy but PathBuf has no concept of mulitiple extensions.
     let mut bstr = file.to_path_buf().into_os_string();
     bstr.push(suffix);
@@ -31,7 +35,7 @@ pub(crate) fn needs_backup(file: &Path, conf: &Config) -> Result<bool> {
         Backup::Auto if file.exists() => {
             has_backup(file)?
         }
-        Backup::Numbered if file.exists() => true,
+        Backup::Numbered | Backup::Simple if file.exists() => true,
         _ => false,
     };
     Ok(need)
@@ -153,7 +157,7 @@ mod tests {
             File::create(&base)?;
         }
 
-        let backup = get_backup_path(&base)?;
+        let backup = get_backup_path(&base, &Config::default())?;
         let mut bs = base.into_os_string();
         bs.push(".~1~");
         assert_eq!(PathBuf::from(bs), backup);
@@ -161,6 +165,23 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_gen_simple_backup_path() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let base = tdir.path().join("file.txt");
+
+        let mut conf = Config {
+            backup: Backup::Simple,
+            ..Config::default()
+        };
+        assert_eq!(tdir.path().join("file.txt~"), get_backup_path(&base, &conf)?);
+
+        conf.backup_suffix = ".bak".to_string();
+        assert_eq!(tdir.path().join("file.txt.bak"), get_backup_path(&base, &conf)?);
+
+        Ok(())
+    }
+
     #[test]
     fn test_needs_backup() -> Result<()> {
         let tdir = TempDir::new()?;
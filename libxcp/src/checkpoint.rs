@@ -0,0 +1,165 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Persistent records of completed files, so that an interrupted copy
+//! can be resumed; see [Config::checkpoint](crate::config::Config::checkpoint).
+//!
+//! The file holds one NUL-terminated record per completed source
+//! file: its size, modification time in seconds and nanoseconds, and
+//! path, separated by spaces. Records are appended as each file
+//! completes; a partial record left by a crash is ignored.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::errors::Result;
+
+// The size and modification time of a completed source.
+type Stamp = (u64, i64, i64);
+
+#[derive(Debug)]
+pub struct Checkpoint {
+    completed: HashMap<PathBuf, Stamp>,
+    // None when only reading, e.g. for a dry run.
+    file: Option<Mutex<File>>,
+}
+
+impl Checkpoint {
+    /// Read the records in `path` without adding to them. A missing
+    /// file has none.
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self::read(path)?.0)
+    }
+
+    /// Read the records in `path`, creating it if necessary, and
+    /// append to it as files are completed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let (mut checkpoint, partial) = Self::read(path)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        // Terminate any partial record so the next one is intact.
+        if partial {
+            file.write_all(&[0])?;
+        }
+        checkpoint.file = Some(Mutex::new(file));
+        Ok(checkpoint)
+    }
+
+    // Also returns whether the last record is incomplete.
+    fn read(path: &Path) -> Result<(Self, bool)> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let completed = data
+            .split_inclusive(|b| *b == 0)
+            .filter_map(|record| parse_record(record.strip_suffix(&[0])?))
+            .collect();
+        let partial = data.last().is_some_and(|b| *b != 0);
+        Ok((Self { completed, file: None }, partial))
+    }
+
+    /// Whether `from` was completed by an earlier copy, and is
+    /// unchanged since.
+    pub fn is_complete(&self, from: &Path, meta: &Metadata) -> bool {
+        self.completed.get(from) == Some(&stamp(meta))
+    }
+
+    /// Record that `from`, as described by `meta`, has been copied.
+    pub fn record(&self, from: &Path, meta: &Metadata) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let (size, secs, nsecs) = stamp(meta);
+        let mut record = format!("{} {} {} ", size, secs, nsecs).into_bytes();
+        record.extend_from_slice(from.as_os_str().as_bytes());
+        record.push(0);
+        // A single write, so concurrent records aren't interleaved.
+        file.lock().unwrap().write_all(&record)?;
+        Ok(())
+    }
+}
+
+fn stamp(meta: &Metadata) -> Stamp {
+    (meta.len(), meta.mtime(), meta.mtime_nsec())
+}
+
+fn parse_record(record: &[u8]) -> Option<(PathBuf, Stamp)> {
+    let mut fields = record.splitn(4, |b| *b == b' ');
+    let mut number = || std::str::from_utf8(fields.next()?).ok()?.parse::<i64>().ok();
+    let size = u64::try_from(number()?).ok()?;
+    let secs = number()?;
+    let nsecs = number()?;
+    let path = PathBuf::from(OsStr::from_bytes(fields.next()?));
+    Some((path, (size, secs, nsecs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_checkpoint() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("checkpoint");
+        let done = dir.path().join("done file.txt");
+        let changed = dir.path().join("changed.txt");
+        write(&done, "done")?;
+        write(&changed, "changed")?;
+
+        let checkpoint = Checkpoint::open(&path)?;
+        assert!(!checkpoint.is_complete(&done, &done.metadata()?));
+        checkpoint.record(&done, &done.metadata()?)?;
+        checkpoint.record(&changed, &changed.metadata()?)?;
+        drop(checkpoint);
+
+        write(&changed, "changed again")?;
+        // As if interrupted mid-record.
+        OpenOptions::new().append(true).open(&path)?.write_all(b"12 34")?;
+
+        let checkpoint = Checkpoint::open(&path)?;
+        assert_eq!(2, checkpoint.completed.len());
+        assert!(checkpoint.is_complete(&done, &done.metadata()?));
+        assert!(!checkpoint.is_complete(&changed, &changed.metadata()?));
+        checkpoint.record(&changed, &changed.metadata()?)?;
+        drop(checkpoint);
+
+        let checkpoint = Checkpoint::load(&path)?;
+        assert!(checkpoint.is_complete(&changed, &changed.metadata()?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_missing() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("checkpoint");
+        let checkpoint = Checkpoint::load(&path)?;
+        assert!(checkpoint.completed.is_empty());
+        checkpoint.record(&path, &dir.path().metadata()?)?;
+        assert!(!path.exists());
+
+        Ok(())
+    }
+}
@@ -16,11 +16,42 @@
 
 //! Driver configuration support.
 
+use std::cmp;
+use std::ffi::{OsStr, OsString};
+use std::fs::Metadata;
+use std::ops::Range;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use std::result;
 use std::str::FromStr;
 
+use libfs::FileType;
+use regex::bytes::Regex;
+use rustix::process::{getrlimit, Resource};
+
+use crate::checkpoint::Checkpoint;
+use crate::dedup::Dedup;
 use crate::errors::XcpError;
 
+// Descriptors reserved for stdio, logging, progress, etc. when
+// deriving the copy-queue length from the open-file limit.
+const FD_HEADROOM: u64 = 32;
+// Each in-flight file holds its source and destination open.
+const FDS_PER_FILE: u64 = 2;
+// Upper bound on the derived queue length.
+const MAX_QUEUE_LEN: usize = 128;
+
+// Tuning for `auto_block_size`. Files up to AUTO_MIN_BLOCK are copied
+// as one block; larger files are split into about AUTO_BLOCKS blocks,
+// up to AUTO_MAX_BLOCK each.
+const AUTO_MIN_BLOCK: u64 = 1024 * 1024;
+const AUTO_MAX_BLOCK: u64 = 64 * 1024 * 1024;
+const AUTO_BLOCKS: u64 = 64;
+
 /// Enum defining configuration options for handling
 /// [reflinks](https://btrfs.readthedocs.io/en/latest/Reflink.html). [FromStr]
 /// is supported.
@@ -53,6 +84,35 @@ impl FromStr for Reflink {
     }
 }
 
+/// How file data is copied between blocks of the source and target;
+/// an escape hatch for filesystems where the default performs
+/// badly. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CopyMethod {
+    /// Use `copy_file_range` where available (Linux), which lets the
+    /// kernel or filesystem optimise the copy; otherwise read and
+    /// write.
+    #[default]
+    Cfr,
+    /// Read and write through a user-space buffer.
+    ReadWrite,
+    /// Map the source into memory, and write from the mapping.
+    Mmap,
+}
+
+impl FromStr for CopyMethod {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cfr" => Ok(CopyMethod::Cfr),
+            "rw" => Ok(CopyMethod::ReadWrite),
+            "mmap" => Ok(CopyMethod::Mmap),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'copy-method': {}", s))),
+        }
+    }
+}
+
 /// Enum defining configuration options for handling backups of
 /// overwritten files. [FromStr] is supported.
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -64,6 +124,9 @@ pub enum Backup {
     /// Create numbered backups. Numbered backups follow the semantics
     /// of `cp` numbered backups (e.g. `file.txt.~123~`).
     Numbered,
+    /// Create a single backup by appending a suffix (by default `~`,
+    /// e.g. `file.txt~`), replacing any previous backup.
+    Simple,
 }
 
 impl FromStr for Backup {
@@ -74,11 +137,333 @@ impl FromStr for Backup {
             "none" | "off" => Ok(Backup::None),
             "auto" => Ok(Backup::Auto),
             "numbered" => Ok(Backup::Numbered),
+            "simple" => Ok(Backup::Simple),
             _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'backup': {}", s))),
         }
     }
 }
 
+/// Enum defining configuration options for handling sparse
+/// files. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Sparse {
+    /// Detect sparse source files and recreate their holes in the
+    /// destination.
+    #[default]
+    Auto,
+    /// Skip writing any blocks consisting entirely of zeros, creating
+    /// holes in the destination even if the source is not sparse.
+    Always,
+    /// Never attempt sparse detection and always copy the full file
+    /// range. This avoids relying on extent-mapping, which can be
+    /// unreliable on some filesystems.
+    Never,
+}
+
+impl FromStr for Sparse {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(Sparse::Always),
+            "auto" => Ok(Sparse::Auto),
+            "never" => Ok(Sparse::Never),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'sparse': {}", s))),
+        }
+    }
+}
+
+/// Enum defining how existing target files are compared with their
+/// sources to decide whether they can be skipped.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum QuickCheck {
+    /// Always copy files.
+    #[default]
+    None,
+    /// Skip files whose target has the same size and modification
+    /// time as the source.
+    SizeMtime,
+    /// Skip files whose target has the same size as the source,
+    /// regardless of their timestamps.
+    Size,
+    /// Skip files whose target has the same contents as the source,
+    /// regardless of their timestamps. Both files are read in full
+    /// to compare their checksums, using the [Config::verify]
+    /// algorithm or XXH64 if that is [Verify::None].
+    Checksum,
+}
+
+/// Enum defining the checksum used to verify copied files. [FromStr]
+/// is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Verify {
+    /// Do not verify copied files.
+    #[default]
+    None,
+    /// Compare [XXH64](https://xxhash.com/) digests. This is fast,
+    /// but not cryptographically secure.
+    Xxh64,
+    /// Compare SHA-256 digests.
+    Sha256,
+}
+
+impl FromStr for Verify {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" | "off" => Ok(Verify::None),
+            "xxhash" | "xxh64" => Ok(Verify::Xxh64),
+            "sha256" => Ok(Verify::Sha256),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'verify': {}", s))),
+        }
+    }
+}
+
+/// Enum defining whether files are written to a temporary file and
+/// renamed into place. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Atomic {
+    /// Write directly to the destination file.
+    #[default]
+    Never,
+    /// Replace files atomically where possible, otherwise write to
+    /// the destination directly with a warning.
+    Auto,
+    /// Return an error if a file cannot be replaced atomically.
+    Always,
+}
+
+impl FromStr for Atomic {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "never" | "off" => Ok(Atomic::Never),
+            "auto" => Ok(Atomic::Auto),
+            "always" => Ok(Atomic::Always),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'atomic': {}", s))),
+        }
+    }
+}
+
+/// An include or exclude pattern applied to paths within each
+/// source; see [Config::filters].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    /// Copy matching files, even if excluded by an earlier pattern or
+    /// by a `.gitignore`.
+    Include(String),
+    /// Skip matching files.
+    Exclude(String),
+}
+
+/// Permission changes applied to copied files and directories, in
+/// the form accepted by `chmod(1)`. This is either an octal mode such
+/// as `0644`, or comma-separated symbolic clauses such as
+/// `u=rw,go=r`. A clause with no `ugoa` applies to all. `X` sets
+/// execute only on directories and on files that are already
+/// executable by someone. [FromStr] is supported.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chmod {
+    clauses: Vec<ChmodClause>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ChmodClause {
+    // The bits affected, from the 'ugoa' prefix.
+    who: u32,
+    op: char,
+    bits: u32,
+    // Whether 'X' was given.
+    cond_exec: bool,
+}
+
+impl Chmod {
+    /// Apply the changes to an existing mode.
+    pub fn apply(&self, mode: u32, is_dir: bool) -> u32 {
+        self.clauses.iter().fold(mode & 0o7777, |mode, clause| {
+            let mut bits = clause.bits;
+            if clause.cond_exec && (is_dir || mode & 0o111 != 0) {
+                bits |= 0o111;
+            }
+            let bits = bits & clause.who;
+            match clause.op {
+                '+' => mode | bits,
+                '-' => mode & !bits,
+                _ => (mode & !clause.who) | bits,
+            }
+        })
+    }
+}
+
+impl FromStr for Chmod {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let invalid = || XcpError::InvalidArguments(format!("Unexpected value for 'chmod': {}", s));
+
+        if !s.is_empty() && s.chars().all(|c| c.is_digit(8)) {
+            let bits = u32::from_str_radix(s, 8).map_err(|_| invalid())?;
+            if bits > 0o7777 {
+                return Err(invalid());
+            }
+            let clauses = vec![ChmodClause { who: 0o7777, op: '=', bits, cond_exec: false }];
+            return Ok(Chmod { clauses });
+        }
+
+        let mut clauses = Vec::new();
+        for clause in s.split(',') {
+            let mut chars = clause.chars().peekable();
+            let mut who = 0;
+            while let Some(c) = chars.next_if(|c| "ugoa".contains(*c)) {
+                who |= match c {
+                    'u' => 0o4700,
+                    'g' => 0o2070,
+                    'o' => 0o1007,
+                    _ => 0o7777,
+                };
+            }
+            if who == 0 {
+                who = 0o7777;
+            }
+            // Each clause may have several operations, e.g. 'u+x-w'.
+            let mut op = chars.next().filter(|c| "+-=".contains(*c)).ok_or_else(invalid)?;
+            loop {
+                let mut bits = 0;
+                let mut cond_exec = false;
+                while let Some(c) = chars.next_if(|c| !"+-=".contains(*c)) {
+                    match c {
+                        'r' => bits |= 0o444,
+                        'w' => bits |= 0o222,
+                        'x' => bits |= 0o111,
+                        'X' => cond_exec = true,
+                        's' => bits |= 0o6000,
+                        't' => bits |= 0o1000,
+                        _ => return Err(invalid()),
+                    }
+                }
+                clauses.push(ChmodClause { who, op, bits, cond_exec });
+                match chars.next() {
+                    Some(next) => op = next,
+                    None => break,
+                }
+            }
+        }
+        Ok(Chmod { clauses })
+    }
+}
+
+/// A substitution applied to the name of each entry created under
+/// the destination, in the form `s/PATTERN/REPLACEMENT/` used by
+/// `sed(1)`. PATTERN is a regular expression, and REPLACEMENT may
+/// refer to its groups as `$1`. The first match is replaced, or all
+/// of them with a trailing `g`. Any character may be used in place of
+/// `/`, and is escaped with `\`. [FromStr] is supported.
+#[derive(Clone, Debug)]
+pub struct Rename {
+    pattern: Regex,
+    replacement: Vec<u8>,
+    global: bool,
+}
+
+impl Rename {
+    /// Apply the substitution to a single file name.
+    pub fn apply(&self, name: &OsStr) -> OsString {
+        let renamed = if self.global {
+            self.pattern.replace_all(name.as_bytes(), self.replacement.as_slice())
+        } else {
+            self.pattern.replace(name.as_bytes(), self.replacement.as_slice())
+        };
+        OsString::from_vec(renamed.into_owned())
+    }
+}
+
+impl FromStr for Rename {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let invalid = |why: &str| XcpError::InvalidArguments(format!("Unexpected value for 'rename': {}: {}", s, why));
+
+        let mut chars = s.chars();
+        let delim = match (chars.next(), chars.next()) {
+            (Some('s'), Some(delim)) if !delim.is_alphanumeric() && delim != '\\' => delim,
+            _ => return Err(invalid("expected 's/PATTERN/REPLACEMENT/'")),
+        };
+        let mut parts = vec![String::new()];
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.clone().next() == Some(delim) => {
+                    parts.last_mut().unwrap().push(delim);
+                    chars.next();
+                }
+                c if c == delim => parts.push(String::new()),
+                c => parts.last_mut().unwrap().push(c),
+            }
+        }
+        let [pattern, replacement, flags] = <[String; 3]>::try_from(parts)
+            .map_err(|_| invalid("expected 's/PATTERN/REPLACEMENT/'"))?;
+        let global = match flags.as_str() {
+            "" => false,
+            "g" => true,
+            _ => return Err(invalid("the only flag supported is 'g'")),
+        };
+        if replacement.contains('/') {
+            return Err(invalid("names cannot contain '/'"));
+        }
+        let pattern = Regex::new(&pattern).map_err(|e| invalid(&e.to_string()))?;
+        Ok(Rename { pattern, replacement: replacement.into_bytes(), global })
+    }
+}
+
+/// The types of entry that are copied, as a comma-separated set of
+/// `f` (regular files), `d` (directories), `l` (symlinks) and `s`
+/// (special files). [FromStr] is supported; the [Default] is all of
+/// them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EntryTypes {
+    pub files: bool,
+    pub dirs: bool,
+    pub symlinks: bool,
+    pub specials: bool,
+}
+
+impl EntryTypes {
+    /// Whether entries of type `ft` are copied.
+    pub fn selects(&self, ft: &FileType) -> bool {
+        match ft {
+            FileType::File => self.files,
+            FileType::Dir => self.dirs,
+            FileType::Symlink => self.symlinks,
+            FileType::Socket | FileType::Fifo | FileType::Char | FileType::Block | FileType::Other => self.specials,
+        }
+    }
+}
+
+impl Default for EntryTypes {
+    fn default() -> Self {
+        EntryTypes { files: true, dirs: true, symlinks: true, specials: true }
+    }
+}
+
+impl FromStr for EntryTypes {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut types = EntryTypes { files: false, dirs: false, symlinks: false, specials: false };
+        for t in s.split(',') {
+            match t.trim() {
+                "f" => types.files = true,
+                "d" => types.dirs = true,
+                "l" => types.symlinks = true,
+                "s" => types.specials = true,
+                _ => return Err(XcpError::InvalidArguments(format!("Unexpected value for 'type': {}", t))),
+            }
+        }
+        Ok(types)
+    }
+}
+
 /// A structure defining the runtime options for copy-drivers. This
 /// would normally be passed to `load_driver()`.
 #[derive(Clone, Debug)]
@@ -88,9 +473,47 @@ pub struct Config {
     pub workers: usize,
 
     /// Block size for operations. Defaults to the full file size. Use
-    /// a smaller value for finer-grained feedback.
+    /// a smaller value for finer-grained feedback; [Config::bwlimit],
+    /// [Config::timeout] and [Config::interrupt] also act between
+    /// blocks.
     pub block_size: u64,
 
+    /// Pick the block size for each file from its length and the
+    /// filesystem's preferred I/O size, rather than using
+    /// `block_size`. Small files are copied as a single block, while
+    /// larger files use larger blocks to reduce per-block
+    /// overhead. Default is `false`.
+    pub auto_block_size: bool,
+
+    /// Limit the combined copy rate of all workers to this many bytes
+    /// per second. Default is `None`, for no limit.
+    pub bwlimit: Option<u64>,
+
+    /// Give up on any file whose copy takes longer than this, failing
+    /// it with [XcpError::TimedOut]. The deadline is checked between
+    /// blocks, so a block blocked in the kernel (e.g. on a hung
+    /// network mount) can't be cut short. Default is `None`.
+    pub timeout: Option<Duration>,
+
+    /// Only copy the source from this byte offset, into the same
+    /// offset of the target. Setting this or [Config::length] makes
+    /// the copy a ranged one; the target is then updated in place
+    /// rather than replaced, and only grows if the range extends past
+    /// its end. Intended for single-file copies. Default is `None`.
+    pub offset: Option<u64>,
+
+    /// Only copy this many bytes of the source; see
+    /// [Config::offset]. Default is `None`, for the rest of the file.
+    pub length: Option<u64>,
+
+    /// Resume interrupted copies into existing targets. If a target
+    /// is shorter than its source, or has holes as left by an
+    /// interrupted copy, and its leading data matches the source,
+    /// only the rest of the source is copied into it; otherwise it is
+    /// copied in full. Intended for single-file copies; mirrors and
+    /// ranged copies are not supported. Default is `false`.
+    pub append: bool,
+
     /// Use .gitignore if present.
     ///
     /// NOTE: This is fairly basic at the moment, and only honours a
@@ -99,15 +522,84 @@ pub struct Config {
     /// `false`.
     pub gitignore: bool,
 
+    /// Additional ignore files, in gitignore syntax, applied to each
+    /// source. Their patterns are relative to the source directory,
+    /// and take precedence over `.gitignore`; later files take
+    /// precedence over earlier ones. `.xcpignore` files found within
+    /// the sources are honoured unless `no_gitignore` is set, and
+    /// take precedence over these.
+    pub ignore_files: Vec<PathBuf>,
+
+    /// Disable all ignore file processing, so `.gitignore`,
+    /// `.xcpignore` and `ignore_files` are not read. Include and
+    /// exclude `filters` still apply. Default is `false`.
+    pub no_gitignore: bool,
+
+    /// Include and exclude patterns.
+    ///
+    /// Patterns use gitignore syntax, and are matched against paths
+    /// relative to each source; a pattern without a `/` matches a
+    /// file name at any depth. Where several patterns match a path
+    /// the last one wins. These take precedence over any
+    /// `.gitignore` rules. Default is empty.
+    pub filters: Vec<Filter>,
+
     /// Do not overwrite existing files. Default is `false`.
     pub no_clobber: bool,
 
+    /// Skip files whose target already exists with the same or a
+    /// newer modification time. Default is `false`.
+    pub update: bool,
+
+    /// Skip any file whose target already exists, regardless of its
+    /// contents or age. Unlike `no_clobber` this is not an error, and
+    /// it takes precedence; existing directories are still copied
+    /// into. Default is `false`.
+    pub ignore_existing: bool,
+
+    /// Skip files that appear unchanged at the target; see
+    /// [QuickCheck]. Combines with `update`, so a file is skipped if
+    /// either check passes, except for [QuickCheck::Checksum] which
+    /// overrides it. Default is [QuickCheck::None].
+    pub quick_check: QuickCheck,
+
+    /// Skip files smaller than this many bytes. Directories are still
+    /// created and descended into. Default is `None`.
+    pub min_size: Option<u64>,
+
+    /// Skip files larger than this many bytes. Default is `None`.
+    pub max_size: Option<u64>,
+
+    /// Stop queuing files once their total size would exceed this
+    /// many bytes. Files already queued are still copied, and the
+    /// copy then fails with [XcpError::SizeLimitReached]. Default is
+    /// `None`.
+    ///
+    /// [XcpError::SizeLimitReached]: crate::errors::XcpError::SizeLimitReached
+    pub max_total_size: Option<u64>,
+
+    /// Delete entries within each copied directory that do not exist
+    /// in its source. Entries excluded by the ignore rules are kept.
+    /// Default is `false`.
+    pub delete: bool,
+
     /// Do not copy the file permissions. Default is `false`.
     pub no_perms: bool,
 
+    /// Clear the setuid, setgid and sticky bits of copied files. By
+    /// default the mode is copied in full, as with `cp -p`; plain
+    /// `cp` drops them. Default is `false`.
+    pub strip_special_bits: bool,
+
     /// Do not copy the file permissions. Default is `false`.
     pub no_timestamps: bool,
 
+    /// Do not copy [xattrs](https://man7.org/linux/man-pages/man7/xattr.7.html),
+    /// including ACLs, security labels and file capabilities. Setting
+    /// capabilities requires `CAP_SETFCAP`; without it a warning is
+    /// issued and the copy continues. Default is `false`.
+    pub no_xattr: bool,
+
     /// Copy ownership.
     ///
     /// Whether to copy ownship (user/group).  This option requires
@@ -119,6 +611,36 @@ pub struct Config {
     /// Dereference symlinks. Default is `false`.
     pub dereference: bool,
 
+    /// Dereference symlinks given as sources, but not those found
+    /// while walking the source tree. This is the equivalent of
+    /// `cp -H`; `dereference` takes precedence. Default is `false`.
+    pub dereference_command_line: bool,
+
+    /// Copy the contents of FIFOs and character devices given as
+    /// sources into regular files, rather than recreating them.
+    /// Special files found while walking the source tree are still
+    /// recreated. Default is `false`.
+    pub read_special: bool,
+
+    /// Stream the data written to each FIFO into the FIFO at its
+    /// target, rather than only recreating it. An existing FIFO at
+    /// the target is written to rather than replaced. The copy waits
+    /// for a reader of the target and a writer of the source, up to
+    /// the [Config::timeout], and ends when the writer closes the
+    /// source. Default is `false`.
+    pub pipe_through: bool,
+
+    /// Do not descend into directories on other filesystems than the
+    /// source; the mount-point directories themselves are still
+    /// created. Default is `false`.
+    pub one_file_system: bool,
+
+    /// Descend at most this many levels below each source
+    /// directory. The source itself is depth 0, so `Some(1)` copies
+    /// only its immediate children; directories at the limit are still
+    /// created, but empty. Default is `None` (unlimited).
+    pub max_depth: Option<usize>,
+
     /// Target should not be a directory.
     ///
     /// Analogous to cp's no-target-directory. Expected behavior is that when
@@ -126,9 +648,52 @@ pub struct Config {
     /// in target, overwrite target. Default is 'false`.
     pub no_target_directory: bool,
 
-    /// Sync each file to disk after writing. Default is `false`.
+    /// Treat the destination as a directory to copy the sources into,
+    /// even if it doesn't exist yet. `xcp` sets this if the destination
+    /// has a trailing slash. Default is `false`.
+    pub dest_is_dir: bool,
+
+    /// Copy the contents of a source directory given with a trailing
+    /// slash (e.g. `src/`) into the destination, rather than the
+    /// directory itself, as with rsync. Default is `false`.
+    pub copy_contents: bool,
+
+    /// Also copy the sources to each of these destinations, treated
+    /// as the main destination is. Each file is read once and written
+    /// to every destination, so tee copies are never reflinked or
+    /// sparse, and hardlinks are copied as separate files. Whether a
+    /// file needs copying is decided by the main destination alone.
+    /// Default is empty.
+    pub tee: Vec<PathBuf>,
+
+    /// Copy each source to its own path under the destination,
+    /// rather than just its final component; e.g. `a/b/c.txt` is
+    /// copied to `dest/a/b/c.txt`. Leading `/` and `.` components are
+    /// dropped, and `..` is not allowed. Parent directories are
+    /// created as needed. Default is `false`.
+    pub relative: bool,
+
+    /// Sync each file to disk after writing, and each target directory
+    /// once the copy is complete. Default is `false`.
     pub fsync: bool,
 
+    /// Sync each target directory once the copy is complete, even
+    /// without [Config::fsync], so that its new entries are
+    /// durable. Default is `false`.
+    pub sync_dest_dirs: bool,
+
+    /// Advise the kernel to drop the copied data from the page cache,
+    /// so large copies don't evict other data. Only supported on
+    /// Linux. Default is `false`.
+    pub no_cache: bool,
+
+    /// Copy with direct I/O (`O_DIRECT`), bypassing the page cache
+    /// where the filesystems support it. The block size must then be
+    /// a multiple of the source and target filesystems' block sizes;
+    /// with `auto_block_size` it is rounded up to one. Only supported
+    /// on Linux. Default is `false`.
+    pub direct_io: bool,
+
     /// Reflink options.
     ///
     /// Whether and how to use reflinks. 'auto' (the default) will
@@ -137,13 +702,186 @@ pub struct Config {
     /// and 'never' will always perform a full data copy.
     pub reflink: Reflink,
 
+    /// How blocks of file data are copied; see [CopyMethod]. Sparse
+    /// and direct I/O copies use their own methods. Default is
+    /// [CopyMethod::Cfr].
+    pub copy_method: CopyMethod,
+
     /// Backup options
     ///
     /// Whether to create backups of overwritten files. Current
-    /// options are `None` or 'Numbered'. Numbered backups follow the
-    /// semantics of `cp` numbered backups
+    /// options are `None`, 'Auto', 'Numbered' or 'Simple'. Numbered
+    /// backups follow the semantics of `cp` numbered backups
     /// (e.g. `file.txt.~123~`). Default is `None`.
     pub backup: Backup,
+
+    /// The suffix appended to simple backups. Default is `~`.
+    pub backup_suffix: String,
+
+    /// Atomic replacement of destination files.
+    ///
+    /// With 'auto' or 'always' each file is copied to a temporary file
+    /// in the destination directory, which is renamed over the target
+    /// once the copy (and any verification) has succeeded; on failure
+    /// the original target is left untouched. A target that cannot be
+    /// renamed over (e.g. a bind-mounted file) is written in place
+    /// with 'auto', or is an error with 'always'. Default is 'never'.
+    pub atomic: Atomic,
+
+    /// Sparse file handling.
+    ///
+    /// 'auto' (the default) detects sparse source files and skips
+    /// their holes, 'always' additionally skips any all-zero blocks
+    /// in non-sparse files, and 'never' disables sparse detection
+    /// and copies the full file.
+    pub sparse: Sparse,
+
+    /// Write zeros for the holes in sparse sources, rather than
+    /// leaving holes in the target. Unlike `Sparse::Never` the
+    /// source's holes are still detected, and are not read. This
+    /// can't be combined with `Sparse::Always`. Default is `false`.
+    pub no_punch_holes: bool,
+
+    /// Treat source files that change while being copied as errors.
+    ///
+    /// After copying each file its source is checked for changes to
+    /// its size or modification time. By default this only logs a
+    /// warning; if set an [XcpError::SourceChanged] is reported via
+    /// the [StatusUpdater] instead. Default is `false`.
+    ///
+    /// [XcpError::SourceChanged]: crate::errors::XcpError::SourceChanged
+    /// [StatusUpdater]: crate::feedback::StatusUpdater
+    pub strict: bool,
+
+    /// Remove each source file once it has been successfully copied
+    /// (and verified, if enabled), giving move semantics. Source
+    /// directories are removed once empty. Files that fail to copy,
+    /// or change during the copy, are left in place. Default is
+    /// `false`.
+    pub remove_source_files: bool,
+
+    /// Preserve hardlinks within the copied tree. Each further path to
+    /// an already-copied file is recreated as a hardlink to its copy,
+    /// rather than copying the data again. Default is `false`.
+    pub preserve_links: bool,
+
+    /// Copy [POSIX ACLs](https://man7.org/linux/man-pages/man5/acl.5.html),
+    /// including the default ACLs of directories. These are set on
+    /// each directory when it is created, so new entries inherit
+    /// them. A warning is issued if the filesystem does not support
+    /// ACLs. Default is `false`.
+    pub preserve_acl: bool,
+
+    /// Copy the inode flags of files and directories that users may
+    /// set, such as immutable and append-only (see `chattr(1)`). These
+    /// are applied after everything else, as they can prevent further
+    /// changes. A warning is issued if they can't be copied, e.g. if
+    /// the filesystem doesn't support them. Linux only. Default is
+    /// `false`.
+    pub preserve_flags: bool,
+
+    /// Permission changes to apply to each copied file and directory,
+    /// after any mode has been copied from the source. Default is
+    /// `None`.
+    pub chmod: Option<Chmod>,
+
+    /// Permissions for the directories created that have no source,
+    /// such as the destination itself and the parents created for
+    /// [Config::relative]. These are applied to the mode the
+    /// directory is created with. Default is `None`, leaving the
+    /// umask's mode.
+    pub dir_mode: Option<Chmod>,
+
+    /// A substitution applied to the name of each entry created
+    /// within the destination. A destination given as the target
+    /// itself is not renamed. The contents of a renamed directory are
+    /// copied into its new name. Default is `None`.
+    pub rename: Option<Rename>,
+
+    /// Continue copying after an individual file fails.
+    ///
+    /// Errors are still reported via the [StatusUpdater], but the
+    /// drivers will skip the failing file rather than aborting the
+    /// whole copy. Default is `false`.
+    ///
+    /// [StatusUpdater]: crate::feedback::StatusUpdater
+    pub continue_on_error: bool,
+
+    /// Walk the source tree but do not perform any operations.
+    ///
+    /// Each operation that would be performed is reported as a
+    /// [StatusUpdate::Planned] instead, along with the usual size
+    /// updates. The destination is not modified. Default is `false`.
+    ///
+    /// [StatusUpdate::Planned]: crate::feedback::StatusUpdate::Planned
+    pub dry_run: bool,
+
+    /// Create only the directories of the source tree.
+    ///
+    /// Files, symlinks and special files are skipped by the walk, so
+    /// only directories are reported as entries. Default is `false`.
+    pub dirs_only: bool,
+
+    /// The types of entry to copy.
+    ///
+    /// Entries of other types are skipped, but directories are still
+    /// descended into. Skipped directories aren't created with their
+    /// source's attributes; instead the parents of each copied entry
+    /// are created as needed, with [Config::dir_mode] applied. Default
+    /// is all types.
+    pub types: EntryTypes,
+
+    /// Verify copied files.
+    ///
+    /// Once all data for a file has been written both the source and
+    /// destination are read back and their digests compared; a
+    /// mismatch is reported as an error for that file. Holes in
+    /// sparse source files are skipped. Default is `None`.
+    pub verify: Verify,
+
+    /// Maximum number of open files.
+    ///
+    /// Limits the number of files the parblock driver has in flight
+    /// at any time. 0 (the default) derives a value from the
+    /// process's `RLIMIT_NOFILE`.
+    pub max_open_files: usize,
+
+    /// Number of times to retry a block copy after a transient I/O
+    /// error (e.g. `EINTR`, `EAGAIN` or `EIO`), with exponential
+    /// backoff of up to 5 seconds. Currently only supported by the parblock
+    /// driver. Default is 0.
+    pub retries: u32,
+
+    /// A flag to stop the copy early, e.g. from a signal handler. Once
+    /// set no new blocks are copied, blocks already being copied are
+    /// completed, and the driver returns [XcpError::Interrupted].
+    /// Targets being copied to are truncated to the data written
+    /// before the interruption; temporary files of atomic copies are
+    /// removed.
+    /// Default is `None`.
+    pub interrupt: Option<Arc<AtomicBool>>,
+
+    /// Log a warning, with the reason, for each file that is copied
+    /// because it could not be reflinked with `Reflink::Auto`.
+    /// Otherwise this is only logged at debug level. Default is
+    /// `false`.
+    pub warn_no_reflink: bool,
+
+    /// A record of the files already copied. Files it holds that are
+    /// unchanged are skipped, and each file copied is added to it
+    /// once complete, so that an interrupted copy can be resumed.
+    /// Default is `None`.
+    pub checkpoint: Option<Arc<Checkpoint>>,
+
+    /// An index of the files copied by their contents. If set, each
+    /// source file is hashed before it is copied, and a file with the
+    /// same contents as one already copied is reflinked to it where
+    /// possible, or otherwise hardlinked; hardlinked copies share the
+    /// metadata of the first. Hashing reads every source an extra
+    /// time, so this costs CPU and I/O even when nothing is
+    /// duplicated. Not supported with [Config::tee] or ranged copies.
+    /// Default is `None`.
+    pub dedup: Option<Arc<Dedup>>,
 }
 
 impl Config {
@@ -154,6 +892,62 @@ impl Config {
             self.workers
         }
     }
+
+    // The block size to use when copying the file described by
+    // `meta`.
+    pub(crate) fn file_block_size(&self, meta: &Metadata) -> u64 {
+        if self.auto_block_size {
+            auto_block_size(meta.len(), meta.blksize())
+        } else {
+            self.block_size
+        }
+    }
+
+    // Whether the copy has been interrupted; see `interrupt`.
+    pub(crate) fn interrupted(&self) -> bool {
+        self.interrupt.as_ref().is_some_and(|i| i.load(Ordering::Relaxed))
+    }
+
+    // Whether only part of the source is copied; see `offset`.
+    pub(crate) fn ranged(&self) -> bool {
+        self.offset.is_some() || self.length.is_some()
+    }
+
+    // The part of a source of `len` bytes to copy; the whole file
+    // unless `offset` or `length` are set.
+    pub(crate) fn copy_range(&self, len: u64) -> Range<u64> {
+        let start = cmp::min(self.offset.unwrap_or(0), len);
+        let end = match self.length {
+            Some(length) => cmp::min(start.saturating_add(length), len),
+            None => len,
+        };
+        start..end
+    }
+
+    // Whether a file of `len` bytes is within `min_size` and
+    // `max_size`.
+    pub(crate) fn size_in_range(&self, len: u64) -> bool {
+        self.min_size.map_or(true, |min| len >= min) && self.max_size.map_or(true, |max| len <= max)
+    }
+
+    // The length of the bounded copy queue. Every queued block
+    // keeps its file open, so this sets the number of open files.
+    pub(crate) fn queue_len(&self) -> usize {
+        let max_files = if self.max_open_files > 0 {
+            self.max_open_files as u64
+        } else {
+            let limit = getrlimit(Resource::Nofile).current.unwrap_or(u64::MAX);
+            limit.saturating_sub(FD_HEADROOM) / FDS_PER_FILE
+        };
+        // Running workers also hold open files.
+        let queued = max_files.saturating_sub(self.num_workers() as u64);
+        let len = cmp::max(1, cmp::min(queued, usize::MAX as u64) as usize);
+        if self.max_open_files > 0 {
+            len
+        } else {
+            cmp::min(len, MAX_QUEUE_LEN)
+        }
+    }
 }
 
 impl Default for Config {
@@ -161,16 +955,194 @@ impl Default for Config {
         Config {
             workers: num_cpus::get(),
             block_size: u64::MAX,
+            auto_block_size: false,
+            bwlimit: None,
+            timeout: None,
+            offset: None,
+            length: None,
+            append: false,
             gitignore: false,
+            ignore_files: Vec::new(),
+            no_gitignore: false,
+            filters: Vec::new(),
             no_clobber: false,
+            update: false,
+            ignore_existing: false,
+            quick_check: QuickCheck::None,
+            min_size: None,
+            max_size: None,
+            max_total_size: None,
+            delete: false,
             no_perms: false,
+            strip_special_bits: false,
             no_timestamps: false,
+            no_xattr: false,
             ownership: false,
             dereference: false,
+            dereference_command_line: false,
+            read_special: false,
+            pipe_through: false,
+            one_file_system: false,
+            max_depth: None,
             no_target_directory: false,
+            dest_is_dir: false,
+            copy_contents: false,
+            tee: Vec::new(),
+            relative: false,
             fsync: false,
+            sync_dest_dirs: false,
+            no_cache: false,
+            direct_io: false,
             reflink: Reflink::Auto,
+            copy_method: CopyMethod::Cfr,
             backup: Backup::None,
+            backup_suffix: "~".to_string(),
+            atomic: Atomic::Never,
+            sparse: Sparse::Auto,
+            strict: false,
+            remove_source_files: false,
+            preserve_links: false,
+            preserve_acl: false,
+            preserve_flags: false,
+            chmod: None,
+            dir_mode: None,
+            rename: None,
+            continue_on_error: false,
+            dry_run: false,
+            dirs_only: false,
+            types: EntryTypes::default(),
+            verify: Verify::None,
+            max_open_files: 0,
+            retries: 0,
+            interrupt: None,
+            warn_no_reflink: false,
+            no_punch_holes: false,
+            checkpoint: None,
+            dedup: None,
+        }
+    }
+}
+
+// Scale the block size with the file, in power-of-two multiples of
+// the filesystem block size.
+fn auto_block_size(len: u64, fs_block: u64) -> u64 {
+    if len <= AUTO_MIN_BLOCK {
+        return cmp::max(len, 1);
+    }
+    let min = cmp::max(fs_block, AUTO_MIN_BLOCK).next_power_of_two();
+    let target = (len / AUTO_BLOCKS).next_power_of_two();
+    cmp::min(cmp::max(target, min), cmp::max(min, AUTO_MAX_BLOCK))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_block_size() {
+        const MB: u64 = 1024 * 1024;
+        assert_eq!(1, auto_block_size(0, 4096));
+        assert_eq!(1000, auto_block_size(1000, 4096));
+        assert_eq!(MB, auto_block_size(MB, 4096));
+        assert_eq!(MB, auto_block_size(10 * MB, 4096));
+        assert_eq!(2 * MB, auto_block_size(100 * MB, 4096));
+        assert_eq!(64 * MB, auto_block_size(100 * 1024 * MB, 4096));
+        assert_eq!(4 * MB, auto_block_size(10 * MB, 4 * MB));
+    }
+
+    #[test]
+    fn test_copy_range() {
+        let config = Config::default();
+        assert!(!config.ranged());
+        assert_eq!(0..100, config.copy_range(100));
+
+        let config = Config { offset: Some(10), ..Config::default() };
+        assert!(config.ranged());
+        assert_eq!(10..100, config.copy_range(100));
+
+        let config = Config { offset: Some(10), length: Some(20), ..Config::default() };
+        assert_eq!(10..30, config.copy_range(100));
+        assert_eq!(10..25, config.copy_range(25));
+        assert_eq!(5..5, config.copy_range(5));
+
+        let config = Config { length: Some(u64::MAX), ..Config::default() };
+        assert_eq!(0..100, config.copy_range(100));
+    }
+
+    #[test]
+    fn test_size_in_range() {
+        assert!(Config::default().size_in_range(0));
+
+        let config = Config { min_size: Some(10), max_size: Some(20), ..Config::default() };
+        assert!(!config.size_in_range(9));
+        assert!(config.size_in_range(10));
+        assert!(config.size_in_range(20));
+        assert!(!config.size_in_range(21));
+    }
+
+    #[test]
+    fn test_chmod_octal() {
+        let chmod = Chmod::from_str("0644").unwrap();
+        assert_eq!(0o644, chmod.apply(0o755, false));
+        assert_eq!(0o644, chmod.apply(0o4777, true));
+        assert_eq!(0o750, Chmod::from_str("750").unwrap().apply(0o644, false));
+        assert!(Chmod::from_str("17777").is_err());
+    }
+
+    #[test]
+    fn test_chmod_symbolic() {
+        let chmod = Chmod::from_str("u=rw,go=r").unwrap();
+        assert_eq!(0o644, chmod.apply(0o777, false));
+        assert_eq!(0o755, Chmod::from_str("go-w").unwrap().apply(0o777, false));
+        assert_eq!(0o744, Chmod::from_str("u+x").unwrap().apply(0o644, false));
+        assert_eq!(0o555, Chmod::from_str("+x-w").unwrap().apply(0o644, false));
+        assert_eq!(0o2755, Chmod::from_str("g+s").unwrap().apply(0o755, true));
+        assert_eq!(0o1777, Chmod::from_str("+t").unwrap().apply(0o777, true));
+    }
+
+    #[test]
+    fn test_chmod_conditional_exec() {
+        let chmod = Chmod::from_str("a=rX").unwrap();
+        assert_eq!(0o444, chmod.apply(0o644, false));
+        assert_eq!(0o555, chmod.apply(0o744, false));
+        assert_eq!(0o555, chmod.apply(0o700, true));
+    }
+
+    #[test]
+    fn test_chmod_invalid() {
+        for mode in ["", "u", "u=rwz", "q+r", "0o644", "u+r,,", "8"] {
+            assert!(Chmod::from_str(mode).is_err(), "{}", mode);
+        }
+    }
+
+    #[test]
+    fn test_entry_types() {
+        let types = EntryTypes::from_str("f, l").unwrap();
+        assert!(types.selects(&FileType::File));
+        assert!(types.selects(&FileType::Symlink));
+        assert!(!types.selects(&FileType::Dir));
+        assert!(!types.selects(&FileType::Fifo));
+        assert!(EntryTypes::default().selects(&FileType::Socket));
+        assert!(EntryTypes::from_str("f,x").is_err());
+        assert!(EntryTypes::from_str("").is_err());
+    }
+
+    #[test]
+    fn test_rename() {
+        let rename = |expr: &str, name: &str| Rename::from_str(expr).unwrap().apply(OsStr::new(name));
+        assert_eq!("file.txt.bak", rename("s/$/.bak/", "file.txt"));
+        assert_eq!("bar-foo", rename("s/foo/bar/", "foo-foo"));
+        assert_eq!("bar-bar", rename("s/foo/bar/g", "foo-foo"));
+        assert_eq!("2024-report", rename(r"s/(\w+)-(\d+)/$2-$1/", "report-2024"));
+        assert_eq!("a_b", rename(r"s|/|_|", "a/b"));
+        assert_eq!("x_y", rename(r"s/\//_/", "x/y"));
+        assert_eq!("unchanged", rename("s/foo/bar/", "unchanged"));
+    }
+
+    #[test]
+    fn test_rename_invalid() {
+        for expr in ["", "s", "s/foo/bar", "s/foo/bar/x", "s/(/x/", "y/a/b/", "s|a|b/c|", "s/a/b/c/"] {
+            assert!(Rename::from_str(expr).is_err(), "{}", expr);
         }
     }
 }
@@ -0,0 +1,65 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! An index of the files copied so far by their contents, so that
+//! later copies of identical files can be linked to them; see
+//! [Config::dedup](crate::config::Config::dedup).
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// A file's size and the SHA-256 digest of its contents.
+type Key = (u64, Vec<u8>);
+
+#[derive(Debug, Default)]
+pub struct Dedup {
+    targets: Mutex<HashMap<Key, PathBuf>>,
+}
+
+impl Dedup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A completed target with `len` bytes matching `digest`, if any.
+    pub fn find(&self, len: u64, digest: &[u8]) -> Option<PathBuf> {
+        self.targets.lock().unwrap().get(&(len, digest.to_vec())).cloned()
+    }
+
+    /// Record that `to` has been copied with `len` bytes matching
+    /// `digest`. Only the first target with given contents is kept.
+    pub fn insert(&self, len: u64, digest: &[u8], to: &Path) {
+        self.targets.lock().unwrap()
+            .entry((len, digest.to_vec()))
+            .or_insert_with(|| to.to_path_buf());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_target_kept() {
+        let dedup = Dedup::new();
+        assert_eq!(None, dedup.find(4, b"abcd"));
+        dedup.insert(4, b"abcd", Path::new("first"));
+        dedup.insert(4, b"abcd", Path::new("second"));
+        assert_eq!(Some(PathBuf::from("first")), dedup.find(4, b"abcd"));
+        assert_eq!(None, dedup.find(5, b"abcd"));
+    }
+}
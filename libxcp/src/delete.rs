@@ -0,0 +1,105 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Removal of target entries that no longer exist in the source; see
+//! [Config::delete].
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+
+use ignore::WalkBuilder;
+use log::{debug, info};
+use rustix::io::Errno;
+
+use crate::config::Config;
+use crate::errors::Result;
+use crate::feedback::{PlannedOp, StatusUpdate, StatusUpdater};
+use crate::paths::Ignores;
+
+/// Delete the entries under `target` that have no counterpart under
+/// `source`. Entries the ignore rules would skip are left in place,
+/// as are any directories containing them.
+pub(crate) fn delete_extras(
+    source: &Path,
+    target: &Path,
+    ignores: &Arc<Ignores>,
+    config: &Config,
+    stats: &Arc<dyn StatusUpdater>,
+) -> Result<()> {
+    if !target.is_dir() || target.is_symlink() {
+        return Ok(());
+    }
+
+    // Rules are matched against the source path an entry would have
+    // been copied from.
+    let walker = WalkBuilder::new(target)
+        .standard_filters(false)
+        .follow_links(false)
+        .same_file_system(config.one_file_system)
+        .max_depth(config.max_depth)
+        .filter_entry({
+            let ignores = ignores.clone();
+            let source = source.to_path_buf();
+            let target = target.to_path_buf();
+            move |e| match e.path().strip_prefix(&target) {
+                Ok(rel) => ignores.allowed(&source.join(rel), e.file_type().is_some_and(|ft| ft.is_dir())),
+                Err(_) => true,
+            }
+        })
+        .build();
+
+    // Parents are visited before their contents.
+    let mut extras = Vec::new();
+    for entry in walker {
+        let entry = entry?;
+        if entry.depth() == 0 {
+            continue;
+        }
+        let from = source.join(entry.path().strip_prefix(target)?);
+        match from.symlink_metadata() {
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let is_dir = entry.file_type().is_some_and(|ft| ft.is_dir());
+                extras.push((from, entry.into_path(), is_dir));
+            }
+            Err(e) => return Err(e.into()),
+            Ok(_) => {}
+        }
+    }
+
+    for (from, to, is_dir) in extras.into_iter().rev() {
+        if config.dry_run {
+            stats.send(StatusUpdate::Planned(PlannedOp::Delete, from, to))?;
+        } else if is_dir {
+            remove_dir(&to)?;
+        } else {
+            debug!("Deleting {:?}", to);
+            fs::remove_file(&to)?;
+        }
+    }
+    Ok(())
+}
+
+// Directories still holding ignored entries are kept.
+fn remove_dir(dir: &Path) -> Result<()> {
+    match fs::remove_dir(dir) {
+        Ok(()) => debug!("Deleted directory {:?}", dir),
+        Err(e) if e.raw_os_error() == Some(Errno::NOTEMPTY.raw_os_error()) => info!("Not deleting non-empty directory {:?}", dir),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Select between the other drivers based on the workload. The
+//! first files of the sources are sampled; if they are large
+//! compared to the block size the `parblock` driver is used,
+//! otherwise `parfile`.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::info;
+
+use crate::config::Config;
+use crate::drivers::{load_driver, CopyDriver, Drivers};
+use crate::errors::Result;
+use crate::feedback::StatusUpdater;
+
+// The number of files to inspect when choosing a driver.
+#[cfg(feature = "parblock")]
+const SAMPLE_FILES: usize = 100;
+
+// ********************************************************************** //
+
+pub struct Driver {
+    config: Arc<Config>,
+}
+
+impl Driver {
+    pub fn new(config: Arc<Config>) -> Result<Self> {
+        Ok(Self {
+            config,
+        })
+    }
+}
+
+impl CopyDriver for Driver {
+    fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
+        let driver = select_driver(&sources, &self.config);
+        info!("Automatically selected the {:?} driver", driver);
+        load_driver(driver, &self.config)?.copy(sources, dest, stats)
+    }
+}
+
+/// Pick a driver for copying `sources`. Block-level parallelism only
+/// pays off if the average file is big enough to give each worker at
+/// least one block; otherwise per-file parallelism has less
+/// overhead. Only the first few files found are sampled.
+#[cfg(feature = "parblock")]
+pub fn select_driver(sources: &[PathBuf], config: &Config) -> Drivers {
+    let threshold = config.block_size.saturating_mul(config.num_workers() as u64);
+    match average_file_size(sources, config) {
+        Some(average) if average >= threshold => Drivers::ParBlock,
+        _ => Drivers::ParFile,
+    }
+}
+
+/// Pick a driver for copying `sources`; without the `parblock`
+/// feature this is always `parfile`.
+#[cfg(not(feature = "parblock"))]
+pub fn select_driver(_sources: &[PathBuf], _config: &Config) -> Drivers {
+    Drivers::ParFile
+}
+
+#[cfg(feature = "parblock")]
+fn average_file_size(sources: &[PathBuf], config: &Config) -> Option<u64> {
+    let (first, rest) = sources.split_first()?;
+    let mut builder = ignore::WalkBuilder::new(first);
+    for source in rest {
+        builder.add(source);
+    }
+    let sizes = builder
+        .standard_filters(false)
+        .follow_links(config.dereference)
+        .max_depth(config.max_depth)
+        .build()
+        .filter_map(|e| e.ok()?.metadata().ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .take(SAMPLE_FILES)
+        .collect::<Vec<u64>>();
+    if sizes.is_empty() {
+        return None;
+    }
+    Some(sizes.iter().sum::<u64>() / sizes.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write, File};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_select_driver() -> Result<()> {
+        let dir = TempDir::new()?;
+        let small = dir.path().join("small");
+        create_dir_all(&small)?;
+        for i in 0..10 {
+            write(small.join(format!("file{}.txt", i)), "content")?;
+        }
+        let large = dir.path().join("large.bin");
+        File::create(&large)?.set_len(64 * 1024 * 1024)?;
+
+        let config = Config {
+            workers: 4,
+            block_size: 1024 * 1024,
+            ..Config::default()
+        };
+        assert_eq!(Drivers::ParFile, select_driver(&[small], &config));
+        assert_eq!(Drivers::ParFile, select_driver(&[], &config));
+        #[cfg(feature = "parblock")]
+        assert_eq!(Drivers::ParBlock, select_driver(&[large], &config));
+        #[cfg(not(feature = "parblock"))]
+        assert_eq!(Drivers::ParFile, select_driver(&[large], &config));
+
+        Ok(())
+    }
+}
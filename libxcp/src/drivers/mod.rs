@@ -16,12 +16,16 @@
 
 //! Support for pluggable copy drivers.
 //!
-//! Two drivers are currently supported:
+//! Three drivers are currently supported:
 //! * `parfile`: Parallelise copying at the file level. This can improve
 //!   speed on modern NVME devices, but can bottleneck on larger files.
 //! * `parblock`: Parallelise copying at the block level. Block-size is
 //!   configurable. This can have better performance for large files,
 //!   but has a higher overhead.
+//! * `sequential`: Copy one file at a time on the calling thread. This
+//!   has the least overhead, and is the easiest to debug.
+//!
+//! Additionally `auto` samples the sources and picks one of the above.
 //!
 //! Drivers are configured with the [Config] struct. A convenience
 //! function [load_driver()] is provided to load a dynamic-dispatched
@@ -31,9 +35,11 @@
 //!
 //! See the example in top-level module.
 
+pub mod auto;
 pub mod parfile;
 #[cfg(feature = "parblock")]
 pub mod parblock;
+pub mod sequential;
 
 use std::path::{Path, PathBuf};
 use std::result;
@@ -59,11 +65,13 @@ pub trait CopyDriver {
 /// An enum specifing the driver to use. This is just a helper for
 /// applications to use with [load_driver()]. [FromStr] is implemented
 /// to help with this.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Drivers {
+    Auto,
     ParFile,
     #[cfg(feature = "parblock")]
     ParBlock,
+    Sequential,
 }
 
 // String conversion helper as a convenience for command-line parsing.
@@ -72,9 +80,13 @@ impl FromStr for Drivers {
 
     fn from_str(s: &str) -> result::Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
+            "auto" => Ok(Drivers::Auto),
             "parfile" => Ok(Drivers::ParFile),
             #[cfg(feature = "parblock")]
             "parblock" => Ok(Drivers::ParBlock),
+            #[cfg(not(feature = "parblock"))]
+            "parblock" => Err(XcpError::UnknownDriver(format!("{} (not enabled in this build)", s))),
+            "sequential" => Ok(Drivers::Sequential),
             _ => Err(XcpError::UnknownDriver(s.to_owned())),
         }
     }
@@ -83,9 +95,11 @@ impl FromStr for Drivers {
 /// Load and configure the given driver.
 pub fn load_driver(driver: Drivers, config: &Arc<Config>) -> Result<Box<dyn CopyDriver + Send>> {
     let driver_impl: Box<dyn CopyDriver + Send> = match driver {
+        Drivers::Auto => Box::new(auto::Driver::new(config.clone())?),
         Drivers::ParFile => Box::new(parfile::Driver::new(config.clone())?),
         #[cfg(feature = "parblock")]
         Drivers::ParBlock => Box::new(parblock::Driver::new(config.clone())?),
+        Drivers::Sequential => Box::new(sequential::Driver::new(config.clone())?),
     };
 
     Ok(driver_impl)
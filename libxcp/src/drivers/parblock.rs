@@ -19,25 +19,24 @@
 //! but has a higher overhead.
 
 use std::cmp;
-use std::fs::remove_file;
 use std::ops::Range;
-use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 use cfg_if::cfg_if;
 use crossbeam_channel as cbc;
-use libfs::copy_node;
-use log::{error, info};
+use log::{error, info, warn};
 use blocking_threadpool::{Builder, ThreadPool};
+use rustix::io::Errno;
 
-use crate::config::Config;
+use crate::config::{Config, Sparse};
 use crate::drivers::CopyDriver;
 use crate::errors::{Result, XcpError};
-use crate::feedback::{StatusUpdate, StatusUpdater};
-use crate::operations::{CopyHandle, Operation, tree_walker};
-use libfs::{copy_file_offset, map_extents, merge_extents, probably_sparse};
+use crate::feedback::{CreatedKind, StatusUpdate, StatusUpdater};
+use crate::operations::{copy_failure, copy_special, copy_symlink, finalise, remove_source_file, CopyHandle, Operation, RateLimiter, tree_walker};
+use libfs::{copy_file_offset_sparse, map_extents, merge_extents, probably_sparse, write_zeros};
 
 // ********************************************************************** //
 
@@ -99,42 +98,126 @@ impl CopyDriver for Driver {
             thread::spawn(move || tree_walker(sources, &d, &c, file_tx, sc))
         };
 
-        walk_worker.join()
+        let deferred = walk_worker.join()
             .map_err(|_| XcpError::CopyError("Error walking copy tree".to_string()))??;
         dispatcher.join()
             .map_err(|_| XcpError::CopyError("Error dispatching copy operation".to_string()))??;
 
-        Ok(())
+        finalise(deferred, &self.config)
     }
 }
 
 // ********************************************************************** //
 
+// Initial delay before retrying a failed block; doubled on each
+// subsequent attempt, up to the maximum.
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// The delay before the given retry, counting from 1.
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_BACKOFF.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY)
+}
+
+// Errors that may succeed if the operation is retried. EIO is
+// included as network filesystems may return it for transient
+// failures.
+fn is_transient(err: &libfs::Error) -> bool {
+    [Errno::INTR, Errno::AGAIN, Errno::IO]
+        .iter()
+        .any(|e| err.raw_os_error() == Some(e.raw_os_error()))
+}
+
+// How the blocks of a range are written.
+#[derive(Clone, Copy, PartialEq)]
+enum BlockMode {
+    Copy,
+    // Leave any all-zero blocks as holes.
+    SkipZeros,
+    // Write zeros without reading the source, for its holes.
+    Zeros,
+}
+
+// Queue the blocks of `range` for copying.
 fn queue_file_range(
     handle: &Arc<CopyHandle>,
     range: Range<u64>,
+    mode: BlockMode,
     pool: &ThreadPool,
     status_channel: &Arc<dyn StatusUpdater>,
 ) -> Result<u64> {
     let len = range.end - range.start;
-    let bsize = handle.config.block_size;
+    let bsize = handle.block_size;
+    // The other modes go through user-space buffers.
+    if mode != BlockMode::Copy {
+        handle.end_direct_io();
+    }
     let blocks = (len / bsize) + (if len % bsize > 0 { 1 } else { 0 });
 
     for blkn in 0..blocks {
+        // Stop queuing the blocks of a failed file.
+        if handle.is_failed() {
+            break;
+        }
         let harc = handle.clone();
         let stat_tx = status_channel.clone();
         let bytes = cmp::min(len - (blkn * bsize), bsize);
         let off = range.start + (blkn * bsize);
 
         pool.execute(move || {
-            let copy_result = copy_file_offset(&harc.infd, &harc.outfd, bytes, off as i64);
+            // Blocks still queued are dropped, leaving the file
+            // incomplete.
+            if harc.config.interrupted() {
+                harc.mark_failed();
+                return;
+            }
+            // The file is incomplete anyway, e.g. the destination is
+            // full.
+            if harc.is_failed() {
+                return;
+            }
+            // Reported once, by the first block past the deadline.
+            if harc.timed_out() {
+                if harc.mark_failed() {
+                    error!("Timed out copying {:?}", harc.from);
+                    if let Err(e) = stat_tx.send(StatusUpdate::Error(XcpError::TimedOut(harc.from.clone()))) {
+                        error!("Failed to send timeout of {:?}: {}", harc.from, e);
+                    }
+                }
+                return;
+            }
+            // Retries don't count against the limit.
+            harc.throttle(bytes);
+            let mut attempt = 0;
+            let copy_result = loop {
+                let r = match mode {
+                    BlockMode::Copy => harc.copy_block(off, bytes),
+                    BlockMode::SkipZeros => copy_file_offset_sparse(&harc.infd, &harc.outfd, bytes, off as i64),
+                    BlockMode::Zeros => write_zeros(&harc.outfd, bytes, off as i64),
+                };
+                match r {
+                    Err(e) if attempt < harc.config.retries && is_transient(&e) => {
+                        attempt += 1;
+                        let delay = retry_delay(attempt);
+                        warn!("Error copying block at {} of {:?}, retrying in {:?}: {}", off, harc.from, delay, e);
+                        thread::sleep(delay);
+                    }
+                    r => break r,
+                }
+            };
             let stat_result = match copy_result {
                 Ok(bytes) => {
+                    harc.wrote(off, bytes as u64);
+                    harc.drop_cache(off, bytes as u64);
                     stat_tx.send(StatusUpdate::Copied(bytes as u64))
                 }
                 Err(e) => {
-                    error!("Error copying: aborting.");
-                    stat_tx.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))
+                    error!("Error copying {:?}", harc.from);
+                    match harc.block_failed(e) {
+                        Some(err) => stat_tx.send(StatusUpdate::Error(err)),
+                        None => Ok(()),
+                    }
                 }
             };
             if let Err(e) = stat_result {
@@ -150,42 +233,91 @@ fn queue_file_range(
 fn queue_file_blocks(
     source: &Path,
     dest: &Path,
+    tees: &[PathBuf],
     pool: &ThreadPool,
     status_channel: &Arc<dyn StatusUpdater>,
     config: &Arc<Config>,
+    limiter: &Option<Arc<RateLimiter>>,
 ) -> Result<u64> {
-    let handle = CopyHandle::new(source, dest, config)?;
-    let len = handle.metadata.len();
+    let handle = match CopyHandle::new(source, dest, config, status_channel, limiter) {
+        Ok(handle) => handle.tee(tees)?,
+        // Otherwise the handle finishes the entry.
+        Err(e) => {
+            status_channel.send(StatusUpdate::Failed(source.to_path_buf()))?;
+            return Err(e);
+        }
+    };
+
+    // Streamed sources can't be split into blocks.
+    if !handle.metadata.is_file() {
+        return handle.copy_file();
+    }
 
     if handle.try_reflink()? {
         info!("Reflinked, skipping rest of copy");
-        return Ok(len);
+        return Ok(handle.metadata.len());
     }
 
     // Put the open files in an Arc, which we drop once work has been
     // queued. This will keep the files open until all work has been
     // consumed, then close them. (This may be overkill; opening the
-    // files in the workers would also be valid.)
+    // files in the workers would also be valid.) Dropping the last
+    // reference also triggers verification, so this acts as a
+    // per-file completion barrier.
     let harc = Arc::new(handle);
 
-    let queue_whole_file = || {
-        queue_file_range(&harc, 0..len, pool, status_channel)
+    // Any existing data in the target is always overwritten; see
+    // CopyHandle::split_range().
+    let (overlap, rest) = harc.split_range();
+    let mut queued = queue_file_range(&harc, overlap, BlockMode::Copy, pool, status_channel)?;
+
+    let mode = if config.sparse == Sparse::Always && harc.sparse_target() {
+        BlockMode::SkipZeros
+    } else {
+        BlockMode::Copy
+    };
+    let queue_rest = || {
+        queue_file_range(&harc, rest.clone(), mode, pool, status_channel)
+    };
+    // The holes before `end`, if they are to be filled.
+    let queue_holes = |pos: u64, end: u64| {
+        if config.no_punch_holes && pos < end {
+            queue_file_range(&harc, pos..end, BlockMode::Zeros, pool, status_channel)
+        } else {
+            Ok(0)
+        }
     };
 
-    if probably_sparse(&harc.infd)? {
+    queued += if config.sparse == Sparse::Auto && harc.sparse_target() && probably_sparse(&harc.infd)? {
         if let Some(extents) = map_extents(&harc.infd)? {
             let sparse_map = merge_extents(extents)?;
             let mut queued = 0;
+            let mut data = 0;
+            let mut pos = rest.start;
             for ext in sparse_map {
-                queued += queue_file_range(&harc, ext.into(), pool, status_channel)?;
+                let ext: Range<u64> = ext.into();
+                let start = cmp::max(ext.start, rest.start);
+                let end = cmp::min(ext.end, rest.end);
+                if start < end {
+                    queued += queue_holes(pos, start)?;
+                    queued += queue_file_range(&harc, start..end, BlockMode::Copy, pool, status_channel)?;
+                    data += end - start;
+                    pos = end;
+                }
             }
-            Ok(queued)
+            // Skipped holes are removed from the progress total.
+            let holes = (rest.end - rest.start) - data;
+            if !config.no_punch_holes && holes > 0 {
+                status_channel.send(StatusUpdate::Holes(holes))?;
+            }
+            queued + queue_holes(pos, rest.end)?
         } else {
-            queue_whole_file()
+            queue_rest()?
         }
     } else {
-        queue_whole_file()
-    }
+        queue_rest()?
+    };
+    Ok(queued)
 }
 
 // Dispatch worker; receives queued files and hands them to
@@ -196,18 +328,27 @@ fn dispatch_worker(file_q: cbc::Receiver<Operation>, stats: &Arc<dyn StatusUpdat
         .num_threads(nworkers)
         // Use bounded queue for backpressure; this limits open
         // files in-flight so we don't run out of file handles.
-        // FIXME: Number is arbitrary ATM, we should be able to
-        // calculate it from ulimits.
-        .queue_len(128)
+        .queue_len(config.queue_len())
         .build();
+    let limiter = RateLimiter::for_config(&config);
     for op in file_q {
+        // Stop queuing new files; the rest of the queue is discarded.
+        if config.interrupted() {
+            continue;
+        }
         match op {
-            Operation::Copy(from, to) => {
+            Operation::Copy(from, to, tees) => {
                 info!("Dispatch[{:?}]: Copy {:?} -> {:?}", thread::current().id(), from, to);
-                let r = queue_file_blocks(&from, &to, &copy_pool, stats, &config);
+                let r = queue_file_blocks(&from, &to, &tees, &copy_pool, stats, &config, &limiter);
                 if let Err(e) = r {
-                    stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
+                    if config.interrupted() {
+                        continue;
+                    }
+                    stats.send(StatusUpdate::Error(copy_failure(&from, &to, &e)))?;
                     error!("Dispatcher: Error copying {:?} -> {:?}.", from, to);
+                    if config.continue_on_error {
+                        continue;
+                    }
                     return Err(e)
                 }
             }
@@ -215,23 +356,36 @@ fn dispatch_worker(file_q: cbc::Receiver<Operation>, stats: &Arc<dyn StatusUpdat
             // Inline the following operations as the should be near-instant.
             Operation::Link(from, to) => {
                 info!("Dispatch[{:?}]: Symlink {:?} -> {:?}", thread::current().id(), from, to);
-                let r = symlink(&from, &to);
-                if let Err(e) = r {
-                    stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
+                if let Err(e) = copy_symlink(&from, &to, &config) {
+                    stats.send(StatusUpdate::Failed(from.clone()))?;
+                    stats.send(StatusUpdate::Error(copy_failure(&from, &to, &e)))?;
+                    if config.continue_on_error {
+                        error!("Error symlinking: {:?} -> {:?}; skipping.", from, to);
+                        continue;
+                    }
                     error!("Error symlinking: {:?} -> {:?}; aborting.", from, to);
-                    return Err(e.into())
+                    return Err(e)
                 }
+                stats.send(StatusUpdate::Created(CreatedKind::Symlink))?;
+                remove_source_file(&from, &config)?;
             }
 
             Operation::Special(from, to) => {
                 info!("Dispatch[{:?}]: Special file {:?} -> {:?}", thread::current().id(), from, to);
-                if to.exists() {
-                    if config.no_clobber {
-                        return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", to).into());
+                if let Err(e) = copy_special(&from, &to, &config, stats) {
+                    stats.send(StatusUpdate::Failed(from.clone()))?;
+                    if config.interrupted() {
+                        continue;
                     }
-                    remove_file(&to)?;
+                    stats.send(StatusUpdate::Error(copy_failure(&from, &to, &e)))?;
+                    if config.continue_on_error {
+                        error!("Error copying special file: {:?} -> {:?}; skipping.", from, to);
+                        continue;
+                    }
+                    error!("Error copying special file: {:?} -> {:?}; aborting.", from, to);
+                    return Err(e)
                 }
-                copy_node(&from, &to)?;
+                stats.send(StatusUpdate::Created(CreatedKind::Special))?;
             }
         }
     }
@@ -242,3 +396,30 @@ fn dispatch_worker(file_q: cbc::Receiver<Operation>, stats: &Arc<dyn StatusUpdat
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_transient_errors() {
+        let err = |e: Errno| libfs::Error::from(io::Error::from_raw_os_error(e.raw_os_error()));
+        assert!(is_transient(&err(Errno::INTR)));
+        assert!(is_transient(&err(Errno::AGAIN)));
+        assert!(is_transient(&libfs::Error::from(Errno::IO)));
+        assert!(!is_transient(&err(Errno::NOSPC)));
+        assert!(!is_transient(&err(Errno::ACCESS)));
+        assert!(!is_transient(&libfs::Error::InvalidSource("test")));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(Duration::from_millis(50), retry_delay(1));
+        assert_eq!(Duration::from_millis(100), retry_delay(2));
+        assert_eq!(Duration::from_millis(3200), retry_delay(7));
+        assert_eq!(MAX_RETRY_DELAY, retry_delay(8));
+        assert_eq!(MAX_RETRY_DELAY, retry_delay(40));
+        assert_eq!(MAX_RETRY_DELAY, retry_delay(u32::MAX));
+    }
+}
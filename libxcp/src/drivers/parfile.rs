@@ -19,9 +19,6 @@
 
 use crossbeam_channel as cbc;
 use log::{debug, error, info};
-use libfs::copy_node;
-use std::fs::remove_file;
-use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
@@ -29,8 +26,8 @@ use std::thread;
 use crate::config::Config;
 use crate::drivers::CopyDriver;
 use crate::errors::{Result, XcpError};
-use crate::feedback::{StatusUpdate, StatusUpdater};
-use crate::operations::{CopyHandle, Operation, tree_walker};
+use crate::feedback::{CreatedKind, StatusUpdate, StatusUpdater};
+use crate::operations::{copy_failure, copy_special, copy_symlink, finalise, remove_source_file, CopyHandle, Operation, RateLimiter, tree_walker};
 
 // ********************************************************************** //
 
@@ -63,46 +60,72 @@ impl CopyDriver for Driver {
         // Worker threads. Will consume work and then shutdown once the
         // queue is closed by the walker.
         let nworkers = self.config.num_workers();
+        let limiter = RateLimiter::for_config(&self.config);
         let mut joins = Vec::with_capacity(nworkers);
         for _ in 0..nworkers {
             let copy_worker = {
                 let wrx = work_rx.clone();
                 let sc = stats.clone();
                 let conf = self.config.clone();
-                thread::spawn(move || copy_worker(wrx, &conf, sc))
+                let lim = limiter.clone();
+                thread::spawn(move || copy_worker(wrx, &conf, sc, lim))
             };
             joins.push(copy_worker);
         }
 
-        walk_worker.join()
+        let deferred = walk_worker.join()
             .map_err(|_| XcpError::CopyError("Error walking copy tree".to_string()))??;
         for handle in joins {
             handle.join()
                 .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
         }
 
-        Ok(())
+        finalise(deferred, &self.config)
     }
 
 }
 
 // ********************************************************************** //
 
-fn copy_worker(work: cbc::Receiver<Operation>, config: &Arc<Config>, updates: Arc<dyn StatusUpdater>) -> Result<()> {
+// Run the queued operations until the queue is closed. Also used by
+// the sequential driver.
+pub(crate) fn copy_worker(
+    work: cbc::Receiver<Operation>,
+    config: &Arc<Config>,
+    updates: Arc<dyn StatusUpdater>,
+    limiter: Option<Arc<RateLimiter>>,
+) -> Result<()> {
     debug!("Starting copy worker {:?}", thread::current().id());
     for op in work {
         debug!("Received operation {:?}", op);
+        // Discard the rest of the queue; the walker stops too.
+        if config.interrupted() {
+            continue;
+        }
 
         match op {
-            Operation::Copy(from, to) => {
+            Operation::Copy(from, to, tees) => {
                 info!("Worker[{:?}]: Copy {:?} -> {:?}", thread::current().id(), from, to);
                 // copy_file() sends back its own updates, but we should
                 // send back any errors as they may have occurred
                 // before the copy started..
-                let r = CopyHandle::new(&from, &to, config)
-                    .and_then(|hdl| hdl.copy_file(&updates));
+                let r = match CopyHandle::new(&from, &to, config, &updates, &limiter) {
+                    Ok(hdl) => hdl.tee(&tees).and_then(|hdl| hdl.copy_file()),
+                    // Otherwise the handle finishes the entry.
+                    Err(e) => {
+                        updates.send(StatusUpdate::Failed(from.clone()))?;
+                        Err(e)
+                    }
+                };
                 if let Err(e) = r {
-                    updates.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
+                    if config.interrupted() {
+                        continue;
+                    }
+                    updates.send(StatusUpdate::Error(copy_failure(&from, &to, &e)))?;
+                    if config.continue_on_error {
+                        error!("Error copying: {:?} -> {:?}; skipping.", from, to);
+                        continue;
+                    }
                     error!("Error copying: {:?} -> {:?}; aborting.", from, to);
                     return Err(e)
                 }
@@ -110,18 +133,36 @@ fn copy_worker(work: cbc::Receiver<Operation>, config: &Arc<Config>, updates: Ar
 
             Operation::Link(from, to) => {
                 info!("Worker[{:?}]: Symlink {:?} -> {:?}", thread::current().id(), from, to);
-                let _r = symlink(&from, &to);
+                if let Err(e) = copy_symlink(&from, &to, config) {
+                    updates.send(StatusUpdate::Failed(from.clone()))?;
+                    updates.send(StatusUpdate::Error(copy_failure(&from, &to, &e)))?;
+                    if config.continue_on_error {
+                        error!("Error symlinking: {:?} -> {:?}; skipping.", from, to);
+                        continue;
+                    }
+                    error!("Error symlinking: {:?} -> {:?}; aborting.", from, to);
+                    return Err(e)
+                }
+                updates.send(StatusUpdate::Created(CreatedKind::Symlink))?;
+                remove_source_file(&from, config)?;
             }
 
             Operation::Special(from, to) => {
                 info!("Worker[{:?}]: Special file {:?} -> {:?}", thread::current().id(), from, to);
-                if to.exists() {
-                    if config.no_clobber {
-                        return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", to).into());
+                if let Err(e) = copy_special(&from, &to, config, &updates) {
+                    updates.send(StatusUpdate::Failed(from.clone()))?;
+                    if config.interrupted() {
+                        continue;
                     }
-                    remove_file(&to)?;
+                    updates.send(StatusUpdate::Error(copy_failure(&from, &to, &e)))?;
+                    if config.continue_on_error {
+                        error!("Error copying special file: {:?} -> {:?}; skipping.", from, to);
+                        continue;
+                    }
+                    error!("Error copying special file: {:?} -> {:?}; aborting.", from, to);
+                    return Err(e)
                 }
-                copy_node(&from, &to)?;
+                updates.send(StatusUpdate::Created(CreatedKind::Special))?;
             }
 
         }
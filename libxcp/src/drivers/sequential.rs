@@ -0,0 +1,63 @@
+/*
+ * Copyright © 2018, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Copy files one at a time on the calling thread. This has the
+//! lowest overhead for small copies, and is the simplest to debug; it
+//! can also serve as a reference for the parallel drivers.
+
+use crossbeam_channel as cbc;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::drivers::CopyDriver;
+use crate::drivers::parfile::copy_worker;
+use crate::errors::Result;
+use crate::feedback::StatusUpdater;
+use crate::operations::{finalise, tree_walker, RateLimiter};
+
+// ********************************************************************** //
+
+pub struct Driver {
+    config: Arc<Config>,
+}
+
+impl Driver {
+    pub fn new(config: Arc<Config>) -> Result<Self> {
+        // The walk is also single-threaded.
+        let config = Config {
+            workers: 1,
+            ..(*config).clone()
+        };
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+}
+
+impl CopyDriver for Driver {
+    fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
+        // The whole tree is walked first, creating the target
+        // directories, and the queued work is then run in order.
+        let (work_tx, work_rx) = cbc::unbounded();
+        let walked = tree_walker(sources, dest, &self.config, work_tx, stats.clone());
+
+        let limiter = RateLimiter::for_config(&self.config);
+        copy_worker(work_rx, &self.config, stats, limiter)?;
+
+        finalise(walked?, &self.config)
+    }
+}
@@ -16,21 +16,35 @@
 
 //! Custom error types.
 
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rustix::io::Errno;
 
 pub use anyhow::Result;
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Clone, Debug, thiserror::Error)]
 pub enum XcpError {
+    #[error("Failed to replace {0:?} atomically and 'always' was specified: {1}")]
+    AtomicFailed(PathBuf, String),
+
     #[error("Error during copy: {0}")]
     CopyError(String),
 
+    /// The path, the error message, and the OS error code if the
+    /// failure had one.
+    #[error("Failed to copy {0:?}: {1}")]
+    CopyFailed(PathBuf, String, Option<i32>),
+
     #[error("Destination Exists: {0}, {1}")]
     DestinationExists(&'static str, PathBuf),
 
     #[error("Early shutdown: {0}")]
     EarlyShutdown(&'static str),
 
+    #[error("Copy interrupted")]
+    Interrupted,
+
     #[error("Invalid arguments: {0}")]
     InvalidArguments(String),
 
@@ -40,9 +54,27 @@ pub enum XcpError {
     #[error("Invalid source: {0}")]
     InvalidSource(&'static str),
 
+    #[error("No space left on the destination while copying to {0:?}; free some space and try again")]
+    OutOfSpace(PathBuf),
+
     #[error("Failed to reflink file and 'always' was specified: {0}")]
     ReflinkFailed(String),
 
+    #[error("{0:?} and {1:?} are the same file")]
+    SameFile(PathBuf, PathBuf),
+
+    #[error("Reached the --max-total-size limit of {0} bytes after queuing {1} bytes; {2} files of {3} bytes were not copied")]
+    SizeLimitReached(u64, u64, u64, u64),
+
+    #[error("Source file changed during copy, the copy may be inconsistent: {0:?}")]
+    SourceChanged(PathBuf),
+
+    #[error("Symlink loop detected: {0:?} points to its ancestor {1:?}")]
+    SymlinkLoop(PathBuf, PathBuf),
+
+    #[error("Timed out copying {0:?}")]
+    TimedOut(PathBuf),
+
     #[error("Unknown driver: {0}")]
     UnknownDriver(String),
 
@@ -51,4 +83,86 @@ pub enum XcpError {
 
     #[error("Unsupported OS")]
     UnsupportedOS(&'static str),
+
+    #[error("Verification failed, destination does not match source: {0:?}")]
+    VerifyFailed(PathBuf),
+}
+
+impl XcpError {
+    /// A [XcpError::CopyFailed] for `path`, keeping the OS error code
+    /// behind `err` if there is one. If `err` is already an error
+    /// about a file, such as [XcpError::TimedOut], it is returned
+    /// unchanged.
+    pub fn copy_failed(path: &Path, err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<XcpError>() {
+            Some(e) if e.path().is_some() => e.clone(),
+            _ => XcpError::CopyFailed(path.to_path_buf(), err.to_string(), os_error(err)),
+        }
+    }
+
+    /// A stable name for the kind of error, for machine-readable
+    /// output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            XcpError::AtomicFailed(..) => "atomic_failed",
+            XcpError::CopyError(_) => "copy_error",
+            XcpError::CopyFailed(..) => "copy_failed",
+            XcpError::DestinationExists(..) => "destination_exists",
+            XcpError::EarlyShutdown(_) => "early_shutdown",
+            XcpError::Interrupted => "interrupted",
+            XcpError::InvalidArguments(_) => "invalid_arguments",
+            XcpError::InvalidDestination(_) => "invalid_destination",
+            XcpError::InvalidSource(_) => "invalid_source",
+            XcpError::OutOfSpace(_) => "out_of_space",
+            XcpError::ReflinkFailed(_) => "reflink_failed",
+            XcpError::SameFile(..) => "same_file",
+            XcpError::SizeLimitReached(..) => "size_limit_reached",
+            XcpError::SourceChanged(_) => "source_changed",
+            XcpError::SymlinkLoop(..) => "symlink_loop",
+            XcpError::TimedOut(_) => "timed_out",
+            XcpError::UnknownDriver(_) => "unknown_driver",
+            XcpError::UnknownFileType(_) => "unknown_file_type",
+            XcpError::UnsupportedOS(_) => "unsupported_os",
+            XcpError::VerifyFailed(_) => "verify_failed",
+        }
+    }
+
+    /// The file the error is about, if it is about one.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            XcpError::AtomicFailed(path, _)
+            | XcpError::CopyFailed(path, ..)
+            | XcpError::DestinationExists(_, path)
+            | XcpError::OutOfSpace(path)
+            | XcpError::SameFile(path, _)
+            | XcpError::SourceChanged(path)
+            | XcpError::SymlinkLoop(path, _)
+            | XcpError::TimedOut(path)
+            | XcpError::UnknownFileType(path)
+            | XcpError::VerifyFailed(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The OS error code (errno) behind the error, where known.
+    pub fn os_error(&self) -> Option<i32> {
+        match self {
+            XcpError::CopyFailed(_, _, code) => *code,
+            XcpError::OutOfSpace(_) => Some(Errno::NOSPC.raw_os_error()),
+            _ => None,
+        }
+    }
+}
+
+/// The first OS error code in the chain of `err`, if any.
+pub(crate) fn os_error(err: &anyhow::Error) -> Option<i32> {
+    err.chain().find_map(|e| {
+        if let Some(e) = e.downcast_ref::<libfs::Error>() {
+            e.raw_os_error()
+        } else if let Some(e) = e.downcast_ref::<io::Error>() {
+            e.raw_os_error()
+        } else {
+            e.downcast_ref::<Errno>().map(|e| e.raw_os_error())
+        }
+    })
 }
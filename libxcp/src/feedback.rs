@@ -23,13 +23,73 @@
 //!
 //! * [NoopUpdater]
 //! * [ChannelUpdater]
+//!
+//! When copying with [crate::copy()], a [ProgressReporter] such as a
+//! progress bar can be driven from the updates with [Reporter].
 
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use crossbeam_channel as cbc;
+use libfs::NoReflink;
 
 use crate::config::Config;
 use crate::errors::{Result, XcpError};
+use crate::Progress;
+
+/// The type of an operation that would be performed; see
+/// [StatusUpdate::Planned].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlannedOp {
+    Copy,
+    Delete,
+    Hardlink,
+    Mkdir,
+    Symlink,
+    Special,
+}
+
+impl fmt::Display for PlannedOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PlannedOp::Copy => "copy",
+            PlannedOp::Delete => "delete",
+            PlannedOp::Hardlink => "link",
+            PlannedOp::Mkdir => "mkdir",
+            PlannedOp::Symlink => "symlink",
+            PlannedOp::Special => "special",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The type of a non-file entry; see [StatusUpdate::Created].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CreatedKind {
+    Dir,
+    Symlink,
+    Hardlink,
+    Special,
+}
+
+/// A file that has been copied successfully; see
+/// [StatusUpdate::Completed].
+#[derive(Clone, Debug, PartialEq)]
+pub struct CopiedFile {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// The number of bytes copied.
+    pub bytes: u64,
+    /// The hex digest of the file's data if it was verified, using
+    /// the algorithm given by `verify` in the [Config]. Holes in
+    /// sparse files are not included.
+    pub checksum: Option<String>,
+    /// Why the file was copied rather than reflinked, if a reflink
+    /// was attempted.
+    pub no_reflink: Option<NoReflink>,
+}
 
 /// A struct representing an updated status.
 #[derive(Debug)]
@@ -37,12 +97,91 @@ pub enum StatusUpdate {
     /// An update representing a successful copy of bytes between
     /// files.
     Copied(u64),
+    /// A file of this number of bytes was reflinked, so its data was
+    /// not copied. Sent instead of any [StatusUpdate::Copied] for it.
+    Reflinked(u64),
     /// An update representing that this number of bytes will need to be copied.
     Size(u64),
+    /// This number of bytes counted by [StatusUpdate::Size] are holes
+    /// in a sparse file, so will be skipped rather than copied. Sent
+    /// as the file's copy starts.
+    Holes(u64),
+    /// All [StatusUpdate::Size] updates have been sent, so the total
+    /// is now known.
+    SizeComplete,
+    /// An entry of any type has been found to copy. Each entry is
+    /// complete at [StatusUpdate::Finished], for regular files that
+    /// were started, [StatusUpdate::Created], or
+    /// [StatusUpdate::Failed].
+    Entry,
+    /// A directory, symlink, hardlink or special file has been
+    /// created at the target.
+    Created(CreatedKind),
+    /// This entry failed before its copy started, or before it was
+    /// created, so has no [StatusUpdate::Finished] or
+    /// [StatusUpdate::Created]. The failure itself is reported with
+    /// [StatusUpdate::Error], unless the copy was interrupted.
+    Failed(PathBuf),
+    /// An operation that would have been performed, along with its
+    /// source and target. For [PlannedOp::Delete] the source is the
+    /// missing path the target corresponds to. Only sent when
+    /// `dry_run` is set in the [Config].
+    Planned(PlannedOp, PathBuf, PathBuf),
+    /// Copying of this file has started.
+    Started(PathBuf),
+    /// All data for this file has been copied and its metadata
+    /// applied. Every [StatusUpdate::Started] will have a
+    /// corresponding `Finished`, even if the copy failed.
+    Finished(PathBuf),
+    /// This file was copied successfully. Sent before the
+    /// corresponding [StatusUpdate::Finished].
+    Completed(CopiedFile),
     /// An error during a copy operation.
     Error(XcpError)
 }
 
+/// Totals for a copy, as returned by [crate::copy()]. Those copying
+/// with a driver directly can accumulate them by passing each update
+/// to [CopyStats::update].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CopyStats {
+    /// Regular files copied successfully, including those reflinked.
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    pub hardlinks: u64,
+    pub specials: u64,
+    /// Bytes of data copied, excluding reflinked files.
+    pub bytes: u64,
+    pub reflinked_bytes: u64,
+    pub reflinked_files: u64,
+    pub errors: u64,
+    /// The duration of the copy; only set by [crate::copy()].
+    pub elapsed: Duration,
+}
+
+impl CopyStats {
+    /// Add the effect of `update` to the totals.
+    pub fn update(&mut self, update: &StatusUpdate) {
+        match update {
+            StatusUpdate::Copied(bytes) => self.bytes += bytes,
+            StatusUpdate::Reflinked(bytes) => {
+                self.reflinked_bytes += bytes;
+                self.reflinked_files += 1;
+            }
+            StatusUpdate::Completed(_) => self.files += 1,
+            StatusUpdate::Created(kind) => match kind {
+                CreatedKind::Dir => self.dirs += 1,
+                CreatedKind::Symlink => self.symlinks += 1,
+                CreatedKind::Hardlink => self.hardlinks += 1,
+                CreatedKind::Special => self.specials += 1,
+            },
+            StatusUpdate::Error(_) => self.errors += 1,
+            _ => {}
+        }
+    }
+}
+
 pub trait StatusUpdater: Sync + Send {
     fn send(&self, update: StatusUpdate) -> Result<()>;
 }
@@ -57,6 +196,7 @@ pub struct ChannelUpdater {
     chan_rx: cbc::Receiver<StatusUpdate>,
     config: Arc<Config>,
     sent: AtomicU64,
+    pending: AtomicU64,
 }
 
 impl ChannelUpdater {
@@ -68,6 +208,7 @@ impl ChannelUpdater {
             chan_rx,
             config: config.clone(),
             sent: AtomicU64::new(0),
+            pending: AtomicU64::new(0),
         }
     }
 
@@ -91,15 +232,36 @@ impl ChannelUpdater {
 impl StatusUpdater for ChannelUpdater {
     // Wrapper around channel-send that groups updates together
     fn send(&self, update: StatusUpdate) -> Result<()> {
-        if let StatusUpdate::Copied(bytes) = update {
-            // Avoid saturating the queue with small writes
-            let bsize = self.config.block_size;
-            let prev_written = self.sent.fetch_add(bytes, Ordering::Relaxed);
-            if ((prev_written + bytes) / bsize) > (prev_written / bsize) {
+        match update {
+            StatusUpdate::Copied(bytes) => {
+                // Avoid saturating the queue with small writes
+                let bsize = self.config.block_size;
+                let prev_written = self.sent.fetch_add(bytes, Ordering::Relaxed);
+                self.pending.fetch_add(bytes, Ordering::Relaxed);
+                if ((prev_written + bytes) / bsize) > (prev_written / bsize) {
+                    self.flush()?;
+                }
+            }
+            StatusUpdate::Finished(_) | StatusUpdate::Created(_) => {
+                // Ensure the totals are accurate at the end of each
+                // file, or of each FIFO streamed by pipe_through.
+                self.flush()?;
+                self.chan_tx.send(update)?;
+            }
+            _ => {
                 self.chan_tx.send(update)?;
             }
-        } else {
-            self.chan_tx.send(update)?;
+        }
+        Ok(())
+    }
+}
+
+impl ChannelUpdater {
+    // Send any accumulated copied bytes.
+    fn flush(&self) -> Result<()> {
+        let pending = self.pending.swap(0, Ordering::Relaxed);
+        if pending > 0 {
+            self.chan_tx.send(StatusUpdate::Copied(pending))?;
         }
         Ok(())
     }
@@ -113,3 +275,104 @@ impl StatusUpdater for NoopUpdater {
         Ok(())
     }
 }
+
+/// A display of copy progress, such as a progress bar. Only the size
+/// and byte counts are required; the per-file events default to
+/// doing nothing.
+pub trait ProgressReporter {
+    /// The total number of bytes to copy is now known.
+    fn set_size(&self, size: u64);
+    /// More bytes have been found to copy.
+    fn inc_size(&self, size: u64);
+    /// Bytes counted in the total will not be copied, as they are
+    /// holes in a sparse file.
+    fn dec_size(&self, _size: u64) {
+    }
+    /// Bytes have been copied.
+    fn inc(&self, size: u64);
+    /// A file of `size` bytes has been reflinked rather than copied.
+    fn reflinked(&self, _size: u64) {
+    }
+    fn start_file(&self, _path: &Path) {
+    }
+    fn finish_file(&self, _path: &Path) {
+    }
+    fn error(&self, _err: &XcpError) {
+    }
+    /// More entries of any type have been found to copy.
+    fn inc_entries(&self, _count: u64) {
+    }
+    /// Entries have been completed.
+    fn entries_done(&self, _count: u64) {
+    }
+    /// The copy has finished, with the totals of what was copied;
+    /// see [CopyStats].
+    fn end(&self, stats: &CopyStats);
+}
+
+/// Drives a [ProgressReporter] from the updates passed to
+/// [Progress]. [ProgressReporter::end()] is not called, as the copy
+/// may still fail after the last update.
+pub struct Reporter<'a> {
+    reporter: &'a dyn ProgressReporter,
+    total: u64,
+    // Copied bytes held back until the interval has passed.
+    pending: u64,
+    interval: Duration,
+    last: Instant,
+}
+
+impl<'a> Reporter<'a> {
+    pub fn new(reporter: &'a dyn ProgressReporter) -> Self {
+        Self::with_interval(reporter, Duration::ZERO)
+    }
+
+    /// Pass on the bytes copied at most once per `interval`, as
+    /// redrawing progress for every block can be costly when they
+    /// are small. [Reporter::flush()] should be called before
+    /// [ProgressReporter::end()] so that the final count is complete.
+    pub fn with_interval(reporter: &'a dyn ProgressReporter, interval: Duration) -> Self {
+        Self { reporter, total: 0, pending: 0, interval, last: Instant::now() }
+    }
+
+    /// Pass on any copied bytes held back by the interval.
+    pub fn flush(&mut self) {
+        if self.pending > 0 {
+            self.reporter.inc(self.pending);
+            self.pending = 0;
+        }
+        self.last = Instant::now();
+    }
+}
+
+impl Progress for Reporter<'_> {
+    fn update(&mut self, update: &StatusUpdate) {
+        match update {
+            StatusUpdate::Copied(bytes) => {
+                self.pending += bytes;
+                if self.last.elapsed() >= self.interval {
+                    self.flush();
+                }
+            }
+            StatusUpdate::Size(bytes) => {
+                self.total += bytes;
+                self.reporter.inc_size(*bytes);
+            }
+            StatusUpdate::Holes(bytes) => {
+                self.total = self.total.saturating_sub(*bytes);
+                self.reporter.dec_size(*bytes);
+            }
+            StatusUpdate::Reflinked(bytes) => self.reporter.reflinked(*bytes),
+            StatusUpdate::SizeComplete => self.reporter.set_size(self.total),
+            StatusUpdate::Started(path) => self.reporter.start_file(path),
+            StatusUpdate::Finished(path) => {
+                self.reporter.finish_file(path);
+                self.reporter.entries_done(1);
+            }
+            StatusUpdate::Entry => self.reporter.inc_entries(1),
+            StatusUpdate::Created(_) | StatusUpdate::Failed(_) => self.reporter.entries_done(1),
+            StatusUpdate::Error(err) => self.reporter.error(err),
+            StatusUpdate::Planned(..) | StatusUpdate::Completed(_) => {}
+        }
+    }
+}
@@ -21,6 +21,44 @@
 //!
 //! # Usage example
 //!
+//! The simplest way to copy is with [copy()], which runs a driver to
+//! completion and passes each [feedback::StatusUpdate] to a
+//! [Progress] implementation; any `FnMut(&StatusUpdate)` closure will
+//! do. The totals for the copy are returned as a
+//! [feedback::CopyStats].
+//!
+//!     # use libxcp::errors::Result;
+//!     # use std::path::PathBuf;
+//!     # use std::sync::Arc;
+//!     # use tempfile::TempDir;
+//!     use libxcp::config::Config;
+//!     use libxcp::copy;
+//!     use libxcp::drivers::Drivers;
+//!     use libxcp::feedback::StatusUpdate;
+//!     # fn main() -> Result<()> {
+//!
+//!     let sources = vec![PathBuf::from("src")];
+//!     let dest = TempDir::new()?;
+//!     let config = Arc::new(Config {
+//!         workers: 4,
+//!         ..Config::default()
+//!     });
+//!
+//!     let stats = copy(sources, dest.path(), Drivers::ParFile, &config, &mut |update: &StatusUpdate| {
+//!         if let StatusUpdate::Started(path) = update {
+//!             println!("Copying {:?}", path);
+//!         }
+//!     })?;
+//!
+//!     println!("Copied {} files, {} bytes in {:?}", stats.files, stats.bytes, stats.elapsed);
+//!     # Ok(())
+//!     # }
+//!
+//! # Lower-level usage
+//!
+//! Drivers can also be run directly, with updates received over a
+//! channel:
+//!
 //!     # use libxcp::errors::Result;
 //!     # use std::path::PathBuf;
 //!     # use std::sync::Arc;
@@ -57,12 +95,26 @@
 //!             StatusUpdate::Copied(v) => {
 //!                 println!("Copied {} bytes", v);
 //!             },
+//!             StatusUpdate::Reflinked(v) => {
+//!                 println!("Reflinked {} bytes", v);
+//!             },
 //!             StatusUpdate::Size(v) => {
 //!                 println!("Size update: {}", v);
 //!             },
 //!             StatusUpdate::Error(e) => {
 //!                 panic!("Error during copy: {}", e);
 //!             }
+//!             // Only sent on dry-runs.
+//!             StatusUpdate::Planned(..) => {}
+//!             StatusUpdate::Holes(_) | StatusUpdate::SizeComplete => {}
+//!             StatusUpdate::Entry | StatusUpdate::Created(_) | StatusUpdate::Failed(_) => {}
+//!             StatusUpdate::Started(path) => {
+//!                 println!("Copying {:?}", path);
+//!             }
+//!             StatusUpdate::Completed(file) => {
+//!                 println!("Copied {:?} to {:?}", file.from, file.to);
+//!             }
+//!             StatusUpdate::Finished(_) => {}
 //!         }
 //!     }
 //!
@@ -76,15 +128,116 @@
 //!
 //! [xcp]: https://crates.io/crates/xcp/
 
+pub mod checkpoint;
 pub mod config;
+pub mod dedup;
 pub mod drivers;
 pub mod errors;
 pub mod feedback;
 
 // Internal
 mod backup;
+mod delete;
 mod operations;
 mod paths;
+mod verify;
+
+pub use operations::{create_dirs, RateLimiter};
+pub use verify::Checksum;
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::drivers::{load_driver, Drivers};
+use crate::errors::{Result, XcpError};
+use crate::feedback::{ChannelUpdater, CopyStats, StatusUpdate, StatusUpdater};
+use crate::operations::check_sources;
+
+/// Receives status updates from [copy()] as the copy runs, e.g. to
+/// display a progress bar. This is implemented for any
+/// `FnMut(&StatusUpdate)` closure.
+pub trait Progress {
+    fn update(&mut self, update: &StatusUpdate);
+}
+
+impl<F: FnMut(&StatusUpdate)> Progress for F {
+    fn update(&mut self, update: &StatusUpdate) {
+        self(update)
+    }
+}
+
+/// Copy `sources` to `dest` with the given driver, blocking until the
+/// copy is complete. Updates are passed to `progress` on the calling
+/// thread as they arrive, and the totals are returned once it
+/// succeeds.
+///
+/// Failures of individual files are passed to `progress` as
+/// [StatusUpdate::Error]. Unless [Config::continue_on_error] is set,
+/// the first of these stops the driver, setting [Config::interrupt]
+/// if one is given, and is returned once the driver has finished the
+/// blocks in progress. Otherwise the copy continues and an
+/// [XcpError::CopyError] with the number of failures is returned at
+/// the end. The totals of a failed copy can
+/// be kept by passing each update to [CopyStats::update] in
+/// `progress`.
+pub fn copy(sources: Vec<PathBuf>, dest: &Path, driver: Drivers, config: &Arc<Config>, progress: &mut dyn Progress) -> Result<CopyStats> {
+    // Invalid arguments are returned before any thread is started.
+    check_sources(&sources, dest, config)?;
+    // A flag is needed to stop the driver after an error.
+    let config = match config.interrupt {
+        Some(_) => config.clone(),
+        None => Arc::new(Config { interrupt: Some(Arc::default()), ..(**config).clone() }),
+    };
+    let interrupt = config.interrupt.clone().expect("interrupt flag is set");
+    let driver = load_driver(driver, &config)?;
+
+    let start = Instant::now();
+    let updater = ChannelUpdater::new(&config);
+    let stat_rx = updater.rx_channel();
+    let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
+
+    let dest = dest.to_path_buf();
+    let handle = thread::spawn(move || {
+        driver.copy(sources, &dest, stats)
+    });
+
+    // Our end of the channel ends once the driver has finished and
+    // dropped the updater.
+    let mut totals = CopyStats::default();
+    let mut failure = None;
+    for stat in stat_rx {
+        // Once stopping, the remaining updates are drained so that
+        // the driver can finish.
+        if failure.is_some() {
+            continue;
+        }
+        progress.update(&stat);
+        totals.update(&stat);
+        if let StatusUpdate::Error(e) = stat {
+            if !config.continue_on_error {
+                interrupt.store(true, Ordering::Relaxed);
+                failure = Some(e);
+            }
+        }
+    }
+
+    let result = handle.join()
+        .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))?;
+    if let Some(e) = failure {
+        return Err(e.into());
+    }
+    result?;
+
+    if totals.errors > 0 {
+        return Err(XcpError::CopyError(format!("{} files failed to copy", totals.errors)).into());
+    }
+    totals.elapsed = start.elapsed();
+    Ok(totals)
+}
 
 #[cfg(test)]
 #[allow(unused)]
@@ -130,6 +283,16 @@ mod tests {
                     println!("Error during copy: {}", e);
                     return Err(e.into());
                 }
+                StatusUpdate::Planned(..)
+                    | StatusUpdate::Reflinked(_)
+                    | StatusUpdate::Holes(_)
+                    | StatusUpdate::SizeComplete
+                    | StatusUpdate::Entry
+                    | StatusUpdate::Created(_)
+                    | StatusUpdate::Failed(_)
+                    | StatusUpdate::Started(_)
+                    | StatusUpdate::Completed(_)
+                    | StatusUpdate::Finished(_) => {}
             }
         }
 
@@ -140,4 +303,202 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn copy_with_progress() -> Result<()> {
+        let dest = TempDir::new()?;
+        let config = Arc::new(Config::default());
+
+        let (mut size, mut copied, mut files) = (0, 0, 0);
+        let (mut entries, mut created, mut completed) = (0, 0, 0);
+        crate::copy(vec![PathBuf::from("src")], dest.path(), Drivers::ParFile, &config, &mut |update: &StatusUpdate| {
+            match update {
+                StatusUpdate::Size(v) => size += v,
+                StatusUpdate::Copied(v) => copied += v,
+                StatusUpdate::Finished(_) => files += 1,
+                StatusUpdate::Completed(_) => completed += 1,
+                StatusUpdate::Entry => entries += 1,
+                StatusUpdate::Created(_) => created += 1,
+                _ => {}
+            }
+        })?;
+
+        assert!(size > 0);
+        assert_eq!(size, copied);
+        assert!(files > 0);
+        assert_eq!(files, completed);
+        // The source directories are entries too.
+        assert!(created > 0);
+        assert_eq!(entries, files + created);
+        assert!(dest.path().join("src").join("lib.rs").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn copy_returns_stats() -> Result<()> {
+        let source = TempDir::new()?;
+        let dest = TempDir::new()?;
+        std::fs::create_dir(source.path().join("dir"))?;
+        std::fs::write(source.path().join("dir/file.txt"), "data")?;
+        std::fs::write(source.path().join("empty.txt"), "")?;
+        std::os::unix::fs::symlink("dir/file.txt", source.path().join("link"))?;
+
+        let config = Arc::new(Config::default());
+        let stats = crate::copy(vec![source.path().to_path_buf()], dest.path(), Drivers::ParFile, &config, &mut |_: &StatusUpdate| {})?;
+
+        assert_eq!(2, stats.files);
+        // The source directory is created too.
+        assert_eq!(2, stats.dirs);
+        assert_eq!(1, stats.symlinks);
+        assert_eq!(0, stats.hardlinks);
+        assert_eq!(0, stats.specials);
+        assert_eq!(4, stats.bytes + stats.reflinked_bytes);
+        assert_eq!(0, stats.errors);
+
+        Ok(())
+    }
+
+    #[test]
+    fn copy_error_stops_driver() -> Result<()> {
+        let source = TempDir::new()?;
+        let dest = TempDir::new()?;
+        for i in 0..100 {
+            std::fs::write(source.path().join(format!("file{}.txt", i)), "data")?;
+        }
+        // A directory in the way of a file fails its copy.
+        let target = dest.path().join(source.path().file_name().unwrap());
+        std::fs::create_dir_all(target.join("file0.txt/sub"))?;
+
+        let config = Arc::new(Config::default());
+        let result = crate::copy(vec![source.path().to_path_buf()], dest.path(), Drivers::ParFile, &config, &mut |_: &StatusUpdate| {});
+        assert!(result.is_err());
+
+        // The driver has finished, so nothing more is written.
+        let count = || std::fs::read_dir(&target).unwrap().count();
+        let copied = count();
+        thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(copied, count());
+        // The caller's config is not changed.
+        assert!(config.interrupt.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn copy_with_reporter() -> Result<()> {
+        use std::cell::Cell;
+        use crate::feedback::{CopyStats, ProgressReporter, Reporter};
+
+        #[derive(Default)]
+        struct Counts {
+            size: Cell<u64>,
+            found: Cell<u64>,
+            copied: Cell<u64>,
+        }
+        impl ProgressReporter for Counts {
+            fn set_size(&self, size: u64) {
+                self.size.set(size);
+            }
+            fn inc_size(&self, size: u64) {
+                self.found.set(self.found.get() + size);
+            }
+            fn inc(&self, size: u64) {
+                self.copied.set(self.copied.get() + size);
+            }
+            fn end(&self, _stats: &CopyStats) {
+            }
+        }
+
+        let dest = TempDir::new()?;
+        let config = Arc::new(Config::default());
+        let counts = Counts::default();
+        crate::copy(vec![PathBuf::from("src")], dest.path(), Drivers::ParFile, &config, &mut Reporter::new(&counts))?;
+
+        assert!(counts.size.get() > 0);
+        assert_eq!(counts.size.get(), counts.found.get());
+        assert_eq!(counts.size.get(), counts.copied.get());
+
+        Ok(())
+    }
+
+    #[test]
+    fn copy_rejects_dir_into_itself() -> Result<()> {
+        let config = Arc::new(Config::default());
+        let mut updates = 0;
+        let result = crate::copy(vec![PathBuf::from("src")], &PathBuf::from("src/copy"), Drivers::ParFile, &config, &mut |_: &StatusUpdate| {
+            updates += 1;
+        });
+
+        let err = result.unwrap_err();
+        assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::InvalidDestination(_))));
+        // Nothing was started.
+        assert_eq!(0, updates);
+
+        Ok(())
+    }
+
+    #[test]
+    fn copy_reports_failure() -> Result<()> {
+        let dest = TempDir::new()?;
+        let config = Arc::new(Config {
+            no_clobber: true,
+            ..Config::default()
+        });
+        std::fs::write(dest.path().join("lib.rs"), "existing")?;
+
+        let mut errors = 0;
+        let result = crate::copy(vec![PathBuf::from("src/lib.rs")], dest.path(), Drivers::ParFile, &config, &mut |update: &StatusUpdate| {
+            if let StatusUpdate::Error(_) = update {
+                errors += 1;
+            }
+        });
+
+        assert!(result.is_err());
+        assert_eq!(1, errors);
+
+        Ok(())
+    }
+
+    #[test]
+    fn file_start_finish_paired() -> Result<()> {
+        let drivers = [
+            Drivers::ParFile,
+            #[cfg(feature = "parblock")]
+            Drivers::ParBlock,
+            Drivers::Sequential,
+        ];
+        for drv in drivers {
+            let sources = vec![PathBuf::from("src")];
+            let dest = TempDir::new()?;
+
+            let config = Arc::new(Config::default());
+            let updater = ChannelUpdater::new(&config);
+            let stat_rx = updater.rx_channel();
+            let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
+
+            let driver = load_driver(drv, &config)?;
+            let handle = thread::spawn(move || {
+                driver.copy(sources, dest.path(), stats)
+            });
+
+            let (mut started, mut finished) = (vec![], vec![]);
+            for stat in stat_rx {
+                match stat {
+                    StatusUpdate::Started(p) => started.push(p),
+                    StatusUpdate::Finished(p) => finished.push(p),
+                    _ => {}
+                }
+            }
+            handle.join()
+                .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
+
+            assert!(!started.is_empty());
+            started.sort();
+            finished.sort();
+            assert_eq!(started, finished);
+        }
+
+        Ok(())
+    }
 }
@@ -14,98 +14,691 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::{cmp, thread};
-use std::fs::{self, canonicalize, create_dir_all, read_link, File, Metadata};
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::{cmp, iter, thread};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::{Entry, RandomState};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, canonicalize, create_dir_all, read_link, File, Metadata, OpenOptions, Permissions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind, Read, Write};
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, FileExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once, OnceLock};
+use std::time::{Duration, Instant};
 
 use crossbeam_channel as cbc;
 use libfs::{
-    allocate_file, copy_file_bytes, copy_owner, copy_permissions, copy_timestamps, next_sparse_segments, probably_sparse, reflink, sync, FileType
+    advise_sequential, allocate_file, clone_file, copy_acl, copy_node, copy_capabilities, copy_flags, copy_default_acl, copy_file_offset, copy_file_offset_direct, copy_file_offset_mmap, copy_file_offset_rw, copy_file_offset_sparse, copy_link_owner, copy_link_xattr, copy_owner, copy_permissions, copy_timestamps, copy_xattr,
+    drop_cache, leading_data_len, next_sparse_segments, probably_sparse, reflink_extents, reflink_with_reason, set_direct_io, sync, write_zeros, FileType, NoReflink, CLONEFILE_SUPPORTED
 };
+use ignore::{WalkBuilder, WalkState};
 use log::{debug, error, info, warn};
-use walkdir::WalkDir;
+use rustix::event::{poll, PollFd, PollFlags};
+use rustix::fs::{fcntl_setfl, OFlags};
+use rustix::io::Errno;
 
 use crate::backup::{get_backup_path, needs_backup};
-use crate::config::{Config, Reflink};
-use crate::errors::{Result, XcpError};
-use crate::feedback::{StatusUpdate, StatusUpdater};
-use crate::paths::{parse_ignore, ignore_filter};
+use crate::config::{Atomic, Chmod, Config, CopyMethod, QuickCheck, Reflink, Sparse, Verify};
+use crate::delete::delete_extras;
+use crate::errors::{os_error, Result, XcpError};
+use crate::feedback::{CopiedFile, CreatedKind, PlannedOp, StatusUpdate, StatusUpdater};
+use crate::paths::{is_within, ignore_filter, Ignores};
+use crate::verify::{content_digest, contents_match, data_ranges, files_match, prefix_matches};
 
+// The read size when streaming a special file.
+const STREAM_BUF_SIZE: usize = 1024 * 1024;
+
+// The most read at once for a tee copy; see Config::tee.
+const TEE_BUF_SIZE: u64 = 1024 * 1024;
+
+// How often to check for the other ends of FIFOs, and for the
+// timeout, while waiting on them; see Config::pipe_through.
+const PIPE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The setuid, setgid and sticky bits; see Config::strip_special_bits.
+const SPECIAL_BITS: u32 = 0o7000;
+
+/// Limits the combined rate of copies made by all workers to
+/// [Config::bwlimit]. Each copy reserves its bytes before starting,
+/// and waits until the limit would allow them to have been copied.
 #[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    // When the bytes reserved so far will have been paid for.
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// A limiter allowing `rate` bytes per second.
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Create a shared limiter if the [Config] sets a limit.
+    pub fn for_config(config: &Config) -> Option<Arc<Self>> {
+        config.bwlimit.map(|rate| Arc::new(Self::new(rate)))
+    }
+
+    /// Block until `bytes` may be copied.
+    pub fn acquire(&self, bytes: u64) {
+        let until = {
+            let mut next = self.next.lock().unwrap();
+            // Unused time isn't banked, so an idle period doesn't
+            // allow a burst over the limit.
+            let start = cmp::max(*next, Instant::now());
+            *next = start + Duration::from_secs_f64(bytes as f64 / self.rate);
+            *next
+        };
+        let now = Instant::now();
+        if until > now {
+            thread::sleep(until - now);
+        }
+    }
+}
+
 pub struct CopyHandle {
+    pub from: PathBuf,
+    pub to: PathBuf,
     pub infd: File,
     pub outfd: File,
     pub metadata: Metadata,
     pub config: Arc<Config>,
+    /// The block size to copy this file with; see
+    /// [Config::auto_block_size].
+    pub block_size: u64,
+    /// The limiter shared by all copies, if the rate is limited.
+    pub limiter: Option<Arc<RateLimiter>>,
+    /// The part of the source to copy; see [Config::offset].
+    pub range: Range<u64>,
+    stats: Arc<dyn StatusUpdater>,
+    failed: AtomicBool,
+    // The length of the target before the copy, for ranged copies.
+    existing: u64,
+    // The temporary file being written to, for atomic copies.
+    tmp: Option<PathBuf>,
+    // Whether the target was created as a clone of the source, or of
+    // an identical earlier target; see Config::dedup.
+    cloned: bool,
+    // Whether the target is a hardlink to an identical earlier target,
+    // and so shares its metadata.
+    linked: bool,
+    // The digest of the source, if deduplicating.
+    digest: Option<Vec<u8>>,
+    // Why a reflink attempt failed.
+    no_reflink: OnceLock<NoReflink>,
+    // Whether holes can be left in the target.
+    sparse_target: bool,
+    // The bytes read from a FIFO or device; see Config::read_special.
+    streamed: AtomicU64,
+    // The alignment of blocks copied with direct I/O; see
+    // Config::direct_io.
+    direct_align: u64,
+    // Whether the files are still open for direct I/O.
+    direct: AtomicBool,
+    // When the copy must be complete by; see Config::timeout.
+    deadline: Option<Instant>,
+    // Copies of the same source to the other destinations, written
+    // from the blocks read for this one; see Config::tee.
+    mirrors: Vec<CopyHandle>,
+    // Whether this is one of another handle's mirrors.
+    mirror: bool,
+    // The end of the data written contiguously from the start of the
+    // target, which an interrupted copy is truncated to. Blocks
+    // written out of order don't advance it, so it may be short.
+    written: AtomicU64,
 }
 
 impl CopyHandle {
-    pub fn new(from: &Path, to: &Path, config: &Arc<Config>) -> Result<CopyHandle> {
+    pub fn new(from: &Path, to: &Path, config: &Arc<Config>, stats: &Arc<dyn StatusUpdater>, limiter: &Option<Arc<RateLimiter>>) -> Result<CopyHandle> {
         let infd = File::open(from)?;
         let metadata = infd.metadata()?;
 
-        if needs_backup(to, config)? {
-            let backup = get_backup_path(to)?;
-            info!("Backup: Rename {:?} to {:?}", to, backup);
-            fs::rename(to, backup)?;
+        // Writing to the source, via a hardlink or a symlink to it,
+        // would truncate it before it is read.
+        if let Ok(tmeta) = to.metadata() {
+            if (tmeta.dev(), tmeta.ino()) == (metadata.dev(), metadata.ino()) {
+                return Err(XcpError::SameFile(from.to_path_buf(), to.to_path_buf()).into());
+            }
+        }
+
+        // Checked before the target is created, so that a rejected
+        // block size leaves nothing behind.
+        let mut block_size = config.file_block_size(&metadata);
+        let direct_align = if config.direct_io && config.tee.is_empty() && metadata.is_file() {
+            let align = direct_io_alignment(&metadata, to)?;
+            // A single block needs no alignment; any tail is copied
+            // without direct I/O.
+            if block_size % align != 0 && block_size < metadata.len() {
+                if !config.auto_block_size {
+                    let msg = format!("Block size {} is not a multiple of the {} byte alignment required for direct I/O of {:?}",
+                                      block_size, align, from);
+                    return Err(XcpError::InvalidArguments(msg).into());
+                }
+                block_size = block_size.next_multiple_of(align);
+            }
+            align
+        } else {
+            0
+        };
+
+        let resumed = if config.append && metadata.is_file() {
+            resume_target(&infd, metadata.len(), to, block_size)?
+        } else {
+            None
+        };
+        let range = match &resumed {
+            Some((_, offset)) => *offset..metadata.len(),
+            None => config.copy_range(metadata.len()),
+        };
+        let kept = resumed.as_ref().map_or(0, |(_, offset)| *offset);
+        let in_place = config.ranged() || resumed.is_some();
+
+        let digest = match &config.dedup {
+            Some(_) if metadata.is_file() && metadata.len() > 0 && !in_place => {
+                Some(content_digest(&infd, metadata.len())?)
+            }
+            _ => None,
+        };
+        let duplicate = config.dedup.as_ref()
+            .zip(digest.as_ref())
+            .and_then(|(dedup, digest)| dedup.find(metadata.len(), digest));
+
+        // Ranged and resumed copies update the target in place.
+        // Otherwise atomic copies leave the target (and any backup)
+        // alone until the copy has succeeded.
+        let (outfd, tmp, existing) = if let Some((fd, offset)) = resumed {
+            (fd, None, offset)
+        } else if config.ranged() {
+            let fd = OpenOptions::new().write(true).create(true).truncate(false).open(to)?;
+            let existing = fd.metadata()?.len();
+            (fd, None, existing)
+        } else {
+            match create_atomic_tmp(to, config)? {
+                Some((fd, path)) => (fd, Some(path), 0),
+                None => {
+                    backup_target(to, config)?;
+                    (File::create(to)?, None, 0)
+                }
+            }
+        };
+        // Clones would skip the read that the other destinations are
+        // written from.
+        let (outfd, cloned, linked) = if let Some(existing) = &duplicate {
+            dedup_target(existing, tmp.as_deref().unwrap_or(to), outfd, config)?
+        } else if CLONEFILE_SUPPORTED && config.reflink != Reflink::Never && !in_place && config.tee.is_empty() {
+            let (outfd, cloned) = clone_target(from, tmp.as_deref().unwrap_or(to), outfd)?;
+            (outfd, cloned, false)
+        } else {
+            (outfd, false, false)
+        };
+        // A link has nothing left to copy.
+        let range = if linked { range.end..range.end } else { range };
+        let written = AtomicU64::new(cmp::min(range.start, existing));
+        // A clone already has the source's contents.
+        if !cloned && !linked {
+            if let Err(e) = allocate_file(&outfd, cmp::max(existing, range.end)) {
+                if let Some(path) = &tmp {
+                    let _ = fs::remove_file(path);
+                }
+                return Err(e.into());
+            }
+        }
+        // If the filesystem supports holes the extended target will
+        // be one; otherwise the extension is allocated (e.g. FAT).
+        let sparse_target = !cloned && !linked && probably_sparse(&outfd)?;
+
+        if config.no_cache {
+            for fd in [&infd, &outfd] {
+                if let Err(e) = advise_sequential(fd) {
+                    debug!("Failed to advise sequential access to {:?}: {}", fd, e);
+                }
+            }
         }
 
-        let outfd = File::create(to)?;
-        allocate_file(&outfd, metadata.len())?;
+        let direct = direct_align > 0 && !cloned && !linked && enable_direct_io(from, &infd, &outfd);
 
         let handle = CopyHandle {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
             infd,
             outfd,
+            block_size,
             metadata,
             config: config.clone(),
+            limiter: limiter.clone(),
+            range,
+            stats: stats.clone(),
+            failed: AtomicBool::new(false),
+            existing,
+            tmp,
+            cloned,
+            linked,
+            digest,
+            no_reflink: OnceLock::new(),
+            sparse_target,
+            streamed: AtomicU64::new(0),
+            direct_align,
+            direct: AtomicBool::new(direct),
+            deadline: config.timeout.map(|t| Instant::now() + t),
+            mirrors: Vec::new(),
+            mirror: false,
+            written,
         };
+        stats.send(StatusUpdate::Started(handle.from.clone()))?;
+        // The kept part of a resumed target counts as copied, as does
+        // a linked duplicate, so that the progress completes.
+        if kept > 0 {
+            stats.send(StatusUpdate::Copied(kept))?;
+        }
+        if linked {
+            stats.send(StatusUpdate::Copied(handle.metadata.len()))?;
+        }
 
         Ok(handle)
     }
 
-    /// Copy len bytes from wherever the descriptor cursors are set.
-    fn copy_bytes(&self, len: u64, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
+    /// Also copy the source to each of `targets` as it is read; see
+    /// [Config::tee]. Under [Config::continue_on_error] a target that
+    /// can't be created is reported and skipped.
+    pub fn tee(mut self, targets: &[PathBuf]) -> Result<CopyHandle> {
+        let stats: Arc<dyn StatusUpdater> = Arc::new(MirrorUpdater(self.stats.clone()));
+        for to in targets {
+            match CopyHandle::new(&self.from, to, &self.config, &stats, &None) {
+                Ok(mut mirror) => {
+                    mirror.mirror = true;
+                    self.mirrors.push(mirror);
+                }
+                Err(e) if self.config.continue_on_error => {
+                    error!("Error copying {:?} -> {:?}; skipping: {}", self.from, to, e);
+                    self.stats.send(StatusUpdate::Error(XcpError::copy_failed(to, &e)))?;
+                }
+                Err(e) => {
+                    self.mark_failed();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    // Write `buf` at `off` to each mirror. A failed mirror is
+    // abandoned under continue_on_error, and the copy continues to
+    // the others.
+    fn write_mirrors(&self, buf: &[u8], off: u64) -> Result<(), libfs::Error> {
+        for mirror in self.mirrors.iter().filter(|m| !m.failed.load(Ordering::Relaxed)) {
+            if let Err(e) = mirror.outfd.write_all_at(buf, off) {
+                if !self.config.continue_on_error {
+                    return Err(e.into());
+                }
+                error!("Error copying {:?} -> {:?}; skipping: {}", self.from, mirror.to, e);
+                mirror.mark_failed();
+                let err = XcpError::copy_failed(&mirror.to, &e.into());
+                if let Err(e) = self.stats.send(StatusUpdate::Error(err)) {
+                    error!("Failed to send copy failure: {}", e);
+                }
+            } else {
+                mirror.wrote(off, buf.len() as u64);
+            }
+        }
+        Ok(())
+    }
+
+    // Copy the block of `bytes` at `off` to this target and each
+    // mirror, reading it once.
+    fn copy_block_tee(&self, off: u64, bytes: u64) -> Result<usize, libfs::Error> {
+        let mut buf = vec![0; cmp::min(bytes, TEE_BUF_SIZE) as usize];
+        let mut copied = 0;
+        while copied < bytes {
+            let pos = off + copied;
+            let len = cmp::min(bytes - copied, buf.len() as u64) as usize;
+            let n = match self.infd.read_at(&mut buf[..len], pos) {
+                // The source has been truncated; as with
+                // copy_file_range(), the copy is short.
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            self.outfd.write_all_at(&buf[..n], pos)?;
+            self.wrote(pos, n as u64);
+            self.write_mirrors(&buf[..n], pos)?;
+            copied += n as u64;
+        }
+        Ok(copied as usize)
+    }
+
+    /// Record that `bytes` at `off` have been written to the target.
+    pub fn wrote(&self, off: u64, bytes: u64) {
+        let _ = self.written.compare_exchange(off, off + bytes, Ordering::AcqRel, Ordering::Acquire);
+    }
+
+    // Cut an interrupted copy back to the data known to be written,
+    // as the rest of a preallocated target would look complete to
+    // '--size-only'; '--append' resumes from what is left. Atomic
+    // copies are removed instead, and ranged copies keep the
+    // target's previous contents.
+    fn truncate_incomplete(&self) {
+        if self.tmp.is_some() || self.cloned || self.linked || !self.metadata.is_file() {
+            return;
+        }
+        let base = if self.config.ranged() { self.existing } else { 0 };
+        let len = cmp::max(base, self.written.load(Ordering::Acquire));
+        if self.outfd.metadata().is_ok_and(|m| m.len() > len) {
+            debug!("Truncating interrupted copy {:?} to {} bytes", self.to, len);
+            if let Err(e) = self.outfd.set_len(len) {
+                warn!("Failed to truncate interrupted copy {:?}: {}", self.to, e);
+            }
+        }
+    }
+
+    /// Wait until `bytes` may be copied under any rate limit.
+    pub fn throttle(&self, bytes: u64) {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(bytes);
+        }
+    }
+
+    /// Drop the copied range `off..off+len` from the page cache, if
+    /// [Config::no_cache] is set.
+    pub fn drop_cache(&self, off: u64, len: u64) {
+        if !self.config.no_cache {
+            return;
+        }
+        for fd in [&self.infd, &self.outfd] {
+            if let Err(e) = drop_cache(fd, off, len) {
+                debug!("Failed to drop cache of {:?}: {}", fd, e);
+            }
+        }
+    }
+
+    // Stop between blocks if the copy has been interrupted, or has
+    // run past its deadline.
+    fn check_continue(&self) -> Result<()> {
+        if self.config.interrupted() {
+            return Err(XcpError::Interrupted.into());
+        }
+        if self.timed_out() {
+            return Err(XcpError::TimedOut(self.from.clone()).into());
+        }
+        Ok(())
+    }
+
+    /// Whether some of the data copy has failed; see
+    /// [CopyHandle::mark_failed].
+    pub fn is_failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Abandon the copy after a block fails with `err`, returning the
+    /// error to report, if any. Only the first block to run out of
+    /// space reports it, and the partial temporary file is removed
+    /// at once, as the copy is likely to be aborted before this
+    /// handle is dropped.
+    pub fn block_failed(&self, err: libfs::Error) -> Option<XcpError> {
+        let err: anyhow::Error = err.into();
+        let first = self.mark_failed();
+        if !is_out_of_space(&err) {
+            return Some(XcpError::copy_failed(&self.from, &err));
+        }
+        if !first {
+            return None;
+        }
+        if let Some(tmp) = &self.tmp {
+            debug!("Removing incomplete copy {:?}", tmp);
+            match fs::remove_file(tmp) {
+                Err(e) if e.kind() != ErrorKind::NotFound => warn!("Failed to remove {:?}: {}", tmp, e),
+                _ => {}
+            }
+        }
+        Some(XcpError::OutOfSpace(self.to.clone()))
+    }
+
+    /// Whether the copy has run past [Config::timeout].
+    pub fn timed_out(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    /// Copy the block of `bytes` at `off`, with direct I/O if it is
+    /// still in use and the block is aligned for it.
+    pub fn copy_block(&self, off: u64, bytes: u64) -> Result<usize, libfs::Error> {
+        if !self.mirrors.is_empty() {
+            return self.copy_block_tee(off, bytes);
+        }
+        if self.direct.load(Ordering::Acquire) {
+            if off % self.direct_align == 0 && bytes % self.direct_align == 0 {
+                // copy_file_range() may go through the page cache
+                // regardless, so this uses an aligned buffer.
+                match copy_file_offset_direct(&self.infd, &self.outfd, bytes, off as i64, self.direct_align) {
+                    // The block is retried below.
+                    Err(e) if e.raw_os_error() == Some(Errno::INVAL.raw_os_error()) => {
+                        debug!("Direct I/O of {:?} failed, continuing without: {}", self.from, e);
+                    }
+                    r => return r,
+                }
+            }
+            self.end_direct_io();
+        }
+        match self.config.copy_method {
+            CopyMethod::Cfr => copy_file_offset(&self.infd, &self.outfd, bytes, off as i64),
+            CopyMethod::ReadWrite => copy_file_offset_rw(&self.infd, &self.outfd, bytes, off as i64),
+            CopyMethod::Mmap => copy_file_offset_mmap(&self.infd, &self.outfd, bytes, off as i64),
+        }
+    }
+
+    /// Stop using direct I/O for the rest of the copy, e.g. before
+    /// operations that go through user-space buffers.
+    pub fn end_direct_io(&self) {
+        if !self.direct.load(Ordering::Acquire) {
+            return;
+        }
+        for fd in [&self.infd, &self.outfd] {
+            if let Err(e) = set_direct_io(fd, false) {
+                warn!("Failed to disable direct I/O for {:?}: {}", fd, e);
+            }
+        }
+        self.direct.store(false, Ordering::Release);
+    }
+
+    /// Copy `len` bytes at `start`.
+    fn copy_bytes(&self, start: u64, len: u64) -> Result<u64> {
         let mut written = 0;
         while written < len {
-            let bytes_to_copy = cmp::min(len - written, self.config.block_size);
-            let bytes = copy_file_bytes(&self.infd, &self.outfd, bytes_to_copy)? as u64;
+            self.check_continue()?;
+            let bytes_to_copy = cmp::min(len - written, self.block_size);
+            self.throttle(bytes_to_copy);
+            let bytes = self.copy_block(start + written, bytes_to_copy)? as u64;
+            self.wrote(start + written, bytes);
+            self.drop_cache(start + written, bytes);
             written += bytes;
-            updates.send(StatusUpdate::Copied(bytes))?;
+            self.stats.send(StatusUpdate::Copied(bytes))?;
         }
 
         Ok(written)
     }
 
-    /// Wrapper around copy_bytes that looks for sparse blocks and skips them.
-    fn copy_sparse(&self, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
-        let len = self.metadata.len();
-        let mut pos = 0;
+    /// Wrapper around copy_bytes that looks for sparse blocks in
+    /// `range` and skips them.
+    fn copy_sparse(&self, range: Range<u64>) -> Result<u64> {
+        let mut pos = range.start;
 
-        while pos < len {
+        while pos < range.end {
             let (next_data, next_hole) = next_sparse_segments(&self.infd, &self.outfd, pos)?;
+            if self.config.no_punch_holes {
+                self.write_zeros(pos..cmp::min(next_data, range.end))?;
+            }
+            if next_data >= range.end {
+                break;
+            }
+            let end = cmp::min(next_hole, range.end);
 
-            let _written = self.copy_bytes(next_hole - next_data, updates)?;
-            pos = next_hole;
+            let _written = self.copy_bytes(next_data, end - next_data)?;
+            pos = end;
         }
 
-        Ok(len)
+        Ok(range.end - range.start)
+    }
+
+    /// Remove the holes in `range` from the progress total, as they
+    /// are skipped rather than copied. They are counted as copied
+    /// when filled with zeros instead.
+    fn report_holes(&self, range: &Range<u64>) -> Result<()> {
+        if self.config.no_punch_holes {
+            return Ok(());
+        }
+        let data = data_ranges(&self.infd, range.end)?
+            .into_iter()
+            .map(|r| r.end.saturating_sub(cmp::max(r.start, range.start)))
+            .sum::<u64>();
+        let holes = (range.end - range.start).saturating_sub(data);
+        if holes > 0 {
+            self.stats.send(StatusUpdate::Holes(holes))?;
+        }
+        Ok(())
     }
 
+    /// Fill `range` of the target with zeros.
+    fn write_zeros(&self, range: Range<u64>) -> Result<()> {
+        self.end_direct_io();
+        let mut pos = range.start;
+        while pos < range.end {
+            self.check_continue()?;
+            let bytes_to_write = cmp::min(range.end - pos, self.block_size);
+            self.throttle(bytes_to_write);
+            let bytes = write_zeros(&self.outfd, bytes_to_write, pos as i64)? as u64;
+            self.wrote(pos, bytes);
+            self.drop_cache(pos, bytes);
+            pos += bytes;
+            self.stats.send(StatusUpdate::Copied(bytes))?;
+        }
+        Ok(())
+    }
+
+    /// Copy `range`, skipping any all-zero blocks; these will be left
+    /// as holes.
+    fn copy_zeros_sparse(&self, range: Range<u64>) -> Result<u64> {
+        self.end_direct_io();
+        let mut pos = range.start;
+
+        while pos < range.end {
+            self.check_continue()?;
+            let bytes_to_copy = cmp::min(range.end - pos, self.block_size);
+            self.throttle(bytes_to_copy);
+            let bytes = copy_file_offset_sparse(&self.infd, &self.outfd, bytes_to_copy, pos as i64)? as u64;
+            self.wrote(pos, bytes);
+            self.drop_cache(pos, bytes);
+            pos += bytes;
+            self.stats.send(StatusUpdate::Copied(bytes))?;
+        }
+
+        Ok(range.end - range.start)
+    }
+
+    /// Copy a source that can only be read in order, such as a FIFO,
+    /// until it is exhausted.
+    fn copy_stream(&self) -> Result<u64> {
+        let mut buf = vec![0; STREAM_BUF_SIZE];
+        loop {
+            self.check_continue()?;
+            let bytes = match (&self.infd).read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            self.throttle(bytes as u64);
+            (&self.outfd).write_all(&buf[..bytes])?;
+            self.write_mirrors(&buf[..bytes], self.streamed.load(Ordering::Relaxed))?;
+            self.streamed.fetch_add(bytes as u64, Ordering::Relaxed);
+            self.stats.send(StatusUpdate::Copied(bytes as u64))?;
+        }
+
+        Ok(self.streamed.load(Ordering::Relaxed))
+    }
+
+    /// Split the copy range into the part overlapping existing data
+    /// in the target, and the rest. Holes can't be skipped in the
+    /// former as they would leave the old data in place.
+    pub fn split_range(&self) -> (Range<u64>, Range<u64>) {
+        let mid = self.existing.clamp(self.range.start, self.range.end);
+        (self.range.start..mid, mid..self.range.end)
+    }
+
+    /// Whether holes in the source may be skipped. If the target
+    /// filesystem doesn't support holes they are copied as zeros, so
+    /// that the target is identical to the source. Holes are never
+    /// skipped when there are mirrors, which are written from the
+    /// same blocks.
+    pub fn sparse_target(&self) -> bool {
+        self.sparse_target && self.mirrors.is_empty()
+    }
+
+    // Copy the data in the copy range.
+    fn copy_data(&self) -> Result<u64> {
+        let (overlap, rest) = self.split_range();
+        let copied = self.copy_bytes(overlap.start, overlap.end - overlap.start)?;
+        let rest = match self.config.sparse {
+            _ if !self.sparse_target() => self.copy_bytes(rest.start, rest.end - rest.start)?,
+            Sparse::Always => self.copy_zeros_sparse(rest)?,
+            Sparse::Auto if probably_sparse(&self.infd)? => {
+                self.report_holes(&rest)?;
+                self.copy_sparse(rest)?
+            }
+            Sparse::Auto | Sparse::Never => self.copy_bytes(rest.start, rest.end - rest.start)?,
+        };
+        Ok(copied + rest)
+    }
+
+    /// Attempt to reflink the whole file, according to
+    /// [Config::reflink]. A successful reflink is reported as
+    /// [StatusUpdate::Reflinked].
     pub fn try_reflink(&self) -> Result<bool> {
+        if self.cloned {
+            self.stats.send(StatusUpdate::Reflinked(self.metadata.len()))?;
+            return Ok(true);
+        }
+        // Reflinks are of whole files, and skip the read that any
+        // mirrors are written from.
+        if self.config.ranged() || self.config.append || self.linked || !self.mirrors.is_empty() {
+            return Ok(false);
+        }
         match self.config.reflink {
             Reflink::Always | Reflink::Auto => {
                 debug!("Attempting reflink from {:?}->{:?}", self.infd, self.outfd);
-                let worked = reflink(&self.infd, &self.outfd)?;
-                if worked {
+                let Some(reason) = reflink_with_reason(&self.infd, &self.outfd)? else {
                     debug!("Reflink {:?} succeeded", self.outfd);
-                    Ok(true)
-                } else if self.config.reflink == Reflink::Always {
-                    Err(XcpError::ReflinkFailed(format!("{:?}->{:?}", self.infd, self.outfd)).into())
+                    self.stats.send(StatusUpdate::Reflinked(self.metadata.len()))?;
+                    return Ok(true);
+                };
+                // E.g. a partially-sparse file whose layout the
+                // filesystem won't clone in one go; its extents may
+                // still be cloned one at a time.
+                if reason == NoReflink::Refused && reflink_extents(&self.infd, &self.outfd)?.is_none() {
+                    debug!("Reflink of the extents of {:?} succeeded", self.outfd);
+                    self.stats.send(StatusUpdate::Reflinked(self.metadata.len()))?;
+                    return Ok(true);
+                }
+                if self.config.reflink == Reflink::Always {
+                    // Don't leave an empty file behind.
+                    self.mark_failed();
+                    if let Err(e) = fs::remove_file(self.outpath()) {
+                        warn!("Failed to remove {:?} after failed reflink: {}", self.outpath(), e);
+                    }
+                    let msg = format!("{:?} -> {:?}; {}", self.from, self.to, reason);
+                    return Err(XcpError::ReflinkFailed(msg).into());
+                }
+                if self.config.warn_no_reflink {
+                    warn!("Could not reflink {:?}, copying instead: {}", self.from, reason);
                 } else {
-                    debug!("Failed to reflink, falling back to copy");
-                    Ok(false)
+                    debug!("Failed to reflink {:?} ({}), falling back to copy", self.from, reason);
                 }
+                let _ = self.no_reflink.set(reason);
+                Ok(false)
             }
 
             Reflink::Never => {
@@ -114,28 +707,109 @@ impl CopyHandle {
         }
     }
 
-    pub fn copy_file(&self, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
-        if self.try_reflink()? {
-            return Ok(self.metadata.len());
-        }
-        let total = if probably_sparse(&self.infd)? {
-            self.copy_sparse(updates)?
+    pub fn copy_file(&self) -> Result<u64> {
+        let result = if !self.metadata.is_file() {
+            self.copy_stream()
+        } else if self.try_reflink()? {
+            Ok(self.metadata.len())
         } else {
-            self.copy_bytes(self.metadata.len(), updates)?
+            self.copy_data()
         };
+        if result.is_err() {
+            self.mark_failed();
+        }
+        result
+    }
+
+    /// Flag that some of the data copy failed, and so the file should
+    /// not be verified. The failure should be reported separately.
+    /// Returns whether this is the first failure. Any mirrors are
+    /// incomplete too.
+    pub fn mark_failed(&self) -> bool {
+        for mirror in &self.mirrors {
+            mirror.mark_failed();
+        }
+        !self.failed.swap(true, Ordering::Relaxed)
+    }
+
+    // The file actually being written.
+    fn outpath(&self) -> &Path {
+        self.tmp.as_deref().unwrap_or(&self.to)
+    }
+
+    // Re-check the source, in case it was modified while we were
+    // copying it. Returns whether it is unchanged.
+    fn check_source(&self) -> Result<bool> {
+        // Streamed sources have no fixed size to compare.
+        if !self.metadata.is_file() {
+            return Ok(true);
+        }
+        let meta = self.infd.metadata()?;
+        if meta.len() != self.metadata.len()
+            || meta.mtime() != self.metadata.mtime()
+            || meta.mtime_nsec() != self.metadata.mtime_nsec()
+        {
+            if self.config.strict {
+                return Err(XcpError::SourceChanged(self.from.clone()).into());
+            }
+            warn!("Source file {:?} changed during copy, the copy may be inconsistent", self.from);
+            return Ok(false);
+        }
+        Ok(true)
+    }
 
-        Ok(total)
+    // Returns the checksum of the verified copy.
+    fn verify_copy(&self) -> Result<Option<String>> {
+        // A stream can't be read a second time.
+        if self.config.verify == Verify::None || self.failed.load(Ordering::Relaxed) || !self.metadata.is_file() {
+            return Ok(None);
+        }
+        debug!("Verifying {:?} -> {:?}", self.from, self.to);
+        self.end_direct_io();
+        // The output descriptor is write-only.
+        let verifd = File::open(self.outpath())?;
+        match files_match(&self.infd, &verifd, self.metadata.len(), self.config.verify)? {
+            Some(checksum) => Ok(Some(checksum)),
+            None => Err(XcpError::VerifyFailed(self.to.clone()).into()),
+        }
     }
 
     fn finalise_copy(&self) -> Result<()> {
+        // Xattrs must come first as setting them requires write
+        // permission on the file.
+        if !self.config.no_xattr {
+            if let Err(e) = copy_xattr(&self.infd, &self.outfd) {
+                warn!("Failed to copy xattrs from {:?}: {}", self.from, e);
+            }
+        }
+        if self.config.preserve_acl && !copy_acl(&self.infd, &self.outfd)? {
+            acl_warning(&self.from);
+        }
         if !self.config.no_perms {
-            copy_permissions(&self.infd, &self.outfd)?;
+            self.copy_mode()?;
         }
         if !self.config.no_timestamps {
             copy_timestamps(&self.infd, &self.outfd)?;
         }
-        if self.config.ownership && copy_owner(&self.infd, &self.outfd).is_err() {
-            warn!("Failed to copy file ownership: {:?}", self.infd);
+        if self.config.ownership {
+            if let Err(e) = copy_owner(&self.infd, &self.outfd) {
+                owner_warning(&self.from, e);
+            }
+            // chown() clears the setuid and setgid bits.
+            if !self.config.no_perms && self.metadata.mode() & SPECIAL_BITS != 0 {
+                self.copy_mode()?;
+            }
+        }
+        // After the ownership, as chown() also clears capabilities.
+        if !self.config.no_xattr {
+            if let Err(e) = copy_capabilities(&self.infd, &self.outfd) {
+                capabilities_warning(&self.from, e);
+            }
+        }
+        // After the ownership, as chown() may clear setuid bits.
+        if let Some(chmod) = &self.config.chmod {
+            let mode = chmod.apply(self.outfd.metadata()?.mode(), false);
+            self.outfd.set_permissions(Permissions::from_mode(mode))?;
         }
         if self.config.fsync {
             debug!("Syncing file {:?}", self.outfd);
@@ -143,119 +817,1326 @@ impl CopyHandle {
         }
         Ok(())
     }
+
+    // Copy the source's permissions, less any special bits being
+    // stripped.
+    fn copy_mode(&self) -> Result<()> {
+        copy_permissions(&self.infd, &self.outfd)?;
+        let mode = self.outfd.metadata()?.mode();
+        if self.config.strip_special_bits && mode & SPECIAL_BITS != 0 {
+            debug!("Clearing special bits of {:?}", self.to);
+            self.outfd.set_permissions(Permissions::from_mode(mode & !SPECIAL_BITS))?;
+        }
+        Ok(())
+    }
+
+    // Move an atomic copy into place, or discard it if the copy
+    // failed.
+    fn commit(&self) -> Result<()> {
+        let Some(tmp) = &self.tmp else {
+            return Ok(());
+        };
+        if self.failed.load(Ordering::Relaxed) {
+            debug!("Removing incomplete copy {:?}", tmp);
+            return match fs::remove_file(tmp) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        let renamed = backup_target(&self.to, &self.config)
+            .and_then(|_| Ok(fs::rename(tmp, &self.to)?));
+        if renamed.is_err() {
+            let _ = fs::remove_file(tmp);
+        }
+        renamed
+    }
 }
 
 impl Drop for CopyHandle {
     fn drop(&mut self) {
         // FIXME: Should we check for panicking() here?
-        if let Err(e) = self.finalise_copy() {
+
+        // All data has been written at this point, whether by a
+        // single copy_file() or by the last outstanding block. The
+        // source is only removed if every step below succeeds.
+        // The source is removed and checkpointed by the primary copy
+        // only.
+        let mut remove_source = self.config.remove_source_files && !self.mirror;
+        let mut source_unchanged = false;
+
+        if !self.failed.load(Ordering::Relaxed) {
+            let checked = self.check_source();
+            source_unchanged = matches!(checked, Ok(true));
+            if !source_unchanged {
+                remove_source = false;
+            }
+            if let Err(e) = checked {
+                error!("Error checking {:?}: {}", self.from, e);
+                self.mark_failed();
+                let err = XcpError::copy_failed(&self.from, &e);
+                if let Err(e) = self.stats.send(StatusUpdate::Error(err)) {
+                    error!("Failed to send source change: {}", e);
+                }
+            }
+        }
+        let checksum = match self.verify_copy() {
+            Ok(checksum) => checksum,
+            Err(e) => {
+                error!("Error verifying {:?}: {}", self.to, e);
+                self.mark_failed();
+                let err = XcpError::copy_failed(&self.from, &e);
+                if let Err(e) = self.stats.send(StatusUpdate::Error(err)) {
+                    error!("Failed to send verification failure: {}", e);
+                }
+                None
+            }
+        };
+        // An interrupted copy is incomplete, so shouldn't get the
+        // source's timestamps; '--update' would then skip it.
+        let interrupted = self.failed.load(Ordering::Relaxed) && self.config.interrupted();
+        if interrupted {
+            debug!("Not finalising interrupted copy {:?}", self.to);
+            self.truncate_incomplete();
+        } else if self.linked {
+            debug!("Not finalising {:?}, which is linked to an identical copy", self.to);
+        } else if let Err(e) = self.finalise_copy() {
             error!("Error during finalising copy operation {:?} -> {:?}: {}", self.infd, self.outfd, e);
+            remove_source = false;
+        }
+        let committed = self.commit();
+        if let Err(e) = &committed {
+            error!("Error replacing {:?}: {}", self.to, e);
+            remove_source = false;
+            let err = XcpError::copy_failed(&self.from, e);
+            if let Err(e) = self.stats.send(StatusUpdate::Error(err)) {
+                error!("Failed to send replacement failure: {}", e);
+            }
+        }
+        if remove_source && !self.failed.load(Ordering::Relaxed) {
+            if let Err(e) = remove_source_file(&self.from, &self.config) {
+                error!("Error removing source {:?}: {}", self.from, e);
+                let err = XcpError::copy_failed(&self.from, &e);
+                if let Err(e) = self.stats.send(StatusUpdate::Error(err)) {
+                    error!("Failed to send removal failure: {}", e);
+                }
+            }
+        }
+        if committed.is_ok() && !self.failed.load(Ordering::Relaxed) {
+            // Last, as the flags may prevent any further changes.
+            if self.config.preserve_flags && !self.linked {
+                copy_inode_flags(&self.infd, &self.outfd, &self.from);
+            }
+            if let (Some(dedup), Some(digest)) = (&self.config.dedup, &self.digest) {
+                if source_unchanged {
+                    dedup.insert(self.metadata.len(), digest, &self.to);
+                }
+            }
+            // A changed or streamed source must be copied again.
+            if let Some(checkpoint) = self.config.checkpoint.as_ref().filter(|_| source_unchanged && self.metadata.is_file() && !self.mirror) {
+                if let Err(e) = checkpoint.record(&self.from, &self.metadata) {
+                    error!("Failed to record {:?} in the checkpoint: {}", self.from, e);
+                }
+            }
+            let copied = CopiedFile {
+                from: self.from.clone(),
+                to: self.to.clone(),
+                bytes: if self.metadata.is_file() {
+                    self.range.end - self.range.start
+                } else {
+                    self.streamed.load(Ordering::Relaxed)
+                },
+                checksum,
+                no_reflink: self.no_reflink.get().copied(),
+            };
+            if let Err(e) = self.stats.send(StatusUpdate::Completed(copied)) {
+                error!("Failed to send completion of {:?}: {}", self.from, e);
+            }
+        }
+        if let Err(e) = self.stats.send(StatusUpdate::Finished(self.from.clone())) {
+            error!("Failed to send completion of {:?}: {}", self.from, e);
         }
     }
 }
 
+// Forwards only the errors of a mirror; its progress is that of the
+// primary copy.
+struct MirrorUpdater(Arc<dyn StatusUpdater>);
+
+impl StatusUpdater for MirrorUpdater {
+    fn send(&self, update: StatusUpdate) -> Result<()> {
+        match update {
+            StatusUpdate::Error(_) => self.0.send(update),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Whether `err` is from the destination filesystem running out of
+// space.
+fn is_out_of_space(err: &anyhow::Error) -> bool {
+    os_error(err) == Some(Errno::NOSPC.raw_os_error())
+}
+
+/// The error to report for a failed copy of `from` to `to`. Running
+/// out of space is reported as [XcpError::OutOfSpace].
+pub(crate) fn copy_failure(from: &Path, to: &Path, err: &anyhow::Error) -> XcpError {
+    if is_out_of_space(err) {
+        XcpError::OutOfSpace(to.to_path_buf())
+    } else {
+        XcpError::copy_failed(from, err)
+    }
+}
+
+/// Create `path` and any missing parents, for directories that have
+/// no source. Each directory created has `mode` applied to it; see
+/// [Config::dir_mode].
+pub fn create_dirs(path: &Path, mode: Option<&Chmod>) -> Result<()> {
+    let Some(mode) = mode else {
+        create_dir_all(path)?;
+        return Ok(());
+    };
+    let missing = path.ancestors()
+        .take_while(|p| !p.as_os_str().is_empty() && !p.exists())
+        .collect::<Vec<_>>();
+    create_dir_all(path)?;
+    // Innermost first, so a mode without search access doesn't
+    // prevent the rest being set.
+    for dir in missing {
+        let current = fs::metadata(dir)?.permissions().mode();
+        fs::set_permissions(dir, Permissions::from_mode(mode.apply(current, true)))?;
+    }
+    Ok(())
+}
+
+/// Remove a source file once it has been copied, if
+/// `remove_source_files` is set.
+pub(crate) fn remove_source_file(from: &Path, config: &Config) -> Result<()> {
+    if config.remove_source_files {
+        debug!("Removing source file {:?}", from);
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+fn backup_target(to: &Path, config: &Config) -> Result<()> {
+    if needs_backup(to, config)? {
+        let backup = get_backup_path(to, config)?;
+        info!("Backup: Rename {:?} to {:?}", to, backup);
+        fs::rename(to, backup)?;
+    }
+    Ok(())
+}
+
+// Where files can only be cloned by path (i.e. macOS's clonefile()),
+// the newly-created target is replaced with a clone of the source if
+// possible. Otherwise it is recreated empty for a regular copy.
+fn clone_target(from: &Path, outpath: &Path, outfd: File) -> Result<(File, bool)> {
+    // Replacing a symlink would stop us writing through it.
+    if outpath.symlink_metadata()?.file_type().is_symlink() {
+        return Ok((outfd, false));
+    }
+    drop(outfd);
+    fs::remove_file(outpath)?;
+    if clone_file(from, outpath)? {
+        debug!("Cloned {:?} to {:?}", from, outpath);
+        return Ok((OpenOptions::new().write(true).open(outpath)?, true));
+    }
+    debug!("Failed to clone {:?}, falling back to copy", from);
+    Ok((OpenOptions::new().write(true).create_new(true).open(outpath)?, false))
+}
+
+// With Config::dedup, replace the newly-created target with a copy of
+// `existing`, an earlier target with the same contents. This is a
+// reflink where possible, and otherwise a hardlink unless reflinks
+// are required. Returns the target, and whether it was reflinked or
+// hardlinked; if neither, it is left empty for a regular copy.
+fn dedup_target(existing: &Path, outpath: &Path, outfd: File, config: &Config) -> Result<(File, bool, bool)> {
+    if config.reflink != Reflink::Never && reflink_with_reason(&File::open(existing)?, &outfd)?.is_none() {
+        debug!("Reflinked {:?} to identical {:?}", outpath, existing);
+        return Ok((outfd, true, false));
+    }
+    // Replacing a symlink would stop us writing through it.
+    if config.reflink == Reflink::Always || outpath.symlink_metadata()?.file_type().is_symlink() {
+        return Ok((outfd, false, false));
+    }
+    drop(outfd);
+    fs::remove_file(outpath)?;
+    match fs::hard_link(existing, outpath) {
+        Ok(()) => {
+            debug!("Linked {:?} to identical {:?}", outpath, existing);
+            Ok((OpenOptions::new().write(true).open(outpath)?, false, true))
+        }
+        Err(e) => {
+            debug!("Failed to link {:?} to {:?} ({}), copying instead", outpath, existing, e);
+            Ok((OpenOptions::new().write(true).create_new(true).open(outpath)?, false, false))
+        }
+    }
+}
+
+// With Config::append, open an existing target to resume a copy into,
+// returning it along with the offset to resume from. An interrupted
+// copy leaves the target at its full length with holes where data is
+// missing, so this is the end of the target's leading data, rounded
+// down to a whole block so that direct I/O stays aligned. Returns None
+// if there is nothing to keep, or the data before the offset doesn't
+// match the source; the target is then copied in full. Otherwise it is
+// truncated at the offset, so that the rest is copied as a new target
+// would be, including any holes.
+fn resume_target(infd: &File, len: u64, to: &Path, block_size: u64) -> Result<Option<(File, u64)>> {
+    let outfd = match OpenOptions::new().read(true).write(true).open(to) {
+        Ok(fd) => fd,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if !outfd.metadata()?.is_file() || outfd.metadata()?.len() > len {
+        return Ok(None);
+    }
+    let data = leading_data_len(&outfd)?;
+    let offset = data - data % block_size;
+    if offset == 0 {
+        return Ok(None);
+    }
+    if !prefix_matches(infd, &outfd, offset)? {
+        info!("{:?} does not match the source, copying it in full", to);
+        return Ok(None);
+    }
+    debug!("Resuming copy to {:?} at offset {}", to, offset);
+    allocate_file(&outfd, offset)?;
+    Ok(Some((outfd, offset)))
+}
+
+// Create a uniquely-named temporary file alongside the target, to be
+// renamed over it once copied. Returns None if the copy should be made
+// in place instead.
+fn create_atomic_tmp(to: &Path, config: &Config) -> Result<Option<(File, PathBuf)>> {
+    if config.atomic == Atomic::Never {
+        return Ok(None);
+    }
+    let fallback = |reason: String| {
+        if config.atomic == Atomic::Always {
+            Err(XcpError::AtomicFailed(to.to_path_buf(), reason).into())
+        } else {
+            warn!("Cannot replace {:?} atomically, writing in place: {}", to, reason);
+            Ok(None)
+        }
+    };
+
+    let dir = match to.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let fname = to.file_name()
+        .ok_or(XcpError::InvalidDestination("Destination has no file name"))?;
+
+    // Renaming would replace a symlink rather than write through it,
+    // and can't replace a file mounted from another filesystem.
+    if let Ok(meta) = to.symlink_metadata() {
+        if meta.file_type().is_symlink() {
+            return fallback("the destination is a symlink".to_string());
+        }
+        if meta.dev() != dir.metadata()?.dev() {
+            return fallback("the destination is a mount point".to_string());
+        }
+    }
+
+    loop {
+        // Each RandomState is seeded differently, so this gives an
+        // unpredictable name.
+        let mut name = OsString::from(".");
+        name.push(fname);
+        name.push(format!(".xcp-{:016x}", RandomState::new().build_hasher().finish()));
+        let path = dir.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(fd) => {
+                debug!("Copying {:?} via {:?}", to, path);
+                return Ok(Some((fd, path)));
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return fallback(format!("failed to create temporary file: {}", e)),
+        }
+    }
+}
+
+// The alignment needed for direct I/O between the filesystems of the
+// source and target.
+fn direct_io_alignment(meta: &Metadata, to: &Path) -> Result<u64> {
+    let dir = match to.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let (a, b) = (meta.blksize(), fs::metadata(dir)?.blksize());
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    Ok(a / x * b)
+}
+
+// Returns whether direct I/O could be enabled; if not the copy is
+// buffered.
+fn enable_direct_io(from: &Path, infd: &File, outfd: &File) -> bool {
+    let Err(e) = set_direct_io(infd, true).and_then(|_| set_direct_io(outfd, true)) else {
+        return true;
+    };
+    warn!("Direct I/O is not supported for {:?}, copying through the page cache: {}", from, e);
+    let _ = set_direct_io(infd, false);
+    false
+}
+
+// Ownership failures are usually due to lack of privileges, and so
+// will apply to every file; only warn once.
+fn owner_warning(path: &Path, err: libfs::Error) {
+    static WARNED: Once = Once::new();
+    WARNED.call_once(|| {
+        warn!("Failed to copy file ownership of {:?}, continuing without it: {}", path, err);
+    });
+    debug!("Failed to copy file ownership: {:?}: {}", path, err);
+}
+
+// Likewise, ACL support is per-filesystem, so only warn once.
+fn acl_warning(path: &Path) {
+    static WARNED: Once = Once::new();
+    WARNED.call_once(|| {
+        warn!("ACLs are not supported when copying {:?}, continuing without them", path);
+    });
+}
+
+// Capabilities need privileges to set, which an unprivileged copy of
+// e.g. a root filesystem won't have; the rest of the copy continues.
+fn capabilities_warning(path: &Path, err: libfs::Error) {
+    if err.raw_os_error() == Some(Errno::PERM.raw_os_error()) {
+        warn!("File capabilities of {:?} were not preserved; setting them requires CAP_SETFCAP", path);
+    } else {
+        warn!("Failed to copy the file capabilities of {:?}: {}", path, err);
+    }
+}
+
+// Copy the inode flags of `from`; see Config::preserve_flags. These
+// may not be supported, or need privileges, so only warn once.
+fn copy_inode_flags(infd: &File, outfd: &File, from: &Path) {
+    static WARNED: Once = Once::new();
+    let reason = match copy_flags(infd, outfd) {
+        Ok(true) => return,
+        Ok(false) => "not supported by the filesystem".to_string(),
+        Err(e) => e.to_string(),
+    };
+    WARNED.call_once(|| {
+        warn!("Failed to copy the inode flags of {:?}, continuing without them: {}", from, reason);
+    });
+    debug!("Failed to copy inode flags: {:?}: {}", from, reason);
+}
+
+/// Recreate the symlink `from` at `to`.
+pub fn copy_symlink(from: &Path, to: &Path, config: &Config) -> Result<()> {
+    let lfile = read_link(from)?;
+    symlink(&lfile, to)?;
+    if !config.no_xattr {
+        if let Err(e) = copy_link_xattr(from, to) {
+            warn!("Failed to copy xattrs from {:?}: {}", from, e);
+        }
+    }
+    if config.ownership {
+        if let Err(e) = copy_link_owner(from, to) {
+            owner_warning(to, e);
+        }
+    }
+    Ok(())
+}
+
+/// Recreate the special file `from` at `to`, replacing any existing
+/// file. Under [Config::pipe_through] a FIFO's data is streamed into
+/// `to` instead.
+pub(crate) fn copy_special(from: &Path, to: &Path, config: &Config, stats: &Arc<dyn StatusUpdater>) -> Result<()> {
+    let fifo = |p: &Path| p.symlink_metadata().is_ok_and(|m| m.file_type().is_fifo());
+    let pipe = config.pipe_through && fifo(from);
+    // An existing FIFO is the endpoint to stream into.
+    if !(pipe && fifo(to)) {
+        if to.exists() {
+            if config.no_clobber {
+                return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", to.to_path_buf()).into());
+            }
+            fs::remove_file(to)?;
+        }
+        copy_node(from, to)?;
+    }
+    if pipe {
+        pipe_through(from, to, config, stats)?;
+    }
+    remove_source_file(from, config)
+}
+
+// Stream the data written to the FIFO `from` into the FIFO `to`,
+// once both have been opened by the other ends, until the writer to
+// `from` closes it. Neither open nor waiting for data blocks past
+// the timeout, but a blocked write can't be interrupted.
+fn pipe_through(from: &Path, to: &Path, config: &Config, stats: &Arc<dyn StatusUpdater>) -> Result<()> {
+    let deadline = config.timeout.map(|t| Instant::now() + t);
+    let check_continue = || -> Result<()> {
+        if config.interrupted() {
+            return Err(XcpError::Interrupted.into());
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            return Err(XcpError::TimedOut(from.to_path_buf()).into());
+        }
+        Ok(())
+    };
+
+    // Opening without O_NONBLOCK would wait for the other end.
+    let nonblock = OFlags::NONBLOCK.bits() as i32;
+    let infd = OpenOptions::new().read(true).custom_flags(nonblock).open(from)?;
+    let outfd = loop {
+        match OpenOptions::new().write(true).custom_flags(nonblock).open(to) {
+            Ok(fd) => break fd,
+            // No reader yet.
+            Err(e) if e.raw_os_error() == Some(Errno::NXIO.raw_os_error()) => {
+                check_continue()?;
+                thread::sleep(PIPE_POLL_INTERVAL);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    fcntl_setfl(&outfd, OFlags::empty())?;
+
+    let mut buf = vec![0; STREAM_BUF_SIZE];
+    loop {
+        check_continue()?;
+        // Linux only reports a hangup once a writer has come and
+        // gone, so this also waits for the first writer.
+        let mut fds = [PollFd::new(&infd, PollFlags::IN)];
+        if poll(&mut fds, PIPE_POLL_INTERVAL.as_millis() as i32)? == 0 {
+            continue;
+        }
+        let bytes = match (&infd).read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        (&outfd).write_all(&buf[..bytes])?;
+        stats.send(StatusUpdate::Copied(bytes as u64))?;
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub enum Operation {
-    Copy(PathBuf, PathBuf),
+    /// A copy of a file to its target, and to its target under each
+    /// of [Config::tee].
+    Copy(PathBuf, PathBuf, Vec<PathBuf>),
     Link(PathBuf, PathBuf),
     Special(PathBuf, PathBuf),
 }
 
+// Hand an operation to the workers, or just report it if this is a
+// dry-run.
+fn send_op(op: Operation, work_tx: &cbc::Sender<Operation>, stats: &Arc<dyn StatusUpdater>, config: &Config) -> Result<()> {
+    if config.dry_run {
+        let planned = match op {
+            Operation::Copy(from, to, tees) => {
+                stats.send(StatusUpdate::Planned(PlannedOp::Copy, from.clone(), to))?;
+                for tee in tees {
+                    stats.send(StatusUpdate::Planned(PlannedOp::Copy, from.clone(), tee))?;
+                }
+                return Ok(());
+            }
+            Operation::Link(from, to) => StatusUpdate::Planned(PlannedOp::Symlink, from, to),
+            Operation::Special(from, to) => StatusUpdate::Planned(PlannedOp::Special, from, to),
+        };
+        stats.send(planned)?;
+    } else {
+        work_tx.send(op)?;
+    }
+    Ok(())
+}
+
+/// Directories created by the walker, as `(source, target)`
+/// pairs. These need finalising after all other work is complete, as
+/// copying their contents will otherwise update their timestamps.
+pub type CreatedDirs = Vec<(PathBuf, PathBuf)>;
+
+/// A hardlink found by the walker; `to` should be linked to
+/// `existing`, the target of an earlier path to the same inode.
+#[derive(Debug)]
+pub struct HardLink {
+    pub from: PathBuf,
+    pub existing: PathBuf,
+    pub to: PathBuf,
+}
+
+/// Work that must wait until all copy operations have completed; see
+/// [finalise()].
+#[derive(Debug, Default)]
+pub struct Deferred {
+    pub dirs: CreatedDirs,
+    pub links: Vec<HardLink>,
+    /// The target of each source; their parent directories receive
+    /// new entries too.
+    pub roots: Vec<PathBuf>,
+    /// Set if files were skipped under [Config::max_total_size]; it
+    /// is returned once everything else is finalised.
+    pub size_limit: Option<XcpError>,
+}
+
+// The bytes queued against Config::max_total_size, and the files
+// skipped once it was reached.
+#[derive(Debug, Default)]
+struct Totals {
+    queued: u64,
+    skipped_files: u64,
+    skipped_bytes: u64,
+}
+
 pub fn tree_walker(
     sources: Vec<PathBuf>,
     dest: &Path,
     config: &Config,
     work_tx: cbc::Sender<Operation>,
     stats: Arc<dyn StatusUpdater>,
-) -> Result<()> {
+) -> Result<Deferred> {
     debug!("Starting walk worker {:?}", thread::current().id());
+    let dirs = Mutex::new(CreatedDirs::new());
+    let links = Mutex::new(Vec::new());
+    // Targets of files with multiple links, by (device, inode).
+    let inodes = Mutex::new(HashMap::new());
+    let totals = Mutex::new(Totals::default());
+    let mut roots = Vec::new();
+
+    check_sources(&sources, dest, config)?;
+    let follow_root = config.dereference || config.dereference_command_line;
 
     for source in sources {
-        let sourcedir = source
-            .components()
-            .last()
-            .ok_or(XcpError::InvalidSource("Failed to find source directory name."))?;
+        if config.interrupted() {
+            break;
+        }
+        let tee_bases = config.tee.iter()
+            .map(|tee| target_base(&source, tee, config))
+            .collect::<Result<Vec<_>>>()?;
+        let target_base = target_base(&source, dest, config)?;
+        debug!("Target base is {:?}", target_base);
+        roots.push(target_base.clone());
+        roots.extend(tee_bases.iter().cloned());
+        if config.relative && !config.dry_run {
+            for base in iter::once(&target_base).chain(&tee_bases) {
+                if let Some(parent) = base.parent() {
+                    create_dirs(parent, config.dir_mode.as_ref())?;
+                }
+            }
+        }
 
-        let target_base = if dest.exists() && dest.is_dir() && !config.no_target_directory {
-            dest.join(sourcedir)
+        let ignores = Arc::new(Ignores::new(&source, config)?);
+
+        // Mount points are still created, but are not descended into.
+        let root_dev = if config.one_file_system {
+            Some(source.metadata()?.dev())
         } else {
-            dest.to_path_buf()
+            None
         };
-        debug!("Target base is {:?}", target_base);
 
-        let gitignore = parse_ignore(&source, config)?;
+        let walk = Walk {
+            source: &source,
+            target_base,
+            tee_bases,
+            root_dev,
+            config,
+            work_tx: &work_tx,
+            stats: &stats,
+            dirs: &dirs,
+            links: &links,
+            inodes: &inodes,
+            totals: &totals,
+        };
 
-        for entry in WalkDir::new(&source)
-            .into_iter()
-            .filter_entry(|e| ignore_filter(e, &gitignore))
-        {
-            debug!("Got tree entry {:?}", entry);
-            let epath = entry?.into_path();
-            let from = if config.dereference {
-                let cpath = canonicalize(&epath)?;
-                debug!("Dereferencing {:?} into {:?}", epath, cpath);
-                cpath
-            } else {
-                epath.clone()
-            };
-            let meta = from.symlink_metadata()?;
-            let path = epath.strip_prefix(&source)?;
-            let target = if !empty_path(path) {
-                target_base.join(path)
-            } else {
-                target_base.clone()
-            };
+        // If the source is a symlink to a directory it should only be
+        // descended into if we are dereferencing it. The parallel
+        // walker always follows the root, so handle this here.
+        if source.is_symlink() && !follow_root {
+            walk.entry(&source, 0)?;
+            continue;
+        }
+
+        let walker = WalkBuilder::new(&source)
+            .standard_filters(false)
+            .follow_links(config.dereference)
+            .same_file_system(config.one_file_system)
+            .max_depth(config.max_depth)
+            .threads(config.num_workers())
+            .filter_entry({
+                let ignores = ignores.clone();
+                move |e| ignore_filter(e, &ignores)
+            })
+            .build_parallel();
+
+        let failed = Mutex::new(None);
+
+        // Each thread visits a directory before its contents are read,
+        // so creating it in the visitor ensures it exists before any
+        // of its entries are queued.
+        walker.run(|| {
+            Box::new(|entry| {
+                if config.interrupted() {
+                    return WalkState::Quit;
+                }
+                let result = match entry {
+                    Ok(e) => {
+                        debug!("Got tree entry {:?}", e);
+                        walk.entry(e.path(), e.depth())
+                    }
+                    Err(e) => walk.error(e),
+                };
+                match result {
+                    Ok(()) => WalkState::Continue,
+                    Err(err) => {
+                        failed.lock().unwrap().get_or_insert(err);
+                        WalkState::Quit
+                    }
+                }
+            })
+        });
+
+        if let Some(err) = failed.into_inner().unwrap() {
+            return Err(err);
+        }
+
+        if config.delete && !config.interrupted() {
+            delete_extras(&source, &walk.target_base, &ignores, config, &stats)?;
+        }
+    }
+    debug!("Walk-worker finished: {:?}", thread::current().id());
+    stats.send(StatusUpdate::SizeComplete)?;
+
+    // Walker threads finish in any order; parents must precede their
+    // children for finalise_dirs().
+    let mut dirs = dirs.into_inner().unwrap();
+    dirs.sort_by_key(|(_, to)| to.components().count());
+    let totals = totals.into_inner().unwrap();
+    let size_limit = config.max_total_size
+        .filter(|_| totals.skipped_files > 0)
+        .map(|max| XcpError::SizeLimitReached(max, totals.queued, totals.skipped_files, totals.skipped_bytes));
+    Ok(Deferred {
+        dirs,
+        links: links.into_inner().unwrap(),
+        roots,
+        size_limit,
+    })
+}
+
+/// Check that `sources` can be copied to `dest`, before anything is
+/// copied; e.g. that no directory would be copied into itself, as the
+/// walk would otherwise find the copy as it is being made.
+pub fn check_sources(sources: &[PathBuf], dest: &Path, config: &Config) -> Result<()> {
+    let follow_root = config.dereference || config.dereference_command_line;
+    for source in sources {
+        let descends = source.is_dir() && (follow_root || !source.is_symlink());
+        if !descends {
+            continue;
+        }
+        for dest in iter::once(dest).chain(config.tee.iter().map(PathBuf::as_path)) {
+            if is_within(source, &target_base(source, dest, config)?)? {
+                return Err(XcpError::InvalidDestination("Cannot copy a directory into itself.").into());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Where a source is copied to.
+fn target_base(source: &Path, dest: &Path, config: &Config) -> Result<PathBuf> {
+    if config.relative {
+        let mut target = dest.to_path_buf();
+        for component in source.components() {
+            match component {
+                Component::Normal(name) => target.push(renamed_name(name, config)?),
+                Component::ParentDir => {
+                    return Err(XcpError::InvalidSource("Relative sources cannot contain '..'.").into());
+                }
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+        return Ok(target);
+    }
+
+    if config.copy_contents && copies_contents(source) {
+        return Ok(dest.to_path_buf());
+    }
+
+    let sourcedir = source
+        .components()
+        .next_back()
+        .ok_or(XcpError::InvalidSource("Failed to find source directory name."))?;
+
+    Ok(if (config.dest_is_dir || dest.is_dir()) && !config.no_target_directory {
+        match sourcedir {
+            Component::Normal(name) => dest.join(renamed_name(name, config)?),
+            other => dest.join(other),
+        }
+    } else {
+        dest.to_path_buf()
+    })
+}
+
+// `name` as changed by `Config::rename`, if set.
+fn renamed_name(name: &OsStr, config: &Config) -> Result<OsString> {
+    let Some(rename) = &config.rename else {
+        return Ok(name.to_os_string());
+    };
+    let renamed = rename.apply(name);
+    if renamed.is_empty() || renamed == "." || renamed == ".." {
+        let msg = format!("--rename gives an invalid name for {:?}: {:?}", name, renamed);
+        return Err(XcpError::InvalidArguments(msg).into());
+    }
+    Ok(renamed)
+}
+
+// Every name in the relative `path`, changed by `Config::rename`.
+// The descendants of a renamed directory are then renamed alike.
+fn renamed_path(path: &Path, config: &Config) -> Result<PathBuf> {
+    if config.rename.is_none() {
+        return Ok(path.to_path_buf());
+    }
+    path.components()
+        .map(|c| match c {
+            Component::Normal(name) => renamed_name(name, config),
+            other => Ok(other.as_os_str().to_os_string()),
+        })
+        .collect()
+}
+
+// Whether `source` is a directory given with a trailing slash, whose
+// contents are copied under `Config::copy_contents`.
+fn copies_contents(source: &Path) -> bool {
+    source.as_os_str().as_bytes().ends_with(b"/") && source.is_dir()
+}
+
+// Per-source state shared by the walker threads.
+struct Walk<'a> {
+    source: &'a Path,
+    target_base: PathBuf,
+    // The target bases under each of Config::tee.
+    tee_bases: Vec<PathBuf>,
+    root_dev: Option<u64>,
+    config: &'a Config,
+    work_tx: &'a cbc::Sender<Operation>,
+    stats: &'a Arc<dyn StatusUpdater>,
+    dirs: &'a Mutex<CreatedDirs>,
+    links: &'a Mutex<Vec<HardLink>>,
+    inodes: &'a Mutex<HashMap<(u64, u64), PathBuf>>,
+    totals: &'a Mutex<Totals>,
+}
+
+impl Walk<'_> {
+    fn entry(&self, epath: &Path, depth: usize) -> Result<()> {
+        let config = self.config;
+        let stats = self.stats;
 
-            if config.no_clobber && target.exists() {
-                let msg = "Destination file exists and --no-clobber is set.";
-                stats.send(StatusUpdate::Error(
-                    XcpError::DestinationExists(msg, target)))?;
-                return Err(XcpError::EarlyShutdown(msg).into());
+        let from = if config.dereference || (config.dereference_command_line && depth == 0) {
+            let cpath = canonicalize(epath)?;
+            debug!("Dereferencing {:?} into {:?}", epath, cpath);
+            cpath
+        } else {
+            epath.to_path_buf()
+        };
+        let meta = from.symlink_metadata()?;
+        let path = renamed_path(epath.strip_prefix(self.source)?, config)?;
+        let target_in = |base: &Path| if !empty_path(&path) {
+            base.join(&path)
+        } else {
+            base.to_path_buf()
+        };
+        let target = target_in(&self.target_base);
+        // Whether these need copying is decided by the main target.
+        let tees = self.tee_bases.iter().map(|base| target_in(base)).collect::<Vec<_>>();
+
+        let ft = FileType::from(meta.file_type());
+
+        if config.dirs_only && !matches!(ft, FileType::Dir) {
+            debug!("Skipping {:?} as only directories are copied", from);
+            return Ok(());
+        }
+
+        // Directories are still walked, to reach any selected entries
+        // within them.
+        if !config.types.selects(&ft) {
+            debug!("Skipping {:?} as its type is not selected", from);
+            return Ok(());
+        }
+
+        if matches!(ft, FileType::File) && !config.size_in_range(meta.len()) {
+            debug!("Skipping {:?} as its size is out of range", from);
+            return Ok(());
+        }
+
+        // Existing directories are still descended into, so that new
+        // files within them are copied.
+        if config.ignore_existing && !matches!(ft, FileType::Dir) && target.symlink_metadata().is_ok() {
+            debug!("Skipping {:?} as {:?} exists", from, target);
+            return Ok(());
+        }
+
+        if config.no_clobber && !config.ignore_existing && target.exists() {
+            let msg = "Destination file exists and --no-clobber is set.";
+            stats.send(StatusUpdate::Error(
+                XcpError::DestinationExists(msg, target)))?;
+            return Err(XcpError::EarlyShutdown(msg).into());
+        }
+
+        // Without directories the walk doesn't create the parents.
+        if !config.types.dirs && !config.dry_run {
+            for target in iter::once(&target).chain(&tees) {
+                if let Some(parent) = target.parent() {
+                    create_dirs(parent, config.dir_mode.as_ref())?;
+                }
             }
+        }
 
-            let ft = FileType::from(meta.file_type());
-            match ft {
-                FileType::File => {
+        match ft {
+            FileType::File => {
+                let range = config.copy_range(meta.len());
+                if unchanged(&from, &meta, &target, config) {
+                    debug!("Skipping {:?} as {:?} is up to date", from, target);
+                } else if config.checkpoint.as_ref().is_some_and(|c| c.is_complete(&from, &meta)) {
+                    debug!("Skipping {:?} as it was completed by a previous copy", from);
+                } else if self.limit_reached() {
+                    debug!("Skipping {:?} as the total size limit has been reached", from);
+                    self.skip(range.end - range.start);
+                } else if let Some(existing) = self.linked_target(&meta, &target) {
+                    debug!("Deferring hardlink {:?} to {:?}", target, existing);
+                    // Linking is near-instant, so is counted as done.
+                    stats.send(StatusUpdate::Entry)?;
+                    stats.send(StatusUpdate::Created(CreatedKind::Hardlink))?;
+                    if config.dry_run {
+                        stats.send(StatusUpdate::Planned(PlannedOp::Hardlink, from, target))?;
+                    } else {
+                        self.links.lock().unwrap().push(HardLink { from, existing, to: target });
+                    }
+                } else if !self.reserve(range.end - range.start) {
+                    debug!("Skipping {:?} as it would exceed the total size limit", from);
+                    // Later links to it must not be deferred.
+                    self.inodes.lock().unwrap().remove(&(meta.dev(), meta.ino()));
+                } else {
                     debug!("Send copy operation {:?} to {:?}", from, target);
-                    stats.send(StatusUpdate::Size(meta.len()))?;
-                    work_tx.send(Operation::Copy(from, target))?;
+                    stats.send(StatusUpdate::Entry)?;
+                    stats.send(StatusUpdate::Size(range.end - range.start))?;
+                    send_op(Operation::Copy(from, target, tees), self.work_tx, stats, config)?;
                 }
+            }
 
-                FileType::Symlink => {
-                    let lfile = read_link(from)?;
-                    debug!("Send symlink operation {:?} to {:?}", lfile, target);
-                    work_tx.send(Operation::Link(lfile, target))?;
+            FileType::Symlink => {
+                for target in iter::once(target).chain(tees) {
+                    debug!("Send symlink operation {:?} to {:?}", from, target);
+                    stats.send(StatusUpdate::Entry)?;
+                    send_op(Operation::Link(from.clone(), target), self.work_tx, stats, config)?;
                 }
+            }
 
-                FileType::Dir => {
-                    // Create dir tree immediately as we can't
-                    // guarantee a worker will action the creation
-                    // before a subsequent copy operation requires it.
+            FileType::Dir if config.dry_run => {
+                for target in iter::once(target).chain(tees) {
+                    stats.send(StatusUpdate::Planned(PlannedOp::Mkdir, from.clone(), target))?;
+                }
+            }
+
+            FileType::Dir => {
+                if self.root_dev.is_some_and(|dev| dev != meta.dev()) {
+                    debug!("Not descending into mount point {:?}", from);
+                }
+                // Create dir tree immediately as we can't
+                // guarantee a worker will action the creation
+                // before a subsequent copy operation requires it.
+                for target in iter::once(target).chain(tees) {
                     debug!("Creating target directory {:?}", target);
                     if let Err(err) = create_dir_all(&target) {
                         let msg = format!("Error creating target directory: {}", err);
                         error!("{msg}");
                         return Err(XcpError::CopyError(msg).into())
                     }
+                    // The default ACL must be in place before any
+                    // entries are created for them to inherit it.
+                    if config.preserve_acl && !copy_default_acl(&File::open(&from)?, &File::open(&target)?)? {
+                        acl_warning(&from);
+                    }
+                    self.dirs.lock().unwrap().push((from.clone(), target));
                 }
+                stats.send(StatusUpdate::Entry)?;
+                stats.send(StatusUpdate::Created(CreatedKind::Dir))?;
+            }
 
-                FileType::Socket | FileType::Char | FileType::Fifo => {
+            FileType::Char | FileType::Fifo if depth == 0 && config.read_special => {
+                debug!("Reading special file {:?} into {:?}", from, target);
+                stats.send(StatusUpdate::Entry)?;
+                send_op(Operation::Copy(from, target, tees), self.work_tx, stats, config)?;
+            }
+
+            FileType::Socket | FileType::Char | FileType::Fifo => {
+                for target in iter::once(target).chain(tees) {
                     debug!("Special file found: {:?} to {:?}", from, target);
-                    work_tx.send(Operation::Special(from, target))?;
+                    stats.send(StatusUpdate::Entry)?;
+                    send_op(Operation::Special(from.clone(), target), self.work_tx, stats, config)?;
                 }
+            }
 
-                FileType::Block | FileType::Other => {
-                    error!("Unsupported filetype found: {:?} -> {:?}", target, ft);
-                    return Err(XcpError::UnknownFileType(target).into());
-                }
-            };
+            FileType::Block | FileType::Other => {
+                error!("Unsupported filetype found: {:?} -> {:?}", target, ft);
+                return Err(XcpError::UnknownFileType(target).into());
+            }
+        };
+        Ok(())
+    }
+
+    // When preserving hardlinks, return the target of an earlier path
+    // to this file, or record this as the first. Tee copies have
+    // their links copied as separate files.
+    fn linked_target(&self, meta: &Metadata, target: &Path) -> Option<PathBuf> {
+        if !self.config.preserve_links || meta.nlink() < 2 || !self.config.tee.is_empty() {
+            return None;
+        }
+        match self.inodes.lock().unwrap().entry((meta.dev(), meta.ino())) {
+            Entry::Occupied(e) => Some(e.get().clone()),
+            Entry::Vacant(e) => {
+                e.insert(target.to_path_buf());
+                None
+            }
         }
     }
-    debug!("Walk-worker finished: {:?}", thread::current().id());
 
+    fn limit_reached(&self) -> bool {
+        self.totals.lock().unwrap().skipped_files > 0
+    }
+
+    fn skip(&self, size: u64) {
+        let mut totals = self.totals.lock().unwrap();
+        totals.skipped_files += 1;
+        totals.skipped_bytes += size;
+    }
+
+    // Count `size` bytes against Config::max_total_size, returning
+    // false once it would be exceeded; every later file is then
+    // skipped too, so the copy stops rather than filling any gaps.
+    fn reserve(&self, size: u64) -> bool {
+        let Some(max) = self.config.max_total_size else {
+            return true;
+        };
+        let mut totals = self.totals.lock().unwrap();
+        if totals.skipped_files == 0 && totals.queued + size <= max {
+            totals.queued += size;
+            return true;
+        }
+        totals.skipped_files += 1;
+        totals.skipped_bytes += size;
+        false
+    }
+
+    fn error(&self, err: ignore::Error) -> Result<()> {
+        let (path, inner) = unwrap_walk_error(&err);
+        let path = path.unwrap_or(self.source).to_path_buf();
+        match inner {
+            // Following links can create cycles, which the walker
+            // detects for us. This is never recoverable.
+            ignore::Error::Loop { ancestor, child } => {
+                error!("Symlink loop detected at {:?}", child);
+                Err(XcpError::SymlinkLoop(child.clone(), ancestor.clone()).into())
+            }
+            // E.g. an unreadable directory; its contents are skipped.
+            _ if self.config.continue_on_error => {
+                warn!("Skipping {:?}: {}", path, inner);
+                let code = inner.io_error().and_then(io::Error::raw_os_error);
+                self.stats.send(StatusUpdate::Error(XcpError::CopyFailed(path, inner.to_string(), code)))?;
+                Ok(())
+            }
+            _ => {
+                error!("Error reading {:?}: {}", path, inner);
+                let code = inner.io_error().and_then(io::Error::raw_os_error);
+                Err(XcpError::CopyFailed(path, inner.to_string(), code).into())
+            }
+        }
+    }
+}
+
+// Whether the target file exists and can be skipped under
+// `Config::update` or `Config::quick_check`.
+fn unchanged(from: &Path, meta: &Metadata, target: &Path, config: &Config) -> bool {
+    if !config.update && config.quick_check == QuickCheck::None {
+        return false;
+    }
+    let Ok(tmeta) = target.symlink_metadata() else {
+        return false;
+    };
+    if !tmeta.is_file() {
+        return false;
+    }
+    let mtime = (meta.mtime(), meta.mtime_nsec());
+    let tmtime = (tmeta.mtime(), tmeta.mtime_nsec());
+    let same_size = tmeta.len() == meta.len();
+    match config.quick_check {
+        QuickCheck::Checksum => same_size && same_contents(from, target, meta.len(), config),
+        QuickCheck::Size if same_size => true,
+        QuickCheck::SizeMtime if same_size && tmtime == mtime => true,
+        _ => config.update && tmtime >= mtime,
+    }
+}
+
+// Whether the target's checksum matches the source's, under
+// `QuickCheck::Checksum`. Any failure to read them is left to the
+// copy to report.
+fn same_contents(from: &Path, target: &Path, len: u64, config: &Config) -> bool {
+    let algo = match config.verify {
+        Verify::None => Verify::Xxh64,
+        algo => algo,
+    };
+    let compare = || contents_match(&File::open(from)?, &File::open(target)?, len, algo);
+    compare().unwrap_or_else(|err| {
+        debug!("Failed to compare {:?} with {:?}: {}", from, target, err);
+        false
+    })
+}
+
+// The walker wraps errors with the path and depth at which they
+// occurred; return the path and the underlying error.
+fn unwrap_walk_error(err: &ignore::Error) -> (Option<&Path>, &ignore::Error) {
+    match err {
+        ignore::Error::WithPath { path, err } => (Some(path), unwrap_walk_error(err).1),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => unwrap_walk_error(err),
+        _ => (None, err),
+    }
+}
+
+/// Complete the work deferred by [tree_walker()] once all copy
+/// operations have finished. Hardlinks are created first, as doing so
+/// updates their directories' timestamps. With `fsync` set the target
+/// directories are synced last, so that their new entries and
+/// timestamps are durable.
+pub fn finalise(deferred: Deferred, config: &Config) -> Result<()> {
+    // The deferred operations may refer to entries that were never
+    // copied.
+    if config.interrupted() {
+        return Err(XcpError::Interrupted.into());
+    }
+
+    for link in &deferred.links {
+        debug!("Linking {:?} to {:?}", link.to, link.existing);
+        backup_target(&link.to, config)?;
+        if link.to.symlink_metadata().is_ok() {
+            fs::remove_file(&link.to)?;
+        }
+        fs::hard_link(&link.existing, &link.to)?;
+        remove_source_file(&link.from, config)?;
+    }
+
+    // Each directory is complete once everything has been copied, so
+    // its entries are synced here.
+    let sync_dirs = if (config.fsync || config.sync_dest_dirs) && !config.dry_run {
+        let parents = deferred.roots.iter()
+            .filter_map(|root| root.parent())
+            .map(|p| if empty_path(p) { Path::new(".") } else { p });
+        let dirs = deferred.dirs.iter()
+            .map(|(_, to)| to.as_path());
+        parents.chain(dirs)
+            .map(Path::to_path_buf)
+            .collect::<HashSet<PathBuf>>()
+    } else {
+        HashSet::new()
+    };
+
+    finalise_dirs(deferred.dirs, config)?;
+
+    for dir in sync_dirs {
+        debug!("Syncing directory {:?}", dir);
+        sync(&File::open(&dir)?)?;
+    }
+
+    if let Some(err) = deferred.size_limit {
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Apply directory metadata once all copy operations have completed,
+/// and remove the source directories if `remove_source_files` is set.
+/// The source's mode is applied here rather than on creation, so that
+/// e.g. a read-only directory can still be filled.
+pub fn finalise_dirs(dirs: CreatedDirs, config: &Config) -> Result<()> {
+    if config.no_timestamps && config.no_perms && !config.ownership && !config.remove_source_files
+        && config.chmod.is_none() && !config.preserve_acl && !config.preserve_flags
+    {
+        return Ok(());
+    }
+    // Deepest first
+    for (from, to) in dirs.iter().rev() {
+        if config.preserve_acl && !copy_acl(&File::open(from)?, &File::open(to)?)? {
+            acl_warning(from);
+        }
+        // Before the mode, as chown() may clear the setgid bit.
+        if config.ownership {
+            if let Err(e) = copy_owner(&File::open(from)?, &File::open(to)?) {
+                owner_warning(from, e);
+            }
+        }
+        if !config.no_perms {
+            let mut mode = from.metadata()?.mode() & 0o7777;
+            if config.strip_special_bits {
+                mode &= !SPECIAL_BITS;
+            }
+            debug!("Copying directory mode {:?} -> {:?}: {:o}", from, to, mode);
+            fs::set_permissions(to, Permissions::from_mode(mode))?;
+        }
+        if !config.no_timestamps {
+            debug!("Copying directory timestamps {:?} -> {:?}", from, to);
+            let infd = File::open(from)?;
+            let outfd = File::open(to)?;
+            copy_timestamps(&infd, &outfd)?;
+        }
+        // After the timestamps, as the new mode may not allow them.
+        if let Some(chmod) = &config.chmod {
+            let mode = chmod.apply(to.metadata()?.mode(), true);
+            debug!("Setting directory mode {:?} to {:o}", to, mode);
+            fs::set_permissions(to, Permissions::from_mode(mode))?;
+        }
+        if config.preserve_flags {
+            copy_inode_flags(&File::open(from)?, &File::open(to)?, from);
+        }
+        if config.remove_source_files {
+            // Directories still holding files that were skipped or
+            // failed to copy are left in place.
+            match fs::remove_dir(from) {
+                Ok(()) => debug!("Removed source directory {:?}", from),
+                Err(e) => info!("Not removing source directory {:?}: {}", from, e),
+            }
+        }
+    }
     Ok(())
 }
 
 fn empty_path(path: &Path) -> bool {
     *path == PathBuf::new()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{write, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use tempfile::TempDir;
+
+    use crate::feedback::ChannelUpdater;
+
+    // Copy a file, appending to the source before the handle is
+    // finalised, and return any errors reported.
+    fn copy_changing(config: Config) -> Result<Vec<XcpError>> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("source.txt");
+        let to = dir.path().join("dest.txt");
+        write(&from, "original content")?;
+
+        let config = Arc::new(config);
+        let updater = ChannelUpdater::new(&config);
+        let stat_rx = updater.rx_channel();
+        let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
+
+        let handle = CopyHandle::new(&from, &to, &config, &stats, &None)?;
+        handle.copy_file()?;
+        OpenOptions::new().append(true).open(&from)?.write_all(b" and more")?;
+        drop(handle);
+
+        let errors = stat_rx.try_iter()
+            .filter_map(|s| match s {
+                StatusUpdate::Error(e) => Some(e),
+                _ => None,
+            })
+            .collect();
+        Ok(errors)
+    }
+
+    #[test]
+    fn test_source_changed() -> Result<()> {
+        let errors = copy_changing(Config::default())?;
+        assert!(errors.is_empty());
+
+        let errors = copy_changing(Config { strict: true, ..Config::default() })?;
+        assert_eq!(1, errors.len());
+        assert!(matches!(errors[0], XcpError::SourceChanged(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dense_target() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("dest.bin");
+        let len = 1024 * 1024;
+        {
+            let fd = File::create(&from)?;
+            fd.set_len(len)?;
+            (&fd).seek(SeekFrom::Start(len / 2))?;
+            (&fd).write_all(b"data in the middle")?;
+        }
+
+        let config = Arc::new(Config {
+            reflink: Reflink::Never,
+            ..Config::default()
+        });
+        let stats: Arc<dyn StatusUpdater> = Arc::new(ChannelUpdater::new(&config));
+        let mut handle = CopyHandle::new(&from, &to, &config, &stats, &None)?;
+        // As on a filesystem without holes.
+        handle.sparse_target = false;
+        handle.copy_file()?;
+        drop(handle);
+
+        assert_eq!(fs::read(&from)?, fs::read(&to)?);
+        assert!(!probably_sparse(&File::open(&to)?)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_space() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("source.txt");
+        let to = dir.path().join("dest.txt");
+        write(&from, "new content")?;
+        write(&to, "old content")?;
+
+        let config = Arc::new(Config {
+            atomic: Atomic::Always,
+            ..Config::default()
+        });
+        let stats: Arc<dyn StatusUpdater> = Arc::new(ChannelUpdater::new(&config));
+        let handle = CopyHandle::new(&from, &to, &config, &stats, &None)?;
+        let tmp = handle.tmp.clone().unwrap();
+        assert!(tmp.exists());
+
+        let err = handle.block_failed(libfs::Error::OSError(Errno::NOSPC));
+        assert!(matches!(err, Some(XcpError::OutOfSpace(ref path)) if *path == to));
+        assert!(!tmp.exists());
+        // Reported only once.
+        assert!(handle.block_failed(libfs::Error::OSError(Errno::NOSPC)).is_none());
+        drop(handle);
+        assert_eq!("old content", fs::read_to_string(&to)?);
+
+        let err = copy_failure(&from, &to, &std::io::Error::from_raw_os_error(Errno::NOSPC.raw_os_error()).into());
+        assert!(matches!(err, XcpError::OutOfSpace(_)));
+        let err = copy_failure(&from, &to, &std::io::Error::from_raw_os_error(Errno::IO.raw_os_error()).into());
+        assert!(matches!(err, XcpError::CopyFailed(ref path, ..) if *path == from));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rate_limiter_shared() {
+        // 4 threads * 5 blocks * 10KB at 1MB/s should take >= 200ms.
+        let start = Instant::now();
+        let limiter = Arc::new(RateLimiter::new(1_000_000));
+        let threads = (0..4)
+            .map(|_| {
+                let limiter = limiter.clone();
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        limiter.acquire(10_000);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
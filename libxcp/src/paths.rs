@@ -14,38 +14,202 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::path::Path;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use ignore::{DirEntry, Match};
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
-use log::info;
-use walkdir::DirEntry;
+use log::{info, warn};
 
-use crate::config::Config;
+use crate::config::{Config, Filter};
 use crate::errors::Result;
 
-/// Parse a git ignore file.
+/// The name of the per-directory ignore files honoured by xcp.
+pub const XCPIGNORE: &str = ".xcpignore";
+
+/// Parse the source's `.gitignore` file, if enabled, and any ignore
+/// files given in the [Config]. These are merged into one set of
+/// rules, with the ignore files taking precedence over `.gitignore`,
+/// and later ignore files over earlier ones.
 pub fn parse_ignore(source: &Path, config: &Config) -> Result<Option<Gitignore>> {
-    let gitignore = if config.gitignore {
+    if config.no_gitignore || (!config.gitignore && config.ignore_files.is_empty()) {
+        return Ok(None);
+    }
+    let mut builder = GitignoreBuilder::new(source);
+    if config.gitignore {
         let gifile = source.join(".gitignore");
         info!("Using .gitignore file {:?}", gifile);
-        let mut builder = GitignoreBuilder::new(source);
         builder.add(&gifile);
-        let ignore = builder.build()?;
-        Some(ignore)
-    } else {
-        None
-    };
-    Ok(gitignore)
+    }
+    for file in &config.ignore_files {
+        info!("Using ignore file {:?}", file);
+        if let Some(err) = builder.add(file) {
+            return Err(err.into());
+        }
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// The `.xcpignore` files within a source, which apply to their own
+/// directory and its descendants. Each is parsed when first needed.
+pub struct XcpIgnores {
+    root: PathBuf,
+    files: Mutex<HashMap<PathBuf, Option<Arc<Gitignore>>>>,
+}
+
+impl XcpIgnores {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The `.xcpignore` files for a source, or `None` if ignore file
+    /// processing is disabled.
+    pub fn for_source(root: &Path, config: &Config) -> Option<Self> {
+        if config.no_gitignore {
+            None
+        } else {
+            Some(Self::new(root))
+        }
+    }
+
+    /// Match a path against the `.xcpignore` files of the directories
+    /// above it, up to the source. The deepest file with a matching
+    /// rule wins.
+    pub fn matched(&self, path: &Path, is_dir: bool) -> Match<()> {
+        if path == self.root {
+            return Match::None;
+        }
+        for dir in path.ancestors().skip(1) {
+            if let Some(ignore) = self.load(dir) {
+                match ignore.matched(path, is_dir) {
+                    Match::None => {}
+                    Match::Ignore(_) => return Match::Ignore(()),
+                    Match::Whitelist(_) => return Match::Whitelist(()),
+                }
+            }
+            if dir == self.root {
+                break;
+            }
+        }
+        Match::None
+    }
+
+    fn load(&self, dir: &Path) -> Option<Arc<Gitignore>> {
+        let mut files = self.files.lock().unwrap();
+        files.entry(dir.to_path_buf())
+            .or_insert_with(|| {
+                let file = dir.join(XCPIGNORE);
+                if !file.is_file() {
+                    return None;
+                }
+                info!("Using ignore file {:?}", file);
+                let mut builder = GitignoreBuilder::new(dir);
+                if let Some(err) = builder.add(&file) {
+                    warn!("Error reading {:?}: {}", file, err);
+                }
+                match builder.build() {
+                    Ok(ignore) => Some(Arc::new(ignore)),
+                    Err(err) => {
+                        warn!("Error parsing {:?}: {}", file, err);
+                        None
+                    }
+                }
+            })
+            .clone()
+    }
 }
 
-/// Filter to return whether a given file should be ignored by a
-/// filter file.
-pub fn ignore_filter(entry: &DirEntry, ignore: &Option<Gitignore>) -> bool {
-    match ignore {
-        None => true,
-        Some(gi) => {
-            let path = entry.path();
-            let m = gi.matched(path, path.is_dir());
-            !m.is_ignore()
+/// Compile the include/exclude patterns into a matcher rooted at the
+/// source. Includes are whitelist rules, so they can re-add files
+/// excluded by earlier patterns.
+pub fn parse_filters(source: &Path, config: &Config) -> Result<Option<Gitignore>> {
+    if config.filters.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GitignoreBuilder::new(source);
+    for filter in &config.filters {
+        match filter {
+            Filter::Exclude(pattern) => builder.add_line(None, pattern)?,
+            Filter::Include(pattern) => builder.add_line(None, &format!("!{}", pattern))?,
+        };
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// All of the rules deciding which files within a source are
+/// copied.
+pub struct Ignores {
+    ignore: Option<Gitignore>,
+    xcpignores: Option<XcpIgnores>,
+    filters: Option<Gitignore>,
+}
+
+impl Ignores {
+    pub fn new(source: &Path, config: &Config) -> Result<Self> {
+        Ok(Self {
+            ignore: parse_ignore(source, config)?,
+            xcpignores: XcpIgnores::for_source(source, config),
+            filters: parse_filters(source, config)?,
+        })
+    }
+
+    /// Whether a path within the source should be copied, according
+    /// to the include/exclude patterns, the `.xcpignore` files or the
+    /// other ignore files, in that order of precedence. The path need
+    /// not exist.
+    pub fn allowed(&self, path: &Path, is_dir: bool) -> bool {
+        if let Some(f) = &self.filters {
+            let m = f.matched(path, is_dir);
+            if !m.is_none() {
+                return !m.is_ignore();
+            }
+        }
+
+        if let Some(xi) = &self.xcpignores {
+            let m = xi.matched(path, is_dir);
+            if !m.is_none() {
+                return !m.is_ignore();
+            }
+        }
+
+        match &self.ignore {
+            None => true,
+            Some(gi) => {
+                let m = gi.matched(path, is_dir);
+                !m.is_ignore()
+            }
+        }
+    }
+}
+
+/// Filter to return whether a given file should be copied; see
+/// [Ignores::allowed].
+pub fn ignore_filter(entry: &DirEntry, ignores: &Ignores) -> bool {
+    let path = entry.path();
+    ignores.allowed(path, path.is_dir())
+}
+
+/// Canonicalise a path that may not exist yet. The longest existing
+/// prefix is resolved, including any symlinks, and the remainder
+/// appended as-is.
+pub fn resolve_path(path: &Path) -> Result<PathBuf> {
+    for ancestor in path.ancestors() {
+        let existing = if ancestor.as_os_str().is_empty() { Path::new(".") } else { ancestor };
+        match existing.canonicalize() {
+            Ok(resolved) => return Ok(resolved.join(path.strip_prefix(ancestor)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
         }
     }
+    Ok(path.to_path_buf())
+}
+
+/// Whether `target` is `source` or is inside it, once symlinks are
+/// resolved.
+pub fn is_within(source: &Path, target: &Path) -> Result<bool> {
+    Ok(resolve_path(target)?.starts_with(resolve_path(source)?))
 }
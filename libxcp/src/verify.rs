@@ -0,0 +1,510 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Post-copy checksum verification.
+
+use std::cmp;
+use std::fs::File;
+use std::iter;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+
+use libfs::{map_extents, merge_extents, probably_sparse};
+use log::debug;
+
+use crate::config::Verify;
+use crate::errors::{Result, XcpError};
+
+const READ_SIZE: usize = 1024 * 1024;
+
+trait Digest {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self: Box<Self>) -> Vec<u8>;
+}
+
+fn new_digest(algo: Verify) -> Option<Box<dyn Digest>> {
+    match algo {
+        Verify::None => None,
+        Verify::Xxh64 => Some(Box::new(Xxh64::new(0))),
+        Verify::Sha256 => Some(Box::new(Sha256::new())),
+    }
+}
+
+// The ranges of the source file containing data. Holes are skipped
+// on both sides; the destination layout may not match exactly, but
+// the data ranges must.
+pub(crate) fn data_ranges(infd: &File, len: u64) -> Result<Vec<Range<u64>>> {
+    if probably_sparse(infd)? {
+        if let Some(extents) = map_extents(infd)? {
+            let ranges = merge_extents(extents)?
+                .into_iter()
+                .map(|e| e.start..cmp::min(e.end, len))
+                .filter(|r| r.start < r.end)
+                .collect();
+            return Ok(ranges);
+        }
+    }
+    Ok(iter::once(0..len).collect())
+}
+
+fn digest_range(fd: &File, range: &Range<u64>, digest: &mut dyn Digest, buf: &mut [u8]) -> Result<()> {
+    let mut pos = range.start;
+    while pos < range.end {
+        let next = cmp::min((range.end - pos) as usize, buf.len());
+        let read = fd.read_at(&mut buf[..next], pos)?;
+        if read == 0 {
+            return Err(XcpError::CopyError("File ended prematurely during verification".to_string()).into());
+        }
+        digest.update(&buf[..read]);
+        pos += read as u64;
+    }
+    Ok(())
+}
+
+/// Compare the checksums of the data ranges of two files of length
+/// `len`. Both files must be open for reading. Returns the hex digest
+/// if they match, or `None` if they don't or `algo` is
+/// [Verify::None].
+pub(crate) fn files_match(infd: &File, outfd: &File, len: u64, algo: Verify) -> Result<Option<String>> {
+    let (mut indigest, mut outdigest) = match (new_digest(algo), new_digest(algo)) {
+        (Some(i), Some(o)) => (i, o),
+        _ => return Ok(None),
+    };
+    if outfd.metadata()?.len() != len {
+        return Ok(None);
+    }
+
+    let mut buf = vec![0; READ_SIZE];
+    for range in data_ranges(infd, len)? {
+        debug!("Verifying range {:?}", range);
+        digest_range(infd, &range, indigest.as_mut(), &mut buf)?;
+        digest_range(outfd, &range, outdigest.as_mut(), &mut buf)?;
+    }
+
+    let digest = indigest.finish();
+    if digest != outdigest.finish() {
+        return Ok(None);
+    }
+    Ok(Some(digest.iter().map(|b| format!("{:02x}", b)).collect()))
+}
+
+/// The SHA-256 digest of the first `len` bytes of `fd`, including
+/// any holes; see [Config::dedup](crate::config::Config::dedup).
+pub(crate) fn content_digest(fd: &File, len: u64) -> Result<Vec<u8>> {
+    let mut digest: Box<dyn Digest> = Box::new(Sha256::new());
+    let mut buf = vec![0; READ_SIZE];
+    digest_range(fd, &(0..len), digest.as_mut(), &mut buf)?;
+    Ok(digest.finish())
+}
+
+/// Whether two files have the same length `len` and the same `algo`
+/// digest, including any holes, so that data written into a hole of
+/// either is noticed. Both files must be open for reading.
+pub(crate) fn contents_match(infd: &File, outfd: &File, len: u64, algo: Verify) -> Result<bool> {
+    let (mut indigest, mut outdigest) = match (new_digest(algo), new_digest(algo)) {
+        (Some(i), Some(o)) => (i, o),
+        _ => return Ok(false),
+    };
+    if outfd.metadata()?.len() != len {
+        return Ok(false);
+    }
+    let mut buf = vec![0; READ_SIZE];
+    digest_range(infd, &(0..len), indigest.as_mut(), &mut buf)?;
+    digest_range(outfd, &(0..len), outdigest.as_mut(), &mut buf)?;
+    Ok(indigest.finish() == outdigest.finish())
+}
+
+/// A running checksum of data that doesn't come from a file, using
+/// one of the [Config::verify](crate::config::Config::verify)
+/// algorithms.
+pub struct Checksum(Box<dyn Digest>);
+
+impl Checksum {
+    /// `None` for [Verify::None].
+    pub fn new(algo: Verify) -> Option<Self> {
+        new_digest(algo).map(Checksum)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.0.finish()
+    }
+}
+
+/// Whether the first `len` bytes of two files are identical. Both
+/// files must be open for reading.
+pub(crate) fn prefix_matches(infd: &File, outfd: &File, len: u64) -> Result<bool> {
+    let mut inbuf = vec![0; READ_SIZE];
+    let mut outbuf = vec![0; READ_SIZE];
+    let mut pos = 0;
+    while pos < len {
+        let next = cmp::min((len - pos) as usize, READ_SIZE);
+        infd.read_exact_at(&mut inbuf[..next], pos)?;
+        outfd.read_exact_at(&mut outbuf[..next], pos)?;
+        if inbuf[..next] != outbuf[..next] {
+            return Ok(false);
+        }
+        pos += next as u64;
+    }
+    Ok(true)
+}
+
+// ********************************************************************** //
+
+const XXH_PRIME64_1: u64 = 0x9E3779B185EBCA87;
+const XXH_PRIME64_2: u64 = 0xC2B2AE3D27D4EB4F;
+const XXH_PRIME64_3: u64 = 0x165667B19E3779F9;
+const XXH_PRIME64_4: u64 = 0x85EBCA77C2B2AE63;
+const XXH_PRIME64_5: u64 = 0x27D4EB2F165667C5;
+
+/// Streaming [XXH64](https://github.com/Cyan4973/xxHash/blob/dev/doc/xxhash_spec.md).
+struct Xxh64 {
+    seed: u64,
+    acc: [u64; 4],
+    buf: [u8; 32],
+    buflen: usize,
+    total: u64,
+}
+
+fn xxh_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(XXH_PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(XXH_PRIME64_1)
+}
+
+fn xxh_merge(acc: u64, val: u64) -> u64 {
+    (acc ^ xxh_round(0, val))
+        .wrapping_mul(XXH_PRIME64_1)
+        .wrapping_add(XXH_PRIME64_4)
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    u64::from_le_bytes(b[..8].try_into().unwrap())
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes(b[..4].try_into().unwrap())
+}
+
+impl Xxh64 {
+    fn new(seed: u64) -> Self {
+        Xxh64 {
+            seed,
+            acc: [
+                seed.wrapping_add(XXH_PRIME64_1).wrapping_add(XXH_PRIME64_2),
+                seed.wrapping_add(XXH_PRIME64_2),
+                seed,
+                seed.wrapping_sub(XXH_PRIME64_1),
+            ],
+            buf: [0; 32],
+            buflen: 0,
+            total: 0,
+        }
+    }
+
+    fn stripe(acc: &mut [u64; 4], stripe: &[u8]) {
+        for (i, lane) in acc.iter_mut().enumerate() {
+            *lane = xxh_round(*lane, read_u64(&stripe[i * 8..]));
+        }
+    }
+}
+
+impl Digest for Xxh64 {
+    fn update(&mut self, mut data: &[u8]) {
+        self.total += data.len() as u64;
+
+        if self.buflen > 0 {
+            let fill = cmp::min(32 - self.buflen, data.len());
+            self.buf[self.buflen..self.buflen + fill].copy_from_slice(&data[..fill]);
+            self.buflen += fill;
+            data = &data[fill..];
+            if self.buflen < 32 {
+                return;
+            }
+            let buf = self.buf;
+            Self::stripe(&mut self.acc, &buf);
+            self.buflen = 0;
+        }
+
+        let mut chunks = data.chunks_exact(32);
+        for stripe in &mut chunks {
+            Self::stripe(&mut self.acc, stripe);
+        }
+        let rem = chunks.remainder();
+        self.buf[..rem.len()].copy_from_slice(rem);
+        self.buflen = rem.len();
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        let [v1, v2, v3, v4] = self.acc;
+        let mut h = if self.total >= 32 {
+            let h = v1.rotate_left(1)
+                .wrapping_add(v2.rotate_left(7))
+                .wrapping_add(v3.rotate_left(12))
+                .wrapping_add(v4.rotate_left(18));
+            [v1, v2, v3, v4].iter().fold(h, |h, v| xxh_merge(h, *v))
+        } else {
+            self.seed.wrapping_add(XXH_PRIME64_5)
+        };
+        h = h.wrapping_add(self.total);
+
+        let mut rem = &self.buf[..self.buflen];
+        while rem.len() >= 8 {
+            h ^= xxh_round(0, read_u64(rem));
+            h = h.rotate_left(27).wrapping_mul(XXH_PRIME64_1).wrapping_add(XXH_PRIME64_4);
+            rem = &rem[8..];
+        }
+        if rem.len() >= 4 {
+            h ^= (read_u32(rem) as u64).wrapping_mul(XXH_PRIME64_1);
+            h = h.rotate_left(23).wrapping_mul(XXH_PRIME64_2).wrapping_add(XXH_PRIME64_3);
+            rem = &rem[4..];
+        }
+        for b in rem {
+            h ^= (*b as u64).wrapping_mul(XXH_PRIME64_5);
+            h = h.rotate_left(11).wrapping_mul(XXH_PRIME64_1);
+        }
+
+        h ^= h >> 33;
+        h = h.wrapping_mul(XXH_PRIME64_2);
+        h ^= h >> 29;
+        h = h.wrapping_mul(XXH_PRIME64_3);
+        h ^= h >> 32;
+
+        h.to_be_bytes().to_vec()
+    }
+}
+
+// ********************************************************************** //
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Streaming [SHA-256](https://csrc.nist.gov/pubs/fips/180-4/upd1/final).
+struct Sha256 {
+    state: [u32; 8],
+    buf: [u8; 64],
+    buflen: usize,
+    total: u64,
+}
+
+impl Sha256 {
+    fn new() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+                0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+            ],
+            buf: [0; 64],
+            buflen: 0,
+            total: 0,
+        }
+    }
+
+    fn compress(state: &mut [u32; 8], block: &[u8]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+}
+
+impl Digest for Sha256 {
+    fn update(&mut self, mut data: &[u8]) {
+        self.total += data.len() as u64;
+
+        if self.buflen > 0 {
+            let fill = cmp::min(64 - self.buflen, data.len());
+            self.buf[self.buflen..self.buflen + fill].copy_from_slice(&data[..fill]);
+            self.buflen += fill;
+            data = &data[fill..];
+            if self.buflen < 64 {
+                return;
+            }
+            let buf = self.buf;
+            Self::compress(&mut self.state, &buf);
+            self.buflen = 0;
+        }
+
+        let mut chunks = data.chunks_exact(64);
+        for block in &mut chunks {
+            Self::compress(&mut self.state, block);
+        }
+        let rem = chunks.remainder();
+        self.buf[..rem.len()].copy_from_slice(rem);
+        self.buflen = rem.len();
+    }
+
+    fn finish(mut self: Box<Self>) -> Vec<u8> {
+        let bits = self.total.wrapping_mul(8);
+        let padlen = if self.buflen < 56 { 56 - self.buflen } else { 120 - self.buflen };
+        let mut pad = vec![0u8; padlen + 8];
+        pad[0] = 0x80;
+        pad[padlen..].copy_from_slice(&bits.to_be_bytes());
+        // Don't count the padding in the length
+        let total = self.total;
+        self.update(&pad);
+        self.total = total;
+
+        self.state.iter()
+            .flat_map(|s| s.to_be_bytes())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn hex(digest: Box<dyn Digest>, data: &[u8], chunk: usize) -> String {
+        let mut digest = digest;
+        for c in data.chunks(chunk.max(1)) {
+            digest.update(c);
+        }
+        digest.finish().iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    #[test]
+    fn test_xxh64_vectors() {
+        assert_eq!("ef46db3751d8e999", hex(Box::new(Xxh64::new(0)), b"", 1));
+        assert_eq!("44bc2cf5ad770999", hex(Box::new(Xxh64::new(0)), b"abc", 1));
+
+        // Streaming should be independent of chunking
+        let data = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let whole = hex(Box::new(Xxh64::new(0)), &data, data.len());
+        for chunk in [1, 7, 31, 32, 33, 100] {
+            assert_eq!(whole, hex(Box::new(Xxh64::new(0)), &data, chunk));
+        }
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert!(Checksum::new(Verify::None).is_none());
+        let mut sum = Checksum::new(Verify::Xxh64).unwrap();
+        sum.update(b"a");
+        sum.update(b"bc");
+        assert_eq!(0x44bc2cf5ad770999u64.to_be_bytes().to_vec(), sum.finish());
+    }
+
+    #[test]
+    fn test_sha256_vectors() {
+        assert_eq!("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                   hex(Box::new(Sha256::new()), b"", 1));
+        assert_eq!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+                   hex(Box::new(Sha256::new()), b"abc", 1));
+        assert_eq!("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+                   hex(Box::new(Sha256::new()), b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq", 5));
+    }
+
+    #[test]
+    fn test_files_match() -> Result<()> {
+        let dir = TempDir::new()?;
+        let a = dir.path().join("a.bin");
+        let b = dir.path().join("b.bin");
+        File::create(&a)?.write_all(b"some test data")?;
+        File::create(&b)?.write_all(b"some test data")?;
+
+        for algo in [Verify::Xxh64, Verify::Sha256] {
+            assert!(files_match(&File::open(&a)?, &File::open(&b)?, 14, algo)?.is_some());
+        }
+        let digest = files_match(&File::open(&a)?, &File::open(&b)?, 14, Verify::Sha256)?;
+        assert_eq!(Some(hex(Box::new(Sha256::new()), b"some test data", 14)), digest);
+
+        File::create(&b)?.write_all(b"some test dat4")?;
+        for algo in [Verify::Xxh64, Verify::Sha256] {
+            assert!(files_match(&File::open(&a)?, &File::open(&b)?, 14, algo)?.is_none());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_matches() -> Result<()> {
+        let dir = TempDir::new()?;
+        let a = dir.path().join("a.bin");
+        let b = dir.path().join("b.bin");
+        File::create(&a)?.write_all(b"some test data")?;
+        File::create(&b)?.write_all(b"some test")?;
+
+        assert!(prefix_matches(&File::open(&a)?, &File::open(&b)?, 9)?);
+        assert!(prefix_matches(&File::open(&a)?, &File::open(&b)?, 0)?);
+        File::create(&b)?.write_all(b"some tost")?;
+        assert!(!prefix_matches(&File::open(&a)?, &File::open(&b)?, 9)?);
+        assert!(prefix_matches(&File::open(&a)?, &File::open(&b)?, 6)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_contents_match() -> Result<()> {
+        let dir = TempDir::new()?;
+        let a = dir.path().join("a.bin");
+        let b = dir.path().join("b.bin");
+        File::create(&a)?.set_len(4096)?;
+        File::create(&b)?.set_len(4096)?;
+        assert!(contents_match(&File::open(&a)?, &File::open(&b)?, 4096, Verify::Xxh64)?);
+        assert!(!contents_match(&File::open(&a)?, &File::open(&b)?, 4096, Verify::None)?);
+
+        // Data where the source has a hole still differs.
+        File::options().write(true).open(&b)?.write_all(b"data")?;
+        assert!(!contents_match(&File::open(&a)?, &File::open(&b)?, 4096, Verify::Sha256)?);
+        File::create(&b)?.set_len(100)?;
+        assert!(!contents_match(&File::open(&a)?, &File::open(&b)?, 4096, Verify::Xxh64)?);
+
+        Ok(())
+    }
+}
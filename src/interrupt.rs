@@ -0,0 +1,49 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Graceful handling of Ctrl-C. The first SIGINT or SIGTERM sets a
+//! flag that stops the copy once the blocks in progress are written;
+//! a second one terminates immediately.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+static INTERRUPT: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+const SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+extern "C" fn on_signal(_sig: libc::c_int) {
+    // Only async-signal-safe operations are allowed here.
+    if let Some(flag) = INTERRUPT.get() {
+        flag.store(true, Ordering::Relaxed);
+    }
+    for sig in SIGNALS {
+        unsafe { libc::signal(sig, libc::SIG_DFL) };
+    }
+}
+
+/// Install the signal handlers, returning the flag they set.
+pub fn install() -> io::Result<Arc<AtomicBool>> {
+    let flag = INTERRUPT.get_or_init(|| Arc::new(AtomicBool::new(false))).clone();
+    for sig in SIGNALS {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(sig, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(flag)
+}
@@ -0,0 +1,93 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Logging to the terminal without garbling the progress bar.
+
+use std::sync::RwLock;
+
+use indicatif::ProgressBar;
+use libxcp::errors::Result;
+use log::{LevelFilter, Log, Metadata, Record};
+use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
+
+// The bar currently drawn, if any.
+static BAR: RwLock<Option<ProgressBar>> = RwLock::new(None);
+
+struct BarLogger {
+    inner: Box<TermLogger>,
+}
+
+impl Log for BarLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // The bar is cleared while the message is written, and then
+        // redrawn below it.
+        match BAR.read().unwrap().as_ref() {
+            Some(bar) => bar.suspend(|| {
+                self.inner.log(record);
+                self.inner.flush();
+            }),
+            None => self.inner.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+pub fn init(level: LevelFilter) -> Result<()> {
+    let inner = TermLogger::new(level, Config::default(), TerminalMode::Mixed, ColorChoice::Auto);
+    log::set_boxed_logger(Box::new(BarLogger { inner }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Write log messages around `bar` while it is drawn; `None` once it
+/// has finished.
+pub fn set_bar(bar: Option<&ProgressBar>) {
+    *BAR.write().unwrap() = bar.cloned();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_log_with_bar() {
+        let logger = BarLogger {
+            inner: TermLogger::new(LevelFilter::Warn, Config::default(), TerminalMode::Stderr, ColorChoice::Never),
+        };
+        let record = Record::builder()
+            .level(Level::Warn)
+            .args(format_args!("logged while the bar is drawn"))
+            .build();
+
+        let bar = ProgressBar::hidden();
+        set_bar(Some(&bar));
+        logger.log(&record);
+        set_bar(None);
+        logger.log(&record);
+        assert!(BAR.read().unwrap().is_none());
+    }
+}
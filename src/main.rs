@@ -14,36 +14,39 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+mod interrupt;
+mod logging;
+mod manifest;
 mod options;
 mod progress;
-
-use std::path::PathBuf;
-use std::{result, thread};
+mod remote;
+mod stream;
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read};
+use std::iter;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+use std::result;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use glob::{glob, Paths};
-use libxcp::config::{Config, Reflink};
-use libxcp::drivers::load_driver;
+use indicatif::HumanBytes;
+use libfs::is_same_file;
+use libxcp::checkpoint::Checkpoint;
+use libxcp::config::{Backup, Chmod, Config, Reflink, Sparse, Verify};
+use libxcp::{copy, create_dirs};
 use libxcp::errors::{Result, XcpError};
-use libxcp::feedback::{ChannelUpdater, StatusUpdate, StatusUpdater};
+use libxcp::feedback::{CopyStats, PlannedOp, Reporter, StatusUpdate};
+use libxcp::Progress;
 use log::{error, info, warn};
 
-use crate::options::Opts;
-
-fn init_logging(opts: &Opts) -> Result<()> {
-    use simplelog::{ColorChoice, Config, SimpleLogger, TermLogger, TerminalMode};
-
-    TermLogger::init(
-        opts.log_level(),
-        Config::default(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    ).or_else(
-        |_| SimpleLogger::init(opts.log_level(), Config::default())
-    )?;
-
-    Ok(())
-}
+use crate::manifest::Manifest;
+use crate::options::{Opts, ProgressMode};
+use crate::stream::{copy_stream, is_stdio};
 
 // Expand a list of file-paths or glob-patterns into a list of concrete paths.
 // FIXME: This currently eats non-existent files that are not
@@ -64,6 +67,24 @@ fn expand_globs(patterns: &[String]) -> Result<Vec<PathBuf>> {
     Ok(paths)
 }
 
+// Read the sources given with --from-file; empty entries are
+// skipped.
+fn read_source_list(path: &Path, nul: bool) -> Result<Vec<PathBuf>> {
+    let data = if is_stdio(&path.to_string_lossy()) {
+        let mut buf = Vec::new();
+        io::stdin().lock().read_to_end(&mut buf)?;
+        buf
+    } else {
+        fs::read(path)?
+    };
+    let sep = if nul { b'\0' } else { b'\n' };
+    let sources = data.split(|b| *b == sep)
+        .filter(|p| !p.is_empty())
+        .map(|p| PathBuf::from(OsStr::from_bytes(p)))
+        .collect();
+    Ok(sources)
+}
+
 fn expand_sources(source_list: &[String], opts: &Opts) -> Result<Vec<PathBuf>> {
     if opts.glob {
         expand_globs(source_list)
@@ -75,62 +96,202 @@ fn expand_sources(source_list: &[String], opts: &Opts) -> Result<Vec<PathBuf>> {
     }
 }
 
+// Ranged copies are of a single file, and the range must lie within
+// it.
+fn check_range(opts: &Opts, sources: &[PathBuf]) -> Result<()> {
+    let [source] = sources else {
+        return Err(XcpError::InvalidArguments("--offset and --length require a single source file.".to_string()).into());
+    };
+    let meta = source.metadata()?;
+    if !meta.is_file() {
+        return Err(XcpError::InvalidSource("--offset and --length require a regular source file.").into());
+    }
+    let offset = opts.offset.unwrap_or(0);
+    let end = offset.checked_add(opts.length.unwrap_or(0));
+    if end.map_or(true, |end| end > meta.len()) {
+        return Err(XcpError::InvalidArguments(format!("The range exceeds the source size of {} bytes.", meta.len())).into());
+    }
+    Ok(())
+}
+
+// Options that need a local destination, checked for copies made
+// `mode`; `extra` adds those that are specific to the mode. Each is
+// named with the verb that follows it.
+fn check_unsupported(opts: &Opts, mode: &str, extra: &[(bool, &str)]) -> Result<()> {
+    let common = [
+        (opts.remove_source_files, "--remove-source-files is"),
+        (opts.delete, "--delete is"),
+        (opts.offset.is_some() || opts.length.is_some(), "--offset and --length are"),
+        (opts.manifest.is_some(), "--manifest is"),
+        (opts.checkpoint.is_some(), "--checkpoint is"),
+        (!opts.tee.is_empty(), "--tee is"),
+    ];
+    match common.iter().chain(extra).find(|(set, _)| *set) {
+        Some((_, option)) => Err(XcpError::InvalidArguments(format!("{} not supported {}.", option, mode)).into()),
+        None => Ok(()),
+    }
+}
+
 fn opts_check(opts: &Opts) -> Result<()> {
     #[cfg(any(target_os = "linux", target_os = "android"))]
     if opts.reflink == Reflink::Never {
         warn!("--reflink=never is selected, however the Linux kernel may override this.");
     }
 
+    if opts.block_size == 0 {
+        return Err(XcpError::InvalidArguments("--block-size must be greater than zero.".to_string()).into());
+    }
+    if !opts.block_size.is_power_of_two() {
+        warn!("--block-size {} is not a power of two; this may be inefficient.", opts.block_size);
+    }
+    if opts.no_punch_holes && opts.sparse == Sparse::Always {
+        return Err(XcpError::InvalidArguments("--no-punch-holes cannot be used with --sparse=always.".to_string()).into());
+    }
+    if opts.no_preserve_special_bits && opts.preserved().mode {
+        return Err(XcpError::InvalidArguments("--no-preserve-special-bits cannot be used with --preserve=mode.".to_string()).into());
+    }
+    if opts.workers == Some(0) {
+        return Err(XcpError::InvalidArguments("--workers must be greater than zero.".to_string()).into());
+    }
+    if opts.bwlimit == Some(0) {
+        return Err(XcpError::InvalidArguments("--bwlimit must be greater than zero.".to_string()).into());
+    }
+    if let (Some(min), Some(max)) = (opts.min_size, opts.max_size) {
+        if min > max {
+            return Err(XcpError::InvalidArguments("--min-size cannot be greater than --max-size.".to_string()).into());
+        }
+    }
+    if opts.max_total_size == Some(0) {
+        return Err(XcpError::InvalidArguments("--max-total-size must be greater than zero.".to_string()).into());
+    }
+    if opts.timeout == Some(0) {
+        return Err(XcpError::InvalidArguments("--timeout must be greater than zero.".to_string()).into());
+    }
+
     if opts.no_clobber && opts.force {
         return Err(XcpError::InvalidArguments("--force and --noclobber cannot be set at the same time.".to_string()).into());
     }
+
+    let preserve = opts.preserved();
+    if preserve.mode && opts.no_perms {
+        return Err(XcpError::InvalidArguments("--no-perms and --preserve=mode cannot be set at the same time.".to_string()).into());
+    }
+    if preserve.timestamps && opts.no_timestamps {
+        return Err(XcpError::InvalidArguments("--no-timestamps and --preserve=timestamps cannot be set at the same time.".to_string()).into());
+    }
+    if preserve.xattr && opts.no_xattr {
+        return Err(XcpError::InvalidArguments("--no-xattr and --preserve=xattr cannot be set at the same time.".to_string()).into());
+    }
+    if opts.suffix.is_empty() || opts.suffix.contains('/') {
+        return Err(XcpError::InvalidArguments("--suffix must be non-empty and not contain '/'.".to_string()).into());
+    }
+    if opts.remove_source_files && (opts.dereference || opts.dereference_command_line) {
+        // Dereferenced paths are resolved, so we would remove the
+        // link targets rather than the links.
+        return Err(XcpError::InvalidArguments("--remove-source-files cannot be used with --dereference or --dereference-command-line.".to_string()).into());
+    }
+    if opts.no_progress && opts.progress != ProgressMode::Bar {
+        return Err(XcpError::InvalidArguments("--no-progress and --progress cannot be set at the same time.".to_string()).into());
+    }
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let opts = Opts::from_args()?;
-    init_logging(&opts)?;
-    opts_check(&opts)?;
+// Exit codes, following rsync's where they overlap; see the README.
+pub(crate) const EXIT_USAGE: u8 = 1;
+const EXIT_SELECTION: u8 = 3;
+const EXIT_IO: u8 = 11;
+const EXIT_INTERRUPTED: u8 = 20;
+const EXIT_PARTIAL: u8 = 23;
+
+fn exit_code(err: &anyhow::Error) -> u8 {
+    match err.downcast_ref::<XcpError>() {
+        Some(XcpError::InvalidArguments(_) | XcpError::UnknownDriver(_) | XcpError::UnsupportedOS(_)) => EXIT_USAGE,
+        Some(XcpError::InvalidSource(_) | XcpError::InvalidDestination(_) | XcpError::SameFile(..)) => EXIT_SELECTION,
+        Some(XcpError::Interrupted) => EXIT_INTERRUPTED,
+        // Everything else is raised once copying has started.
+        Some(_) => EXIT_PARTIAL,
+        None if err.is::<clap::Error>() => EXIT_USAGE,
+        None => EXIT_IO,
+    }
+}
 
-    let (dest, source_patterns) = match opts.target_directory {
-        Some(ref d) => { (d, opts.paths.as_slice()) }
-        None => {
-            opts.paths.split_last().ok_or(XcpError::InvalidArguments("Insufficient arguments".to_string()))?
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::from(exit_code(&err))
         }
+    }
+}
+
+/// Create the missing directories leading to `dest`, with `mode`;
+/// see `--parents`.
+pub(crate) fn create_parents(dest: &Path, mode: Option<&Chmod>) -> Result<()> {
+    let Some(parent) = dest.parent() else {
+        return Ok(());
     };
-    let dest = PathBuf::from(dest);
+    // create_dir_all() would fail with a bare ENOTDIR.
+    if let Some(existing) = parent.ancestors().find(|p| p.exists()) {
+        if !existing.is_dir() {
+            return Err(XcpError::InvalidDestination("A parent of the destination exists and is not a directory.").into());
+        }
+    }
+    if !parent.as_os_str().is_empty() && !parent.exists() {
+        info!("Creating parent directories {:?}", parent);
+        create_dirs(parent, mode)?;
+    }
+    Ok(())
+}
 
-    let sources = expand_sources(source_patterns, &opts)?;
+// Check the sources and destination before anything is created, so
+// that invalid arguments fail cleanly.
+fn preflight(opts: &Opts, sources: &[PathBuf], dest: &Path) -> Result<()> {
+    let dest_is_dir = opts.dest_is_dir();
     if sources.is_empty() {
         return Err(XcpError::InvalidSource("No source files found.").into());
-    } else if !dest.is_dir() {
+    } else if sources.len() > 1 && opts.no_target_directory {
+        return Err(XcpError::InvalidArguments("--no-target-directory cannot be used with multiple sources.".to_string()).into());
+    } else if !dest.is_dir() && !dest_is_dir {
         if sources.len() == 1 && sources[0].is_dir() && dest.exists() {
             return Err(XcpError::InvalidDestination("Cannot copy a directory to a file.").into());
-        } else if sources.len() > 1 {
+        } else if sources.len() > 1 && dest.exists() {
             return Err(XcpError::InvalidDestination("Multiple sources and destination is not a directory.").into());
+        } else if sources.len() > 1 {
+            return Err(XcpError::InvalidDestination("Multiple sources and destination does not exist; add a trailing '/' to create it.").into());
         }
     }
 
     // Sanity-check all sources up-front
-    for source in &sources {
+    for source in sources {
         info!("Copying source {:?} to {:?}", source, dest);
         if !source.exists() {
             return Err(XcpError::InvalidSource("Source does not exist.").into());
         }
 
-        if source.is_dir() && !opts.recursive {
+        if source.is_dir() && !opts.recursive && !opts.no_recursive {
             return Err(XcpError::InvalidSource("Source is directory and --recursive not specified.").into());
         }
-        if source == &dest {
+        if source == dest && !source.is_dir() {
+            return Err(XcpError::SameFile(source.clone(), dest.to_path_buf()).into());
+        }
+        if source == dest {
             return Err(XcpError::InvalidSource("Cannot copy a directory into itself").into());
         }
+        let relative = opts.relative || opts.from_file.is_some();
+        if relative && source.components().any(|c| c == Component::ParentDir) {
+            return Err(XcpError::InvalidSource("Relative sources cannot contain '..'.").into());
+        }
 
         let sourcedir = source
             .components()
-            .last()
+            .next_back()
             .ok_or(XcpError::InvalidSource("Failed to find source directory name."))?;
 
-        let target_base = if dest.exists() && dest.is_dir() && !opts.no_target_directory {
+        let contents = opts.copy_contents && source.as_os_str().as_bytes().ends_with(b"/") && source.is_dir();
+        let target_base = if relative {
+            dest.join(source.strip_prefix("/").unwrap_or(source))
+        } else if (dest_is_dir || dest.is_dir()) && !opts.no_target_directory && !contents {
             dest.join(sourcedir)
         } else {
             dest.to_path_buf()
@@ -139,46 +300,197 @@ fn main() -> Result<()> {
         if source == &target_base {
             return Err(XcpError::InvalidSource("Source is same as destination").into());
         }
+        // A hardlink to the source, or a symlink to it, would be
+        // truncated by the copy; this is also caught per-file, but
+        // only once copying has started.
+        if source.is_file() && is_same_file(source, &target_base).unwrap_or(false) {
+            return Err(XcpError::SameFile(source.clone(), target_base).into());
+        }
+    }
+    if opts.offset.is_some() || opts.length.is_some() {
+        check_range(opts, sources)?;
     }
 
+    // The parents of a copied directory are created with it, but a
+    // file's are only created with --parents.
+    if !dest_is_dir && !opts.parents && !dest.exists() && !sources[0].is_dir() {
+        let parent = dest.parent().filter(|p| !p.as_os_str().is_empty());
+        if parent.is_some_and(|p| !p.is_dir()) {
+            return Err(XcpError::InvalidDestination("The destination's parent directory does not exist; use --parents to create it.").into());
+        }
+    }
+    Ok(())
+}
 
-    // ========== Start copy ============
+fn run() -> Result<()> {
+    let opts = Opts::from_args()?;
+    logging::init(opts.log_level())?;
+    opts_check(&opts)?;
 
-    let config = Arc::new(Config::from(&opts));
-    let driver = load_driver(opts.driver, &config)?;
+    let (dest, source_patterns) = opts.dest_and_sources()?;
+    if opts.server {
+        return remote::serve(&opts, dest);
+    }
+    if opts.from_file.is_some() && !source_patterns.is_empty() {
+        return Err(XcpError::InvalidArguments("Sources cannot be given with --from-file.".to_string()).into());
+    }
 
-    let updater = ChannelUpdater::new(&config);
-    let stat_rx = updater.rx_channel();
-    let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
+    if is_stdio(dest) || source_patterns.iter().any(|s| is_stdio(s)) {
+        if source_patterns.len() != 1 {
+            return Err(XcpError::InvalidArguments("Only a single source is supported when streaming via '-'.".to_string()).into());
+        }
+        check_unsupported(&opts, "when streaming via '-'", &[])?;
+        return copy_stream(&opts, &source_patterns[0], dest);
+    }
 
-    let handle = thread::spawn(move || -> Result<()> {
-        driver.copy(sources, &dest, stats)
-    });
+    if let Some(remote) = remote::parse_remote(dest) {
+        check_unsupported(&opts, "with a remote destination", &[
+            (opts.types.is_some(), "--type is"),
+            (opts.dirs_only, "--dirs-only is"),
+            (!opts.filters.is_empty(), "--exclude and --include are"),
+            (opts.gitignore || !opts.ignore_file.is_empty(), "--gitignore and --ignore-file are"),
+            (opts.update || opts.ignore_existing, "--update and --ignore-existing are"),
+            (opts.checksum || opts.quick_check || opts.size_only, "--checksum, --quick-check and --size-only are"),
+            (opts.min_size.is_some() || opts.max_size.is_some(), "--min-size and --max-size are"),
+            (opts.max_depth.is_some(), "--max-depth is"),
+            (opts.one_file_system, "--one-file-system is"),
+            (opts.copy_contents, "--copy-contents is"),
+            (opts.rename.is_some(), "--rename is"),
+            (opts.backup != Backup::None, "--backup is"),
+            (opts.verify != Verify::None, "--verify is"),
+        ])?;
+        let sources = match &opts.from_file {
+            Some(list) => read_source_list(list, opts.from0)?,
+            None => expand_sources(source_patterns, &opts)?,
+        };
+        return remote::push(&opts, &sources, &remote);
+    }
 
+    let dest_is_dir = opts.dest_is_dir();
+    if dest_is_dir && !Path::new(dest).is_dir() && Path::new(dest.trim_end_matches('/')).exists() {
+        return Err(XcpError::InvalidDestination("Destination has a trailing '/' but is not a directory.").into());
+    }
+    let dest = PathBuf::from(dest);
+    if opts.delete && dest.exists() && !dest.is_dir() {
+        return Err(XcpError::InvalidDestination("--delete requires the destination to be a directory.").into());
+    }
+
+    let sources = match &opts.from_file {
+        Some(list) => read_source_list(list, opts.from0)?,
+        None => expand_sources(source_patterns, &opts)?,
+    };
+    preflight(&opts, &sources, &dest)?;
 
-    // ========== Collect output and display ============
+    // ========== Start copy ============
+
+    for dest in iter::once(&dest).chain(&opts.tee) {
+        if opts.parents && !opts.dry_run {
+            create_parents(dest, opts.dir_mode.as_ref())?;
+        }
+        if dest_is_dir && !dest.exists() && !opts.dry_run {
+            info!("Creating destination directory {:?}", dest);
+            create_dirs(dest, opts.dir_mode.as_ref())?;
+        }
+    }
 
+    let checkpoint = match &opts.checkpoint {
+        Some(path) if opts.dry_run => Some(Checkpoint::load(path)?),
+        Some(path) => Some(Checkpoint::open(path)?),
+        None => None,
+    };
+    let config = Arc::new(Config {
+        interrupt: Some(interrupt::install()?),
+        checkpoint: checkpoint.map(Arc::new),
+        ..Config::from(&opts)
+    });
+    let mut manifest = match &opts.manifest {
+        Some(path) if !opts.dry_run => Some(Manifest::create(path, opts.manifest_format)?),
+        _ => None,
+    };
+    let mut manifest_err = None;
     let pb = progress::create_bar(&opts, 0)?;
 
-    // Gather the results as we go; our end of the channel has been
-    // moved to the driver call and will end when drained.
-    for stat in stat_rx {
+    // Failures are only collected when continuing past them;
+    // otherwise the first is returned by copy().
+    let mut failed = Vec::new();
+    let mut planned_total = 0;
+    let mut copied = 0;
+    let mut no_reflink = 0;
+    let mut reporter = Reporter::with_interval(pb.as_ref(), Duration::from_millis(opts.progress_interval));
+    // The totals so far, for a copy that doesn't return them.
+    let start = Instant::now();
+    let mut totals = CopyStats::default();
+    let result = copy(sources, &dest, opts.driver, &config, &mut |stat: &StatusUpdate| {
         match stat {
-            StatusUpdate::Copied(v) => pb.inc(v),
-            StatusUpdate::Size(v) => pb.inc_size(v),
+            StatusUpdate::Size(v) => planned_total += v,
+            StatusUpdate::Holes(v) => planned_total -= v,
+            StatusUpdate::Copied(v) => copied += v,
+            StatusUpdate::Completed(file) => {
+                if file.no_reflink.is_some() {
+                    no_reflink += 1;
+                }
+                if let Some(m) = manifest.as_mut() {
+                    if let Err(e) = m.record(file) {
+                        error!("Failed to write to the manifest: {}", e);
+                        manifest_err.get_or_insert(e);
+                        manifest = None;
+                    }
+                }
+            }
+            StatusUpdate::Planned(PlannedOp::Delete, _, to) => {
+                println!("{} {:?}", PlannedOp::Delete, to);
+            }
+            StatusUpdate::Planned(op, from, to) => {
+                println!("{} {:?} -> {:?}", op, from, to);
+            }
             StatusUpdate::Error(e) => {
-                // FIXME: Optional continue?
                 error!("Received error: {}", e);
-                return Err(e.into());
+                if opts.continue_on_error {
+                    failed.push(match e {
+                        XcpError::CopyFailed(path, ..) | XcpError::SourceChanged(path) | XcpError::TimedOut(path) => format!("{:?}", path),
+                        other => other.to_string(),
+                    });
+                }
             }
+            _ => {}
+        }
+        totals.update(stat);
+        reporter.update(stat);
+    });
+    reporter.flush();
+    totals.elapsed = start.elapsed();
+    if let Err(e) = &result {
+        if matches!(e.downcast_ref::<XcpError>(), Some(XcpError::Interrupted)) {
+            pb.end(&totals);
+            eprintln!("Interrupted after copying {} of {}", HumanBytes(copied), HumanBytes(planned_total));
+            return result.map(|_| ());
         }
     }
+    // Failures when continuing past them are reported below.
+    let stats = if failed.is_empty() { result? } else { totals };
 
-    handle.join()
-        .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
+    pb.end(&stats);
 
-    info!("Copy complete");
-    pb.end();
+    if opts.warn_no_reflink && no_reflink > 0 {
+        warn!("{} files copied without reflink", no_reflink);
+    }
+    if let Some(e) = manifest_err {
+        return Err(e);
+    }
+
+    if opts.dry_run {
+        println!("Would copy {}", HumanBytes(planned_total));
+    }
+
+    if !failed.is_empty() {
+        error!("{} files failed to copy:", failed.len());
+        for e in &failed {
+            error!("  {}", e);
+        }
+        return Err(XcpError::CopyError(format!("{} files failed to copy", failed.len())).into());
+    }
+    info!("Copy complete: {} files, {} directories, {} symlinks, {} hardlinks and {} special files",
+          stats.files, stats.dirs, stats.symlinks, stats.hardlinks, stats.specials);
 
     Ok(())
 }
@@ -0,0 +1,95 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Manifests of the copied files; see `--manifest`.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use libxcp::errors::Result;
+use libxcp::feedback::CopiedFile;
+
+use crate::options::ManifestFormat;
+use crate::progress::json_str;
+
+pub struct Manifest {
+    file: File,
+    format: ManifestFormat,
+}
+
+impl Manifest {
+    pub fn create(path: &Path, format: ManifestFormat) -> Result<Self> {
+        let mut file = File::create(path)?;
+        if format == ManifestFormat::Csv {
+            file.write_all(b"source,dest,bytes,checksum\n")?;
+        }
+        Ok(Self { file, format })
+    }
+
+    /// Append a record of a copied file. Records are unbuffered, so
+    /// each is complete on disk once written.
+    pub fn record(&mut self, copied: &CopiedFile) -> Result<()> {
+        let record = match self.format {
+            ManifestFormat::Text => {
+                let mut line = format!("{:?} -> {:?} {}", copied.from, copied.to, copied.bytes);
+                if let Some(checksum) = &copied.checksum {
+                    line.push(' ');
+                    line.push_str(checksum);
+                }
+                line
+            }
+            ManifestFormat::Json => format!(
+                r#"{{"source":{},"dest":{},"bytes":{},"checksum":{}}}"#,
+                json_str(&copied.from.to_string_lossy()),
+                json_str(&copied.to.to_string_lossy()),
+                copied.bytes,
+                copied.checksum.as_deref().map_or("null".to_string(), json_str),
+            ),
+            ManifestFormat::Csv => format!(
+                "{},{},{},{}",
+                csv_field(&copied.from.to_string_lossy()),
+                csv_field(&copied.to.to_string_lossy()),
+                copied.bytes,
+                copied.checksum.as_deref().unwrap_or(""),
+            ),
+        };
+        self.file.write_all(format!("{}\n", record).as_bytes())?;
+        Ok(())
+    }
+}
+
+// Quote a CSV field if necessary, as in RFC 4180.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!("plain.txt", csv_field("plain.txt"));
+        assert_eq!("\"a,b\"", csv_field("a,b"));
+        assert_eq!("\"say \"\"hi\"\"\"", csv_field("say \"hi\""));
+        assert_eq!("\"two\nlines\"", csv_field("two\nlines"));
+    }
+}
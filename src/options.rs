@@ -14,14 +14,121 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use clap::{ArgAction, Parser};
+use std::path::PathBuf;
+use std::process;
+use std::result;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use libxcp::config::{Backup, Config, Reflink};
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser};
+
+use libxcp::config::{Atomic, Backup, Chmod, Config, CopyMethod, EntryTypes, Filter, QuickCheck, Reflink, Rename, Sparse, Verify};
+use libxcp::dedup::Dedup;
 use log::LevelFilter;
 use unbytify::unbytify;
 
 use libxcp::drivers::Drivers;
-use libxcp::errors::Result;
+use libxcp::errors::{Result, XcpError};
+
+use crate::EXIT_USAGE;
+
+/// The set of file attributes to preserve, as given to
+/// `--preserve`. This is additive to the default behaviour.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Preserve {
+    pub mode: bool,
+    pub ownership: bool,
+    pub timestamps: bool,
+    pub xattr: bool,
+    pub links: bool,
+    pub acl: bool,
+    pub flags: bool,
+}
+
+impl FromStr for Preserve {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut preserve = Preserve::default();
+        for attr in s.split(',') {
+            match attr.trim().to_lowercase().as_str() {
+                "mode" => preserve.mode = true,
+                "ownership" => preserve.ownership = true,
+                "timestamps" => preserve.timestamps = true,
+                "xattr" => preserve.xattr = true,
+                "links" => preserve.links = true,
+                "acl" => preserve.acl = true,
+                "flags" => preserve.flags = true,
+                "all" => {
+                    preserve.mode = true;
+                    preserve.ownership = true;
+                    preserve.timestamps = true;
+                    preserve.xattr = true;
+                    preserve.links = true;
+                    preserve.acl = true;
+                    preserve.flags = true;
+                }
+                _ => return Err(XcpError::InvalidArguments(format!("Unexpected value for 'preserve': {}", attr))),
+            }
+        }
+        Ok(preserve)
+    }
+}
+
+/// How to report progress, as given to `--progress`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ProgressMode {
+    /// Draw a progress bar if stderr is a terminal.
+    #[default]
+    Bar,
+    /// Draw a progress bar even if stderr is not a terminal, e.g. for
+    /// tools that capture and render it.
+    Always,
+    /// Emit newline-delimited JSON records on stderr.
+    Json,
+    /// No progress output.
+    None,
+}
+
+impl FromStr for ProgressMode {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bar" | "auto" => Ok(ProgressMode::Bar),
+            "always" => Ok(ProgressMode::Always),
+            "json" => Ok(ProgressMode::Json),
+            "none" | "off" | "never" => Ok(ProgressMode::None),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'progress': {}", s))),
+        }
+    }
+}
+
+/// The format of the '--manifest' file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ManifestFormat {
+    /// One line per file.
+    #[default]
+    Text,
+    /// Newline-delimited JSON records.
+    Json,
+    /// Comma-separated values, with a header.
+    Csv,
+}
+
+impl FromStr for ManifestFormat {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(ManifestFormat::Text),
+            "json" => Ok(ManifestFormat::Json),
+            "csv" => Ok(ManifestFormat::Csv),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'manifest-format': {}", s))),
+        }
+    }
+}
 
 #[derive(Clone, Debug, Parser)]
 #[command(
@@ -36,10 +143,52 @@ pub struct Opts {
     #[arg(short, long, action = ArgAction::Count)]
     pub verbose: u8,
 
+    /// Suppress non-error output.
+    ///
+    /// Disables the progress bar and only logs errors, regardless of
+    /// whether stderr is a terminal.
+    #[arg(short, long, conflicts_with_all = ["verbose", "progress"])]
+    pub quiet: bool,
+
     /// Copy directories recursively
     #[arg(short, long)]
     pub recursive: bool,
 
+    /// Copy directories without recursing into them
+    ///
+    /// Each source directory is created with copies of its immediate
+    /// files, symlinks and special files. Its subdirectories are
+    /// created empty, and nothing below them is read; this is useful
+    /// for staging a directory skeleton. This is the same walk as
+    /// '--recursive --max-depth 1', but is a separate mode: it can't
+    /// be combined with either, or with options that need
+    /// '--recursive' such as '--delete'.
+    #[arg(short = 'd', long, visible_alias = "no-directory-recursion",
+          conflicts_with_all = ["recursive", "max_depth"])]
+    pub no_recursive: bool,
+
+    /// Create only the directories of the source tree
+    ///
+    /// Directories are created with their permissions and ownership
+    /// preserved as usual, but no files, symlinks or special files
+    /// are copied. Unlike '--dry-run' the destination is modified;
+    /// this is useful for creating a tree's skeleton before copying
+    /// its data in separate jobs. The progress bar counts directories
+    /// rather than bytes. This is the same as '--type d', so the two
+    /// can't be combined.
+    #[arg(long, conflicts_with_all = ["offset", "length", "append", "dedup", "remove_source_files", "types"])]
+    pub dirs_only: bool,
+
+    /// Copy only entries of the given types
+    ///
+    /// Takes a comma-separated set of 'f' (regular files), 'd'
+    /// (directories), 'l' (symlinks) and 's' (special files), e.g.
+    /// '--type f,l'. Directories are descended into whether or not
+    /// they are selected; without 'd' only the parents of copied
+    /// entries are created, without the source's attributes.
+    #[arg(long = "type", value_name = "TYPES")]
+    pub types: Option<EntryTypes>,
+
     /// Dereference symlinks in source
     ///
     /// Follow symlinks, possibly recursively, when copying source
@@ -47,12 +196,74 @@ pub struct Opts {
     #[arg(short = 'L', long)]
     pub dereference: bool,
 
+    /// Dereference symlinks given as sources
+    ///
+    /// Follow symlinks given on the command line, but copy any
+    /// symlinks found within the source tree as symlinks.
+    #[arg(short = 'H', long)]
+    pub dereference_command_line: bool,
+
+    /// Copy the contents of FIFOs and devices given as sources
+    ///
+    /// Read FIFOs and character devices given on the command line
+    /// until they are exhausted, and write the data to a regular
+    /// file, rather than recreating the special file. Similar to
+    /// rsync's '--copy-devices'.
+    #[arg(long)]
+    pub read_special: bool,
+
+    /// Stream data between FIFOs, rather than recreating them empty
+    ///
+    /// Each source FIFO is read until its writer closes it, and the
+    /// data written to the FIFO at the destination, which is created
+    /// if necessary; an existing FIFO is written to rather than
+    /// replaced. The copy waits for both ends to be opened, so see
+    /// also '--timeout'.
+    #[arg(long, conflicts_with = "read_special")]
+    pub pipe_through: bool,
+
+    /// Stay on the source filesystem
+    ///
+    /// Directories that are mount points for other filesystems are
+    /// created, but their contents are not copied.
+    #[arg(short = 'x', long)]
+    pub one_file_system: bool,
+
+    /// Descend at most N directory levels below each source
+    ///
+    /// The source directory itself is level 0, so '--max-depth 1'
+    /// copies only its immediate children. Directories at the limit
+    /// are created, but their contents are not copied.
+    #[arg(long, value_name = "N")]
+    pub max_depth: Option<usize>,
+
     /// Number of parallel workers.
     ///
-    /// Default is 4; if the value is negative or 0 it uses the number
-    /// of logical CPUs.
-    #[arg(short, long, default_value = "4")]
-    pub workers: usize,
+    /// Defaults to the number of logical CPUs. More workers can help
+    /// hide the latency of network filesystems, while fewer may be
+    /// faster on local disks. Each worker holds its current file
+    /// open; see '--max-open-files'.
+    #[arg(short, long, value_name = "N")]
+    pub workers: Option<usize>,
+
+    /// Maximum number of files to hold open at once.
+    ///
+    /// Limits the number of files queued for copying by the
+    /// 'parblock' driver. Each file uses 2 file descriptors. By
+    /// default this is derived from the open-file limit (`ulimit
+    /// -n`). The files being copied by the workers count towards
+    /// this limit, so it should be larger than '--workers'.
+    #[arg(long, value_name = "N")]
+    pub max_open_files: Option<usize>,
+
+    /// Retry transient block-copy errors.
+    ///
+    /// Retry a block up to N times if copying it fails with a
+    /// transient error (EINTR, EAGAIN or EIO), with exponential
+    /// backoff of up to 5 seconds. Other errors fail immediately. Only supported by the
+    /// 'parblock' driver.
+    #[arg(long, value_name = "N", default_value = "0")]
+    pub retries: u32,
 
     /// Block size for operations.
     ///
@@ -61,10 +272,143 @@ pub struct Opts {
     #[arg(long,  default_value = "1MB", value_parser=unbytify)]
     pub block_size: u64,
 
+    /// Limit the copy rate to RATE bytes per second.
+    ///
+    /// Accepts the same size modifiers as '--block-size', e.g.
+    /// "10M". The limit applies to the combined rate of all workers.
+    /// Each block of '--block-size' waits for its share of the limit
+    /// before it is copied, so smaller blocks give smoother rates.
+    #[arg(long, value_name = "RATE", value_parser=unbytify)]
+    pub bwlimit: Option<u64>,
+
+    /// Fail any file that takes longer than SECONDS to copy.
+    ///
+    /// For sources on unreliable network mounts. The deadline is
+    /// checked before each block of '--block-size', so smaller blocks
+    /// make it more precise; a read or write that is already blocked
+    /// can't be interrupted. Timed out files are errors, so see also
+    /// '--continue-on-error'.
+    #[arg(long, value_name = "SECONDS")]
+    pub timeout: Option<u64>,
+
+    /// Only copy the source file from byte offset N.
+    ///
+    /// The data is written at the same offset of the destination,
+    /// which is updated in place rather than replaced; this can be
+    /// used to resume an interrupted copy. Only a single source file
+    /// may be given. Accepts the same size modifiers as
+    /// '--block-size'.
+    #[arg(long, value_name = "N", value_parser=unbytify,
+          conflicts_with_all = ["recursive", "atomic", "backup", "reflink", "verify", "remove_source_files"])]
+    pub offset: Option<u64>,
+
+    /// Only copy M bytes of the source file.
+    ///
+    /// See '--offset'; the range must lie within the source file.
+    #[arg(long, value_name = "M", value_parser=unbytify,
+          conflicts_with_all = ["recursive", "atomic", "backup", "reflink", "verify", "remove_source_files"])]
+    pub length: Option<u64>,
+
+    /// Resume an interrupted copy of a single file.
+    ///
+    /// If the destination is shorter than the source, or has holes
+    /// left by an interrupted copy, and the data it has matches the
+    /// start of the source, only the rest of the source is copied.
+    /// Otherwise the file is copied in full.
+    #[arg(long, visible_alias = "partial",
+          conflicts_with_all = ["recursive", "offset", "length", "atomic", "backup", "reflink", "tee"])]
+    pub append: bool,
+
+    /// Pick the block size for each file automatically.
+    ///
+    /// Files up to 1MB are copied as a single block; larger files are
+    /// split into around 64 blocks, rounded up to a power of two
+    /// between the filesystem's preferred I/O size (or 1MB) and 64MB.
+    #[arg(long, conflicts_with = "block_size")]
+    pub auto_block_size: bool,
+
     /// Do not overwrite an existing file
     #[arg(short, long)]
     pub no_clobber: bool,
 
+    /// Only copy files that are newer than the destination.
+    ///
+    /// Skip a file if the destination exists and has the same or a
+    /// newer modification time. As timestamps are copied by default,
+    /// repeated runs only copy files that have since changed. Note
+    /// that some filesystems store timestamps with coarse precision
+    /// (e.g. 2 seconds on FAT), so a file modified shortly after
+    /// being copied to these may be skipped.
+    #[arg(short, long)]
+    pub update: bool,
+
+    /// Only copy files that don't exist at the destination.
+    ///
+    /// Existing files are silently skipped, whatever their contents
+    /// or age; new files within existing directories are still
+    /// copied. Unlike '--no-clobber' this is not an error.
+    #[arg(long)]
+    pub ignore_existing: bool,
+
+    /// Skip files that have the same size and modification time at
+    /// the destination.
+    ///
+    /// This is a fast check for repeated syncs of the same tree; the
+    /// file contents are not compared. See '--update' for the
+    /// caveats of timestamp comparisons.
+    #[arg(long)]
+    pub quick_check: bool,
+
+    /// Skip files that have the same size at the destination.
+    ///
+    /// Like '--quick-check', but ignores the modification time.
+    #[arg(long, conflicts_with = "quick_check")]
+    pub size_only: bool,
+
+    /// Skip files that have the same contents at the destination.
+    ///
+    /// Both files are read in full and their checksums compared,
+    /// using the '--verify' algorithm if one is given. This trades
+    /// I/O for accuracy: unlike '--quick-check' and '--update' it
+    /// notices changes that kept the size and timestamps, such as
+    /// files restored from a backup. It overrides '--update'.
+    #[arg(short = 'c', long, conflicts_with_all = ["quick_check", "size_only"])]
+    pub checksum: bool,
+
+    /// Only copy files of at least SIZE bytes.
+    ///
+    /// Smaller files are skipped; directories are still created and
+    /// descended into. Accepts the same size modifiers as
+    /// '--block-size'.
+    #[arg(long, value_name = "SIZE", value_parser=unbytify)]
+    pub min_size: Option<u64>,
+
+    /// Only copy files of at most SIZE bytes.
+    ///
+    /// See '--min-size'.
+    #[arg(long, value_name = "SIZE", value_parser=unbytify)]
+    pub max_size: Option<u64>,
+
+    /// Stop once the files queued total SIZE bytes.
+    ///
+    /// A safety stop for copies that are larger than expected. Files
+    /// that would take the total over SIZE, and all files found after
+    /// them, are not copied; those already queued or in progress are
+    /// completed, and xcp then exits with an error reporting what was
+    /// skipped. Accepts the same size modifiers as '--block-size'.
+    #[arg(long, value_name = "SIZE", value_parser=unbytify)]
+    pub max_total_size: Option<u64>,
+
+    /// Delete destination files that are not in the source.
+    ///
+    /// Within each copied directory, remove any files and
+    /// directories that do not exist in its source, making the
+    /// destination a mirror of it. Files excluded by '--exclude' or
+    /// the ignore files are kept. Requires '--recursive'. Use
+    /// '--dry-run' to list the files that would be deleted.
+    #[arg(long, requires = "recursive")]
+    pub delete: bool,
+
     /// Force (compatability only)
     ///
     /// Overwrite files; this is the default behaviour, this flag is
@@ -82,6 +426,50 @@ pub struct Opts {
     #[arg(long)]
     pub gitignore: bool,
 
+    /// Ignore files matching the rules in FILE.
+    ///
+    /// FILE uses gitignore syntax, with patterns relative to each
+    /// source directory. Can be given multiple times. Rules are
+    /// applied in this order of precedence: '--exclude' and
+    /// '--include' patterns; '.xcpignore' files within the source,
+    /// the deepest first; ignore files given here, the last first;
+    /// and finally '.gitignore' if '--gitignore' is set.
+    #[arg(long, value_name = "FILE")]
+    pub ignore_file: Vec<PathBuf>,
+
+    /// Don't read any ignore files.
+    ///
+    /// Copy everything, including files '.gitignore' or '.xcpignore'
+    /// would exclude. '--exclude' and '--include' patterns are still
+    /// honoured.
+    #[arg(long, conflicts_with_all = ["gitignore", "ignore_file"])]
+    pub no_gitignore: bool,
+
+    /// Exclude files matching PATTERN.
+    ///
+    /// Patterns use gitignore syntax and are matched relative to each
+    /// source directory; a pattern without a '/' matches a file name
+    /// at any depth. Can be given multiple times, and combined with
+    /// '--include'. Where several patterns match a file the last one
+    /// wins (note this differs from rsync, where the first match
+    /// wins). These override any ignore file rules.
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+
+    /// Include files matching PATTERN.
+    ///
+    /// Re-includes files excluded by an earlier '--exclude' or by an
+    /// ignore file. Note that files within an excluded directory
+    /// cannot be re-included, as the directory is not walked. See
+    /// '--exclude' for the pattern syntax.
+    #[arg(long, value_name = "PATTERN")]
+    pub include: Vec<String>,
+
+    /// The combined '--exclude' and '--include' patterns, in the order
+    /// given.
+    #[arg(skip)]
+    pub filters: Vec<Filter>,
+
     /// Expand file patterns.
     ///
     /// Glob (expand) filename patterns natively (note; the shell may still do its own expansion first)
@@ -92,14 +480,49 @@ pub struct Opts {
     #[arg(long)]
     pub no_progress: bool,
 
+    /// Progress reporting.
+    ///
+    /// 'bar' or 'auto' (the default) draws a progress bar when stderr
+    /// is a terminal; 'always' draws it regardless, e.g. for tools
+    /// that capture and render it. 'json' emits newline-delimited JSON
+    /// records on stderr instead, for consumption by other programs;
+    /// see the README for the format. 'none' or 'never' is the
+    /// equivalent of '--no-progress'.
+    #[arg(long, value_name = "MODE", default_value = "bar")]
+    pub progress: ProgressMode,
+
+    /// Update the progress at most once every MS milliseconds.
+    ///
+    /// The bytes copied in between are added together, which saves
+    /// redrawing for every block when copying many small files. 0
+    /// updates for every block.
+    #[arg(long, value_name = "MS", default_value = "100")]
+    pub progress_interval: u64,
+
     /// Do not copy the file permissions.
     #[arg(long)]
     pub no_perms: bool,
 
+    /// Clear the setuid, setgid and sticky bits of copied files.
+    ///
+    /// The file mode is copied in full by default, as with 'cp -p';
+    /// use this when copying to a less trusted location. Plain 'cp'
+    /// drops these bits.
+    #[arg(long)]
+    pub no_preserve_special_bits: bool,
+
     /// Do not copy the file timestamps.
     #[arg(long)]
     pub no_timestamps: bool,
 
+    /// Do not copy extended attributes.
+    ///
+    /// Note that this includes ACLs, security labels and file
+    /// capabilities. Xattrs are also skipped by '--no-perms' unless
+    /// '--preserve=xattr' is given.
+    #[arg(long)]
+    pub no_xattr: bool,
+
     /// Copy ownership.
     ///
     /// Whether to copy ownship (user/group).  This option requires
@@ -109,12 +532,38 @@ pub struct Opts {
     #[arg(short, long)]
     pub ownership: bool,
 
+    /// Copy ownership as numeric ids (compatibility only)
+    ///
+    /// Ownership is always copied as the raw uid and gid, without
+    /// looking up user or group names, so ids are kept as-is between
+    /// systems; this flag is for compatibility with `rsync` only.
+    #[arg(long)]
+    pub numeric_ids: bool,
+
+    /// Preserve the specified attributes.
+    ///
+    /// A comma-separated list of attributes to preserve; currently
+    /// 'mode', 'ownership', 'timestamps', 'xattr', 'links', 'acl',
+    /// 'flags' or 'all'. Mode, timestamps and xattrs are preserved by
+    /// default, so this is mostly useful for 'ownership', which is the
+    /// equivalent of '--ownership'; 'links', which recreates hardlinks
+    /// within the copied tree rather than copying each path
+    /// separately; 'acl', which copies POSIX ACLs, including the
+    /// default ACLs of directories, even when xattrs are not copied;
+    /// and 'flags', which copies inode flags such as immutable and
+    /// append-only (Linux only; see chattr(1)).
+    #[arg(long, value_name = "ATTRS")]
+    pub preserve: Option<Preserve>,
+
     /// Driver to use, defaults to 'file-parallel'.
     ///
-    /// Currently there are 2; the default "parfile", which
-    /// parallelises copies across workers at the file level, and an
+    /// Currently there are 3; the default "parfile", which
+    /// parallelises copies across workers at the file level, an
     /// experimental "parblock" driver, which parellelises at the
-    /// block level. See also '--block-size'.
+    /// block level, and "sequential", which copies one file at a time
+    /// on a single thread. See also '--block-size'. "auto" samples the
+    /// source files and uses "parblock" if they are large relative to
+    /// the block size and number of workers, otherwise "parfile".
     #[arg(long, default_value = "parfile")]
     pub driver: Drivers,
 
@@ -126,14 +575,143 @@ pub struct Opts {
     #[arg(short = 'T', long)]
     pub no_target_directory: bool,
 
-    /// Copy into a subdirectory of the target
-    #[arg(long)]
+    /// Copy all sources into DIR.
+    ///
+    /// Analogous to cp's target-directory. DIR is always treated as a
+    /// directory, and is created if necessary; all the positional
+    /// arguments are then sources.
+    #[arg(short = 't', long, value_name = "DIR", conflicts_with = "no_target_directory")]
     pub target_directory: Option<String>,
 
+    /// Create missing parent directories of the destination.
+    ///
+    /// As with 'install -D', the directories leading to the
+    /// destination are created before copying. The source paths are
+    /// not reproduced under the destination.
+    #[arg(short = 'D', long)]
+    pub parents: bool,
+
+    /// Copy the contents of a source directory with a trailing '/'.
+    ///
+    /// As with rsync, 'xcp -r --copy-contents src/ dest' copies the
+    /// entries of 'src' into 'dest', rather than creating
+    /// 'dest/src'. Sources without a trailing '/' are copied as
+    /// usual.
+    #[arg(long, conflicts_with = "from_file")]
+    pub copy_contents: bool,
+
+    /// Copy each source to its full path under the destination.
+    ///
+    /// As with rsync's '-R', 'xcp -R /var/log/app/file.log /backup/'
+    /// creates '/backup/var/log/app/file.log', along with any missing
+    /// directories in between. The leading '/' of an absolute source
+    /// is dropped, and sources may not contain '..'. The destination
+    /// is always a directory. Implied by '--from-file'.
+    #[arg(short = 'R', long, conflicts_with_all = ["copy_contents", "no_target_directory"])]
+    pub relative: bool,
+
+    /// Also copy the sources to DEST, reading them only once.
+    ///
+    /// DEST is treated as the destination is, and each file is written
+    /// to both from the same reads; e.g. 'xcp -r src /mnt/a --tee
+    /// /mnt/b' creates '/mnt/a/src' and '/mnt/b/src'. Can be given
+    /// multiple times. Tee copies are never reflinked or sparse, and
+    /// hardlinks are copied as separate files. With
+    /// '--continue-on-error' a failure writing to one destination
+    /// doesn't stop the copies to the others.
+    #[arg(long, value_name = "DEST",
+          conflicts_with_all = ["offset", "length", "delete", "remove_source_files", "checkpoint", "direct_io"])]
+    pub tee: Vec<PathBuf>,
+
+    /// Read the sources from FILE, or stdin if FILE is '-'.
+    ///
+    /// FILE holds one path per line, or NUL-separated paths with
+    /// '--from0', such as the output of 'find' or 'git ls-files'. Each
+    /// path is copied to the same relative path under the destination,
+    /// which must be a directory; as with rsync's '--files-from',
+    /// 'a/b/c.txt' is copied to 'DEST/a/b/c.txt'. No other sources may
+    /// be given.
+    #[arg(long, value_name = "FILE")]
+    pub from_file: Option<PathBuf>,
+
+    /// The paths in '--from-file' are separated by NULs rather than
+    /// newlines.
+    #[arg(long, requires = "from_file")]
+    pub from0: bool,
+
+    /// Write a manifest of the copied files to FILE.
+    ///
+    /// Each successfully copied file is recorded with its source,
+    /// destination and size, and its checksum if '--verify' is set.
+    /// Records are written as each file completes, so an interrupted
+    /// copy leaves a manifest of the files copied so far.
+    #[arg(long, value_name = "FILE")]
+    pub manifest: Option<PathBuf>,
+
+    /// The format of the '--manifest' file.
+    ///
+    /// 'text' (the default) writes a line per file of the quoted
+    /// source and destination, the size, and any checksum. 'json'
+    /// writes newline-delimited JSON records, and 'csv'
+    /// comma-separated values with a header line.
+    #[arg(long, value_name = "FORMAT", default_value = "text", requires = "manifest")]
+    pub manifest_format: ManifestFormat,
+
+    /// Record the completed files in FILE, to resume an interrupted copy.
+    ///
+    /// Each source file is added once it has been fully copied, along
+    /// with its size and modification time. A later copy of the same
+    /// sources with the same FILE skips the files it holds, unless
+    /// they have changed since.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["offset", "length"])]
+    pub checkpoint: Option<PathBuf>,
+
+    /// Link files with identical contents to the first copy of them.
+    ///
+    /// Each source file is hashed with SHA-256 before it is copied; a
+    /// file with the same size and hash as one already copied in this
+    /// run is reflinked to it where possible, and otherwise
+    /// hardlinked. Hardlinked copies share the permissions and
+    /// timestamps of the first. Hashing reads every source file an
+    /// extra time, so this costs CPU and I/O even if there are no
+    /// duplicates.
+    #[arg(long, conflicts_with_all = ["tee", "offset", "length", "append"])]
+    pub dedup: bool,
+
     /// Sync each file to disk after writing.
+    ///
+    /// The target directories are also synced once the copy is
+    /// complete, so that new entries are durable. This can be
+    /// significantly slower.
     #[arg(long)]
     pub fsync: bool,
 
+    /// Sync the target directories to disk once the copy is complete.
+    ///
+    /// This makes the new directory entries durable without syncing
+    /// the file data, as '--fsync' does; e.g. where the data is
+    /// synced by other means. Implied by '--fsync'.
+    #[arg(long)]
+    pub sync_dest_dirs: bool,
+
+    /// Avoid filling the page cache with the copied data.
+    ///
+    /// Files are read sequentially and copied blocks are dropped from
+    /// the cache as the copy proceeds, so that large copies don't
+    /// evict other data. Only effective on Linux.
+    #[arg(long)]
+    pub no_cache: bool,
+
+    /// Copy with direct I/O, bypassing the page cache.
+    ///
+    /// The '--block-size' must be a multiple of the block sizes of
+    /// the source and destination filesystems; '--auto-block-size'
+    /// rounds up to one. Filesystems without direct I/O support are
+    /// copied through the page cache as usual. Only effective on
+    /// Linux.
+    #[arg(long)]
+    pub direct_io: bool,
+
     /// Reflink options.
     ///
     /// Whether and how to use reflinks. 'auto' (the default) will
@@ -147,16 +725,167 @@ pub struct Opts {
     #[arg(long, default_value = "auto")]
     pub reflink: Reflink,
 
+    /// How to copy file data.
+    ///
+    /// 'cfr' (the default) uses copy_file_range() on Linux, which
+    /// lets the kernel optimise the copy, and reads and writes
+    /// elsewhere. 'rw' always reads and writes through a buffer, and
+    /// 'mmap' writes from a memory mapping of the source. These are
+    /// for filesystems where copy_file_range() is slow or unreliable.
+    #[arg(long, value_name = "METHOD", default_value = "cfr")]
+    pub copy_method: CopyMethod,
+
+    /// Warn about files that could not be reflinked.
+    ///
+    /// With '--reflink=auto', log the reason for each file that is
+    /// copied rather than reflinked, e.g. because the filesystem does
+    /// not support reflinks or the files are on different
+    /// filesystems, and report the total once the copy completes.
+    #[arg(long)]
+    pub warn_no_reflink: bool,
+
     /// Backup options
     ///
     /// Whether to create backups of overwritten files. Current
-    /// options are 'none'/'off', or 'numbered', or 'auto'. Numbered
-    /// backups follow the semantics of `cp` numbered backups
+    /// options are 'none'/'off', 'numbered', 'auto', or 'simple'.
+    /// Numbered backups follow the semantics of `cp` numbered backups
     /// (e.g. `file.txt.~123~`). 'auto' will only create a numbered
-    /// backup if a previous backups exists. Default is 'none'.
+    /// backup if a previous backups exists. 'simple' keeps a single
+    /// backup named with '--suffix' (e.g. `file.txt~`). Default is
+    /// 'none'.
     #[arg(long, default_value = "none")]
     pub backup: Backup,
 
+    /// The suffix for '--backup=simple' backups.
+    #[arg(short = 'S', long, value_name = "SUFFIX", default_value = "~")]
+    pub suffix: String,
+
+    /// Sparse file handling.
+    ///
+    /// 'auto' (the default) detects sparse source files and only
+    /// copies their data extents. 'always' additionally skips writing
+    /// any all-zero blocks, so the destination will be sparse even if
+    /// the source is not; this reads the whole file in user-space,
+    /// and so cannot use accelerated copies. 'never' disables sparse
+    /// detection and copies the full file; this is useful where
+    /// extent-mapping is unreliable (e.g. some network filesystems),
+    /// at the cost of expanding any holes.
+    #[arg(long, default_value = "auto")]
+    pub sparse: Sparse,
+
+    /// Write the holes of sparse files as zeros.
+    ///
+    /// Holes in the source are still detected and skipped when
+    /// reading, but the destination is written in full, so it is not
+    /// sparse. This uses more space and writes more data than leaving
+    /// holes, but suits filesystems where explicit zeros are cheap
+    /// (e.g. with compression) or holes are undesirable.
+    #[arg(long)]
+    pub no_punch_holes: bool,
+
+    /// Fail files that change while being copied.
+    ///
+    /// Source files are checked for changes to their size or
+    /// modification time once copied. By default a changed file only
+    /// generates a warning; with this flag set it is reported as an
+    /// error.
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Remove source files once copied.
+    ///
+    /// Each source file is deleted after it has been copied (and
+    /// verified, if '--verify' is set); source directories are
+    /// removed once empty. Files that fail to copy are left in place.
+    /// This gives move semantics across filesystems.
+    #[arg(long)]
+    pub remove_source_files: bool,
+
+    /// Continue copying after an error.
+    ///
+    /// By default the first failed file aborts the copy. With this
+    /// flag set errors are logged and the copy continues; a summary
+    /// of the failed files is printed at the end and xcp exits with
+    /// code 23.
+    #[arg(long)]
+    pub continue_on_error: bool,
+
+    /// Show what would be copied.
+    ///
+    /// Walks the source tree and prints each operation that would be
+    /// performed, along with the total size, but does not modify the
+    /// destination.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Replace destination files atomically.
+    ///
+    /// Copy each file to a temporary file next to the destination,
+    /// and rename it into place once the copy has succeeded, so an
+    /// interrupted copy never leaves a partially-written file. With
+    /// 'auto' (the default if no value is given) files that cannot be
+    /// renamed over are written in place with a warning; with
+    /// 'always' this is an error.
+    #[arg(long, value_name = "MODE", num_args = 0..=1, require_equals = true,
+          default_value = "never", default_missing_value = "auto")]
+    pub atomic: Atomic,
+
+    /// Verify copied files.
+    ///
+    /// After each file is copied, read back the source and
+    /// destination and compare their checksums. Optionally takes the
+    /// algorithm to use; 'xxhash' (the default) or 'sha256'. Holes in
+    /// sparse files are not read.
+    #[arg(long, value_name = "ALGORITHM", num_args = 0..=1, require_equals = true,
+          default_value = "none", default_missing_value = "xxhash")]
+    pub verify: Verify,
+
+    /// Set the permissions of copied files and directories.
+    ///
+    /// Takes an octal mode (e.g. '0644') or comma-separated symbolic
+    /// clauses as used by chmod (e.g. 'u=rw,go=r'). 'X' sets execute
+    /// only on directories and already-executable files. This is
+    /// applied after any mode preserved from the source, so overrides
+    /// it.
+    #[arg(long, value_name = "MODE")]
+    pub chmod: Option<Chmod>,
+
+    /// Set the permissions of directories created without a source.
+    ///
+    /// Applies to the destination directory when it is created, and
+    /// to any directories created by '--parents' or '--relative';
+    /// copied directories are unaffected. Takes the same forms as
+    /// '--chmod', applied to the mode given by the umask, e.g. '0755'
+    /// or 'go-w'.
+    #[arg(long, value_name = "MODE")]
+    pub dir_mode: Option<Chmod>,
+
+    /// Rename copied files and directories.
+    ///
+    /// Takes a substitution as used by sed, e.g. 's/$/.bak/',
+    /// which is applied to the name of each entry created within the
+    /// destination; the contents of a renamed directory are copied
+    /// into its new name. The pattern is a regular expression, and
+    /// the replacement may refer to its groups as '$1'. A trailing
+    /// 'g' replaces every match rather than the first. A destination
+    /// given as the target file itself is not renamed.
+    #[arg(long, value_name = "EXPR", conflicts_with = "delete")]
+    pub rename: Option<Rename>,
+
+    /// Remote shell used for remote destinations.
+    ///
+    /// A destination of the form '[user@]host:path' is copied to by
+    /// running 'xcp --server' on the host with this command, which is
+    /// given the host and the remote command as arguments. xcp must
+    /// be installed on the host. Defaults to 'ssh'.
+    #[arg(short = 'e', long, value_name = "COMMAND", default_value = "ssh")]
+    pub rsh: String,
+
+    /// Receive a remote copy on stdin. This is run on the remote host
+    /// by a copy to a '[user@]host:path' destination.
+    #[arg(long, hide = true)]
+    pub server: bool,
+
     /// Path list.
     ///
     /// Source and destination files, or multiple source(s) to a directory.
@@ -165,10 +894,56 @@ pub struct Opts {
 
 impl Opts {
     pub fn from_args() -> Result<Opts> {
-        Ok(Opts::parse())
+        let matches = Opts::command().try_get_matches().unwrap_or_else(|e| {
+            // Clap exits with 2 on usage errors; ours is 1.
+            if e.use_stderr() {
+                let _ = e.print();
+                process::exit(EXIT_USAGE.into());
+            }
+            e.exit()
+        });
+        let mut opts = Opts::from_arg_matches(&matches)?;
+        opts.filters = ordered_filters(&matches);
+        Ok(opts)
+    }
+
+    pub fn preserved(&self) -> Preserve {
+        self.preserve.unwrap_or_default()
+    }
+
+    pub fn progress_mode(&self) -> ProgressMode {
+        if self.no_progress || self.quiet {
+            ProgressMode::None
+        } else {
+            self.progress
+        }
+    }
+
+    /// Split the paths into the destination and the source patterns.
+    pub fn dest_and_sources(&self) -> Result<(&String, &[String])> {
+        match self.target_directory {
+            Some(ref d) => Ok((d, self.paths.as_slice())),
+            None => {
+                let (dest, sources) = self.paths.split_last()
+                    .ok_or(XcpError::InvalidArguments("Insufficient arguments".to_string()))?;
+                Ok((dest, sources))
+            }
+        }
+    }
+
+    /// Whether the destination must be a directory. As with rsync
+    /// this is indicated with a trailing slash, and the directory
+    /// will be created if necessary. This is implied by
+    /// `--from-file` and `--target-directory`.
+    pub fn dest_is_dir(&self) -> bool {
+        self.relative || self.from_file.is_some() || self.target_directory.is_some() || self.dest_and_sources()
+            .is_ok_and(|(dest, _)| dest.ends_with('/'))
     }
 
     pub fn log_level(&self) -> LevelFilter {
+        if self.quiet {
+            return LevelFilter::Error;
+        }
         match self.verbose {
             0 => LevelFilter::Warn,
             1 => LevelFilter::Info,
@@ -178,29 +953,103 @@ impl Opts {
     }
 }
 
+// Clap collects each option separately, but the precedence of include
+// and exclude patterns depends on their relative order.
+fn ordered_filters(matches: &ArgMatches) -> Vec<Filter> {
+    let collect = |id: &str, make: fn(String) -> Filter| -> Vec<(usize, Filter)> {
+        match (matches.indices_of(id), matches.get_many::<String>(id)) {
+            (Some(indices), Some(values)) => indices
+                .zip(values)
+                .map(|(i, v)| (i, make(v.clone())))
+                .collect(),
+            _ => Vec::new(),
+        }
+    };
+    let mut filters = collect("exclude", Filter::Exclude);
+    filters.extend(collect("include", Filter::Include));
+    filters.sort_by_key(|(i, _)| *i);
+    filters.into_iter()
+        .map(|(_, f)| f)
+        .collect()
+}
+
 impl From<&Opts> for Config {
     fn from(opts: &Opts) -> Self {
         Config {
-            workers: if opts.workers == 0 {
-                num_cpus::get()
-            } else {
-                opts.workers
-            },
-            block_size: if opts.no_progress {
-                usize::MAX as u64
-            } else {
-                opts.block_size
-            },
+            workers: opts.workers.unwrap_or_else(num_cpus::get),
+            block_size: opts.block_size,
+            auto_block_size: opts.auto_block_size,
+            bwlimit: opts.bwlimit,
+            timeout: opts.timeout.map(Duration::from_secs),
+            offset: opts.offset,
+            length: opts.length,
+            append: opts.append,
             gitignore: opts.gitignore,
+            ignore_files: opts.ignore_file.clone(),
+            no_gitignore: opts.no_gitignore,
+            filters: opts.filters.clone(),
             no_clobber: opts.no_clobber,
+            update: opts.update,
+            ignore_existing: opts.ignore_existing,
+            quick_check: if opts.checksum {
+                QuickCheck::Checksum
+            } else if opts.size_only {
+                QuickCheck::Size
+            } else if opts.quick_check {
+                QuickCheck::SizeMtime
+            } else {
+                QuickCheck::None
+            },
+            min_size: opts.min_size,
+            max_size: opts.max_size,
+            max_total_size: opts.max_total_size,
+            delete: opts.delete,
             no_perms: opts.no_perms,
+            strip_special_bits: opts.no_preserve_special_bits,
             no_timestamps: opts.no_timestamps,
-            ownership: opts.ownership,
+            no_xattr: opts.no_xattr || (opts.no_perms && !opts.preserved().xattr),
+            ownership: opts.ownership || opts.preserved().ownership,
             dereference: opts.dereference,
+            dereference_command_line: opts.dereference_command_line,
+            read_special: opts.read_special,
+            pipe_through: opts.pipe_through,
+            one_file_system: opts.one_file_system,
+            max_depth: if opts.no_recursive { Some(1) } else { opts.max_depth },
             no_target_directory: opts.no_target_directory,
+            copy_contents: opts.copy_contents,
+            tee: opts.tee.clone(),
+            dest_is_dir: opts.dest_is_dir(),
+            relative: opts.relative || opts.from_file.is_some(),
             fsync: opts.fsync,
+            sync_dest_dirs: opts.sync_dest_dirs,
+            no_cache: opts.no_cache,
+            direct_io: opts.direct_io,
             reflink: opts.reflink,
+            copy_method: opts.copy_method,
             backup: opts.backup,
+            backup_suffix: opts.suffix.clone(),
+            atomic: opts.atomic,
+            sparse: opts.sparse,
+            no_punch_holes: opts.no_punch_holes,
+            strict: opts.strict,
+            remove_source_files: opts.remove_source_files,
+            preserve_links: opts.preserved().links,
+            preserve_acl: opts.preserved().acl,
+            preserve_flags: opts.preserved().flags,
+            chmod: opts.chmod.clone(),
+            dir_mode: opts.dir_mode.clone(),
+            rename: opts.rename.clone(),
+            continue_on_error: opts.continue_on_error,
+            dry_run: opts.dry_run,
+            dirs_only: opts.dirs_only,
+            types: opts.types.unwrap_or_default(),
+            verify: opts.verify,
+            max_open_files: opts.max_open_files.unwrap_or(0),
+            retries: opts.retries,
+            interrupt: None,
+            warn_no_reflink: opts.warn_no_reflink,
+            checkpoint: None,
+            dedup: opts.dedup.then(|| Arc::new(Dedup::new())),
         }
     }
 }
@@ -14,69 +14,493 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::options::Opts;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use libxcp::errors::Result;
+use console::Term;
+use indicatif::{HumanBytes, HumanDuration, ProgressDrawTarget};
+use libxcp::drivers::Drivers;
+use libxcp::errors::{Result, XcpError};
+use libxcp::feedback::{CopyStats, ProgressReporter};
+
+use crate::logging;
+use crate::options::{Opts, ProgressMode};
+
+// How often to sample the transfer rate, and the weighting of each
+// new sample in the smoothed rate.
+const RATE_INTERVAL: Duration = Duration::from_millis(500);
+const RATE_SMOOTHING: f64 = 0.3;
 
 struct NoopBar;
 
 struct VisualBar {
     bar: indicatif::ProgressBar,
+    // Whether to show the current file name, or the number of
+    // in-flight files.
+    show_name: bool,
+    // Whether to show an estimated time remaining.
+    show_eta: bool,
+    state: Mutex<BarState>,
+}
+
+// Counts directories rather than bytes, for '--dirs-only'.
+struct DirBar {
+    bar: indicatif::ProgressBar,
+}
+
+struct JsonBar {
+    state: Mutex<JsonState>,
+}
+
+#[derive(Default)]
+struct JsonState {
+    total: u64,
+    copied: u64,
+    entries: u64,
+    entries_done: u64,
+    current: String,
+    reflinked: u64,
 }
 
-pub trait ProgressBar {
-    #[allow(unused)]
-    fn set_size(&self, size: u64);
-    fn inc_size(&self, size: u64);
-    fn inc(&self, size: u64);
-    fn end(&self);
+struct BarState {
+    current: String,
+    in_flight: u64,
+    sample_start: Instant,
+    sample_bytes: u64,
+    rate: Option<f64>,
+    // The total is incomplete until the walk has finished.
+    size_known: bool,
+    // Entries of all types, alongside the byte counts.
+    entries: u64,
+    entries_done: u64,
 }
 
 
-impl ProgressBar for NoopBar {
+impl BarState {
+    fn new(size_known: bool) -> Mutex<Self> {
+        Mutex::new(BarState {
+            current: String::new(),
+            in_flight: 0,
+            sample_start: Instant::now(),
+            sample_bytes: 0,
+            rate: None,
+            size_known,
+            entries: 0,
+            entries_done: 0,
+        })
+    }
+}
+
+impl ProgressReporter for NoopBar {
     fn set_size(&self, _size: u64) {
     }
     fn inc_size(&self, _size: u64) {
     }
     fn inc(&self, _size: u64) {
     }
-    fn end(&self) {
+    fn start_file(&self, _path: &Path) {
+    }
+    fn finish_file(&self, _path: &Path) {
+    }
+    fn error(&self, _err: &XcpError) {
+    }
+    fn end(&self, _stats: &CopyStats) {
     }
 }
 
-impl ProgressBar for VisualBar {
+impl ProgressReporter for VisualBar {
     fn set_size(&self, size: u64) {
         self.bar.set_length(size);
+        let mut state = self.state.lock().unwrap();
+        state.size_known = true;
+        self.update_message(&state);
     }
 
     fn inc_size(&self, size: u64) {
         self.bar.inc_length(size);
     }
 
+    fn dec_size(&self, size: u64) {
+        let len = self.bar.length().unwrap_or(0);
+        self.bar.set_length(len.saturating_sub(size));
+    }
+
     fn inc(&self, size: u64) {
         self.bar.inc(size);
+
+        let mut state = self.state.lock().unwrap();
+        state.sample_bytes += size;
+        let elapsed = state.sample_start.elapsed();
+        if elapsed >= RATE_INTERVAL {
+            let sample = state.sample_bytes as f64 / elapsed.as_secs_f64();
+            state.rate = Some(match state.rate {
+                Some(rate) => (RATE_SMOOTHING * sample) + ((1.0 - RATE_SMOOTHING) * rate),
+                None => sample,
+            });
+            state.sample_start = Instant::now();
+            state.sample_bytes = 0;
+            self.update_message(&state);
+        }
+    }
+
+    // Counted in the bar's position, but not in the copy rate.
+    fn reflinked(&self, size: u64) {
+        self.bar.inc(size);
+    }
+
+    fn start_file(&self, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight += 1;
+        state.current = path.file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.update_message(&state);
+    }
+
+    fn finish_file(&self, _path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight = state.in_flight.saturating_sub(1);
+        self.update_message(&state);
+    }
+
+    // Errors are logged separately.
+    fn error(&self, _err: &XcpError) {
+    }
+
+    fn inc_entries(&self, count: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.entries += count;
+        self.update_entries(&state);
+    }
+
+    fn entries_done(&self, count: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.entries_done += count;
+        self.update_entries(&state);
     }
 
-    fn end(&self) {
+    fn end(&self, stats: &CopyStats) {
         self.bar.finish();
+        logging::set_bar(None);
+        let _ = writeln!(io::stderr().lock(), "{}", format_summary(stats));
+    }
+}
+
+impl ProgressReporter for DirBar {
+    fn set_size(&self, _size: u64) {
+    }
+
+    fn inc_size(&self, _size: u64) {
+    }
+
+    fn inc(&self, _size: u64) {
+    }
+
+    fn inc_entries(&self, count: u64) {
+        self.bar.inc_length(count);
+    }
+
+    fn entries_done(&self, count: u64) {
+        self.bar.inc(count);
+    }
+
+    fn end(&self, stats: &CopyStats) {
+        self.bar.finish();
+        logging::set_bar(None);
+        let _ = writeln!(io::stderr().lock(), "Created {} directories in {}",
+                         stats.dirs, HumanDuration(stats.elapsed));
+    }
+}
+
+impl DirBar {
+    fn new(force: bool) -> Result<Self> {
+        let bar = indicatif::ProgressBar::new(0).with_style(
+            indicatif::ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} directories")?
+                .progress_chars("#>-"),
+        );
+        force_draw(&bar, force);
+        logging::set_bar(Some(&bar));
+        Ok(Self { bar })
+    }
+}
+
+impl ProgressReporter for JsonBar {
+    fn set_size(&self, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.total = size;
+        self.emit_progress(&state);
+    }
+
+    fn inc_size(&self, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.total += size;
+        self.emit_progress(&state);
+    }
+
+    fn dec_size(&self, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.total = state.total.saturating_sub(size);
+        self.emit_progress(&state);
+    }
+
+    fn inc(&self, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.copied += size;
+        self.emit_progress(&state);
+    }
+
+    fn reflinked(&self, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.reflinked += size;
+        self.emit_progress(&state);
+    }
+
+    fn start_file(&self, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        state.current = path.to_string_lossy().to_string();
+        self.emit_progress(&state);
+    }
+
+    // Reported with the next byte progress, to avoid a record per
+    // entry.
+    fn inc_entries(&self, count: u64) {
+        self.state.lock().unwrap().entries += count;
+    }
+
+    fn entries_done(&self, count: u64) {
+        self.state.lock().unwrap().entries_done += count;
+    }
+
+    fn error(&self, err: &XcpError) {
+        let path = err.path()
+            .map_or("null".to_string(), |path| json_str(&path.to_string_lossy()));
+        let errno = err.os_error()
+            .map_or("null".to_string(), |code| code.to_string());
+        emit(format!(r#"{{"type":"error","path":{},"kind":"{}","errno":{},"message":{}}}"#,
+                     path, err.kind(), errno, json_str(&err.to_string())));
+    }
+
+    fn end(&self, stats: &CopyStats) {
+        let state = self.state.lock().unwrap();
+        emit(format!(
+            r#"{{"type":"summary","total":{},"copied":{},"reflinked":{},"files":{},"reflinked_files":{},"errors":{},"entries":{},"elapsed_ms":{},"bytes_per_sec":{}}}"#,
+            state.total, stats.bytes, stats.reflinked_bytes, stats.files, stats.reflinked_files, stats.errors, state.entries_done,
+            stats.elapsed.as_millis(),
+            average_rate(stats.bytes, stats.elapsed)));
+    }
+}
+
+impl JsonBar {
+    fn new(size: u64) -> Self {
+        let state = JsonState {
+            total: size,
+            ..JsonState::default()
+        };
+        Self {
+            state: Mutex::new(state),
+        }
+    }
+
+    fn emit_progress(&self, state: &JsonState) {
+        emit(format!(r#"{{"type":"progress","total":{},"copied":{},"reflinked":{},"file":{},"entries_total":{},"entries":{}}}"#,
+                     state.total, state.copied, state.reflinked, json_str(&state.current), state.entries, state.entries_done));
+    }
+}
+
+// Write a single record; failures are ignored as there is nowhere
+// to report them.
+fn emit(record: String) {
+    let _ = writeln!(io::stderr().lock(), "{}", record);
+}
+
+// Quote and escape a string for JSON output.
+pub(crate) fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Draw `bar` even if stderr isn't a terminal, which indicatif would
+// otherwise hide it for.
+fn force_draw(bar: &indicatif::ProgressBar, force: bool) {
+    if force {
+        bar.set_draw_target(ProgressDrawTarget::term_like(Box::new(Term::stderr())));
     }
 }
 
 impl VisualBar {
-    fn new(size: u64) -> Result<Self> {
+    fn new(size: u64, show_name: bool, force: bool) -> Result<Self> {
         let bar = indicatif::ProgressBar::new(size).with_style(
             indicatif::ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")?
+                .template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] {prefix}{bytes}/{total_bytes} {msg}")?
                 .progress_chars("#>-"),
         );
-        Ok(Self { bar })
+        force_draw(&bar, force);
+        // A zero size means it will be supplied by the walker later.
+        logging::set_bar(Some(&bar));
+        Ok(Self { bar, show_name, show_eta: true, state: BarState::new(size > 0) })
+    }
+
+    // For use when the total size is unknown.
+    fn new_spinner(force: bool) -> Result<Self> {
+        let bar = indicatif::ProgressBar::new_spinner().with_style(
+            indicatif::ProgressStyle::default_spinner()
+                .template("[{elapsed_precise}] {spinner} {bytes} {msg}")?,
+        );
+        force_draw(&bar, force);
+        logging::set_bar(Some(&bar));
+        Ok(Self { bar, show_name: true, show_eta: false, state: BarState::new(false) })
+    }
+
+    // A single file copy has nothing to count, so the entries are
+    // only shown for trees.
+    fn update_entries(&self, state: &BarState) {
+        if state.entries > 1 {
+            self.bar.set_prefix(format!("{}/{} entries, ", state.entries_done, state.entries));
+        }
+    }
+
+    fn update_message(&self, state: &BarState) {
+        let mut rate = state.rate
+            .map(|r| format!("{}/s", HumanBytes(r as u64)))
+            .unwrap_or_default();
+        if self.show_eta {
+            let remaining = self.bar.length()
+                .filter(|_| state.size_known)
+                .map(|len| len.saturating_sub(self.bar.position()));
+            let _ = write!(rate, " ({})", format_eta(remaining, state.rate));
+        }
+        let msg = if self.show_name {
+            format!("{} {}", rate, state.current)
+        } else {
+            format!("{} {} files", rate, state.in_flight)
+        };
+        self.bar.set_message(msg.trim().to_string());
     }
 }
 
-pub fn create_bar(opts: &Opts, size: u64) -> Result<Box<dyn ProgressBar>> {
-    if opts.no_progress {
-        Ok(Box::new(NoopBar {}))
+// The average transfer rate in bytes per second over a whole copy.
+fn average_rate(bytes: u64, elapsed: Duration) -> u64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        (bytes as f64 / secs) as u64
     } else {
-        Ok(Box::new(VisualBar::new(size)?))
+        0
+    }
+}
+
+// The line printed once a copy has completed. Reflinked files are
+// counted separately, as their data wasn't copied.
+fn format_summary(stats: &CopyStats) -> String {
+    let files = stats.files.saturating_sub(stats.reflinked_files);
+    let mut summary = format!("Copied {} files, {} in {} ({}/s)",
+                              files, HumanBytes(stats.bytes), HumanDuration(stats.elapsed),
+                              HumanBytes(average_rate(stats.bytes, stats.elapsed)));
+    if stats.reflinked_files > 0 {
+        let _ = write!(summary, "; reflinked {} files, {}", stats.reflinked_files, HumanBytes(stats.reflinked_bytes));
+    }
+    summary
+}
+
+// Estimate the time remaining from the smoothed transfer rate; this
+// is indeterminate until both the rate and the total are known.
+fn format_eta(remaining: Option<u64>, rate: Option<f64>) -> String {
+    match (remaining, rate) {
+        (Some(remaining), Some(rate)) if rate > 0.0 => {
+            let secs = (remaining as f64 / rate).ceil();
+            format!("ETA {}", HumanDuration(Duration::from_secs_f64(secs)))
+        }
+        _ => "ETA --".to_string(),
+    }
+}
+
+pub fn create_bar(opts: &Opts, size: u64) -> Result<Box<dyn ProgressReporter>> {
+    if opts.dry_run {
+        return Ok(Box::new(NoopBar {}));
+    }
+    match opts.progress_mode() {
+        ProgressMode::None => Ok(Box::new(NoopBar {})),
+        ProgressMode::Json => Ok(Box::new(JsonBar::new(size))),
+        mode @ (ProgressMode::Bar | ProgressMode::Always) if opts.dirs_only => {
+            Ok(Box::new(DirBar::new(mode == ProgressMode::Always)?))
+        }
+        mode @ (ProgressMode::Bar | ProgressMode::Always) => {
+            // Parblock copies many files at once, so a single name is
+            // meaningless.
+            let show_name = matches!(opts.driver, Drivers::ParFile | Drivers::Sequential);
+            Ok(Box::new(VisualBar::new(size, show_name, mode == ProgressMode::Always)?))
+        }
+    }
+}
+
+/// Create a bar for a streaming copy, where the size may not be known
+/// in advance.
+pub fn create_stream_bar(opts: &Opts, size: Option<u64>) -> Result<Box<dyn ProgressReporter>> {
+    match size {
+        None if !opts.dry_run && matches!(opts.progress_mode(), ProgressMode::Bar | ProgressMode::Always) => {
+            Ok(Box::new(VisualBar::new_spinner(opts.progress_mode() == ProgressMode::Always)?))
+        }
+        _ => create_bar(opts, size.unwrap_or(0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_str() {
+        assert_eq!(r#""plain""#, json_str("plain"));
+        assert_eq!(r#""a \"quoted\" \\path""#, json_str(r#"a "quoted" \path"#));
+        assert_eq!(r#""line\nbreak\u0001""#, json_str("line\nbreak\u{1}"));
+    }
+
+    #[test]
+    fn test_format_eta() {
+        assert_eq!("ETA --", format_eta(None, Some(1024.0)));
+        assert_eq!("ETA --", format_eta(Some(1024), None));
+        assert_eq!("ETA --", format_eta(Some(1024), Some(0.0)));
+        assert_eq!("ETA 5 minutes", format_eta(Some(300 * 1024), Some(1024.0)));
+        assert_eq!("ETA 5 hours", format_eta(Some(5 * 3600 * 1024), Some(1024.0)));
+    }
+
+    #[test]
+    fn test_format_summary() {
+        let stats = CopyStats {
+            files: 3,
+            bytes: 4 * 1024 * 1024 * 1024,
+            elapsed: Duration::from_secs(32),
+            ..CopyStats::default()
+        };
+        assert_eq!("Copied 3 files, 4.00 GiB in 32 seconds (128.00 MiB/s)", format_summary(&stats));
+        assert_eq!("Copied 0 files, 0 B in 0 seconds (0 B/s)", format_summary(&CopyStats::default()));
+        let stats = CopyStats {
+            files: 412,
+            bytes: 3 * 1024 * 1024 * 1024,
+            reflinked_bytes: 40 * 1024 * 1024 * 1024,
+            reflinked_files: 12,
+            elapsed: Duration::from_secs(32),
+            ..CopyStats::default()
+        };
+        assert_eq!("Copied 400 files, 3.00 GiB in 32 seconds (96.00 MiB/s); reflinked 12 files, 40.00 GiB",
+                   format_summary(&stats));
     }
 }
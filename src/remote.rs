@@ -0,0 +1,635 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Copies to a remote destination, given as `[user@]host:path`. The
+//! files are sent over a remote shell such as `ssh` to `xcp --server`
+//! on the other host, which writes them under the path.
+//!
+//! The stream starts with [MAGIC], the [VERSION] byte and a flags
+//! byte, followed by one record per entry. Each record is a type byte,
+//! the entry's path relative to the destination, and the fields for
+//! that type:
+//!
+//! * directory: the mode.
+//! * symlink: the target.
+//! * file: the mode, the mtime's seconds and nanoseconds, and the size;
+//!   then the data in chunks of at most [CHUNK_SIZE], each prefixed
+//!   with its length, ending with an empty chunk and the XXH64 checksum
+//!   of the data.
+//!
+//! The stream ends with an `END` type byte. Paths and symlink targets
+//! are prefixed with their length, and all integers are big-endian.
+//! The receiver refuses a stream with another version, so the format
+//! can change as long as [VERSION] does too.
+//!
+//! Each file is received into a temporary file beside it, which is
+//! only renamed into place once its size and checksum match.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File, FileTimes, Metadata, OpenOptions, Permissions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
+
+use libxcp::config::Verify;
+use libxcp::errors::{Result, XcpError};
+use libxcp::feedback::{CopyStats, PlannedOp, ProgressReporter};
+use libxcp::{Checksum, RateLimiter};
+use log::{info, warn};
+
+use crate::options::Opts;
+use crate::progress;
+
+const MAGIC: &[u8] = b"XCP";
+// The first version had a single file length instead of chunks.
+const VERSION: u8 = 2;
+
+// Set when the entries go inside the destination directory, rather
+// than a single source being written as the destination itself.
+const INTO_DIR: u8 = 1;
+
+const DIR: u8 = b'd';
+const FILE: u8 = b'f';
+const SYMLINK: u8 = b'l';
+const END: u8 = b'e';
+
+// Far beyond PATH_MAX, so only reached by a corrupt stream.
+const MAX_PATH_LEN: u32 = 64 * 1024;
+const CHUNK_SIZE: u32 = 1024 * 1024;
+
+/// A destination on another host.
+#[derive(Debug, PartialEq)]
+pub struct Remote<'a> {
+    pub host: &'a str,
+    pub path: &'a str,
+}
+
+/// Parse a `host:path` destination. As with rsync, a colon after a
+/// slash is part of a local path, as is any path that exists.
+pub fn parse_remote(dest: &str) -> Option<Remote<'_>> {
+    let (host, path) = dest.split_once(':')?;
+    if host.is_empty() || host.contains('/') || Path::new(dest).exists() {
+        return None;
+    }
+    let path = if path.is_empty() { "." } else { path };
+    Some(Remote { host, path })
+}
+
+// An entry to send, with its path relative to the source's parent.
+struct Entry {
+    from: PathBuf,
+    path: PathBuf,
+    meta: Metadata,
+}
+
+fn entry_metadata(path: &Path, opts: &Opts, top: bool) -> Result<Metadata> {
+    Ok(if opts.dereference || (opts.dereference_command_line && top) {
+        fs::metadata(path)?
+    } else {
+        fs::symlink_metadata(path)?
+    })
+}
+
+fn collect(opts: &Opts, sources: &[PathBuf]) -> Result<Vec<Entry>> {
+    let relative = opts.relative || opts.from_file.is_some();
+    let mut entries = Vec::new();
+    // The parents already sent for relative sources.
+    let mut parents = HashSet::new();
+    for source in sources {
+        let meta = entry_metadata(source, opts, true)?;
+        if meta.is_dir() && !opts.recursive {
+            return Err(XcpError::InvalidSource("Source is directory and --recursive not specified.").into());
+        }
+        let path = if relative {
+            let path = relative_path(source)?;
+            // The parents are found innermost first, but must be
+            // created outermost first.
+            let mut new = Vec::new();
+            for (from, parent) in source.ancestors().skip(1).zip(path.ancestors().skip(1)) {
+                if !parent.as_os_str().is_empty() && parents.insert(parent.to_path_buf()) {
+                    new.push(Entry { from: from.to_path_buf(), path: parent.to_path_buf(), meta: fs::metadata(from)? });
+                }
+            }
+            entries.extend(new.into_iter().rev());
+            path
+        } else {
+            let name = source.file_name()
+                .ok_or(XcpError::InvalidSource("Failed to find source directory name."))?;
+            PathBuf::from(name)
+        };
+        walk(opts, source.clone(), path, meta, &mut entries)?;
+    }
+    Ok(entries)
+}
+
+// The source's whole path, as it's copied with '--relative'.
+fn relative_path(source: &Path) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in source.components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::ParentDir => {
+                return Err(XcpError::InvalidSource("Relative sources cannot contain '..'.").into());
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    if path.as_os_str().is_empty() {
+        return Err(XcpError::InvalidSource("Failed to find source directory name.").into());
+    }
+    Ok(path)
+}
+
+fn walk(opts: &Opts, from: PathBuf, path: PathBuf, meta: Metadata, entries: &mut Vec<Entry>) -> Result<()> {
+    let ft = meta.file_type();
+    if !(ft.is_dir() || ft.is_file() || ft.is_symlink()) {
+        warn!("Skipping special file {:?}, which can't be copied to a remote host", from);
+        return Ok(());
+    }
+    let is_dir = ft.is_dir();
+    entries.push(Entry { from: from.clone(), path: path.clone(), meta });
+    if is_dir {
+        let mut children = fs::read_dir(&from)?
+            .map(|e| e.map(|e| e.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        children.sort();
+        for name in children {
+            let child = from.join(&name);
+            let meta = entry_metadata(&child, opts, false)?;
+            walk(opts, child, path.join(&name), meta, entries)?;
+        }
+    }
+    Ok(())
+}
+
+// Quote `s` for the remote shell, which runs the command given to
+// ssh.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn remote_command(opts: &Opts, remote: &Remote) -> Result<Command> {
+    let mut rsh = opts.rsh.split_whitespace();
+    let program = rsh.next()
+        .ok_or(XcpError::InvalidArguments("--rsh cannot be empty.".to_string()))?;
+    let mut server = vec!["xcp", "--server"];
+    for (set, flag) in [(opts.no_clobber, "--no-clobber"), (opts.no_perms, "--no-perms"), (opts.no_timestamps, "--no-timestamps")] {
+        if set {
+            server.push(flag);
+        }
+    }
+    server.push("--");
+    let mut command = Command::new(program);
+    command.args(rsh)
+        .arg(remote.host)
+        .arg(format!("{} {}", server.join(" "), shell_quote(remote.path)));
+    Ok(command)
+}
+
+/// Send `sources` to `remote`, via `xcp --server` run there with
+/// [Opts::rsh].
+pub fn push(opts: &Opts, sources: &[PathBuf], remote: &Remote) -> Result<()> {
+    let entries = collect(opts, sources)?;
+    let into_dir = sources.len() > 1 || opts.dest_is_dir();
+
+    if opts.dry_run {
+        for entry in &entries {
+            let op = if entry.meta.is_dir() { PlannedOp::Mkdir } else { PlannedOp::Copy };
+            println!("{} {:?} -> {}:{:?}", op, entry.from, remote.host, Path::new(remote.path).join(&entry.path));
+        }
+        return Ok(());
+    }
+
+    let mut command = remote_command(opts, remote)?;
+    info!("Copying to {:?} with {:?}", remote, command);
+    let mut child = command.stdin(Stdio::piped()).spawn()?;
+    let stdin = child.stdin.take().expect("stdin is piped");
+
+    let total = entries.iter()
+        .filter(|e| e.meta.is_file())
+        .map(|e| e.meta.len())
+        .sum();
+    let start = Instant::now();
+    let pb = progress::create_bar(opts, total)?;
+    let mut stats = CopyStats::default();
+    let sent = send(opts, BufWriter::new(stdin), &entries, into_dir, pb.as_ref(), &mut stats);
+    // The remote's failure explains any broken pipe here, so is
+    // reported first.
+    let status = child.wait()?;
+    stats.elapsed = start.elapsed();
+    pb.end(&stats);
+    if !status.success() {
+        return Err(XcpError::CopyError(format!("The remote xcp on {} failed ({})", remote.host, status)).into());
+    }
+    sent
+}
+
+// The entries sent are counted in `stats`.
+fn send(opts: &Opts, mut out: impl Write, entries: &[Entry], into_dir: bool, pb: &dyn ProgressReporter, stats: &mut CopyStats) -> Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&[VERSION, if into_dir { INTO_DIR } else { 0 }])?;
+
+    let limiter = opts.bwlimit.map(RateLimiter::new);
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    for entry in entries {
+        let meta = &entry.meta;
+        if meta.is_dir() {
+            out.write_all(&[DIR])?;
+            write_bytes(&mut out, entry.path.as_os_str().as_bytes())?;
+            out.write_all(&meta.mode().to_be_bytes())?;
+            stats.dirs += 1;
+        } else if meta.is_symlink() {
+            out.write_all(&[SYMLINK])?;
+            write_bytes(&mut out, entry.path.as_os_str().as_bytes())?;
+            write_bytes(&mut out, fs::read_link(&entry.from)?.as_os_str().as_bytes())?;
+            stats.symlinks += 1;
+        } else {
+            let infd = File::open(&entry.from)?;
+            out.write_all(&[FILE])?;
+            write_bytes(&mut out, entry.path.as_os_str().as_bytes())?;
+            out.write_all(&meta.mode().to_be_bytes())?;
+            out.write_all(&meta.mtime().to_be_bytes())?;
+            out.write_all(&(meta.mtime_nsec() as u32).to_be_bytes())?;
+            out.write_all(&meta.len().to_be_bytes())?;
+
+            pb.start_file(&entry.from);
+            let mut sum = checksum();
+            let mut data = infd.take(meta.len());
+            let mut sent = 0;
+            loop {
+                let len = match data.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => len,
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                if let Some(limiter) = &limiter {
+                    limiter.acquire(len as u64);
+                }
+                out.write_all(&(len as u32).to_be_bytes())?;
+                out.write_all(&buf[..len])?;
+                sum.update(&buf[..len]);
+                sent += len as u64;
+                pb.inc(len as u64);
+            }
+            // The length has already been sent, so can't be changed.
+            if sent != meta.len() {
+                return Err(XcpError::SourceChanged(entry.from.clone()).into());
+            }
+            out.write_all(&0u32.to_be_bytes())?;
+            out.write_all(&sum.finish())?;
+            stats.files += 1;
+            stats.bytes += sent;
+            pb.finish_file(&entry.from);
+        }
+    }
+    out.write_all(&[END])?;
+    out.flush()?;
+    Ok(())
+}
+
+fn checksum() -> Checksum {
+    Checksum::new(Verify::Xxh64).expect("XXH64 is a checksum")
+}
+
+fn write_bytes(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    out.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    out.write_all(bytes)
+}
+
+fn read_array<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_path(input: &mut impl Read) -> Result<PathBuf> {
+    let len = u32::from_be_bytes(read_array(input)?);
+    if len > MAX_PATH_LEN {
+        return Err(XcpError::CopyError(format!("Invalid path length from the remote xcp: {}", len)).into());
+    }
+    let mut buf = vec![0; len as usize];
+    input.read_exact(&mut buf)?;
+    Ok(PathBuf::from(OsString::from_vec(buf)))
+}
+
+// Where the entry at `path` is written. A single source given as the
+// destination itself replaces the first component.
+fn target(dest: &Path, path: &Path, into_dir: bool) -> Result<PathBuf> {
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(XcpError::CopyError(format!("Invalid path from the remote xcp: {:?}", path)).into());
+    }
+    Ok(if into_dir {
+        dest.join(path)
+    } else {
+        let rest = path.components().skip(1).collect::<PathBuf>();
+        if rest.as_os_str().is_empty() {
+            dest.to_path_buf()
+        } else {
+            dest.join(rest)
+        }
+    })
+}
+
+/// Receive entries sent by [push()] on stdin, and write them under
+/// `dest`.
+pub fn serve(opts: &Opts, dest: &str) -> Result<()> {
+    let mut input = BufReader::new(io::stdin().lock());
+    receive(opts, &mut input, Path::new(dest))
+}
+
+fn receive(opts: &Opts, input: &mut impl Read, dest: &Path) -> Result<()> {
+    if read_array::<3>(input)? != MAGIC {
+        return Err(XcpError::CopyError("Unexpected data from the remote xcp; is xcp installed there?".to_string()).into());
+    }
+    let [version, flags] = read_array(input)?;
+    if version != VERSION {
+        return Err(XcpError::CopyError(format!(
+            "The remote xcp sends version {} of the stream, but this one reads version {}; install the same version of xcp on both hosts",
+            version, VERSION)).into());
+    }
+    let into_dir = flags & INTO_DIR != 0 || dest.is_dir();
+    if into_dir {
+        fs::create_dir_all(dest)?;
+    }
+
+    // Directory modes are set last so that read-only directories can
+    // still be filled.
+    let mut dirs = Vec::new();
+    loop {
+        let [tag] = read_array(input)?;
+        if tag == END {
+            break;
+        }
+        let path = read_path(input)?;
+        let to = target(dest, &path, into_dir)?;
+        if opts.no_clobber && tag != DIR && to.symlink_metadata().is_ok() {
+            return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", to).into());
+        }
+        // Failures are reported with the path they occurred at.
+        let mut entry = || -> Result<()> {
+            match tag {
+                DIR => {
+                    let mode = u32::from_be_bytes(read_array(input)?);
+                    match fs::create_dir(&to) {
+                        Err(e) if e.kind() == ErrorKind::AlreadyExists && to.is_dir() => {}
+                        r => r?,
+                    }
+                    dirs.push((to.clone(), mode));
+                }
+                SYMLINK => {
+                    let link = read_path(input)?;
+                    if to.symlink_metadata().is_ok() {
+                        fs::remove_file(&to)?;
+                    }
+                    symlink(link, &to)?;
+                }
+                FILE => {
+                    let mode = u32::from_be_bytes(read_array(input)?);
+                    let secs = i64::from_be_bytes(read_array(input)?);
+                    let nsecs = u32::from_be_bytes(read_array(input)?);
+                    let len = u64::from_be_bytes(read_array(input)?);
+                    let (mut outfd, temp) = temp_file(&to)?;
+                    let received = (|| -> Result<()> {
+                        receive_data(input, &mut outfd, len)?;
+                        if !opts.no_timestamps {
+                            outfd.set_times(FileTimes::new().set_modified(mtime(secs, nsecs)))?;
+                        }
+                        if !opts.no_perms {
+                            outfd.set_permissions(Permissions::from_mode(mode))?;
+                        }
+                        // Renaming replaces a symlink at `to` rather than
+                        // writing through it.
+                        fs::rename(&temp, &to)?;
+                        Ok(())
+                    })();
+                    if received.is_err() {
+                        let _ = fs::remove_file(&temp);
+                    }
+                    received?;
+                }
+                _ => {
+                    return Err(XcpError::CopyError(format!("Unexpected record from the remote xcp: {:?}", tag as char)).into());
+                }
+            }
+            Ok(())
+        };
+        entry().map_err(|e| XcpError::copy_failed(&to, &e))?;
+    }
+
+    if !opts.no_perms {
+        for (dir, mode) in dirs.iter().rev() {
+            fs::set_permissions(dir, Permissions::from_mode(*mode))?;
+        }
+    }
+    Ok(())
+}
+
+// Create a new file next to `to` to receive its data into, so `to` is
+// only replaced once the data has been checked.
+fn temp_file(to: &Path) -> Result<(File, PathBuf)> {
+    let fname = to.file_name()
+        .ok_or(XcpError::InvalidDestination("Destination has no file name"))?;
+    loop {
+        let mut name = OsString::from(".");
+        name.push(fname);
+        name.push(format!(".xcp-{:016x}", RandomState::new().build_hasher().finish()));
+        let temp = to.with_file_name(name);
+        match OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(fd) => return Ok((fd, temp)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Write a file's chunks to `out`, checking them against its size and
+// checksum.
+fn receive_data(input: &mut impl Read, out: &mut impl Write, len: u64) -> Result<()> {
+    let mut sum = checksum();
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    let mut received = 0;
+    loop {
+        let chunk = u32::from_be_bytes(read_array(input)?);
+        if chunk == 0 {
+            break;
+        }
+        if chunk > CHUNK_SIZE {
+            return Err(XcpError::CopyError(format!("Invalid chunk length from the remote xcp: {}", chunk)).into());
+        }
+        let data = &mut buf[..chunk as usize];
+        input.read_exact(data)?;
+        out.write_all(data)?;
+        sum.update(data);
+        received += chunk as u64;
+    }
+    if received != len {
+        return Err(XcpError::CopyError(format!("Received {} bytes of a {} byte file from the remote xcp", received, len)).into());
+    }
+    if read_array::<8>(input)?.as_slice() != sum.finish() {
+        return Err(XcpError::CopyError("Checksum mismatch in the data from the remote xcp".to_string()).into());
+    }
+    Ok(())
+}
+
+fn mtime(secs: i64, nsecs: u32) -> SystemTime {
+    let nsecs = Duration::from_nanos(nsecs as u64);
+    if secs >= 0 {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs as u64) + nsecs
+    } else {
+        SystemTime::UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nsecs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use tempfile::TempDir;
+
+    fn opts(args: &[&str]) -> Opts {
+        Opts::parse_from(["xcp", "--no-progress"].iter().chain(args))
+    }
+
+    // Send `source` as `push()` would, with the stream passed through
+    // `corrupt`.
+    fn stream(source: &Path, corrupt: impl Fn(&mut Vec<u8>)) -> Result<Vec<u8>> {
+        let opts = opts(&["-r", "src", "dest"]);
+        let entries = collect(&opts, &[source.to_path_buf()])?;
+        let mut out = Vec::new();
+        send(&opts, &mut out, &entries, true, progress::create_bar(&opts, 0)?.as_ref(), &mut CopyStats::default())?;
+        corrupt(&mut out);
+        Ok(out)
+    }
+
+    fn source_tree(dir: &TempDir) -> Result<PathBuf> {
+        let source = dir.path().join("src");
+        fs::create_dir_all(source.join("sub"))?;
+        fs::write(source.join("sub/file.txt"), "data")?;
+        symlink("sub/file.txt", source.join("link"))?;
+        Ok(source)
+    }
+
+    #[test]
+    fn test_send_receive() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = source_tree(&dir)?;
+        let dest = dir.path().join("dest");
+        let data = stream(&source, |_| {})?;
+        receive(&opts(&["src", "dest"]), &mut data.as_slice(), &dest)?;
+
+        assert_eq!("data", fs::read_to_string(dest.join("src/sub/file.txt"))?);
+        assert_eq!(PathBuf::from("sub/file.txt"), fs::read_link(dest.join("src/link"))?);
+
+        // A symlink in the way is replaced rather than written through.
+        let outside = dir.path().join("outside.txt");
+        fs::write(&outside, "outside")?;
+        fs::remove_file(dest.join("src/sub/file.txt"))?;
+        symlink(&outside, dest.join("src/sub/file.txt"))?;
+        receive(&opts(&["src", "dest"]), &mut data.as_slice(), &dest)?;
+        assert_eq!("outside", fs::read_to_string(&outside)?);
+        assert!(dest.join("src/sub/file.txt").symlink_metadata()?.is_file());
+        assert_eq!("data", fs::read_to_string(dest.join("src/sub/file.txt"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_receive_relative() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = source_tree(&dir)?;
+        fs::write(source.join("file.txt"), "top")?;
+        let dest = dir.path().join("dest");
+        let sources = [source.join("sub/file.txt"), source.join("file.txt")];
+
+        let opts = opts(&["-R", "src", "dest"]);
+        let entries = collect(&opts, &sources)?;
+        let mut data = Vec::new();
+        send(&opts, &mut data, &entries, true, progress::create_bar(&opts, 0)?.as_ref(), &mut CopyStats::default())?;
+        receive(&opts, &mut data.as_slice(), &dest)?;
+
+        // Same-named files keep their own directories.
+        let root = dest.join(relative_path(&source)?);
+        assert_eq!("data", fs::read_to_string(root.join("sub/file.txt"))?);
+        assert_eq!("top", fs::read_to_string(root.join("file.txt"))?);
+        // Each parent is sent once, before its entries.
+        let paths = entries.iter().map(|e| e.path.as_path()).collect::<Vec<_>>();
+        let sub = relative_path(&source.join("sub"))?;
+        assert_eq!(1, paths.iter().filter(|p| **p == sub).count());
+        assert!(paths.iter().position(|p| *p == sub) < paths.iter().position(|p| *p == sub.join("file.txt")));
+
+        assert!(relative_path(Path::new("../file")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_receive_invalid() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = source_tree(&dir)?;
+        let dest = dir.path().join("dest");
+
+        let other_version = stream(&source, |s| s[MAGIC.len()] = VERSION + 1)?;
+        let err = receive(&opts(&["src", "dest"]), &mut other_version.as_slice(), &dest).unwrap_err();
+        assert!(err.to_string().contains("install the same version"));
+
+        // The file is sent last, so its checksum is just before END.
+        let bad_checksum = stream(&source, |s| {
+            let i = s.len() - 2;
+            s[i] ^= 1;
+        })?;
+        fs::create_dir_all(dest.join("src/sub"))?;
+        fs::write(dest.join("src/sub/file.txt"), "old")?;
+        let err = receive(&opts(&["src", "dest"]), &mut bad_checksum.as_slice(), &dest).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"));
+        // The previous file is left as it was, without the temporary
+        // file beside it.
+        assert_eq!("old", fs::read_to_string(dest.join("src/sub/file.txt"))?);
+        assert_eq!(1, fs::read_dir(dest.join("src/sub"))?.count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_remote() {
+        assert_eq!(Some(Remote { host: "host", path: "/data" }), parse_remote("host:/data"));
+        assert_eq!(Some(Remote { host: "user@host", path: "." }), parse_remote("user@host:"));
+        assert_eq!(None, parse_remote("./host:/data"));
+        assert_eq!(None, parse_remote(":/data"));
+        assert_eq!(None, parse_remote("/tmp/dest"));
+    }
+
+    #[test]
+    fn test_target() -> Result<()> {
+        let dest = Path::new("/dest");
+        assert_eq!(PathBuf::from("/dest/src/file"), target(dest, Path::new("src/file"), true)?);
+        assert_eq!(PathBuf::from("/dest/file"), target(dest, Path::new("src/file"), false)?);
+        assert_eq!(PathBuf::from("/dest"), target(dest, Path::new("src"), false)?);
+        assert!(target(dest, Path::new("../file"), true).is_err());
+        assert!(target(dest, Path::new("/etc/passwd"), true).is_err());
+        assert!(target(dest, Path::new(""), true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!("'/a path'", shell_quote("/a path"));
+        assert_eq!(r"'it'\''s'", shell_quote("it's"));
+    }
+}
@@ -0,0 +1,130 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Streaming copies from stdin and/or to stdout. These can't be
+//! seeked or mapped, so bypass the drivers and perform a plain
+//! sequential copy.
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use libfs::sync;
+use libxcp::errors::{Result, XcpError};
+use libxcp::feedback::{CopyStats, PlannedOp};
+use libxcp::RateLimiter;
+use log::info;
+
+use crate::create_parents;
+use crate::options::Opts;
+use crate::progress;
+
+/// The path argument used to represent stdin or stdout.
+pub const STDIO_PATH: &str = "-";
+
+const STREAM_BUF_SIZE: usize = 1024 * 1024;
+
+pub fn is_stdio(path: &str) -> bool {
+    path == STDIO_PATH
+}
+
+pub fn copy_stream(opts: &Opts, source: &str, dest: &str) -> Result<()> {
+    info!("Streaming {:?} to {:?}", source, dest);
+
+    let (mut reader, size): (Box<dyn Read>, Option<u64>) = if is_stdio(source) {
+        (Box::new(io::stdin().lock()), None)
+    } else {
+        let path = Path::new(source);
+        if !path.exists() {
+            return Err(XcpError::InvalidSource("Source does not exist.").into());
+        }
+        if path.is_dir() {
+            return Err(XcpError::InvalidSource("Cannot stream a directory.").into());
+        }
+        let infd = File::open(path)?;
+        let len = infd.metadata()?.len();
+        (Box::new(infd), Some(len))
+    };
+
+    // Destination checks only apply to real files.
+    let outfile = if is_stdio(dest) {
+        None
+    } else {
+        let path = Path::new(dest);
+        if path.is_dir() {
+            return Err(XcpError::InvalidDestination("Cannot stream into a directory; a file name is required.").into());
+        }
+        if opts.no_clobber && path.exists() {
+            return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", path.to_path_buf()).into());
+        }
+        Some(path)
+    };
+
+    if opts.dry_run {
+        println!("{} {:?} -> {:?}", PlannedOp::Copy, source, dest);
+        return Ok(());
+    }
+
+    let (mut writer, outfd): (Box<dyn Write>, Option<File>) = match outfile {
+        None => (Box::new(io::stdout().lock()), None),
+        Some(path) => {
+            if opts.parents {
+                create_parents(path, opts.dir_mode.as_ref())?;
+            }
+            let outfd = File::create(path)?;
+            (Box::new(outfd.try_clone()?), Some(outfd))
+        }
+    };
+
+    let start = Instant::now();
+    let pb = progress::create_stream_bar(opts, size)?;
+    let name = outfile.unwrap_or(Path::new(source));
+    pb.start_file(name);
+
+    let limiter = opts.bwlimit.map(RateLimiter::new);
+    let mut buf = vec![0; STREAM_BUF_SIZE];
+    let mut copied = 0;
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(limiter) = &limiter {
+            limiter.acquire(len as u64);
+        }
+        writer.write_all(&buf[..len])?;
+        copied += len as u64;
+        pb.inc(len as u64);
+    }
+    writer.flush()?;
+    pb.finish_file(name);
+
+    if let Some(outfd) = outfd {
+        if opts.fsync {
+            sync(&outfd)?;
+        }
+    }
+    if let Some(parent) = outfile.filter(|_| opts.fsync || opts.sync_dest_dirs).and_then(Path::parent) {
+        let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+        sync(&File::open(parent)?)?;
+    }
+    pb.end(&CopyStats { files: 1, bytes: copied, elapsed: start.elapsed(), ..CopyStats::default() });
+
+    Ok(())
+}
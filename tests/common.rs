@@ -14,9 +14,13 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::fs::{create_dir_all, set_permissions, write, File, Permissions};
-use std::os::unix::fs::{symlink, PermissionsExt};
+use std::fs::{canonicalize, create_dir_all, set_permissions, write, File, Permissions};
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt, PermissionsExt};
 use std::os::unix::net::UnixListener;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
 use cfg_if::cfg_if;
 use test_case::test_case;
 
@@ -44,13 +48,21 @@ fn no_args() {
     assert!(stderr.contains("Insufficient arguments"));
 }
 
+#[test]
+fn unknown_option() {
+    let out = run(&["--no-such-option", "/dev/null", "/dev/null"]).unwrap();
+
+    assert!(!out.status.success());
+    assert!(out.status.code().unwrap() == 1);
+}
+
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
 fn source_missing(drv: &str) {
     let out = run(&["--driver", drv, "/this/should/not/exist", "/dev/null"]).unwrap();
 
     assert!(!out.status.success());
-    assert!(out.status.code().unwrap() == 1);
+    assert!(out.status.code().unwrap() == 3);
 
     let stderr = String::from_utf8(out.stderr).unwrap();
     assert!(stderr.contains("Source does not exist"));
@@ -69,7 +81,7 @@ fn source_missing_globbed(drv: &str) {
     .unwrap();
 
     assert!(!out.status.success());
-    assert!(out.status.code().unwrap() == 1);
+    assert!(out.status.code().unwrap() == 3);
 
     let stderr = String::from_utf8(out.stderr).unwrap();
     assert!(stderr.contains("No source files found"));
@@ -246,6 +258,50 @@ fn multiple_files_to_a_file(drv: &str) {
     assert!(stderr.contains("Multiple sources and destination is not a directory"));
 }
 
+#[test]
+fn multiple_files_to_missing_dest() {
+    let dir = tempdir_rel().unwrap();
+    let source1_path = dir.path().join("source1.txt");
+    let source2_path = dir.path().join("source2.txt");
+    let dest_path = dir.path().join("dest");
+    create_file(&source1_path, "one").unwrap();
+    create_file(&source2_path, "two").unwrap();
+
+    let out = run(&[
+        source1_path.to_str().unwrap(),
+        source2_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Multiple sources and destination does not exist"));
+    assert!(!dest_path.exists());
+}
+
+#[test]
+fn multiple_files_no_target_directory() {
+    let dir = tempdir_rel().unwrap();
+    let source1_path = dir.path().join("source1.txt");
+    let source2_path = dir.path().join("source2.txt");
+    let dest_path = dir.path().join("dest");
+    create_file(&source1_path, "one").unwrap();
+    create_file(&source2_path, "two").unwrap();
+    create_dir_all(&dest_path).unwrap();
+
+    let out = run(&[
+        "-T",
+        source1_path.to_str().unwrap(),
+        source2_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("--no-target-directory cannot be used with multiple sources"));
+    assert_eq!(0, std::fs::read_dir(&dest_path).unwrap().count());
+}
+
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
 fn directory_to_a_file(drv: &str) {
@@ -348,1079 +404,4953 @@ fn dest_file_exists_noclobber(drv: &str) {
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn file_copy(drv: &str) {
+#[test_case("sequential"; "Test with sequential driver")]
+fn dir_copy_update(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let source_path = dir.path().join("source.txt");
-    let dest_path = dir.path().join("dest.txt");
-    let text = "This is a test file.";
-
-    create_file(&source_path, text).unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    create_dir_all(&dest_path).unwrap();
+
+    // Destination newer; skipped.
+    create_file(&source_path.join("newer.txt"), "source").unwrap();
+    set_time_past(&source_path.join("newer.txt")).unwrap();
+    create_file(&dest_path.join("newer.txt"), "dest").unwrap();
+    // Destination older; copied.
+    create_file(&dest_path.join("older.txt"), "dest").unwrap();
+    set_time_past(&dest_path.join("older.txt")).unwrap();
+    create_file(&source_path.join("older.txt"), "source").unwrap();
+    // Destination missing; copied.
+    create_file(&source_path.join("new.txt"), "source").unwrap();
 
     let out = run(&[
         "--driver",
         drv,
+        "-r",
+        "-T",
+        "--update",
         source_path.to_str().unwrap(),
         dest_path.to_str().unwrap(),
     ])
     .unwrap();
 
     assert!(out.status.success());
-    assert!(file_contains(&dest_path, text).unwrap());
-    assert!(files_match(&source_path, &dest_path));
+    assert!(file_contains(&dest_path.join("newer.txt"), "dest").unwrap());
+    assert!(file_contains(&dest_path.join("older.txt"), "source").unwrap());
+    assert!(file_contains(&dest_path.join("new.txt"), "source").unwrap());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn file_copy_reflink_auto(drv: &str) {
+fn dir_copy_ignore_existing(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let source_path = dir.path().join("source.txt");
-    let dest_path = dir.path().join("dest.txt");
-    let text = "This is a test file.";
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_dir_all(dest_path.join("sub")).unwrap();
 
-    create_file(&source_path, text).unwrap();
+    // Destination older; still skipped.
+    create_file(&dest_path.join("sub/existing.txt"), "dest").unwrap();
+    set_time_past(&dest_path.join("sub/existing.txt")).unwrap();
+    create_file(&source_path.join("sub/existing.txt"), "source").unwrap();
+    // Destination missing within an existing directory; copied.
+    create_file(&source_path.join("sub/new.txt"), "source").unwrap();
 
     let out = run(&[
-        "--driver", drv,
-        "--reflink=auto",
+        "--driver",
+        drv,
+        "-r",
+        "-T",
+        "--ignore-existing",
+        "--no-clobber",
         source_path.to_str().unwrap(),
         dest_path.to_str().unwrap(),
     ])
     .unwrap();
 
-    // Should always work, even on non-CoW FS
     assert!(out.status.success());
-    assert!(file_contains(&dest_path, text).unwrap());
-    assert!(files_match(&source_path, &dest_path));
+    assert!(file_contains(&dest_path.join("sub/existing.txt"), "dest").unwrap());
+    assert!(file_contains(&dest_path.join("sub/new.txt"), "source").unwrap());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn file_copy_reflink_never(drv: &str) {
+fn file_copy_update_repeated(drv: &str) {
     let dir = tempdir_rel().unwrap();
     let source_path = dir.path().join("source.txt");
     let dest_path = dir.path().join("dest.txt");
-    let text = "This is a test file.";
-
-    create_file(&source_path, text).unwrap();
+    create_file(&source_path, "original").unwrap();
+    set_time_past(&source_path).unwrap();
 
-    let out = run(&[
-        "--driver", drv,
-        "--reflink=never",
+    let args = [
+        "--driver",
+        drv,
+        "--update",
         source_path.to_str().unwrap(),
         dest_path.to_str().unwrap(),
-    ])
-    .unwrap();
+    ];
+    assert!(run(&args).unwrap().status.success());
+    assert!(file_contains(&dest_path, "original").unwrap());
+
+    // The timestamps were copied, so a second run skips the file,
+    // even if its contents have changed since.
+    let mtime = dest_path.metadata().unwrap().modified().unwrap();
+    create_file(&dest_path, "modified").unwrap();
+    File::open(&dest_path).unwrap().set_modified(mtime).unwrap();
+    assert!(run(&args).unwrap().status.success());
+    assert!(file_contains(&dest_path, "modified").unwrap());
+}
 
-    // Should always work
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_quick_check_twice(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    create_file(&source_path.join("sub").join("other.txt"), "other").unwrap();
+
+    let copy = |extra: &[&str]| {
+        let mut args = vec!["--driver", drv, "-r", "-T", "--quick-check"];
+        args.extend_from_slice(extra);
+        args.push(source_path.to_str().unwrap());
+        args.push(dest_path.to_str().unwrap());
+        run(&args).unwrap()
+    };
+    assert!(copy(&[]).status.success());
+
+    let out = copy(&["--dry-run"]);
     assert!(out.status.success());
-    assert!(file_contains(&dest_path, text).unwrap());
-    assert!(files_match(&source_path, &dest_path));
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(!stdout.lines().any(|l| l.starts_with("copy ")), "{}", stdout);
+
+    // A change in size is picked up.
+    create_file(&source_path.join("file.txt"), "new content").unwrap();
+    let out = copy(&["--dry-run"]);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(1, stdout.lines().filter(|l| l.starts_with("copy ")).count(), "{}", stdout);
 }
 
-#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
-fn file_copy_perms(drv: &str) {
-    cfg_if! {
-        if #[cfg(feature = "test_no_xattr")] {
-            let fs_supports_xattr = false;
-        } else {
-            let fs_supports_xattr = true;
-        }
-    }
+fn file_copy_size_only(drv: &str) {
     let dir = tempdir_rel().unwrap();
     let source_path = dir.path().join("source.txt");
     let dest_path = dir.path().join("dest.txt");
-    let text = "This is a test file.";
-
-    create_file(&source_path, text).unwrap();
-
-    if fs_supports_xattr {
-        xattr::set(&source_path, "user.test", b"my test").unwrap();
-    }
-
-    let mut perms = source_path.metadata().unwrap().permissions();
-    perms.set_readonly(true);
-    set_permissions(&source_path, perms).unwrap();
+    create_file(&source_path, "source").unwrap();
+    create_file(&dest_path, "target").unwrap();
+    set_time_past(&dest_path).unwrap();
 
     let out = run(&[
         "--driver",
         drv,
+        "--size-only",
         source_path.to_str().unwrap(),
         dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-    assert!(file_contains(&dest_path, text).unwrap());
-    assert!(files_match(&source_path, &dest_path));
-    assert!(dest_path.metadata().unwrap()
-        .permissions().readonly());
-
-    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
-    if fs_supports_xattr {
-        assert_eq!(
-            xattr::get(&dest_path, "user.test").unwrap().unwrap(),
-            b"my test"
-        );
-    }
-}
-
-#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
-#[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
-fn file_copy_no_perms(drv: &str) {
-    let dir = tempdir_rel().unwrap();
-    let source_path = dir.path().join("source.txt");
-    let dest_path = dir.path().join("dest.txt");
-    let text = "This is a test file.";
-
-    create_file(&source_path, text).unwrap();
-    let mut perms = source_path.metadata().unwrap().permissions();
-    perms.set_readonly(true);
-    set_permissions(&source_path, perms).unwrap();
+    assert!(file_contains(&dest_path, "target").unwrap());
 
     let out = run(&[
         "--driver",
         drv,
-        "--no-perms",
+        "--quick-check",
         source_path.to_str().unwrap(),
         dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-    assert!(file_contains(&dest_path, text).unwrap());
-    assert!(files_match(&source_path, &dest_path));
-    assert!(!dest_path.metadata().unwrap()
-        .permissions().readonly());
+    assert!(file_contains(&dest_path, "source").unwrap());
 }
 
-
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn file_copy_timestamps(drv: &str) {
+fn file_copy_bwlimit(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let source_path = dir.path().join("source.txt");
-    let dest_path = dir.path().join("dest.txt");
-    let text = "This is a test file.";
-
-    create_file(&source_path, text).unwrap();
-
-    set_time_past(&source_path).unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    write(&source_path, rand_data(256 * 1024)).unwrap();
 
+    // 256KB at 512KB/s.
+    let start = Instant::now();
     let out = run(&[
         "--driver",
         drv,
+        "--reflink=never",
+        "--block-size=16K",
+        "--bwlimit=512K",
         source_path.to_str().unwrap(),
         dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-    assert!(file_contains(&dest_path, text).unwrap());
+    assert!(start.elapsed() >= Duration::from_millis(450));
     assert!(files_match(&source_path, &dest_path));
-
-    let smeta = source_path.metadata().unwrap();
-    let dmeta = dest_path.metadata().unwrap();
-    assert!(timestamps_same(&smeta.modified().unwrap(), &dmeta.modified().unwrap()));
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn file_copy_no_timestamps(drv: &str) {
+fn file_copy_timeout(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    write(&source_path, rand_data(512 * 1024)).unwrap();
+
+    // 512KB at 64KB/s would take 8 seconds. Disabling the progress
+    // output doesn't change the blocks the deadline is checked
+    // between.
+    for progress in ["--progress=auto", "--no-progress", "--quiet", "--progress=json"] {
+        let start = Instant::now();
+        let out = run(&[
+            "--driver",
+            drv,
+            "--reflink=never",
+            "--block-size=16K",
+            "--bwlimit=64K",
+            "--timeout=1",
+            progress,
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(Some(23), out.status.code(), "{}", progress);
+        assert!(start.elapsed() < Duration::from_secs(4));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("Timed out copying"), "{}", stderr);
+        if progress == "--progress=json" {
+            assert!(stderr.contains(r#""kind":"timed_out","#), "{}", stderr);
+        }
+    }
+}
+
+#[test]
+fn timeout_zero() {
     let dir = tempdir_rel().unwrap();
     let source_path = dir.path().join("source.txt");
     let dest_path = dir.path().join("dest.txt");
-    let text = "This is a test file.";
-
-    create_file(&source_path, text).unwrap();
-    set_time_past(&source_path).unwrap();
+    create_file(&source_path, "content").unwrap();
 
     let out = run(&[
-        "--driver",
-        drv,
-        "--no-timestamps",
+        "--timeout=0",
         source_path.to_str().unwrap(),
         dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
-    assert!(out.status.success());
-    assert!(file_contains(&dest_path, text).unwrap());
-    assert!(files_match(&source_path, &dest_path));
-
-    let smeta = source_path.metadata().unwrap();
-    let dmeta = dest_path.metadata().unwrap();
-    assert!(!timestamps_same(&smeta.modified().unwrap(), &dmeta.modified().unwrap()));
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn file_copy_rel(drv: &str) {
+fn file_copy_no_cache(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let source_path = dir.path().join("source.txt");
-    let dest_path = dir.path().join("dest.txt");
-    let text = "This is a test file.";
-
-    create_file(&source_path, text).unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    write(&source_path, rand_data(1024 * 1024)).unwrap();
 
     let out = run(&[
         "--driver",
         drv,
+        "--block-size=64K",
+        "--no-cache",
         source_path.to_str().unwrap(),
         dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-    assert!(file_contains(&dest_path, text).unwrap());
     assert!(files_match(&source_path, &dest_path));
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn file_copy_multiple(drv: &str) {
+fn file_copy_direct_io(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let dest = dir.path().join("dest");
-    create_dir_all(&dest).unwrap();
-
-    let (f1, f2) = (dir.path().join("file1.txt"), dir.path().join("file2.txt"));
-    create_file(&f1, "test").unwrap();
-    create_file(&f2, "test").unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    // The unaligned tail is copied without direct I/O.
+    write(&source_path, rand_data(1024 * 1024 + 100)).unwrap();
 
     let out = run(&[
         "--driver",
         drv,
-        "-vv",
-        f1.to_str().unwrap(),
-        f2.to_str().unwrap(),
-        dest.to_str().unwrap(),
+        "--block-size=64K",
+        "--direct-io",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-    assert!(dest.join("file1.txt").exists());
-    assert!(dest.join("file2.txt").exists());
+    assert!(files_match(&source_path, &dest_path));
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn copy_empty_dir(drv: &str) {
+fn direct_io_misaligned_block_size(drv: &str) {
     let dir = tempdir_rel().unwrap();
-
-    let source_path = dir.path().join("mydir");
-    create_dir_all(&source_path).unwrap();
-
-    let dest_base = dir.path().join("dest");
-    create_dir_all(&dest_base).unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    write(&source_path, rand_data(64 * 1024)).unwrap();
 
     let out = run(&[
         "--driver",
         drv,
-        "-r",
+        "--block-size=1000",
+        "--direct-io",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
-    assert!(out.status.success());
-
-    assert!(dest_base.join("mydir").exists());
-    assert!(dest_base.join("mydir").is_dir());
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("alignment required for direct I/O"));
+    assert!(!dest_path.exists());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn copy_target_directory(drv: &str) {
+fn file_copy_range_resume(drv: &str) {
     let dir = tempdir_rel().unwrap();
-
-    let source_path = dir.path().join("mydir");
-    create_dir_all(&source_path).unwrap();
-
-    let dest_base = dir.path().join("dest");
-    create_dir_all(&dest_base).unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    let data = rand_data(1024 * 1024);
+    write(&source_path, &data).unwrap();
+    write(&dest_path, &data[..300_000]).unwrap();
 
     let out = run(&[
         "--driver",
         drv,
-        "-r",
-        "--target-directory",
-        dest_base.to_str().unwrap(),
+        "--block-size=64K",
+        "--offset=300000",
         source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-
-    assert!(dest_base.join("mydir").exists());
-    assert!(dest_base.join("mydir").is_dir());
+    assert!(files_match(&source_path, &dest_path));
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn copy_all_dirs(drv: &str) {
+fn file_copy_range_slice(drv: &str) {
     let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let new_path = dir.path().join("new.txt");
+    create_file(&source_path, "0123456789abcdefghij").unwrap();
+    create_file(&dest_path, "XXXXXXXXXXXXXXXXXXXXXXXXX").unwrap();
 
-    let source_path = dir.path().join("mydir");
-    create_dir_all(&source_path).unwrap();
-    create_dir_all(source_path.join("one/two/three/")).unwrap();
+    for dest in [&dest_path, &new_path] {
+        let out = run(&[
+            "--driver",
+            drv,
+            "--offset=10",
+            "--length=5",
+            source_path.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert!(out.status.success());
+    }
+    assert!(file_contains(&dest_path, "XXXXXXXXXXabcdeXXXXXXXXXX").unwrap());
+    assert_eq!(b"\0\0\0\0\0\0\0\0\0\0abcde".to_vec(), std::fs::read(&new_path).unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn file_copy_append(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let data = rand_data(1024 * 1024);
+    write(&source_path, &data).unwrap();
+
+    // Cut short, left at full length with the end missing as by an
+    // interrupted copy, and mismatched.
+    let short = dir.path().join("short.bin");
+    write(&short, &data[..300_000]).unwrap();
+    let interrupted = dir.path().join("interrupted.bin");
+    let fd = File::create(&interrupted).unwrap();
+    fd.set_len(data.len() as u64).unwrap();
+    (&fd).write_all(&data[..256 * 1024]).unwrap();
+    let mismatched = dir.path().join("mismatched.bin");
+    let mut other = data.clone();
+    other[1000] ^= 0xff;
+    write(&mismatched, &other[..500_000]).unwrap();
+
+    for dest_path in [&short, &interrupted, &mismatched] {
+        let out = run(&[
+            "--driver",
+            drv,
+            "--block-size=64K",
+            "--append",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert!(out.status.success());
+        assert!(files_match(&source_path, dest_path));
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_range_overwrites_holes(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    File::create(&source_path).unwrap().set_len(256 * 1024).unwrap();
+    write(&dest_path, vec![0xff; 256 * 1024]).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--block-size=64K",
+        "--offset=0",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
+}
+
+#[test]
+fn range_exceeds_source() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "0123456789").unwrap();
+
+    for args in [["--offset=11", "--length=0"], ["--offset=5", "--length=6"]] {
+        let out = run(&[
+            args[0],
+            args[1],
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert!(!out.status.success());
+        assert!(!dest_path.exists());
+    }
+
+    let out = run(&["--offset=5", "--atomic", source_path.to_str().unwrap(), dest_path.to_str().unwrap()]).unwrap();
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn interrupt_removes_atomic_tmp(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    write(&source_path, rand_data(1024 * 1024)).unwrap();
+
+    // Slow the copy down so it can be interrupted part-way.
+    let child = get_command().unwrap()
+        .args([
+            "--driver",
+            drv,
+            "--reflink=never",
+            "--block-size=16K",
+            "--bwlimit=256K",
+            "--atomic",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    let kill = Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(kill.success());
+    let out = child.wait_with_output().unwrap();
+
+    assert_eq!(Some(20), out.status.code());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Interrupted after copying"));
+    assert!(!dest_path.exists());
+    let entries = std::fs::read_dir(dir.path()).unwrap().count();
+    assert_eq!(1, entries);
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn interrupt_truncates_partial_copy(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    let data = rand_data(1024 * 1024);
+    write(&source_path, &data).unwrap();
+
+    let child = get_command().unwrap()
+        .args([
+            "--driver",
+            drv,
+            "--reflink=never",
+            "--block-size=16K",
+            "--bwlimit=256K",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    let kill = Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(kill.success());
+    let out = child.wait_with_output().unwrap();
+    assert_eq!(Some(20), out.status.code());
+
+    // Only the data written is left, so the target isn't mistaken
+    // for a complete copy.
+    let partial = std::fs::read(&dest_path).unwrap();
+    assert!(partial.len() < data.len());
+    assert_eq!(&data[..partial.len()], &partial[..]);
+
+    let out = run(&["--driver", drv, "--size-only", source_path.to_str().unwrap(), dest_path.to_str().unwrap()]).unwrap();
+    assert!(out.status.success());
+    assert_eq!(data, std::fs::read(&dest_path).unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_checkpoint(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file1.txt"), "content1").unwrap();
+    create_file(&source_path.join("file2.txt"), "content2").unwrap();
+    let dest_path = dir.path().join("dest");
+    let checkpoint = dir.path().join("checkpoint");
+
+    let copy = || run(&[
+        "--driver", drv,
+        "-r",
+        "-T",
+        "--checkpoint", checkpoint.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(copy().status.success());
+    assert!(checkpoint.exists());
+
+    // Unchanged sources are not copied again; changed ones are.
+    create_file(&dest_path.join("file1.txt"), "modified").unwrap();
+    create_file(&source_path.join("file2.txt"), "new content2").unwrap();
+    assert!(copy().status.success());
+    assert!(file_contains(&dest_path.join("file1.txt"), "modified").unwrap());
+    assert!(file_contains(&dest_path.join("file2.txt"), "new content2").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_manifest(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    create_file(&source_path.join("sub").join("a,b.txt"), "more content").unwrap();
+
+    for (format, dest_path) in ["text", "json", "csv"].map(|f| (f, dir.path().join(format!("dest_{}", f)))) {
+        let expected = match format {
+            "text" => format!("{:?} -> {:?} 7", source_path.join("file.txt"), dest_path.join("file.txt")),
+            "json" => format!(r#"{{"source":"{}","dest":"{}","bytes":7,"checksum":null}}"#,
+                              source_path.join("file.txt").display(), dest_path.join("file.txt").display()),
+            _ => format!("\"{}\",\"{}\",12,", source_path.join("sub").join("a,b.txt").display(),
+                         dest_path.join("sub").join("a,b.txt").display()),
+        };
+        let manifest_path = dir.path().join(format!("manifest.{}", format));
+        let out = run(&[
+            "--driver",
+            drv,
+            "-r",
+            "--manifest",
+            manifest_path.to_str().unwrap(),
+            "--manifest-format",
+            format,
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert!(out.status.success());
+
+        let manifest = std::fs::read_to_string(&manifest_path).unwrap();
+        let lines = manifest.lines().collect::<Vec<_>>();
+        let records = if format == "csv" {
+            assert_eq!("source,dest,bytes,checksum", lines[0]);
+            &lines[1..]
+        } else {
+            &lines[..]
+        };
+        assert_eq!(2, records.len());
+        assert!(records.contains(&expected.as_str()), "{}", manifest);
+    }
+}
+
+#[test]
+fn file_copy_manifest_checksum() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let manifest_path = dir.path().join("manifest.txt");
+    create_file(&source_path, "abc").unwrap();
+
+    let out = run(&[
+        "--verify=sha256",
+        "--manifest",
+        manifest_path.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    let manifest = std::fs::read_to_string(&manifest_path).unwrap();
+    assert_eq!(
+        format!("{:?} -> {:?} 3 ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\n", source_path, dest_path),
+        manifest
+    );
+}
+
+#[test]
+fn bwlimit_zero() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "content").unwrap();
+
+    let out = run(&[
+        "--bwlimit=0",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
+}
+
+#[test]
+fn no_punch_holes_conflicts_with_sparse_always() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "content").unwrap();
+
+    let out = run(&[
+        "--no-punch-holes",
+        "--sparse=always",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
+}
+
+#[test]
+fn workers_zero() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "content").unwrap();
+
+    let out = run(&[
+        "--workers=0",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_single_worker(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    for i in 0..10 {
+        create_file(&source_path.join(format!("file{}.txt", i)), &format!("content {}", i)).unwrap();
+    }
+    let dest_path = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "--workers=1",
+        "--block-size=4",
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    for i in 0..10 {
+        assert!(file_contains(&dest_path.join(format!("file{}.txt", i)), &format!("content {}", i)).unwrap());
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_delete(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    create_file(&source_path.join("sub").join("file.txt"), "content").unwrap();
+    create_dir_all(dest_path.join("sub")).unwrap();
+    create_dir_all(dest_path.join("gone").join("deeper")).unwrap();
+    create_file(&dest_path.join("extra.txt"), "extra").unwrap();
+    create_file(&dest_path.join("sub").join("extra.txt"), "extra").unwrap();
+    create_file(&dest_path.join("gone").join("deeper").join("extra.txt"), "extra").unwrap();
+    create_file(&dest_path.join("kept.log"), "excluded").unwrap();
+
+    let args = |extra: &'static str| {
+        vec![
+            "--driver",
+            drv,
+            "-r",
+            "-T",
+            "--delete",
+            "--exclude",
+            "*.log",
+            extra,
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ]
+    };
+
+    let out = run(&args("--dry-run")).unwrap();
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let deletes = stdout.lines().filter(|l| l.starts_with("delete ")).collect::<Vec<_>>();
+    assert_eq!(5, deletes.len(), "{}", stdout);
+    assert!(dest_path.join("extra.txt").exists());
+
+    let out = run(&args("--no-progress")).unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("file.txt"), "content").unwrap());
+    assert!(file_contains(&dest_path.join("sub").join("file.txt"), "content").unwrap());
+    assert!(!dest_path.join("extra.txt").exists());
+    assert!(!dest_path.join("sub").join("extra.txt").exists());
+    assert!(!dest_path.join("gone").exists());
+    assert!(file_contains(&dest_path.join("kept.log"), "excluded").unwrap());
+}
+
+#[test]
+fn delete_requires_recursive() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    create_file(&source_path, "content").unwrap();
+
+    let out = run(&[
+        "--delete",
+        source_path.to_str().unwrap(),
+        dir.path().join("dest.txt").to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn file_copy(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, text).unwrap());
+    assert!(files_match(&source_path, &dest_path));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_reflink_auto(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--reflink=auto",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    // Should always work, even on non-CoW FS
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, text).unwrap());
+    assert!(files_match(&source_path, &dest_path));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_reflink_never(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--reflink=never",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    // Should always work
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, text).unwrap());
+    assert!(files_match(&source_path, &dest_path));
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn file_copy_perms(drv: &str) {
+    cfg_if! {
+        if #[cfg(feature = "test_no_xattr")] {
+            let fs_supports_xattr = false;
+        } else {
+            let fs_supports_xattr = true;
+        }
+    }
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+
+    if fs_supports_xattr {
+        xattr::set(&source_path, "user.test", b"my test").unwrap();
+    }
+
+    let mut perms = source_path.metadata().unwrap().permissions();
+    perms.set_readonly(true);
+    set_permissions(&source_path, perms).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, text).unwrap());
+    assert!(files_match(&source_path, &dest_path));
+    assert!(dest_path.metadata().unwrap()
+        .permissions().readonly());
+
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    if fs_supports_xattr {
+        assert_eq!(
+            xattr::get(&dest_path, "user.test").unwrap().unwrap(),
+            b"my test"
+        );
+    }
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn file_copy_special_bits(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.sh");
+    let kept_path = dir.path().join("kept.sh");
+    let stripped_path = dir.path().join("stripped.sh");
+    create_file(&source_path, "#!/bin/sh").unwrap();
+    set_permissions(&source_path, Permissions::from_mode(0o4755)).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        source_path.to_str().unwrap(),
+        kept_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert_eq!(0o4755, kept_path.metadata().unwrap().mode() & 0o7777);
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--no-preserve-special-bits",
+        source_path.to_str().unwrap(),
+        stripped_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert_eq!(0o755, stripped_path.metadata().unwrap().mode() & 0o7777);
+}
+
+#[test]
+fn no_preserve_special_bits_conflicts_with_preserve_mode() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "content").unwrap();
+
+    let out = run(&[
+        "--no-preserve-special-bits",
+        "--preserve=mode",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn file_copy_no_perms(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+    let mut perms = source_path.metadata().unwrap().permissions();
+    perms.set_readonly(true);
+    set_permissions(&source_path, perms).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--no-perms",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, text).unwrap());
+    assert!(files_match(&source_path, &dest_path));
+    assert!(!dest_path.metadata().unwrap()
+        .permissions().readonly());
+}
+
+
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_xattr", ignore = "No FS support")]
+fn file_copy_no_xattr(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+    xattr::set(&source_path, "user.test", b"my test").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--no-xattr",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
+    assert!(xattr::get(&dest_path, "user.test").unwrap().is_none());
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_xattr", ignore = "No FS support")]
+fn file_copy_no_perms_preserve_xattr(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+    xattr::set(&source_path, "user.test", b"my test").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--no-perms",
+        "--preserve=xattr",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
+    assert_eq!(
+        xattr::get(&dest_path, "user.test").unwrap().unwrap(),
+        b"my test"
+    );
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn file_copy_timestamps(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+
+    set_time_past(&source_path).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, text).unwrap());
+    assert!(files_match(&source_path, &dest_path));
+
+    let smeta = source_path.metadata().unwrap();
+    let dmeta = dest_path.metadata().unwrap();
+    assert!(timestamps_same(&smeta.modified().unwrap(), &dmeta.modified().unwrap()));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_no_timestamps(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+    set_time_past(&source_path).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--no-timestamps",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, text).unwrap());
+    assert!(files_match(&source_path, &dest_path));
+
+    let smeta = source_path.metadata().unwrap();
+    let dmeta = dest_path.metadata().unwrap();
+    assert!(!timestamps_same(&smeta.modified().unwrap(), &dmeta.modified().unwrap()));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_preserve_ownership(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--preserve=mode,ownership",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    // Non-root users can still chown to themselves, so this should
+    // always succeed.
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, text).unwrap());
+
+    let smeta = source_path.metadata().unwrap();
+    let dmeta = dest_path.metadata().unwrap();
+    assert_eq!(smeta.uid(), dmeta.uid());
+    assert_eq!(smeta.gid(), dmeta.gid());
+}
+
+#[test]
+fn preserve_invalid_attr() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "test").unwrap();
+
+    let out = run(&[
+        "--preserve=mode,nonsense",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Unexpected value for 'preserve': nonsense"));
+}
+
+#[test]
+fn preserve_conflicts_with_no_perms() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "test").unwrap();
+
+    let out = run(&[
+        "--preserve=mode",
+        "--no-perms",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("--no-perms and --preserve=mode cannot be set at the same time"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn dir_copy_timestamps(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let sub_path = source_path.join("subdir");
+    let dest_base = dir.path().join("dest");
+
+    create_dir_all(&sub_path).unwrap();
+    create_file(&source_path.join("file.txt"), "test").unwrap();
+    create_file(&sub_path.join("file.txt"), "test").unwrap();
+    set_time_past(&sub_path).unwrap();
+    set_time_past(&source_path).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    for (from, to) in [(&source_path, dest_base.clone()), (&sub_path, dest_base.join("subdir"))] {
+        let smeta = from.metadata().unwrap();
+        let dmeta = to.metadata().unwrap();
+        assert!(timestamps_same(&smeta.modified().unwrap(), &dmeta.modified().unwrap()));
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_rel(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, text).unwrap());
+    assert!(files_match(&source_path, &dest_path));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn file_copy_multiple(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
+
+    let (f1, f2) = (dir.path().join("file1.txt"), dir.path().join("file2.txt"));
+    create_file(&f1, "test").unwrap();
+    create_file(&f2, "test").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-vv",
+        f1.to_str().unwrap(),
+        f2.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(dest.join("file1.txt").exists());
+    assert!(dest.join("file2.txt").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn copy_empty_dir(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+
+    assert!(dest_base.join("mydir").exists());
+    assert!(dest_base.join("mydir").is_dir());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_target_directory(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--target-directory",
+        dest_base.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+
+    assert!(dest_base.join("mydir").exists());
+    assert!(dest_base.join("mydir").is_dir());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_target_directory_created(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    create_file(&source_path, "content").unwrap();
+    // Without -t a single file would be copied to this path.
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "-t", dest_base.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    assert!(dest_base.is_dir());
+    assert!(file_contains(&dest_base.join("source.txt"), "content").unwrap());
+}
+
+#[test]
+fn target_directory_conflicts_with_no_target_directory() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    create_file(&source_path, "content").unwrap();
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "-T",
+        "-t", dest_base.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    assert!(!dest_base.exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_all_dirs(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_dir_all(source_path.join("one/two/three/")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+
+    assert!(dest_base.join("mydir/one/two/three/").exists());
+    assert!(dest_base.join("mydir/one/two/three/").is_dir());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_all_dirs_rel(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_dir_all(source_path.join("one/two/three/")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+
+    assert!(dest_base.join("mydir/one/two/three/").exists());
+    assert!(dest_base.join("mydir/one/two/three/").is_dir());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn copy_dirs_files(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+
+    let mut p = source_path.clone();
+    for d in ["one", "two", "three"].iter() {
+        p.push(d);
+        create_dir_all(&p).unwrap();
+        create_file(&p.join(format!("{}.txt", d)), d).unwrap();
+    }
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+
+    assert!(dest_base.join("mydir/one/one.txt").is_file());
+    assert!(dest_base.join("mydir/one/two/two.txt").is_file());
+    assert!(dest_base.join("mydir/one/two/three/three.txt").is_file());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(not(feature = "test_run_expensive"), ignore = "Stress test")]
+fn copy_generated_tree(drv: &str) {
+    // Spam some output to keep CI from timing-out (hopefully).
+    println!("Generating file tree...");
+    let src = gen_global_filetree(false).unwrap();
+
+    let dir = tempdir_rel().unwrap();
+    let dest = dir.path().join("target");
+
+    println!("Running copy...");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        src.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    println!("Compare trees...");
+    compare_trees(&src, &dest).unwrap();
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_dirs_overwrites(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    let source_file = source_path.join("file.txt");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_file, "orig").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base).unwrap();
+    let dest_file = dest_base.join("mydir/file.txt");
+
+    let mut out = run(&[
+        "--driver", drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_file, "orig").unwrap());
+
+    write(&source_file, "new content").unwrap();
+    assert!(file_contains(&source_file, "new content").unwrap());
+
+    out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_file, "new content").unwrap());
+    assert!(files_match(&source_file, &dest_file));
+}
+
+#[test]
+/// when source path is a dir and target is a dir,
+/// using the `-no-target-directory` flag will copy the source
+/// dir _onto_ the target directory, instead of _under_ the
+/// target dir (default behavior)
+fn copy_dirs_overwrites_no_target_dir() {
+    // First pass default behavior
+    {
+        let dir = tempdir_rel().unwrap();
+
+        let source_path = dir.path().join("mydir");
+        let source_file = source_path.join("file.txt");
+        create_dir_all(&source_path).unwrap();
+        create_file(&source_file, "orig").unwrap();
+
+        let dest_base = dir.path().join("dest");
+        create_dir_all(&dest_base).unwrap();
+        let dest_file = dest_base.join("mydir/file.txt");
+
+        let out = run(&[
+            "-r",
+            source_path.to_str().unwrap(),
+            dest_base.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert!(out.status.success());
+        assert!(file_contains(&dest_file, "orig").unwrap());
+    }
+
+    // Second pass `no target directory`
+    {
+        let dir = tempdir_rel().unwrap();
+
+        let source_path = dir.path().join("mydir");
+        let source_file = source_path.join("file.txt");
+        create_dir_all(&source_path).unwrap();
+        create_file(&source_file, "new content").unwrap();
+
+        let dest_base = dir.path().join("dest");
+        create_dir_all(&dest_base).unwrap();
+        let dest_file = dest_base.join("file.txt");
+
+        let out = run(&[
+            "-r",
+            "-T", //-no-target-directory
+            source_path.to_str().unwrap(),
+            dest_base.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert!(out.status.success());
+        assert!(file_contains(&dest_file, "new content").unwrap());
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_to_nonexistent_is_rename(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    let source_file = source_path.join("file.txt");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_file, "orig").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let dest_file = dest_base.join("file.txt");
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(dest_file.exists());
+    assert!(file_contains(&dest_file, "orig").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_overwrite_with_noclobber(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    let source_file = source_path.join("file.txt");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_file, "orig").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base).unwrap();
+    let dest_file = dest_base.join("mydir/file.txt");
+
+    let mut out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_file, "orig").unwrap());
+    assert!(files_match(&source_file, &dest_file));
+
+    write(&source_file, "new content").unwrap();
+    assert!(file_contains(&source_file, "new content").unwrap());
+
+    out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--no-clobber",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_containing_symlinks(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    let source_file = source_path.join("file.txt");
+    let source_rlink = source_path.join("link.txt");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_file, "orig").unwrap();
+    symlink("file.txt", source_rlink).unwrap();
+    symlink("/etc/hosts", source_path.join("hosts")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let dest_file = dest_base.join("file.txt");
+    let dest_rlink = dest_base.join("link.txt");
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(dest_file.exists());
+    assert!(dest_rlink
+        .symlink_metadata()
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert!(dest_base
+        .join("hosts")
+        .symlink_metadata()
+        .unwrap()
+        .file_type()
+        .is_symlink());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_with_hidden_dir(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir/.hidden");
+    let source_file = source_path.join("file.txt");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_file, "orig").unwrap();
+
+    let dest_base = dir.path().join("dest/.hidden");
+    let dest_file = dest_base.join("file.txt");
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(dest_file.exists());
+    assert!(file_contains(&dest_file, "orig").unwrap());
+    assert!(files_match(&source_file, &dest_file));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_with_gitignore(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+
+    let source_file = source_path.join("file.txt");
+    create_file(&source_file, "file content").unwrap();
+
+    let ignore_file = source_path.join(".gitignore");
+    create_file(&ignore_file, "/.ignored\n").unwrap();
+
+    let ignored_path = dir.path().join("mydir/.ignored");
+    let ignored_file = ignored_path.join("file.txt");
+    create_dir_all(&ignored_path).unwrap();
+    create_file(&ignored_file, "ignored content").unwrap();
+
+    let hidden_path = dir.path().join("mydir/.hidden");
+    let hidden_file = hidden_path.join("file.txt");
+    create_dir_all(&hidden_path).unwrap();
+    create_file(&hidden_file, "hidden content").unwrap();
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--gitignore",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(dest_base.join("file.txt").exists());
+    assert!(dest_base.join(".gitignore").exists());
+
+    assert!(!dest_base.join(".ignored").exists());
+
+    assert!(dest_base.join(".hidden").exists());
+    assert!(dest_base.join(".hidden/file.txt").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_dry_run(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "file content").unwrap();
+    create_file(&source_path.join(".gitignore"), "/.ignored\n").unwrap();
+
+    let ignored_path = source_path.join(".ignored");
+    create_dir_all(&ignored_path).unwrap();
+    create_file(&ignored_path.join("file.txt"), "ignored content").unwrap();
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--gitignore",
+        "--dry-run",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(!dest_base.exists());
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains(&format!("mkdir {:?} -> {:?}", source_path, dest_base)));
+    assert!(stdout.contains(&format!("copy {:?} -> {:?}", source_path.join("file.txt"), dest_base.join("file.txt"))));
+    assert!(!stdout.contains(".ignored"));
+    // file.txt + .gitignore
+    assert!(stdout.contains("Would copy 22 B"));
+}
+
+#[test]
+fn stream_from_stdin() {
+    let dir = tempdir_rel().unwrap();
+    let dest_path = dir.path().join("dest.txt");
+
+    let mut child = get_command().unwrap()
+        .args(["-", dest_path.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"streamed content").unwrap();
+    let status = child.wait().unwrap();
+
+    assert!(status.success());
+    assert!(file_contains(&dest_path, "streamed content").unwrap());
+}
+
+#[test]
+fn from_file_relative() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("src");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("a").join("b")).unwrap();
+    create_dir_all(source_path.join("tree")).unwrap();
+    create_file(&source_path.join("top.txt"), "top").unwrap();
+    create_file(&source_path.join("a").join("b").join("deep.txt"), "deep").unwrap();
+    create_file(&source_path.join("tree").join("file.txt"), "tree").unwrap();
+    create_file(&source_path.join("unlisted.txt"), "unlisted").unwrap();
+    let list_path = dir.path().join("list.txt");
+    create_file(&list_path, "top.txt\n./a/b/deep.txt\n\ntree\n").unwrap();
+
+    let out = get_command().unwrap()
+        .current_dir(&source_path)
+        .args([
+            "-r",
+            "--from-file",
+            list_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(file_contains(&dest_path.join("top.txt"), "top").unwrap());
+    assert!(file_contains(&dest_path.join("a").join("b").join("deep.txt"), "deep").unwrap());
+    assert!(file_contains(&dest_path.join("tree").join("file.txt"), "tree").unwrap());
+    assert!(!dest_path.join("unlisted.txt").exists());
+}
+
+#[test]
+fn from_file_stdin_nul() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("src");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("with\nnewline.txt"), "newline").unwrap();
+    create_file(&source_path.join("sub").join("file.txt"), "content").unwrap();
+
+    let mut child = get_command().unwrap()
+        .current_dir(&source_path)
+        .args(["--from-file", "-", "--from0", dest_path.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"with\nnewline.txt\0sub/file.txt\0").unwrap();
+    let status = child.wait().unwrap();
+
+    assert!(status.success());
+    assert!(file_contains(&dest_path.join("with\nnewline.txt"), "newline").unwrap());
+    assert!(file_contains(&dest_path.join("sub").join("file.txt"), "content").unwrap());
+}
+
+#[test]
+fn from_file_rejects_sources_and_parents() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let list_path = dir.path().join("list.txt");
+    create_file(&source_path, "content").unwrap();
+    create_file(&list_path, "../source.txt\n").unwrap();
+
+    let out = run(&[
+        "--from-file",
+        list_path.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dir.path().join("dest").to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+
+    // The listed path exists, but has no place under the destination.
+    let sub_path = dir.path().join("sub");
+    create_dir_all(&sub_path).unwrap();
+    let out = get_command().unwrap()
+        .current_dir(&sub_path)
+        .args(["--from-file", list_path.to_str().unwrap(), "dest"])
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("cannot contain '..'"), "{}", stderr);
+}
+
+#[test]
+fn stream_to_stdout() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let data = rand_data(3 * 1024 * 1024);
+    write(&source_path, &data).unwrap();
+
+    let out = get_command().unwrap()
+        .args(["--no-progress", source_path.to_str().unwrap(), "-"])
+        .output()
+        .unwrap();
+
+    assert!(out.status.success());
+    assert_eq!(data, out.stdout);
+}
+
+#[test]
+fn stream_from_stdin_no_clobber() {
+    let dir = tempdir_rel().unwrap();
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&dest_path, "original content").unwrap();
+
+    let out = get_command().unwrap()
+        .args(["--no-clobber", "-", dest_path.to_str().unwrap()])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert!(!out.status.success());
+    assert!(file_contains(&dest_path, "original content").unwrap());
+}
+
+#[test]
+fn stream_multiple_sources() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    create_file(&source_path, "content").unwrap();
+
+    let out = run(&[
+        source_path.to_str().unwrap(),
+        "-",
+        dir.path().join("dest.txt").to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Only a single source is supported"));
+}
+
+// Creating a mount-point requires privileges, so this only checks
+// that a single-filesystem tree is copied in full.
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_one_file_system(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    create_file(&source_path.join("sub").join("file.txt"), "sub content").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--one-file-system",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("file.txt"), "content").unwrap());
+    assert!(file_contains(&dest_path.join("sub").join("file.txt"), "sub content").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_max_depth(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub").join("deeper")).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    create_file(&source_path.join("sub").join("file.txt"), "sub content").unwrap();
+    create_file(&source_path.join("sub").join("deeper").join("file.txt"), "deeper content").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--max-depth",
+        "1",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("file.txt"), "content").unwrap());
+    assert!(dest_path.join("sub").is_dir());
+    assert!(!dest_path.join("sub").join("file.txt").exists());
+    assert!(!dest_path.join("sub").join("deeper").exists());
+
+    let dest_path = dir.path().join("dest2");
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--max-depth",
+        "2",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("sub").join("file.txt"), "sub content").unwrap());
+    assert!(dest_path.join("sub").join("deeper").is_dir());
+    assert!(!dest_path.join("sub").join("deeper").join("file.txt").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_exclude(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub").join("deeper")).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    create_file(&source_path.join("file.tmp"), "tmp").unwrap();
+    create_file(&source_path.join("sub").join("deeper").join("other.tmp"), "tmp").unwrap();
+    create_file(&source_path.join("sub").join("other.txt"), "sub content").unwrap();
+    create_file(&source_path.join("other.txt"), "top content").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--exclude",
+        "*.tmp",
+        "--exclude",
+        "sub/other.txt",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("file.txt"), "content").unwrap());
+    assert!(file_contains(&dest_path.join("other.txt"), "top content").unwrap());
+    assert!(dest_path.join("sub").join("deeper").exists());
+    assert!(!dest_path.join("file.tmp").exists());
+    assert!(!dest_path.join("sub").join("deeper").join("other.tmp").exists());
+    assert!(!dest_path.join("sub").join("other.txt").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_include_after_exclude(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("keep.log"), "keep").unwrap();
+    create_file(&source_path.join("drop.log"), "drop").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--exclude",
+        "*.log",
+        "--include",
+        "keep.log",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("keep.log"), "keep").unwrap());
+    assert!(!dest_path.join("drop.log").exists());
+
+    // The last matching pattern wins, so reversing the order excludes both.
+    let dest_path = dir.path().join("dest2");
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--include",
+        "keep.log",
+        "--exclude",
+        "*.log",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(!dest_path.join("keep.log").exists());
+    assert!(!dest_path.join("drop.log").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_include_overrides_gitignore(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join(".gitignore"), "*.log\n").unwrap();
+    create_file(&source_path.join("keep.log"), "keep").unwrap();
+    create_file(&source_path.join("drop.log"), "drop").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--gitignore",
+        "--include",
+        "keep.log",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("keep.log"), "keep").unwrap());
+    assert!(!dest_path.join("drop.log").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_xcpignore(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("build")).unwrap();
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join(".gitignore"), "*.log\n").unwrap();
+    create_file(&source_path.join(".xcpignore"), "build/\n!keep.log\n").unwrap();
+    create_file(&source_path.join("sub").join(".xcpignore"), "*.tmp\n").unwrap();
+    create_file(&source_path.join("build").join("out.o"), "object").unwrap();
+    create_file(&source_path.join("keep.log"), "keep").unwrap();
+    create_file(&source_path.join("drop.log"), "drop").unwrap();
+    create_file(&source_path.join("top.tmp"), "top").unwrap();
+    create_file(&source_path.join("sub").join("sub.tmp"), "sub").unwrap();
+    create_file(&source_path.join("sub").join("file.txt"), "content").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--gitignore",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(!dest_path.join("build").exists());
+    assert!(file_contains(&dest_path.join("keep.log"), "keep").unwrap());
+    assert!(!dest_path.join("drop.log").exists());
+    assert!(file_contains(&dest_path.join("top.tmp"), "top").unwrap());
+    assert!(!dest_path.join("sub").join("sub.tmp").exists());
+    assert!(file_contains(&dest_path.join("sub").join("file.txt"), "content").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_no_gitignore(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("node_modules")).unwrap();
+    create_file(&source_path.join(".xcpignore"), "node_modules/\n").unwrap();
+    create_file(&source_path.join("node_modules").join("dep.js"), "dep").unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    create_file(&source_path.join("file.bak"), "backup").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--no-gitignore",
+        "--exclude",
+        "*.bak",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("node_modules").join("dep.js"), "dep").unwrap());
+    assert!(file_contains(&dest_path.join("file.txt"), "content").unwrap());
+    assert!(!dest_path.join("file.bak").exists());
+}
+
+#[test]
+fn no_gitignore_conflicts_with_gitignore() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+
+    let out = run(&[
+        "-r",
+        "--gitignore",
+        "--no-gitignore",
+        source_path.to_str().unwrap(),
+        dir.path().join("dest").to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_ignore_file(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    let ignore_path = dir.path().join("backup.ignore");
+    create_dir_all(&source_path).unwrap();
+    create_file(&ignore_path, "*.bak\n").unwrap();
+    create_file(&source_path.join(".xcpignore"), "!keep.bak\n").unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    create_file(&source_path.join("drop.bak"), "drop").unwrap();
+    create_file(&source_path.join("keep.bak"), "keep").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--ignore-file",
+        ignore_path.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("file.txt"), "content").unwrap());
+    assert!(!dest_path.join("drop.bak").exists());
+    assert!(file_contains(&dest_path.join("keep.bak"), "keep").unwrap());
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--ignore-file",
+        dir.path().join("missing").to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dir.path().join("dest2").to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_json_progress(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file1.txt"), "content1").unwrap();
+    create_file(&source_path.join("file2.txt"), "content2").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--progress=json",
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let records = stderr.lines()
+        .filter(|l| l.starts_with('{'))
+        .collect::<Vec<&str>>();
+    assert!(records.iter().all(|r| r.ends_with('}')));
+    assert!(records.iter().any(|r| r.contains(r#""type":"progress""#) && r.contains("file1.txt")));
+    let summary = records.last().unwrap();
+    // The directory and both files.
+    assert!(summary.starts_with(r#"{"type":"summary","total":16,"copied":16,"reflinked":0,"files":2,"reflinked_files":0,"errors":0,"entries":3,"#));
+    assert!(summary.contains(r#""bytes_per_sec":"#));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_summary(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file1.txt"), "content1").unwrap();
+    create_file(&source_path.join("file2.txt"), "content2").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.lines().any(|l| l.starts_with("Copied 2 files, 16 B in ")));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_summary_with_failure(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file1.txt"), "content1").unwrap();
+    create_file(&source_path.join("file2.txt"), "content2").unwrap();
+    symlink("file1.txt", source_path.join("link")).unwrap();
+    // Non-empty directories in the way fail a file and the symlink.
+    create_dir_all(dest_path.join("file2.txt/sub")).unwrap();
+    create_dir_all(dest_path.join("link/sub")).unwrap();
+
+    let copy = |extra: &[&str]| {
+        let mut args = vec!["--driver", drv, "-r", "-T", "--continue-on-error"];
+        args.extend_from_slice(extra);
+        args.push(source_path.to_str().unwrap());
+        args.push(dest_path.to_str().unwrap());
+        let out = run(&args).unwrap();
+        assert!(!out.status.success());
+        String::from_utf8(out.stderr).unwrap()
+    };
+
+    let stderr = copy(&[]);
+    assert!(stderr.lines().any(|l| l.starts_with("Copied 1 files, 8 B in ")), "{}", stderr);
+
+    let stderr = copy(&["--progress=json"]);
+    let summary = stderr.lines().rfind(|l| l.starts_with(r#"{"type":"summary""#)).unwrap();
+    assert!(summary.contains(r#""copied":8,"reflinked":0,"files":1,"reflinked_files":0,"errors":2,"#), "{}", summary);
+    // The failed entries are complete too.
+    assert!(summary.contains(r#""entries":4,"#), "{}", summary);
+}
+
+#[test]
+fn json_progress_error() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "content").unwrap();
+    create_file(&dest_path, "existing").unwrap();
+
+    let out = run(&[
+        "--progress=json",
+        "--no-clobber",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.lines().any(|l| l.starts_with(r#"{"type":"error","path":"#) && l.contains("dest.txt")));
+}
+
+#[test]
+fn json_progress_error_errno() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let not_dir = dir.path().join("notdir");
+    create_file(&source_path, "content").unwrap();
+    create_file(&not_dir, "file").unwrap();
+    let tee_path = not_dir.join("tee.txt");
+
+    let out = run(&[
+        "--progress=json",
+        "--continue-on-error",
+        "--tee",
+        tee_path.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(file_contains(&dest_path, "content").unwrap());
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let expected = format!(r#"{{"type":"error","path":"{}","kind":"copy_failed","errno":20,"#, tee_path.to_str().unwrap());
+    assert!(stderr.lines().any(|l| l.starts_with(&expected)));
+}
+
+#[test]
+fn no_progress_conflicts_with_progress() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "content").unwrap();
+
+    let out = run(&[
+        "--no-progress",
+        "--progress=json",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_quiet(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    let dest_path = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "--quiet",
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(out.stderr.is_empty());
+    assert!(file_contains(&dest_path.join("file.txt"), "content").unwrap());
+}
+
+#[test]
+fn quiet_conflicts_with_verbose() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "content").unwrap();
+
+    let out = run(&[
+        "-q",
+        "-v",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_low_fd_limit(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    for i in 0..500 {
+        create_file(&source_path.join(format!("file{}.txt", i)), "content").unwrap();
+    }
+
+    // Run under a low open-file limit and let the queue length be
+    // derived from it.
+    let out = Command::new("sh")
+        .args([
+            "-c",
+            "ulimit -n 64 && exec \"$0\" \"$@\"",
+            env!("CARGO_BIN_EXE_xcp"),
+            "--driver",
+            drv,
+            "-r",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+    println!("STDERR: {}", String::from_utf8_lossy(&out.stderr));
+    assert!(out.status.success());
+
+    for i in 0..500 {
+        assert!(file_contains(&dest_path.join(format!("file{}.txt", i)), "content").unwrap());
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_max_open_files(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    for i in 0..100 {
+        create_file(&source_path.join(format!("file{}.txt", i)), "content").unwrap();
+    }
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--max-open-files",
+        "2",
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    for i in 0..100 {
+        assert!(file_contains(&dest_path.join(format!("file{}.txt", i)), "content").unwrap());
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_verify(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    let data = rand_data(1024 * 1024);
+    write(&source_path, &data).unwrap();
+
+    for algo in ["--verify", "--verify=xxhash", "--verify=sha256"] {
+        let out = run(&[
+            "--driver",
+            drv,
+            "--block-size",
+            "64KiB",
+            algo,
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert!(out.status.success());
+        assert!(files_match(&source_path, &dest_path));
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_atomic(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    let data = rand_data(1024 * 1024);
+    write(&source_path, &data).unwrap();
+    create_file(&dest_path, "old content").unwrap();
+
+    for mode in ["--atomic", "--atomic=always"] {
+        let out = run(&[
+            "--driver",
+            drv,
+            "--block-size",
+            "64KiB",
+            "--verify",
+            mode,
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert!(out.status.success());
+        assert!(files_match(&source_path, &dest_path));
+        // No temporary files are left behind.
+        assert_eq!(2, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_atomic(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    for i in 0..20 {
+        create_file(&source_path.join(format!("file{}.txt", i)), &format!("content {}", i)).unwrap();
+    }
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--atomic",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert_eq!(20, std::fs::read_dir(&dest_path).unwrap().count());
+    for i in 0..20 {
+        assert!(file_contains(&dest_path.join(format!("file{}.txt", i)), &format!("content {}", i)).unwrap());
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn file_copy_atomic_symlink_dest(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let real_path = dir.path().join("real.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "new content").unwrap();
+    create_file(&real_path, "old content").unwrap();
+    symlink("real.txt", &dest_path).unwrap();
+
+    // A symlink can't be replaced without replacing the link itself.
+    let out = run(&[
+        "--driver",
+        drv,
+        "--atomic=always",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    assert!(file_contains(&real_path, "old content").unwrap());
+
+    // ...but 'auto' falls back to writing through it.
+    let out = run(&[
+        "--driver",
+        drv,
+        "--atomic=auto",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(dest_path.symlink_metadata().unwrap().file_type().is_symlink());
+    assert!(file_contains(&real_path, "new content").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_trailing_slash_dest(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest");
+    create_file(&source_path, "content").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        source_path.to_str().unwrap(),
+        &format!("{}/", dest_path.to_str().unwrap()),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(dest_path.is_dir());
+    assert!(file_contains(&dest_path.join("source.txt"), "content").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_trailing_slash_dest(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let other_path = dir.path().join("other.txt");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    create_file(&other_path, "other").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        other_path.to_str().unwrap(),
+        &format!("{}/", dest_path.to_str().unwrap()),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("mydir").join("file.txt"), "content").unwrap());
+    assert!(file_contains(&dest_path.join("other.txt"), "other").unwrap());
+}
+
+#[test]
+fn dry_run_trailing_slash_dest() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+
+    let out = run(&[
+        "-r",
+        "--dry-run",
+        source_path.to_str().unwrap(),
+        &format!("{}/", dest_path.to_str().unwrap()),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(!dest_path.exists());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains(&format!("{:?}", dest_path.join("mydir").join("file.txt"))));
+}
+
+#[test]
+fn trailing_slash_dest_is_file() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "new").unwrap();
+    create_file(&dest_path, "old").unwrap();
+
+    let out = run(&[
+        source_path.to_str().unwrap(),
+        &format!("{}/", dest_path.to_str().unwrap()),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    assert!(file_contains(&dest_path, "old").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_parents(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("a").join("b").join("dest.txt");
+    create_file(&source_path, "content").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
+
+    let out = run(&[
+        "--driver", drv,
+        "-D",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "content").unwrap());
+}
+
+#[test]
+fn parents_not_a_directory() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let blocker = dir.path().join("a");
+    create_file(&source_path, "content").unwrap();
+    create_file(&blocker, "not a directory").unwrap();
+
+    let out = run(&[
+        "--parents",
+        source_path.to_str().unwrap(),
+        blocker.join("b").join("dest.txt").to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("not a directory"));
+    assert!(file_contains(&blocker, "not a directory").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_contents(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_dir_all(&dest_path).unwrap();
+    create_file(&source_path.join("file.txt"), "file").unwrap();
+    create_file(&source_path.join("sub/nested.txt"), "nested").unwrap();
+    create_file(&dest_path.join("existing.txt"), "existing").unwrap();
+
+    let contents = format!("{}/", source_path.to_str().unwrap());
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--copy-contents",
+        &contents,
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("file.txt"), "file").unwrap());
+    assert!(file_contains(&dest_path.join("sub/nested.txt"), "nested").unwrap());
+    assert!(file_contains(&dest_path.join("existing.txt"), "existing").unwrap());
+    assert!(!dest_path.join("mydir").exists());
+
+    // Without the option a trailing slash is ignored, as with cp.
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        &contents,
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("mydir/file.txt"), "file").unwrap());
+}
+
+#[test]
+fn file_copy_parent_missing() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("a").join("dest.txt");
+    create_file(&source_path, "content").unwrap();
+
+    let out = run(&[
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("parent directory does not exist"));
+    assert!(!dir.path().join("a").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_remove_source_files(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    let data = rand_data(1024 * 1024);
+    write(&source_path, &data).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--block-size",
+        "64KiB",
+        "--verify",
+        "--remove-source-files",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(!source_path.exists());
+    assert_eq!(data, std::fs::read(&dest_path).unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_remove_source_files(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub").join("deeper")).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    create_file(&source_path.join("sub").join("deeper").join("file.txt"), "deeper content").unwrap();
+    symlink("file.txt", source_path.join("link.txt")).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--remove-source-files",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(!source_path.exists());
+    assert!(file_contains(&dest_path.join("file.txt"), "content").unwrap());
+    assert!(file_contains(&dest_path.join("sub").join("deeper").join("file.txt"), "deeper content").unwrap());
+    assert!(dest_path.join("link.txt").symlink_metadata().unwrap().file_type().is_symlink());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_remove_source_files_excluded(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_dir_all(source_path.join("other")).unwrap();
+    create_file(&source_path.join("sub").join("keep.tmp"), "tmp").unwrap();
+    create_file(&source_path.join("other").join("file.txt"), "content").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--exclude",
+        "*.tmp",
+        "--remove-source-files",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    // Files that weren't copied, and their parents, are left alone.
+    assert!(file_contains(&source_path.join("sub").join("keep.tmp"), "tmp").unwrap());
+    assert!(!source_path.join("other").exists());
+    assert!(file_contains(&dest_path.join("other").join("file.txt"), "content").unwrap());
+}
+
+#[test]
+fn remove_source_files_conflicts_with_dereference() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "content").unwrap();
+
+    let out = run(&[
+        "-L",
+        "--remove-source-files",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    assert!(source_path.exists());
+    assert!(!dest_path.exists());
+}
+
+#[test]
+fn dir_copy_auto_driver() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("small.txt"), "content").unwrap();
+    write(source_path.join("large.bin"), rand_data(4 * 1024 * 1024)).unwrap();
+
+    let out = run(&[
+        "--driver",
+        "auto",
+        "--block-size",
+        "64KiB",
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("small.txt"), "content").unwrap());
+    assert!(files_match(&source_path.join("large.bin"), &dest_path.join("large.bin")));
+}
+
+#[test]
+fn atomic_invalid_mode() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "file content").unwrap();
+
+    let out = run(&[
+        "--atomic=sometimes",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
+}
+
+#[test]
+fn verify_invalid_algorithm() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "file content").unwrap();
+
+    let out = run(&[
+        "--verify=md4",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Unexpected value for 'verify'"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_glob(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
+
+    let (f1, f2) = (dir.path().join("file1.txt"), dir.path().join("file2.txt"));
+    create_file(&f1, "test").unwrap();
+    create_file(&f2, "test").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--glob",
+        dir.path().join("file*.txt").to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(dest.join("file1.txt").exists());
+    assert!(dest.join("file2.txt").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_pattern_no_glob(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
+
+    let f1 = dir.path().join("a [b] c.txt");
+    create_file(&f1, "test").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        dir.path().join("a [b] c.txt").to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(dest.join("a [b] c.txt").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn glob_pattern_error(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
+
+    let (f1, f2) = (dir.path().join("file1.txt"), dir.path().join("file2.txt"));
+    create_file(&f1, "test").unwrap();
+    create_file(&f2, "test").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--glob",
+        dir.path().join("file***.txt").to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Pattern syntax error"));
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_sockets")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_sockets", ignore = "No FS support")]
+fn test_socket_file(drv: &str) {
+
+    let dir = tempdir_rel().unwrap();
+    let from = dir.path().join("from.sock");
+    let to = dir.path().join("to.sock");
+
+    let _sock = UnixListener::bind(&from).unwrap();
+    let ftype = from.metadata().unwrap().file_type();
+    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+
+    let out = run(&[
+        "--driver", drv,
+        from.to_str().unwrap(),
+        to.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    assert!(to.exists());
+    let ftype = to.metadata().unwrap().file_type();
+    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_sockets")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_sockets", ignore = "No FS support")]
+fn test_sockets_dir(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let src_dir = dir.path().join("fromdir");
+    create_dir_all(&src_dir).unwrap();
+
+    let from = src_dir.join("from.sock");
+    let _sock = UnixListener::bind(&from).unwrap();
+    let ftype = from.metadata().unwrap().file_type();
+    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+
+    let to_dir = dir.path().join("todir");
+    let to = to_dir.join("from.sock");
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        src_dir.to_str().unwrap(),
+        to_dir.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    assert!(to.exists());
+    let ftype = to.metadata().unwrap().file_type();
+    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn fifo_recreated(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let from = dir.path().join("from.fifo");
+    let to = dir.path().join("to.fifo");
+    assert!(Command::new("mkfifo").arg(&from).status().unwrap().success());
+
+    let out = run(&[
+        "--driver", drv,
+        from.to_str().unwrap(),
+        to.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    assert!(to.metadata().unwrap().file_type().is_fifo());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn fifo_read_special(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let from = dir.path().join("from.fifo");
+    let to = dir.path().join("to.dat");
+    assert!(Command::new("mkfifo").arg(&from).status().unwrap().success());
+
+    let data = rand_data(3 * 1024 * 1024 + 17);
+    let writer = {
+        let (from, data) = (from.clone(), data.clone());
+        std::thread::spawn(move || File::create(from).unwrap().write_all(&data).unwrap())
+    };
+
+    let out = run(&[
+        "--driver", drv,
+        "--read-special",
+        from.to_str().unwrap(),
+        to.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    writer.join().unwrap();
+
+    assert!(to.metadata().unwrap().is_file());
+    assert_eq!(data, std::fs::read(&to).unwrap());
+    // The source is left alone.
+    assert!(from.symlink_metadata().unwrap().file_type().is_fifo());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn fifo_pipe_through(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let from = dir.path().join("from.fifo");
+    let to = dir.path().join("to.fifo");
+    assert!(Command::new("mkfifo").arg(&from).status().unwrap().success());
+    assert!(Command::new("mkfifo").arg(&to).status().unwrap().success());
+
+    let data = rand_data(3 * 1024 * 1024 + 17);
+    let writer = {
+        let (from, data) = (from.clone(), data.clone());
+        std::thread::spawn(move || File::create(from).unwrap().write_all(&data).unwrap())
+    };
+    let reader = {
+        let to = to.clone();
+        std::thread::spawn(move || std::fs::read(to).unwrap())
+    };
+
+    let out = run(&[
+        "--driver", drv,
+        "--pipe-through",
+        "--timeout=30",
+        from.to_str().unwrap(),
+        to.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    writer.join().unwrap();
+
+    assert_eq!(data, reader.join().unwrap());
+    assert!(to.symlink_metadata().unwrap().file_type().is_fifo());
+}
+
+#[test]
+fn fifo_pipe_through_timeout() {
+    let dir = tempdir_rel().unwrap();
+    let from = dir.path().join("from.fifo");
+    let to = dir.path().join("to.fifo");
+    assert!(Command::new("mkfifo").arg(&from).status().unwrap().success());
+
+    // Nothing opens either end.
+    let out = run(&[
+        "--pipe-through",
+        "--timeout=1",
+        from.to_str().unwrap(),
+        to.to_str().unwrap(),
+    ]).unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Timed out"));
+    // The FIFO is still recreated.
+    assert!(to.symlink_metadata().unwrap().file_type().is_fifo());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn unreadable_file_error(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+
+    let perms = Permissions::from_mode(0o000);
+    set_permissions(&source_path, perms).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn unreadable_file_continue_on_error(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let bad_file = source_path.join("bad.txt");
+    let good_file = source_path.join("good.txt");
+    let dest_base = dir.path().join("dest");
+    let text = "This is a test file.";
+
+    create_dir_all(&source_path).unwrap();
+    create_file(&bad_file, text).unwrap();
+    create_file(&good_file, text).unwrap();
+    set_permissions(&bad_file, Permissions::from_mode(0o000)).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--continue-on-error",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    assert_eq!(Some(23), out.status.code());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("1 files failed to copy"));
+    assert!(stderr.contains("bad.txt"));
+    assert!(file_contains(&dest_base.join("good.txt"), text).unwrap());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn unreadable_dir_continue_on_error(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let bad_dir = source_path.join("bad");
+    let good_file = source_path.join("good.txt");
+    let dest_base = dir.path().join("dest");
+    let text = "This is a test file.";
+
+    create_dir_all(&bad_dir).unwrap();
+    create_file(&bad_dir.join("hidden.txt"), text).unwrap();
+    create_file(&good_file, text).unwrap();
+    set_permissions(&bad_dir, Permissions::from_mode(0o000)).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("bad"));
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "-T",
+        "--continue-on-error",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    set_permissions(&bad_dir, Permissions::from_mode(0o755)).unwrap();
+
+    assert_eq!(Some(23), out.status.code());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("1 files failed to copy"));
+    assert!(file_contains(&dest_base.join("good.txt"), text).unwrap());
+    assert!(!dest_base.join("bad/hidden.txt").exists());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn dest_file_exists_not_writable(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+
+    {
+        create_file(&source_path, "falskjdfa;lskdjfa").unwrap();
+        File::create(&dest_path).unwrap();
+    }
+    set_permissions(&dest_path, Permissions::from_mode(0o000)).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_dirs_backup(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    let source_file = source_path.join("file.txt");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_file, "orig").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base).unwrap();
+    let dummy = dest_base.join("mydir/dummy_dir"); // Non-file member test
+    create_dir_all(&dummy).unwrap();
+    let dest_file = dest_base.join("mydir/file.txt");
+
+    let mut out = run(&[
+        "--driver", drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_file, "orig").unwrap());
+
+    // -- //
+
+    out = run(&[
+        "--driver", drv,
+        "-r",
+        "--backup=auto",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    let backup1 = dest_base.join("mydir/file.txt.~1~");
+    assert!(!backup1.exists());
+
+    write(&source_file, "new content").unwrap();
+    assert!(file_contains(&source_file, "new content").unwrap());
+
+    out = run(&[
+        "--driver", drv,
+        "-r",
+        "--backup=numbered",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_file, "new content").unwrap());
+    assert!(backup1.exists());
+    assert!(files_match(&source_file, &dest_file));
+
+    // -- //
+    let backup2 = dest_base.join("mydir/file.txt.~2~");
+    assert!(!backup2.exists());
+
+    write(&source_file, "new content 2").unwrap();
+    assert!(file_contains(&source_file, "new content 2").unwrap());
+
+    out = run(&[
+        "--driver", drv,
+        "-r",
+        "--backup=auto",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_file, "new content 2").unwrap());
+    assert!(backup1.exists());
+    assert!(backup2.exists());
+    assert!(files_match(&source_file, &dest_file));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_dirs_backup_simple(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    let source_file = source_path.join("file.txt");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_file, "orig").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let dest_file = dest_base.join("file.txt");
+    let backup = dest_base.join("file.txt~");
+
+    let mut out = run(&[
+        "--driver", drv,
+        "-r",
+        "--backup=simple",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_file, "orig").unwrap());
+    assert!(!backup.exists());
+
+    write(&source_file, "new content").unwrap();
+    out = run(&[
+        "--driver", drv,
+        "-T",
+        "-r",
+        "--backup=simple",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_file, "new content").unwrap());
+    assert!(file_contains(&backup, "orig").unwrap());
+
+    // Simple backups are replaced, not accumulated.
+    write(&source_file, "new content 2").unwrap();
+    out = run(&[
+        "--driver", drv,
+        "-T",
+        "-r",
+        "--backup=simple",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_file, "new content 2").unwrap());
+    assert!(file_contains(&backup, "new content").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_backup_suffix(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "new").unwrap();
+    create_file(&dest_path, "old").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--backup=simple",
+        "--suffix=.bak",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "new").unwrap());
+    assert!(file_contains(&dir.path().join("dest.txt.bak"), "old").unwrap());
+}
+
+#[test]
+fn backup_invalid_suffix() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "new").unwrap();
+
+    let out = run(&[
+        "--backup=simple",
+        "--suffix=sub/x",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_deref_symlinks(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    let source_file = source_path.join("file.txt");
+    let source_rlink = source_path.join("link.txt");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_file, "orig").unwrap();
+    symlink("file.txt", source_rlink).unwrap();
+    symlink("/etc/hosts", source_path.join("hosts")).unwrap();
 
     let dest_base = dir.path().join("dest");
-    create_dir_all(&dest_base).unwrap();
+    let dest_file = dest_base.join("file.txt");
+    let dest_rlink = dest_base.join("link.txt");
+
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(dest_file.exists());
+    assert!(! dest_rlink
+        .symlink_metadata()
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert!(! dest_base
+        .join("hosts")
+        .symlink_metadata()
+        .unwrap()
+        .file_type()
+        .is_symlink());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn file_copy_deref_command_line(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_file = dir.path().join("file.txt");
+    let source_link = dir.path().join("link.txt");
+    create_file(&source_file, "orig").unwrap();
+    symlink("file.txt", &source_link).unwrap();
+    let dest_file = dir.path().join("dest.txt");
+
+    let out = run(&[
+        "--driver", drv,
+        "-H",
+        source_link.to_str().unwrap(),
+        dest_file.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(!dest_file.symlink_metadata().unwrap().file_type().is_symlink());
+    assert!(file_contains(&dest_file, "orig").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_deref_command_line(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    symlink("file.txt", source_path.join("link.txt")).unwrap();
+    let source_dlink = dir.path().join("dirlink");
+    symlink("mydir", &source_dlink).unwrap();
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "-H",
+        source_dlink.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    // The top-level link is followed...
+    assert!(dest_base.symlink_metadata().unwrap().is_dir());
+    assert!(file_contains(&dest_base.join("file.txt"), "orig").unwrap());
+    // ...but links within the tree are not.
+    assert!(dest_base.join("link.txt").symlink_metadata().unwrap().file_type().is_symlink());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_symlink_source(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    let source_dlink = dir.path().join("dirlink");
+    symlink("mydir", &source_dlink).unwrap();
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        source_dlink.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    // Without -H the link is copied as-is, and not descended into.
+    assert!(dest_base.symlink_metadata().unwrap().file_type().is_symlink());
+    assert_eq!(1, std::fs::read_dir(&source_path).unwrap().count());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn test_nested_symlinks(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+
+    let file = source_path.join("link-0.txt");
+    create_file(&file, "data").unwrap();
+
+    for i in 1..10 {
+        let from = source_path.join(format!("link-{}.txt", i-1));
+        let to = source_path.join(format!("link-{}.txt", i));
+        symlink(from, to).unwrap();
+    }
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    for i in 1..10 {
+        let dest_link = dest_base.join(format!("link-{}.txt", i));
+        assert!(dest_link.exists());
+        assert!(!dest_link.is_symlink());
+        assert!(dest_link.is_file());
+        assert!(file_contains(&dest_link, "data").unwrap());
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_deref_symlinked_dir(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let other_path = dir.path().join("other");
+    create_dir_all(&other_path).unwrap();
+    create_file(&other_path.join("file.txt"), "other data").unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    symlink(&other_path, source_path.join("linked")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    let dest_linked = dest_base.join("linked");
+    assert!(dest_linked.symlink_metadata().unwrap().is_dir());
+    assert!(file_contains(&dest_linked.join("file.txt"), "other data").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_deref_symlink_loop(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    let sub_path = source_path.join("sub");
+    create_dir_all(&sub_path).unwrap();
+    create_file(&sub_path.join("file.txt"), "data").unwrap();
+    symlink("..", sub_path.join("loop")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Symlink loop detected"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn test_deep_symlinks(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+
+    let file = source_path.join("link-0.txt");
+    create_file(&file, "data").unwrap();
+
+    for i in 1..100 {
+        let from = source_path.join(format!("link-{}.txt", i-1));
+        let to = source_path.join(format!("link-{}.txt", i));
+        symlink(from, to).unwrap();
+    }
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Too many levels of symbolic links"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_preserve_links(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    std::fs::hard_link(source_path.join("file.txt"), source_path.join("sub").join("link.txt")).unwrap();
+
+    let plain_path = dir.path().join("plain");
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        plain_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    let first = plain_path.join("file.txt").metadata().unwrap();
+    let second = plain_path.join("sub").join("link.txt").metadata().unwrap();
+    assert_ne!(first.ino(), second.ino());
 
+    let dest_path = dir.path().join("dest");
     let out = run(&[
         "--driver",
         drv,
         "-r",
+        "--preserve=links",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-
-    assert!(dest_base.join("mydir/one/two/three/").exists());
-    assert!(dest_base.join("mydir/one/two/three/").is_dir());
+    let first = dest_path.join("file.txt").metadata().unwrap();
+    let second = dest_path.join("sub").join("link.txt").metadata().unwrap();
+    assert_eq!(first.ino(), second.ino());
+    assert_eq!(2, first.nlink());
+    assert!(file_contains(&dest_path.join("sub").join("link.txt"), "content").unwrap());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn copy_all_dirs_rel(drv: &str) {
+fn file_copy_auto_block_size(drv: &str) {
     let dir = tempdir_rel().unwrap();
-
-    let source_path = dir.path().join("mydir");
-    create_dir_all(&source_path).unwrap();
-    create_dir_all(source_path.join("one/two/three/")).unwrap();
-
-    let dest_base = dir.path().join("dest");
-    create_dir_all(&dest_base).unwrap();
+    let small_path = dir.path().join("small.bin");
+    let large_path = dir.path().join("large.bin");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&dest_path).unwrap();
+    write(&small_path, rand_data(1000)).unwrap();
+    write(&large_path, rand_data(3 * 1024 * 1024 + 17)).unwrap();
 
     let out = run(&[
         "--driver",
         drv,
-        "-r",
-        source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        "--auto-block-size",
+        small_path.to_str().unwrap(),
+        large_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
 
     assert!(out.status.success());
+    assert!(files_match(&small_path, &dest_path.join("small.bin")));
+    assert!(files_match(&large_path, &dest_path.join("large.bin")));
+}
 
-    assert!(dest_base.join("mydir/one/two/three/").exists());
-    assert!(dest_base.join("mydir/one/two/three/").is_dir());
+#[test]
+fn zero_block_size() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "content").unwrap();
+
+    let out = run(&[
+        "--block-size",
+        "0",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn copy_dirs_files(drv: &str) {
+fn partial_failure_exit_code(drv: &str) {
     let dir = tempdir_rel().unwrap();
+    let file_path = dir.path().join("file.txt");
+    let exists_path = dir.path().join("exists.txt");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&dest_path).unwrap();
+    create_file(&file_path, "content").unwrap();
+    create_file(&exists_path, "new").unwrap();
+    create_file(&dest_path.join("exists.txt"), "old").unwrap();
+
+    // Otherwise the copy of file.txt may be cut short by the
+    // failure.
+    let out = run(&[
+        "--driver",
+        drv,
+        "--no-clobber",
+        "--continue-on-error",
+        file_path.to_str().unwrap(),
+        exists_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
 
-    let source_path = dir.path().join("mydir");
-    create_dir_all(&source_path).unwrap();
-
-    let mut p = source_path.clone();
-    for d in ["one", "two", "three"].iter() {
-        p.push(d);
-        create_dir_all(&p).unwrap();
-        create_file(&p.join(format!("{}.txt", d)), d).unwrap();
-    }
+    assert_eq!(Some(23), out.status.code());
+    assert!(file_contains(&dest_path.join("file.txt"), "content").unwrap());
+    assert!(file_contains(&dest_path.join("exists.txt"), "old").unwrap());
+}
 
-    let dest_base = dir.path().join("dest");
-    create_dir_all(&dest_base).unwrap();
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_fsync(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    write(&source_path, rand_data(1024 * 1024)).unwrap();
 
     let out = run(&[
         "--driver",
         drv,
-        "-r",
+        "--block-size",
+        "64KiB",
+        "--fsync",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
 
     assert!(out.status.success());
-
-    assert!(dest_base.join("mydir/one/one.txt").is_file());
-    assert!(dest_base.join("mydir/one/two/two.txt").is_file());
-    assert!(dest_base.join("mydir/one/two/three/three.txt").is_file());
+    assert!(files_match(&source_path, &dest_path));
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(not(feature = "test_run_expensive"), ignore = "Stress test")]
-fn copy_generated_tree(drv: &str) {
-    // Spam some output to keep CI from timing-out (hopefully).
-    println!("Generating file tree...");
-    let src = gen_global_filetree(false).unwrap();
-
+fn dir_copy_fsync(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let dest = dir.path().join("target");
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    create_file(&source_path.join("sub").join("file.txt"), "sub content").unwrap();
 
-    println!("Running copy...");
     let out = run(&[
-        "--driver", drv,
+        "--driver",
+        drv,
         "-r",
-        src.to_str().unwrap(),
-        dest.to_str().unwrap(),
+        "--fsync",
+        "--atomic=always",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-    assert!(out.status.success());
 
-    println!("Compare trees...");
-    compare_trees(&src, &dest).unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("file.txt"), "content").unwrap());
+    assert!(file_contains(&dest_path.join("sub").join("file.txt"), "sub content").unwrap());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn copy_dirs_overwrites(drv: &str) {
+fn dir_copy_into_itself(drv: &str) {
     let dir = tempdir_rel().unwrap();
-
     let source_path = dir.path().join("mydir");
-    let source_file = source_path.join("file.txt");
     create_dir_all(&source_path).unwrap();
-    create_file(&source_file, "orig").unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
 
-    let dest_base = dir.path().join("dest");
-    create_dir_all(&dest_base).unwrap();
-    let dest_file = dest_base.join("mydir/file.txt");
+    for dest in [source_path.join("backup"), source_path.join("sub").join("backup")] {
+        let out = run(&[
+            "--driver",
+            drv,
+            "-r",
+            source_path.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ])
+        .unwrap();
 
-    let mut out = run(&[
-        "--driver", drv,
+        assert_eq!(Some(3), out.status.code());
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains("Cannot copy a directory into itself"));
+        assert!(!dest.exists());
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_into_itself_via_symlink(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let link_path = dir.path().join("link");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    symlink("mydir", &link_path).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
         "-r",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        link_path.join("backup").to_str().unwrap(),
     ])
     .unwrap();
 
-    assert!(out.status.success());
-    assert!(file_contains(&dest_file, "orig").unwrap());
+    assert_eq!(Some(3), out.status.code());
+    assert!(!source_path.join("backup").exists());
+}
 
-    write(&source_file, "new content").unwrap();
-    assert!(file_contains(&source_file, "new content").unwrap());
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_chmod(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "content").unwrap();
+    set_permissions(&source_path, Permissions::from_mode(0o700)).unwrap();
 
-    out = run(&[
+    for (mode, expected) in [("0644", 0o644), ("go=r,u-x", 0o644), ("a=rX", 0o555)] {
+        let out = run(&[
+            "--driver",
+            drv,
+            "--chmod",
+            mode,
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert!(out.status.success());
+        assert_eq!(expected, dest_path.metadata().unwrap().mode() & 0o7777, "{}", mode);
+        assert!(file_contains(&dest_path, "content").unwrap());
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_chmod(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "content").unwrap();
+    create_file(&source_path.join("sub").join("run.sh"), "#!/bin/sh").unwrap();
+    set_permissions(source_path.join("file.txt"), Permissions::from_mode(0o600)).unwrap();
+    set_permissions(source_path.join("sub").join("run.sh"), Permissions::from_mode(0o700)).unwrap();
+
+    let out = run(&[
         "--driver",
         drv,
         "-r",
+        "--chmod=u=rwX,go=rX",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
 
     assert!(out.status.success());
-    assert!(file_contains(&dest_file, "new content").unwrap());
-    assert!(files_match(&source_file, &dest_file));
+    let mode = |p: &std::path::Path| p.metadata().unwrap().mode() & 0o7777;
+    assert_eq!(0o755, mode(&dest_path));
+    assert_eq!(0o755, mode(&dest_path.join("sub")));
+    assert_eq!(0o644, mode(&dest_path.join("file.txt")));
+    assert_eq!(0o755, mode(&dest_path.join("sub").join("run.sh")));
 }
 
 #[test]
-/// when source path is a dir and target is a dir,
-/// using the `-no-target-directory` flag will copy the source
-/// dir _onto_ the target directory, instead of _under_ the
-/// target dir (default behavior)
-fn copy_dirs_overwrites_no_target_dir() {
-    // First pass default behavior
-    {
-        let dir = tempdir_rel().unwrap();
+fn chmod_invalid_mode() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "content").unwrap();
 
-        let source_path = dir.path().join("mydir");
-        let source_file = source_path.join("file.txt");
-        create_dir_all(&source_path).unwrap();
-        create_file(&source_file, "orig").unwrap();
+    let out = run(&[
+        "--chmod",
+        "u+q",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
 
-        let dest_base = dir.path().join("dest");
-        create_dir_all(&dest_base).unwrap();
-        let dest_file = dest_base.join("mydir/file.txt");
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
+}
 
-        let out = run(&[
-            "-r",
-            source_path.to_str().unwrap(),
-            dest_base.to_str().unwrap(),
-        ])
-        .unwrap();
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn dir_copy_tee(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_a = dir.path().join("a");
+    let dest_b = dir.path().join("b");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_dir_all(&dest_a).unwrap();
+    create_dir_all(&dest_b).unwrap();
+    create_file(&source_path.join("file.txt"), "file").unwrap();
+    write(source_path.join("sub/data.bin"), rand_data(1024 * 1024 + 123)).unwrap();
+    symlink("file.txt", source_path.join("link.txt")).unwrap();
 
-        assert!(out.status.success());
-        assert!(file_contains(&dest_file, "orig").unwrap());
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--block-size=64KB",
+        source_path.to_str().unwrap(),
+        dest_a.to_str().unwrap(),
+        "--tee",
+        dest_b.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    for dest in [&dest_a, &dest_b] {
+        assert!(file_contains(&dest.join("mydir/file.txt"), "file").unwrap());
+        assert!(files_match(&source_path.join("sub/data.bin"), &dest.join("mydir/sub/data.bin")));
+        assert_eq!("file.txt", dest.join("mydir/link.txt").read_link().unwrap().to_str().unwrap());
     }
+}
 
-    // Second pass `no target directory`
-    {
-        let dir = tempdir_rel().unwrap();
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn tee_continue_on_error(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_a = dir.path().join("a");
+    let dest_b = dir.path().join("b");
+    create_file(&source_path, "data").unwrap();
+    create_dir_all(&dest_a).unwrap();
+    // The copy to 'b' can't replace a directory.
+    create_dir_all(dest_b.join("source.txt")).unwrap();
+
+    let args = [
+        "--driver",
+        drv,
+        source_path.to_str().unwrap(),
+        dest_a.to_str().unwrap(),
+        "--tee",
+        dest_b.to_str().unwrap(),
+    ];
+    let out = run(&[&args[..], &["--continue-on-error"]].concat()).unwrap();
+    assert!(!out.status.success());
+    assert!(file_contains(&dest_a.join("source.txt"), "data").unwrap());
+    assert!(dest_b.join("source.txt").is_dir());
 
-        let source_path = dir.path().join("mydir");
-        let source_file = source_path.join("file.txt");
-        create_dir_all(&source_path).unwrap();
-        create_file(&source_file, "new content").unwrap();
+    std::fs::remove_file(dest_a.join("source.txt")).unwrap();
+    let out = run(&args).unwrap();
+    assert!(!out.status.success());
+}
 
-        let dest_base = dir.path().join("dest");
-        create_dir_all(&dest_base).unwrap();
-        let dest_file = dest_base.join("file.txt");
+#[test]
+fn tee_conflicts_with_delete() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
 
-        let out = run(&[
-            "-r",
-            "-T", //-no-target-directory
-            source_path.to_str().unwrap(),
-            dest_base.to_str().unwrap(),
-        ])
-        .unwrap();
+    let out = run(&[
+        "-r",
+        "--delete",
+        source_path.to_str().unwrap(),
+        dir.path().join("a").to_str().unwrap(),
+        "--tee",
+        dir.path().join("b").to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    assert!(!dir.path().join("a").exists());
+}
 
-        assert!(out.status.success());
-        assert!(file_contains(&dest_file, "new content").unwrap());
-    }
+#[test]
+fn json_progress_interval() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    write(&source_path, rand_data(64 * 1024)).unwrap();
+
+    let out = run(&[
+        "--progress=json",
+        "--progress-interval=60000",
+        "--block-size=1KB",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    // The copied bytes are held back until the end, which still has
+    // the full count.
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let records = stderr.lines()
+        .filter(|l| l.starts_with('{'))
+        .collect::<Vec<&str>>();
+    assert!(records.len() < 10);
+    assert!(records.last().unwrap().contains(r#""copied":65536,"#));
+}
+
+// The lsattr flags of `path`, or None if they are unsupported.
+fn lsattr(path: &Path) -> Option<String> {
+    let out = Command::new("lsattr").arg("-d").arg(path).output().ok()?;
+    let stdout = String::from_utf8(out.stdout).ok()?;
+    out.status.success().then(|| stdout.split_whitespace().next().unwrap_or_default().to_string())
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn dir_copy_to_nonexistent_is_rename(drv: &str) {
+fn dir_copy_preserve_flags(drv: &str) {
     let dir = tempdir_rel().unwrap();
-
     let source_path = dir.path().join("mydir");
-    let source_file = source_path.join("file.txt");
+    let dest_path = dir.path().join("dest");
     create_dir_all(&source_path).unwrap();
-    create_file(&source_file, "orig").unwrap();
-
-    let dest_base = dir.path().join("dest");
-    let dest_file = dest_base.join("file.txt");
+    create_file(&source_path.join("file.txt"), "file").unwrap();
+
+    // No-dump needs no privileges, and doesn't prevent cleaning up.
+    let chattr = Command::new("chattr")
+        .arg("+d")
+        .arg(&source_path)
+        .arg(source_path.join("file.txt"))
+        .status();
+    if !chattr.is_ok_and(|s| s.success()) {
+        println!("No inode flag support, skipping test");
+        return;
+    }
 
     let out = run(&[
         "--driver",
         drv,
         "-r",
+        "--preserve=flags",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-    assert!(dest_file.exists());
-    assert!(file_contains(&dest_file, "orig").unwrap());
+    assert!(lsattr(&dest_path).unwrap().contains('d'));
+    assert!(lsattr(&dest_path.join("file.txt")).unwrap().contains('d'));
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn dir_overwrite_with_noclobber(drv: &str) {
+#[test_case("sequential"; "Test with sequential driver")]
+fn dir_copy_sync_dest_dirs(drv: &str) {
     let dir = tempdir_rel().unwrap();
-
     let source_path = dir.path().join("mydir");
-    let source_file = source_path.join("file.txt");
-    create_dir_all(&source_path).unwrap();
-    create_file(&source_file, "orig").unwrap();
-
-    let dest_base = dir.path().join("dest");
-    create_dir_all(&dest_base).unwrap();
-    let dest_file = dest_base.join("mydir/file.txt");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("sub/file.txt"), "content").unwrap();
 
-    let mut out = run(&[
+    let out = run(&[
         "--driver",
         drv,
         "-r",
+        "--sync-dest-dirs",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-    assert!(file_contains(&dest_file, "orig").unwrap());
-    assert!(files_match(&source_file, &dest_file));
+    assert!(file_contains(&dest_path.join("sub/file.txt"), "content").unwrap());
+}
 
-    write(&source_file, "new content").unwrap();
-    assert!(file_contains(&source_file, "new content").unwrap());
+#[cfg_attr(feature = "parblock", test_case("parblock", "rw"; "Test read-write with parallel block driver"))]
+#[cfg_attr(feature = "parblock", test_case("parblock", "mmap"; "Test mmap with parallel block driver"))]
+#[test_case("parfile", "rw"; "Test read-write with parallel file driver")]
+#[test_case("parfile", "mmap"; "Test mmap with parallel file driver")]
+fn file_copy_method(drv: &str, method: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    write(&source_path, rand_data(1024 * 1024 + 4321)).unwrap();
 
-    out = run(&[
+    let out = run(&[
         "--driver",
         drv,
-        "-r",
-        "--no-clobber",
+        "--block-size=64KB",
+        "--copy-method",
+        method,
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
-    assert!(!out.status.success());
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
 }
 
+#[cfg(target_os = "linux")]
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
-fn dir_copy_containing_symlinks(drv: &str) {
+fn file_copy_capabilities(drv: &str) {
     let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    create_file(&source_path, "binary").unwrap();
+
+    // A version 2 capability set with cap_net_bind_service permitted
+    // and effective.
+    let caps = [1, 0, 0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    if xattr::set(&source_path, "security.capability", &caps).is_err() {
+        println!("Setting capabilities requires privileges, skipping test");
+        return;
+    }
 
-    let source_path = dir.path().join("mydir");
-    let source_file = source_path.join("file.txt");
-    let source_rlink = source_path.join("link.txt");
-    create_dir_all(&source_path).unwrap();
-    create_file(&source_file, "orig").unwrap();
-    symlink("file.txt", source_rlink).unwrap();
-    symlink("/etc/hosts", source_path.join("hosts")).unwrap();
-
-    let dest_base = dir.path().join("dest");
-    let dest_file = dest_base.join("file.txt");
-    let dest_rlink = dest_base.join("link.txt");
-
+    // The ownership is copied first, which would clear them.
     let out = run(&[
         "--driver",
         drv,
-        "-r",
+        "--ownership",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
+    assert!(out.status.success());
+    assert_eq!(caps.to_vec(), xattr::get(&dest_path, "security.capability").unwrap().unwrap());
+}
+
+#[test_case("always"; "Test with the bar forced")]
+#[test_case("never"; "Test with the bar disabled")]
+fn file_copy_progress_tty(mode: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "content").unwrap();
 
+    let out = run(&[
+        "--progress",
+        mode,
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
     assert!(out.status.success());
-    assert!(dest_file.exists());
-    assert!(dest_rlink
-        .symlink_metadata()
-        .unwrap()
-        .file_type()
-        .is_symlink());
-    assert!(dest_base
-        .join("hosts")
-        .symlink_metadata()
-        .unwrap()
-        .file_type()
-        .is_symlink());
+
+    // The captured stderr is not a terminal.
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let drawn = stderr.contains("\x1b[") || stderr.contains("[#");
+    assert_eq!(mode == "always", drawn);
+    assert_eq!(mode == "always", stderr.contains("Copied 1 files"));
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn dir_copy_with_hidden_dir(drv: &str) {
+#[test_case("sequential"; "Test with sequential driver")]
+fn dir_copy_dir_mode(drv: &str) {
     let dir = tempdir_rel().unwrap();
-
-    let source_path = dir.path().join("mydir/.hidden");
-    let source_file = source_path.join("file.txt");
-    create_dir_all(&source_path).unwrap();
-    create_file(&source_file, "orig").unwrap();
-
-    let dest_base = dir.path().join("dest/.hidden");
-    let dest_file = dest_base.join("file.txt");
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("sub/file.txt"), "content").unwrap();
+    set_permissions(&source_path, Permissions::from_mode(0o750)).unwrap();
+    // Read-only, so it can only be given its mode once filled.
+    set_permissions(source_path.join("sub"), Permissions::from_mode(0o555)).unwrap();
 
     let out = run(&[
         "--driver",
         drv,
         "-r",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-    assert!(dest_file.exists());
-    assert!(file_contains(&dest_file, "orig").unwrap());
-    assert!(files_match(&source_file, &dest_file));
+    let mode = |p: &Path| p.metadata().unwrap().mode() & 0o7777;
+    assert_eq!(0o750, mode(&dest_path));
+    assert_eq!(0o555, mode(&dest_path.join("sub")));
+    assert!(file_contains(&dest_path.join("sub/file.txt"), "content").unwrap());
+
+    // Allow the tree to be cleaned up.
+    set_permissions(source_path.join("sub"), Permissions::from_mode(0o755)).unwrap();
+    set_permissions(dest_path.join("sub"), Permissions::from_mode(0o755)).unwrap();
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn dir_with_gitignore(drv: &str) {
+fn dir_copy_dir_mode_no_perms(drv: &str) {
     let dir = tempdir_rel().unwrap();
-
     let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
     create_dir_all(&source_path).unwrap();
-
-    let source_file = source_path.join("file.txt");
-    create_file(&source_file, "file content").unwrap();
-
-    let ignore_file = source_path.join(".gitignore");
-    create_file(&ignore_file, "/.ignored\n").unwrap();
-
-    let ignored_path = dir.path().join("mydir/.ignored");
-    let ignored_file = ignored_path.join("file.txt");
-    create_dir_all(&ignored_path).unwrap();
-    create_file(&ignored_file, "ignored content").unwrap();
-
-    let hidden_path = dir.path().join("mydir/.hidden");
-    let hidden_file = hidden_path.join("file.txt");
-    create_dir_all(&hidden_path).unwrap();
-    create_file(&hidden_file, "hidden content").unwrap();
-
-    let dest_base = dir.path().join("dest");
+    set_permissions(&source_path, Permissions::from_mode(0o700)).unwrap();
 
     let out = run(&[
         "--driver",
         drv,
         "-r",
-        "--gitignore",
+        "--no-perms",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-    assert!(dest_base.join("file.txt").exists());
-    assert!(dest_base.join(".gitignore").exists());
-
-    assert!(!dest_base.join(".ignored").exists());
-
-    assert!(dest_base.join(".hidden").exists());
-    assert!(dest_base.join(".hidden/file.txt").exists());
+    assert_ne!(0o700, dest_path.metadata().unwrap().mode() & 0o7777);
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn copy_with_glob(drv: &str) {
+#[test_case("sequential"; "Test with sequential driver")]
+fn dir_copy_size_filter(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let dest = dir.path().join("dest");
-    create_dir_all(&dest).unwrap();
-
-    let (f1, f2) = (dir.path().join("file1.txt"), dir.path().join("file2.txt"));
-    create_file(&f1, "test").unwrap();
-    create_file(&f2, "test").unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    write(source_path.join("small.bin"), rand_data(10)).unwrap();
+    write(source_path.join("sub/medium.bin"), rand_data(2048)).unwrap();
+    write(source_path.join("sub/large.bin"), rand_data(8192)).unwrap();
 
     let out = run(&[
         "--driver",
         drv,
-        "--glob",
-        dir.path().join("file*.txt").to_str().unwrap(),
-        dest.to_str().unwrap(),
+        "-r",
+        "--min-size=1K",
+        "--max-size=4K",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-    assert!(dest.join("file1.txt").exists());
-    assert!(dest.join("file2.txt").exists());
+    assert!(!dest_path.join("small.bin").exists());
+    assert!(files_match(&source_path.join("sub/medium.bin"), &dest_path.join("sub/medium.bin")));
+    assert!(!dest_path.join("sub/large.bin").exists());
 }
 
-#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
-#[test_case("parfile"; "Test with parallel file driver")]
-fn copy_pattern_no_glob(drv: &str) {
+#[test]
+fn size_filter_bad_range() {
     let dir = tempdir_rel().unwrap();
-    let dest = dir.path().join("dest");
-    create_dir_all(&dest).unwrap();
-
-    let f1 = dir.path().join("a [b] c.txt");
-    create_file(&f1, "test").unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "data").unwrap();
 
     let out = run(&[
-        "--driver",
-        drv,
-        dir.path().join("a [b] c.txt").to_str().unwrap(),
-        dest.to_str().unwrap(),
+        "--min-size=2K",
+        "--max-size=1K",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
-    assert!(out.status.success());
-    assert!(dest.join("a [b] c.txt").exists());
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--min-size cannot be greater than --max-size"));
+    assert!(!dest_path.exists());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn glob_pattern_error(drv: &str) {
+fn copy_relative(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let dest = dir.path().join("dest");
-    create_dir_all(&dest).unwrap();
+    let source_path = dir.path().join("src");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("log").join("app")).unwrap();
+    create_dir_all(source_path.join("tree").join("sub")).unwrap();
+    create_file(&source_path.join("log").join("app").join("file.log"), "log").unwrap();
+    create_file(&source_path.join("tree").join("sub").join("file.txt"), "tree").unwrap();
+
+    let out = get_command().unwrap()
+        .current_dir(&source_path)
+        .args([
+            "--driver",
+            drv,
+            "-r",
+            "-R",
+            "log/app/file.log",
+            "./tree",
+            dest_path.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
 
-    let (f1, f2) = (dir.path().join("file1.txt"), dir.path().join("file2.txt"));
-    create_file(&f1, "test").unwrap();
-    create_file(&f2, "test").unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(file_contains(&dest_path.join("log").join("app").join("file.log"), "log").unwrap());
+    assert!(file_contains(&dest_path.join("tree").join("sub").join("file.txt"), "tree").unwrap());
+}
+
+#[test]
+fn copy_relative_absolute() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = canonicalize(dir.path()).unwrap().join("file.txt");
+    let dest_path = dir.path().join("dest");
+    create_file(&source_path, "content").unwrap();
 
     let out = run(&[
-        "--driver",
-        drv,
-        "--glob",
-        dir.path().join("file***.txt").to_str().unwrap(),
-        dest.to_str().unwrap(),
+        "--relative",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
 
-    assert!(!out.status.success());
-    let stderr = String::from_utf8(out.stderr).unwrap();
-    assert!(stderr.contains("Pattern syntax error"));
+    assert!(out.status.success());
+    let target = dest_path.join(source_path.strip_prefix("/").unwrap());
+    assert!(file_contains(&target, "content").unwrap());
 }
 
-#[cfg_attr(all(feature = "parblock", not(feature = "test_no_sockets")), test_case("parblock"; "Test with parallel block driver"))]
-#[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_sockets", ignore = "No FS support")]
-fn test_socket_file(drv: &str) {
-
+#[test]
+fn copy_relative_parent_dir() {
     let dir = tempdir_rel().unwrap();
-    let from = dir.path().join("from.sock");
-    let to = dir.path().join("to.sock");
-
-    let _sock = UnixListener::bind(&from).unwrap();
-    let ftype = from.metadata().unwrap().file_type();
-    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+    let source_path = dir.path().join("sub").join("..").join("file.txt");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(dir.path().join("sub")).unwrap();
+    create_file(&dir.path().join("file.txt"), "content").unwrap();
 
     let out = run(&[
-        "--driver", drv,
-        from.to_str().unwrap(),
-        to.to_str().unwrap(),
-    ]).unwrap();
-    assert!(out.status.success());
+        "-R",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
 
-    assert!(to.exists());
-    let ftype = to.metadata().unwrap().file_type();
-    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("cannot contain '..'"));
+    assert!(!dest_path.exists());
 }
 
-#[cfg_attr(all(feature = "parblock", not(feature = "test_no_sockets")), test_case("parblock"; "Test with parallel block driver"))]
-#[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_sockets", ignore = "No FS support")]
-fn test_sockets_dir(drv: &str) {
+#[test]
+fn file_copy_parents_dir_mode() {
     let dir = tempdir_rel().unwrap();
-    let src_dir = dir.path().join("fromdir");
-    create_dir_all(&src_dir).unwrap();
-
-    let from = src_dir.join("from.sock");
-    let _sock = UnixListener::bind(&from).unwrap();
-    let ftype = from.metadata().unwrap().file_type();
-    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
-
-    let to_dir = dir.path().join("todir");
-    let to = to_dir.join("from.sock");
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("a").join("b").join("dest.txt");
+    create_file(&source_path, "content").unwrap();
+    let before = dir.path().metadata().unwrap().mode();
 
     let out = run(&[
-        "--driver", drv,
-        "-r",
-        src_dir.to_str().unwrap(),
-        to_dir.to_str().unwrap(),
-    ]).unwrap();
-    assert!(out.status.success());
+        "--parents",
+        "--dir-mode=0700",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
 
-    assert!(to.exists());
-    let ftype = to.metadata().unwrap().file_type();
-    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "content").unwrap());
+    let mode = |p: &Path| p.metadata().unwrap().mode() & 0o7777;
+    assert_eq!(0o700, mode(&dir.path().join("a")));
+    assert_eq!(0o700, mode(&dir.path().join("a").join("b")));
+    assert_eq!(before, dir.path().metadata().unwrap().mode());
 }
 
-#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
-fn unreadable_file_error(drv: &str) {
+fn dir_copy_relative_dir_mode(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let source_path = dir.path().join("source.txt");
-    let dest_path = dir.path().join("dest.txt");
-    let text = "This is a test file.";
+    let source_path = dir.path().join("src");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("a").join("tree")).unwrap();
+    create_file(&source_path.join("a").join("tree").join("file.txt"), "content").unwrap();
+    set_permissions(source_path.join("a").join("tree"), Permissions::from_mode(0o711)).unwrap();
+
+    let out = get_command().unwrap()
+        .current_dir(&source_path)
+        .args([
+            "--driver",
+            drv,
+            "-r",
+            "-R",
+            "--dir-mode=u=rwx,g=rx,o=",
+            "a/tree",
+            dest_path.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
 
-    create_file(&source_path, text).unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let mode = |p: &Path| p.metadata().unwrap().mode() & 0o7777;
+    assert_eq!(0o750, mode(&dest_path));
+    assert_eq!(0o750, mode(&dest_path.join("a")));
+    // Copied directories keep the source's mode.
+    assert_eq!(0o711, mode(&dest_path.join("a").join("tree")));
+    assert!(file_contains(&dest_path.join("a").join("tree").join("file.txt"), "content").unwrap());
+}
 
-    let perms = Permissions::from_mode(0);
-    set_permissions(&source_path, perms).unwrap();
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn dir_copy_max_total_size(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    let files = ["one.bin", "two.bin", "sub/three.bin"];
+    for file in files {
+        write(source_path.join(file), rand_data(4096)).unwrap();
+    }
 
     let out = run(&[
         "--driver",
         drv,
+        "-r",
+        "--max-total-size=10K",
         source_path.to_str().unwrap(),
         dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("after queuing 8192 bytes; 1 files of 4096 bytes were not copied"));
+
+    let copied = files.iter()
+        .filter(|file| dest_path.join(file).exists())
+        .inspect(|file| assert!(files_match(&source_path.join(file), &dest_path.join(file))))
+        .count();
+    assert_eq!(2, copied);
+    assert!(dest_path.join("sub").is_dir());
 }
 
-#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
-fn dest_file_exists_not_writable(drv: &str) {
+#[test_case("sequential"; "Test with sequential driver")]
+fn dir_copy_no_recursive(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let source_path = dir.path().join("source.txt");
-    let dest_path = dir.path().join("dest.txt");
-
-    {
-        create_file(&source_path, "falskjdfa;lskdjfa").unwrap();
-        File::create(&dest_path).unwrap();
-    }
-    set_permissions(&dest_path, Permissions::from_mode(0)).unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub/deeper")).unwrap();
+    create_file(&source_path.join("top.txt"), "top").unwrap();
+    create_file(&source_path.join("sub/inner.txt"), "inner").unwrap();
+    symlink("top.txt", source_path.join("link")).unwrap();
 
     let out = run(&[
         "--driver",
         drv,
+        "-d",
         source_path.to_str().unwrap(),
         dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
-    assert!(!out.status.success());
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("top.txt"), "top").unwrap());
+    assert!(dest_path.join("link").is_symlink());
+    assert!(dest_path.join("sub").is_dir());
+    assert_eq!(0, dest_path.join("sub").read_dir().unwrap().count());
 }
 
-#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
-#[test_case("parfile"; "Test with parallel file driver")]
-fn copy_dirs_backup(drv: &str) {
+#[test]
+fn no_recursive_conflicts_with_recursive() {
     let dir = tempdir_rel().unwrap();
-
     let source_path = dir.path().join("mydir");
-    let source_file = source_path.join("file.txt");
+    let dest_path = dir.path().join("dest");
     create_dir_all(&source_path).unwrap();
-    create_file(&source_file, "orig").unwrap();
-
-    let dest_base = dir.path().join("dest");
-    create_dir_all(&dest_base).unwrap();
-    let dummy = dest_base.join("mydir/dummy_dir"); // Non-file member test
-    create_dir_all(&dummy).unwrap();
-    let dest_file = dest_base.join("mydir/file.txt");
 
-    let mut out = run(&[
-        "--driver", drv,
+    let out = run(&[
+        "-d",
         "-r",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
+}
 
-    assert!(out.status.success());
-    assert!(file_contains(&dest_file, "orig").unwrap());
-
-    // -- //
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn dir_copy_dedup(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    let data = rand_data(64 * 1024);
+    for file in ["one.bin", "two.bin", "sub/three.bin"] {
+        write(source_path.join(file), &data).unwrap();
+    }
+    write(source_path.join("other.bin"), rand_data(64 * 1024)).unwrap();
 
-    out = run(&[
-        "--driver", drv,
+    // A single worker, so that each copy completes before the next
+    // duplicate is found.
+    let out = run(&[
+        "--driver",
+        drv,
+        "--workers=1",
+        "--reflink=never",
+        "--dedup",
         "-r",
-        "--backup=auto",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
     assert!(out.status.success());
 
-    let backup1 = dest_base.join("mydir/file.txt.~1~");
-    assert!(!backup1.exists());
+    for file in ["one.bin", "two.bin", "sub/three.bin", "other.bin"] {
+        assert!(files_match(&source_path.join(file), &dest_path.join(file)));
+    }
+    let ino = |file: &str| dest_path.join(file).metadata().unwrap().ino();
+    assert_eq!(ino("one.bin"), ino("two.bin"));
+    assert_eq!(ino("one.bin"), ino("sub/three.bin"));
+    assert_ne!(ino("one.bin"), ino("other.bin"));
+    assert_eq!(3, dest_path.join("one.bin").metadata().unwrap().nlink());
+}
 
-    write(&source_file, "new content").unwrap();
-    assert!(file_contains(&source_file, "new content").unwrap());
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn dir_copy_dirs_only(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub/deeper")).unwrap();
+    create_file(&source_path.join("top.txt"), "top").unwrap();
+    create_file(&source_path.join("sub/deeper/inner.txt"), "inner").unwrap();
+    symlink("top.txt", source_path.join("link")).unwrap();
+    set_permissions(source_path.join("sub"), Permissions::from_mode(0o750)).unwrap();
 
-    out = run(&[
-        "--driver", drv,
+    let out = run(&[
+        "--driver",
+        drv,
         "-r",
-        "--backup=numbered",
+        "--dirs-only",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-    assert!(file_contains(&dest_file, "new content").unwrap());
-    assert!(backup1.exists());
-    assert!(files_match(&source_file, &dest_file));
+    assert!(dest_path.join("sub/deeper").is_dir());
+    assert_eq!(0o750, dest_path.join("sub").metadata().unwrap().permissions().mode() & 0o777);
+    assert!(!dest_path.join("top.txt").exists());
+    assert!(dest_path.join("link").symlink_metadata().is_err());
+    assert_eq!(0, dest_path.join("sub/deeper").read_dir().unwrap().count());
+}
 
-    // -- //
-    let backup2 = dest_base.join("mydir/file.txt.~2~");
-    assert!(!backup2.exists());
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn file_copy_same_file(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let hardlink = dir.path().join("hardlink.txt");
+    let softlink = dir.path().join("softlink.txt");
+    create_file(&source_path, "original data").unwrap();
+    std::fs::hard_link(&source_path, &hardlink).unwrap();
+    symlink("source.txt", &softlink).unwrap();
 
-    write(&source_file, "new content 2").unwrap();
-    assert!(file_contains(&source_file, "new content 2").unwrap());
+    for dest_path in [&source_path, &hardlink, &softlink] {
+        let out = run(&[
+            "--driver",
+            drv,
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(Some(3), out.status.code());
+        assert!(String::from_utf8(out.stderr).unwrap().contains("are the same file"));
+        assert!(file_contains(&source_path, "original data").unwrap());
+    }
+}
 
-    out = run(&[
-        "--driver", drv,
-        "-r",
-        "--backup=auto",
+#[test]
+fn file_copy_numeric_ids() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "numeric").unwrap();
+
+    let out = run(&[
+        "--ownership",
+        "--numeric-ids",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
-
     assert!(out.status.success());
-    assert!(file_contains(&dest_file, "new content 2").unwrap());
-    assert!(backup1.exists());
-    assert!(backup2.exists());
-    assert!(files_match(&source_file, &dest_file));
+
+    let smeta = source_path.metadata().unwrap();
+    let dmeta = dest_path.metadata().unwrap();
+    assert_eq!(smeta.uid(), dmeta.uid());
+    assert_eq!(smeta.gid(), dmeta.gid());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
-fn dir_copy_deref_symlinks(drv: &str) {
+#[test_case("sequential"; "Test with sequential driver")]
+fn dir_copy_rename(drv: &str) {
     let dir = tempdir_rel().unwrap();
-
     let source_path = dir.path().join("mydir");
-    let source_file = source_path.join("file.txt");
-    let source_rlink = source_path.join("link.txt");
-    create_dir_all(&source_path).unwrap();
-    create_file(&source_file, "orig").unwrap();
-    symlink("file.txt", source_rlink).unwrap();
-    symlink("/etc/hosts", source_path.join("hosts")).unwrap();
-
-    let dest_base = dir.path().join("dest");
-    let dest_file = dest_base.join("file.txt");
-    let dest_rlink = dest_base.join("link.txt");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_dir_all(&dest_path).unwrap();
+    create_file(&source_path.join("top.txt"), "top").unwrap();
+    create_file(&source_path.join("sub/inner.txt"), "inner").unwrap();
 
     let out = run(&[
-        "--driver", drv,
-        "--recursive",
-        "--dereference",
+        "--driver",
+        drv,
+        "-r",
+        "--rename",
+        "s/$/.bak/",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
     ])
     .unwrap();
+    assert!(out.status.success());
+    let renamed = dest_path.join("mydir.bak");
+    assert!(file_contains(&renamed.join("top.txt.bak"), "top").unwrap());
+    assert!(file_contains(&renamed.join("sub.bak/inner.txt.bak"), "inner").unwrap());
+    assert!(!dest_path.join("mydir").exists());
+    assert!(!renamed.join("sub").exists());
+}
+
+#[test]
+fn file_copy_rename_keeps_target() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "data").unwrap();
 
+    let out = run(&[
+        "--rename",
+        "s/txt/bak/",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
     assert!(out.status.success());
-    assert!(dest_file.exists());
-    assert!(! dest_rlink
-        .symlink_metadata()
-        .unwrap()
-        .file_type()
-        .is_symlink());
-    assert!(! dest_base
-        .join("hosts")
-        .symlink_metadata()
-        .unwrap()
-        .file_type()
-        .is_symlink());
+    assert!(file_contains(&dest_path, "data").unwrap());
 }
 
-#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
-#[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
-fn test_nested_symlinks(drv: &str) {
+#[test]
+fn dir_copy_remote() {
     let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("top.txt"), "top").unwrap();
+    create_file(&source_path.join("sub/inner.txt"), "inner").unwrap();
+    set_permissions(source_path.join("top.txt"), Permissions::from_mode(0o640)).unwrap();
+    symlink("top.txt", source_path.join("link")).unwrap();
+
+    // A remote shell that runs the command locally, with this xcp.
+    let bin = dir.path().join("bin");
+    create_dir_all(&bin).unwrap();
+    symlink(env!("CARGO_BIN_EXE_xcp"), bin.join("xcp")).unwrap();
+    let rsh = bin.join("fake-ssh");
+    write(&rsh, "#!/bin/sh\nshift\nexec sh -c \"$*\"\n").unwrap();
+    set_permissions(&rsh, Permissions::from_mode(0o755)).unwrap();
+    let path = format!("{}:{}", bin.to_str().unwrap(), std::env::var("PATH").unwrap());
+
+    let out = get_command()
+        .unwrap()
+        .env("PATH", path)
+        .args([
+            "-r",
+            "--rsh",
+            rsh.to_str().unwrap(),
+            source_path.to_str().unwrap(),
+            &format!("somehost:{}", dest_path.to_str().unwrap()),
+        ])
+        .output()
+        .unwrap();
+    println!("STDERR: {}", String::from_utf8_lossy(&out.stderr));
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("top.txt"), "top").unwrap());
+    assert!(file_contains(&dest_path.join("sub/inner.txt"), "inner").unwrap());
+    assert_eq!(0o640, dest_path.join("top.txt").metadata().unwrap().permissions().mode() & 0o777);
+    assert_eq!(Path::new("top.txt"), std::fs::read_link(dest_path.join("link")).unwrap());
+    assert!(timestamps_same(
+        &source_path.join("top.txt").metadata().unwrap().modified().unwrap(),
+        &dest_path.join("top.txt").metadata().unwrap().modified().unwrap()));
+}
 
+#[test]
+fn dir_copy_remote_unsupported() {
+    let dir = tempdir_rel().unwrap();
     let source_path = dir.path().join("mydir");
     create_dir_all(&source_path).unwrap();
-
-    let file = source_path.join("link-0.txt");
-    create_file(&file, "data").unwrap();
-
-    for i in 1..10 {
-        let from = source_path.join(format!("link-{}.txt", i-1));
-        let to = source_path.join(format!("link-{}.txt", i));
-        symlink(from, to).unwrap();
+    let dest = format!("somehost:{}", dir.path().join("dest").to_str().unwrap());
+
+    for (arg, option) in [
+        ("--exclude=*.tmp", "--exclude and --include are"),
+        ("--verify", "--verify is"),
+        ("--max-depth=1", "--max-depth is"),
+        ("--backup=simple", "--backup is"),
+    ] {
+        let out = run(&["-r", arg, source_path.to_str().unwrap(), &dest]).unwrap();
+        assert_eq!(Some(1), out.status.code());
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains(&format!("{} not supported with a remote destination.", option)), "{}", stderr);
     }
+}
 
-    let dest_base = dir.path().join("dest");
-
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn dir_copy_types(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub/deeper")).unwrap();
+    create_dir_all(source_path.join("empty")).unwrap();
+    create_file(&source_path.join("top.txt"), "top").unwrap();
+    create_file(&source_path.join("sub/deeper/inner.txt"), "inner").unwrap();
+    symlink("top.txt", source_path.join("link")).unwrap();
+    symlink("inner.txt", source_path.join("sub/deeper/link")).unwrap();
+
+    // Only files, so just their parents are created.
+    let dest_path = dir.path().join("files");
     let out = run(&[
-        "--driver", drv,
-        "--recursive",
-        "--dereference",
+        "--driver",
+        drv,
+        "-r",
+        "--type",
+        "f",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
-    ]).unwrap();
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path.join("top.txt"), "top").unwrap());
+    assert!(file_contains(&dest_path.join("sub/deeper/inner.txt"), "inner").unwrap());
+    assert!(dest_path.join("link").symlink_metadata().is_err());
+    assert!(dest_path.join("sub/deeper/link").symlink_metadata().is_err());
+    assert!(!dest_path.join("empty").exists());
+
+    // Links and directories, so empty directories are created too.
+    let dest_path = dir.path().join("links");
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--type",
+        "l,d",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
     assert!(out.status.success());
+    assert!(dest_path.join("link").is_symlink());
+    assert!(dest_path.join("sub/deeper/link").is_symlink());
+    assert!(dest_path.join("empty").is_dir());
+    assert!(!dest_path.join("top.txt").exists());
+    assert!(!dest_path.join("sub/deeper/inner.txt").exists());
+}
 
-    for i in 1..10 {
-        let dest_link = dest_base.join(format!("link-{}.txt", i));
-        assert!(dest_link.exists());
-        assert!(!dest_link.is_symlink());
-        assert!(dest_link.is_file());
-        assert!(file_contains(&dest_link, "data").unwrap());
-    }
+#[test]
+fn dir_copy_types_dirs_only() {
+    let out = run(&["-r", "--dirs-only", "--type", "d", "src", "dest"]).unwrap();
+    assert!(!out.status.success());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
-fn test_deep_symlinks(drv: &str) {
+#[test_case("sequential"; "Test with sequential driver")]
+fn file_copy_checksum(drv: &str) {
     let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "source").unwrap();
+    create_file(&dest_path, "target").unwrap();
+    // '--update' alone would skip the older source.
+    set_time_past(&source_path).unwrap();
 
-    let source_path = dir.path().join("mydir");
-    create_dir_all(&source_path).unwrap();
-
-    let file = source_path.join("link-0.txt");
-    create_file(&file, "data").unwrap();
-
-    for i in 1..100 {
-        let from = source_path.join(format!("link-{}.txt", i-1));
-        let to = source_path.join(format!("link-{}.txt", i));
-        symlink(from, to).unwrap();
-    }
+    let copy = |extra: &[&str]| {
+        let mut args = vec!["--driver", drv, "--checksum", "--update"];
+        args.extend_from_slice(extra);
+        args.push(source_path.to_str().unwrap());
+        args.push(dest_path.to_str().unwrap());
+        run(&args).unwrap()
+    };
+    assert!(copy(&[]).status.success());
+    assert!(file_contains(&dest_path, "source").unwrap());
+
+    // Matching contents are skipped whatever their timestamps.
+    set_time_past(&dest_path).unwrap();
+    let out = copy(&["--dry-run"]);
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(!stdout.lines().any(|l| l.starts_with("copy ")), "{}", stdout);
+}
 
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[test_case("sequential"; "Test with sequential driver")]
+fn dir_copy_link_and_special_failure(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
     let dest_base = dir.path().join("dest");
-
-    let out = run(&[
-        "--driver", drv,
-        "--recursive",
-        "--dereference",
-        source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
-    ]).unwrap();
-
-    assert!(!out.status.success());
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "data").unwrap();
+    symlink("file.txt", source_path.join("link")).unwrap();
+    assert!(Command::new("mkfifo").arg(source_path.join("fifo")).status().unwrap().success());
+    // Non-empty directories can't be replaced by either.
+    create_dir_all(dest_base.join("mydir/link/sub")).unwrap();
+    create_dir_all(dest_base.join("mydir/fifo/sub")).unwrap();
+
+    let copy = |extra: &[&str]| {
+        let mut args = vec!["--driver", drv, "-r"];
+        args.extend_from_slice(extra);
+        args.push(source_path.to_str().unwrap());
+        args.push(dest_base.to_str().unwrap());
+        run(&args).unwrap()
+    };
+    let out = copy(&[]);
+    assert_eq!(Some(23), out.status.code());
+
+    let out = copy(&["--continue-on-error"]);
+    assert_eq!(Some(23), out.status.code());
     let stderr = String::from_utf8(out.stderr).unwrap();
-    assert!(stderr.contains("Too many levels of symbolic links"));
+    assert!(stderr.contains("2 files failed to copy"), "{}", stderr);
+    // The failures are listed by source, so they can be retried.
+    assert!(stderr.contains(&format!("  {:?}", source_path.join("link"))), "{}", stderr);
+    assert!(stderr.contains(&format!("  {:?}", source_path.join("fifo"))), "{}", stderr);
+    assert!(file_contains(&dest_base.join("mydir/file.txt"), "data").unwrap());
 }
@@ -18,7 +18,7 @@ mod util;
 
 #[cfg(all(target_os = "linux", feature = "use_linux"))]
 mod test {
-    use std::{process::Command, fs::{File, OpenOptions}, io::SeekFrom};
+    use std::{process::Command, fs::{create_dir_all, File, OpenOptions}, io::SeekFrom};
     use std::io::{Seek, Write};
     use libfs::{map_extents, sync};
     use test_case::test_case;
@@ -37,14 +37,14 @@ mod test {
         {
             let mut infd = File::create(&source_path).unwrap();
             let data = rand_data(size);
-            infd.write(&data).unwrap();
+            infd.write_all(&data).unwrap();
         }
 
         {
             let infd = File::open(&source_path).unwrap();
             let inext = map_extents(&infd).unwrap().unwrap();
             // Single file, extent not shared.
-            assert_eq!(false, inext[0].shared);
+            assert!(!inext[0].shared);
         }
 
         let out = run(&[
@@ -65,8 +65,8 @@ mod test {
             // Extents should be shared.
             let inext = map_extents(&infd).unwrap().unwrap();
             let outext = map_extents(&outfd).unwrap().unwrap();
-            assert_eq!(true, inext[0].shared);
-            assert_eq!(true, outext[0].shared);
+            assert!(inext[0].shared);
+            assert!(outext[0].shared);
         }
 
         {
@@ -77,7 +77,7 @@ mod test {
                 .open(&dest_path).unwrap();
             outfd.seek(SeekFrom::Start(0)).unwrap();
             let data = rand_data(size);
-            outfd.write(&data).unwrap();
+            outfd.write_all(&data).unwrap();
             // brtfs at least seems to need this to force CoW and
             // de-share the extents.
             sync(&outfd).unwrap();
@@ -89,12 +89,142 @@ mod test {
             // First extent should now be un-shared.
             let inext = map_extents(&infd).unwrap().unwrap();
             let outext = map_extents(&outfd).unwrap().unwrap();
-            assert_eq!(false, inext[0].shared);
-            assert_eq!(false, outext[0].shared);
+            assert!(!inext[0].shared);
+            assert!(!outext[0].shared);
         }
 
     }
 
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(not(feature = "test_no_reflink"), ignore = "Requires FS without reflink support")]
+    fn dir_copy_warn_no_reflink(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source_path = dir.path().join("mydir");
+        let dest_path = dir.path().join("dest");
+        create_dir_all(&source_path).unwrap();
+        create_file(&source_path.join("a.txt"), "a").unwrap();
+        create_file(&source_path.join("b.txt"), "b").unwrap();
+
+        let out = run(&[
+            "--driver", drv,
+            "-r",
+            "--warn-no-reflink",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+            .unwrap();
+
+        assert!(out.status.success());
+        // Logging goes to stdout.
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        assert!(stdout.contains(&format!("Could not reflink {:?}", source_path.join("a.txt"))));
+        assert!(stdout.contains("2 files copied without reflink"));
+
+        // Silent by default.
+        let out = run(&[
+            "--driver", drv,
+            "-r",
+            source_path.to_str().unwrap(),
+            dir.path().join("dest2").to_str().unwrap(),
+        ])
+            .unwrap();
+        assert!(!String::from_utf8(out.stdout).unwrap().contains("reflink"));
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_reflink", ignore = "No FS support")]
+    fn file_copy_reflink_summary(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source_path = dir.path().join("source.bin");
+        let dest_path = dir.path().join("dest.bin");
+        File::create(&source_path).unwrap().write_all(&rand_data(128 * 1024)).unwrap();
+
+        let out = run(&[
+            "--driver", drv,
+            "--reflink=always",
+            "--progress=json",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+            .unwrap();
+
+        assert!(out.status.success());
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        let summary = stderr.lines().find(|l| l.starts_with(r#"{"type":"summary""#)).unwrap();
+        assert!(summary.contains(r#""copied":0,"reflinked":131072,"files":1,"reflinked_files":1,"#));
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(not(feature = "test_no_reflink"), ignore = "Requires FS without reflink support")]
+    fn file_copy_reflink_always_unsupported(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source_path = dir.path().join("source.bin");
+        let dest_path = dir.path().join("dest.bin");
+        File::create(&source_path).unwrap().write_all(&rand_data(128 * 1024)).unwrap();
+
+        let out = run(&[
+            "--driver", drv,
+            "--reflink=always",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+            .unwrap();
+
+        assert!(!out.status.success());
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains("does not support reflinks"));
+        assert!(!dest_path.exists());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(not(feature = "test_no_reflink"), ignore = "Requires FS without reflink support")]
+    fn file_copy_atomic_failure_keeps_dest(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source_path = dir.path().join("source.bin");
+        let dest_path = dir.path().join("dest.bin");
+        File::create(&source_path).unwrap().write_all(&rand_data(128 * 1024)).unwrap();
+        File::create(&dest_path).unwrap().write_all(b"old content").unwrap();
+
+        let out = run(&[
+            "--driver", drv,
+            "--atomic",
+            "--reflink=always",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+            .unwrap();
+
+        assert!(!out.status.success());
+        assert!(file_contains(&dest_path, "old content").unwrap());
+        assert_eq!(2, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(not(feature = "test_no_reflink"), ignore = "Requires FS without reflink support")]
+    fn file_copy_failure_keeps_source(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source_path = dir.path().join("source.bin");
+        let dest_path = dir.path().join("dest.bin");
+        File::create(&source_path).unwrap().write_all(&rand_data(128 * 1024)).unwrap();
+
+        let out = run(&[
+            "--driver", drv,
+            "--remove-source-files",
+            "--reflink=always",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+            .unwrap();
+
+        assert!(!out.status.success());
+        assert!(source_path.exists());
+    }
+
     #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
     #[test_case("parfile"; "Test with parallel file driver")]
     #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
@@ -126,6 +256,172 @@ mod test {
         assert_eq!(from_data, to_data);
     }
 
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_sparse_verify(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("target.bin");
+
+        create_sparse(&from, 0, 0).unwrap();
+
+        let out = run(&[
+            "--driver",
+            drv,
+            "--verify",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert!(probably_sparse(&to).unwrap());
+        assert!(files_match(&from, &to));
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[test_case("sequential"; "Test with sequential driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_sparse_progress_total(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("target.bin");
+
+        let slen = create_sparse(&from, 0, 0).unwrap();
+
+        let out = run(&[
+            "--driver",
+            drv,
+            "--progress=json",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        // The total is of the data alone, so matches what was copied.
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        let summary = stderr.lines().find(|l| l.starts_with(r#"{"type":"summary""#)).unwrap();
+        let field = |name: &str| -> u64 {
+            let prefix = format!(r#""{}":"#, name);
+            summary.split(&prefix).nth(1).unwrap().split(',').next().unwrap().parse().unwrap()
+        };
+        assert!(field("total") < slen / 100);
+        assert_eq!(field("total"), field("copied"));
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_sparse_always(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("zeros.bin");
+        let to = dir.path().join("target.bin");
+
+        {
+            let mut data = vec![0u8; 1024 * 1024];
+            data[0] = 1;
+            File::create(&from).unwrap().write_all(&data).unwrap();
+        }
+        assert!(!probably_sparse(&from).unwrap());
+
+        let out = run(&[
+            "--driver",
+            drv,
+            "--sparse=always",
+            "--block-size",
+            "256KiB",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert!(probably_sparse(&to).unwrap());
+        assert!(files_match(&from, &to));
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_sparse_always_zero_runs(drv: &str) {
+        use std::fs::read;
+
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("zeros.bin");
+        let to = dir.path().join("target.bin");
+
+        // Zeros either side of the data, and at the end of a block.
+        let mut data = vec![0u8; 1024 * 1024];
+        data[300 * 1024] = 1;
+        data[(512 * 1024) - 1] = 1;
+        File::create(&from).unwrap().write_all(&data).unwrap();
+
+        let out = run(&[
+            "--driver",
+            drv,
+            "--sparse=always",
+            "--block-size",
+            "256KiB",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert!(probably_sparse(&to).unwrap());
+        assert_eq!(data.len() as u64, to.metadata().unwrap().len());
+        assert_eq!(data, read(&to).unwrap());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_sparse_never(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("target.bin");
+
+        create_sparse(&from, 0, 0).unwrap();
+        assert!(probably_sparse(&from).unwrap());
+
+        let out = run(&[
+            "--driver",
+            drv,
+            "--sparse=never",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert!(!probably_sparse(&to).unwrap());
+        assert!(files_match(&from, &to));
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_no_punch_holes(drv: &str) {
+        use std::fs::read;
+
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("target.bin");
+
+        create_sparse(&from, 1024, 1024).unwrap();
+        assert!(probably_sparse(&from).unwrap());
+
+        let out = run(&[
+            "--driver",
+            drv,
+            "--no-punch-holes",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert!(!probably_sparse(&to).unwrap());
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+    }
+
     #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
     #[test_case("parfile"; "Test with parallel file driver")]
     #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
@@ -274,4 +570,75 @@ mod test {
         println!("Compare trees...");
         compare_trees(&src, &dest).unwrap();
     }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_acl", ignore = "No FS support")]
+    fn dir_copy_preserve_acl(drv: &str) {
+        use exacl::{getfacl, setfacl, AclEntry, AclOption, Flag, Perm};
+
+        let dir = tempdir_rel().unwrap();
+        let source_path = dir.path().join("mydir");
+        let dest_path = dir.path().join("dest");
+        let file_path = source_path.join("file.txt");
+        std::fs::create_dir_all(&source_path).unwrap();
+        create_file(&file_path, "content").unwrap();
+
+        let file_entry = AclEntry::allow_user("mail", Perm::READ, None);
+        let mut acl = getfacl(&file_path, None).unwrap();
+        acl.push(file_entry.clone());
+        acl.push(AclEntry::allow_mask(Perm::READ, None));
+        setfacl(&[&file_path], &acl, None).unwrap();
+
+        let default_entry = AclEntry::allow_user("mail", Perm::READ | Perm::EXECUTE, Flag::DEFAULT);
+        let mut acl = getfacl(&source_path, None).unwrap();
+        acl.extend(exacl::from_mode(0o755).into_iter().map(|mut e| {
+            e.flags = Flag::DEFAULT;
+            e
+        }));
+        acl.push(default_entry.clone());
+        acl.push(AclEntry::allow_mask(Perm::READ | Perm::EXECUTE, Flag::DEFAULT));
+        setfacl(&[&source_path], &acl, None).unwrap();
+
+        let out = run(&[
+            "--driver", drv,
+            "-r",
+            "--no-xattr",
+            "--preserve=acl",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert!(getfacl(dest_path.join("file.txt"), None).unwrap().contains(&file_entry));
+        assert!(getfacl(&dest_path, AclOption::DEFAULT_ACL).unwrap().contains(&default_entry));
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_acl", ignore = "No FS support")]
+    fn file_copy_no_preserve_acl(drv: &str) {
+        use exacl::{getfacl, setfacl, AclEntry, Perm};
+
+        let dir = tempdir_rel().unwrap();
+        let source_path = dir.path().join("source.txt");
+        let dest_path = dir.path().join("dest.txt");
+        create_file(&source_path, "content").unwrap();
+
+        let entry = AclEntry::allow_user("mail", Perm::READ, None);
+        let mut acl = getfacl(&source_path, None).unwrap();
+        acl.push(entry.clone());
+        acl.push(AclEntry::allow_mask(Perm::READ, None));
+        setfacl(&[&source_path], &acl, None).unwrap();
+
+        let out = run(&[
+            "--driver", drv,
+            "--no-xattr",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert!(!getfacl(&dest_path, None).unwrap().contains(&entry));
+    }
 }